@@ -0,0 +1,47 @@
+//! The transactions as Sbanken sent them, attached as files to the Firefly transactions.
+
+use anyhow::{Context, Result};
+use reqwest::Method;
+use sbanken::models::TransactionV1;
+use serde::Serialize;
+
+use crate::firefly::{self, Resource, Single};
+use crate::sync::Bridge;
+
+#[derive(Serialize)]
+struct AttachmentStore<'a> {
+    filename: &'a str,
+    attachable_type: &'a str,
+    attachable_id: &'a str,
+    title: &'a str,
+}
+
+/// Attach `sbanken_transaction` as JSON to the Firefly transaction `transaction_id`.
+pub async fn attach_source(
+    bridge: &Bridge,
+    transaction_id: &str,
+    sbanken_transaction: &TransactionV1,
+) -> Result<()> {
+    let source = serde_json::to_vec_pretty(sbanken_transaction)?;
+    let journal_id = firefly::journal_id(&bridge.firefly_config, transaction_id).await?;
+    let filename = format!("sbanken-{}.json", journal_id);
+
+    let created: Single<Resource> = firefly::send(
+        &bridge.firefly_config,
+        Method::POST,
+        "/attachments",
+        &AttachmentStore {
+            filename: &filename,
+            attachable_type: "TransactionJournal",
+            attachable_id: &journal_id,
+            title: "Sbanken transaction",
+        },
+    )
+    .await
+    .context("unable to create attachment")?;
+
+    let path = format!("/attachments/{}/upload", created.data.id);
+    firefly::upload(&bridge.firefly_config, &path, source)
+        .await
+        .context("unable to upload attachment")
+}
@@ -0,0 +1,121 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use secrecy::{ExposeSecret, Secret};
+use serde::Deserialize;
+use tracing::{info_span, Instrument};
+
+use crate::error::SyncError;
+use crate::http;
+use crate::retry;
+use crate::throttle::Api;
+
+/// Renew a token this long before it runs out, so that it does not run out mid-request.
+const EXPIRY_MARGIN: i64 = 60;
+
+/// What is needed to get a new Sbanken token when the current one runs out.
+#[derive(Debug, Clone)]
+pub struct Login {
+    pub auth_url: String,
+    pub client_id: Secret<String>,
+    pub client_secret: Secret<String>,
+}
+
+/// A Sbanken access token and when it runs out, if Sbanken said so.
+#[derive(Debug, Clone)]
+pub struct AuthToken {
+    pub access_token: Secret<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl AuthToken {
+    /// Whether the token has run out or is about to.
+    pub fn is_expiring(&self) -> bool {
+        self.expires_at.map_or(false, |expires_at| {
+            expires_at - chrono::Duration::seconds(EXPIRY_MARGIN) <= Utc::now()
+        })
+    }
+}
+
+/// Errors which mean that the token was not accepted, e.g. because it ran out.
+pub trait Unauthorized {
+    fn is_unauthorized(&self) -> bool;
+}
+
+impl Unauthorized for reqwest::Error {
+    fn is_unauthorized(&self) -> bool {
+        self.status() == Some(reqwest::StatusCode::UNAUTHORIZED)
+    }
+}
+
+impl<T> Unauthorized for sbanken::apis::Error<T> {
+    fn is_unauthorized(&self) -> bool {
+        match self {
+            sbanken::apis::Error::Reqwest(e) => e.is_unauthorized(),
+            _ => false,
+        }
+    }
+}
+
+impl Login {
+    pub async fn token(&self) -> Result<AuthToken> {
+        get_auth_token(&self.auth_url, &self.client_id, &self.client_secret)
+            .instrument(info_span!("auth"))
+            .await
+    }
+}
+
+pub async fn get_auth_token(
+    auth_url: &str,
+    client_id: &Secret<String>,
+    client_secret: &Secret<String>,
+) -> Result<AuthToken> {
+    #[derive(Debug, Deserialize)]
+    struct AuthSuccess {
+        access_token: Secret<String>,
+        /// Seconds until the token runs out.
+        expires_in: Option<i64>,
+    }
+    #[derive(Debug, Deserialize)]
+    struct AuthError {
+        error: String,
+    }
+    #[derive(Debug, Deserialize)]
+    #[serde(untagged)]
+    enum AuthResponse {
+        Success(AuthSuccess),
+        Error(AuthError),
+    }
+
+    let auth_request = http::client()
+        .post(auth_url)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .basic_auth(
+            utf8_percent_encode(client_id.expose_secret(), NON_ALPHANUMERIC),
+            Some(utf8_percent_encode(
+                client_secret.expose_secret(),
+                NON_ALPHANUMERIC,
+            )),
+        )
+        .form(&[("grant_type", "client_credentials")]);
+    // Asking for a token again only gives another token
+    let auth_response: AuthResponse = retry::send_repeatable(Api::Sbanken, auth_request)
+        .await?
+        .json()
+        .await?;
+
+    match auth_response {
+        AuthResponse::Success(AuthSuccess {
+            access_token,
+            expires_in,
+        }) => Ok(AuthToken {
+            access_token,
+            expires_at: expires_in.map(|seconds| Utc::now() + chrono::Duration::seconds(seconds)),
+        }),
+        AuthResponse::Error(AuthError { error }) => Err(SyncError::Auth {
+            service: "sbanken",
+            reason: error,
+        }
+        .into()),
+    }
+}
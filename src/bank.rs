@@ -0,0 +1,217 @@
+//! Direct access to Sbanken endpoints which are not covered by the generated client.
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Method, RequestBuilder};
+use secrecy::{ExposeSecret, Secret};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::retry;
+use crate::sync::Bridge;
+use crate::throttle::{self, Api};
+
+/// A list of objects as returned by Sbanken, which reports errors in the body.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Items<T> {
+    items: Option<Vec<T>>,
+    /// Number of items in total, of which `items` may only be a page.
+    available_items: Option<i32>,
+    is_error: Option<bool>,
+    error_message: Option<String>,
+}
+
+/// The customers which the client has access to, one per item.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Customers {
+    item: Option<Customer>,
+    items: Option<Vec<Customer>>,
+    is_error: Option<bool>,
+    error_message: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Customer {
+    customer_id: String,
+}
+
+/// Outcome of a request which does not return any objects, with errors reported in the body.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Outcome {
+    is_error: Option<bool>,
+    error_message: Option<String>,
+}
+
+/// Request for `path`, which is made on behalf of the customer of the bridge unless it is not
+/// known yet.
+pub fn request(bridge: &Bridge, method: Method, path: &str) -> RequestBuilder {
+    let config = bridge.sbanken_config();
+    let mut builder = config
+        .client
+        .request(method, &format!("{}{}", config.base_path, path))
+        .header(reqwest::header::ACCEPT, "application/json");
+    let customer_id = bridge.sbanken_customer_id.expose_secret();
+    if !customer_id.is_empty() {
+        builder = builder.header("customerId", customer_id.as_str());
+    }
+    if let Some(token) = &config.oauth_access_token {
+        builder = builder.bearer_auth(token);
+    }
+    builder
+}
+
+/// Id of the customer which the client of the bridge belongs to, as the customers endpoint lists it.
+pub async fn customer_id(bridge: &Bridge) -> Result<Secret<String>> {
+    let path = "/api/v1/Customers";
+    let response: Customers = bridge
+        .with_sbanken_token(|| async {
+            retry::send(Api::Sbanken, request(bridge, Method::GET, path))
+                .await?
+                .error_for_status()
+        })
+        .await
+        .context("unable to get customers from sbanken")?
+        .json()
+        .await
+        .context("invalid customers from sbanken")?;
+
+    if response.is_error.unwrap_or(false) {
+        return Err(anyhow!(
+            "sbanken returned an error for the customers: {}",
+            response.error_message.unwrap_or_default()
+        ));
+    }
+
+    let mut customers: Vec<_> = response
+        .items
+        .unwrap_or_default()
+        .into_iter()
+        .chain(response.item)
+        .collect();
+    match customers.len() {
+        0 => Err(anyhow!(
+            "sbanken returned no customers, set the customer id with --sbanken-customer-id"
+        )),
+        1 => Ok(Secret::new(customers.remove(0).customer_id)),
+        n => Err(anyhow!(
+            "the sbanken client has access to {} customers, choose one with --sbanken-customer-id",
+            n
+        )),
+    }
+}
+
+/// GET the list at `path` with the given query parameters.
+pub async fn get_items<T: DeserializeOwned>(
+    bridge: &Bridge,
+    path: &str,
+    query: &[(&str, String)],
+) -> Result<Vec<T>> {
+    Ok(get_page(bridge, path, query)
+        .await?
+        .items
+        .unwrap_or_default())
+}
+
+/// GET every page of the list at `path`, `length` items at a time, until all the items Sbanken
+/// has available are fetched.
+pub async fn get_all_items<T: DeserializeOwned>(
+    bridge: &Bridge,
+    path: &str,
+    query: &[(&str, String)],
+    length: i32,
+) -> Result<Vec<T>> {
+    let mut items = Vec::new();
+    loop {
+        let mut page_query = query.to_vec();
+        page_query.push(("index", items.len().to_string()));
+        page_query.push(("length", length.to_string()));
+
+        let page: Items<T> = get_page(bridge, path, &page_query).await?;
+        let available = page.available_items;
+        let page = page.items.unwrap_or_default();
+        let fetched = page.len();
+        items.extend(page);
+
+        if !has_more(items.len(), fetched, available, length) {
+            return Ok(items);
+        }
+    }
+}
+
+/// Whether there are more pages after one of `fetched` items, with `total` items fetched so far.
+///
+/// Without the number of available items, a full page is taken to mean that there may be more.
+pub fn has_more(total: usize, fetched: usize, available: Option<i32>, length: i32) -> bool {
+    if fetched == 0 {
+        return false;
+    }
+    match available {
+        Some(available) => total < available.max(0) as usize,
+        None => fetched >= length.max(1) as usize,
+    }
+}
+
+async fn get_page<T: DeserializeOwned>(
+    bridge: &Bridge,
+    path: &str,
+    query: &[(&str, String)],
+) -> Result<Items<T>> {
+    let response: Items<T> = bridge
+        .with_sbanken_token(|| async {
+            retry::send(
+                Api::Sbanken,
+                request(bridge, Method::GET, path).query(query),
+            )
+            .await?
+            .error_for_status()
+        })
+        .await
+        .with_context(|| format!("unable to GET '{}' from sbanken", path))?
+        .json()
+        .await
+        .with_context(|| format!("invalid response from sbanken for GET '{}'", path))?;
+
+    if response.is_error.unwrap_or(true) {
+        return Err(anyhow!(
+            "sbanken returned an error for GET '{}': {}",
+            path,
+            response.error_message.unwrap_or_default()
+        ));
+    }
+
+    Ok(response)
+}
+
+/// POST `body` to `path`, for endpoints which only report whether they succeeded.
+///
+/// This is never retried, as the request may have been carried out even if it failed, e.g. a
+/// transfer.
+pub async fn post<B: Serialize>(bridge: &Bridge, path: &str, body: &B) -> Result<()> {
+    bridge.refresh_sbanken_token().await?;
+    throttle::acquire(Api::Sbanken).await;
+    let response = request(bridge, Method::POST, path)
+        .json(body)
+        .send()
+        .await
+        .with_context(|| format!("unable to POST '{}' to sbanken", path))?;
+
+    let status = response.status();
+    let outcome: Option<Outcome> = response.json().await.ok();
+    let message = outcome
+        .as_ref()
+        .and_then(|outcome| outcome.error_message.clone())
+        .unwrap_or_default();
+    if !status.is_success() || outcome.map_or(false, |outcome| outcome.is_error == Some(true)) {
+        return Err(anyhow!(
+            "sbanken returned {} for POST '{}': {}",
+            status,
+            path,
+            message
+        ));
+    }
+
+    Ok(())
+}
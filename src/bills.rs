@@ -0,0 +1,204 @@
+//! Firefly bills for the eFakturas in Sbanken, so that upcoming payments show up in Firefly.
+
+use anyhow::{Context, Result};
+use reqwest::Method;
+use sbanken::models::TransactionV1;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::info;
+
+use crate::bank;
+use crate::convert::{payment_reference, CURRENCY_CODE};
+use crate::error::Required;
+use crate::firefly::{self, Page, Single};
+use crate::money::Money;
+use crate::plan::Plan;
+use crate::sync::Bridge;
+
+/// Status of an eFaktura which has not been paid yet.
+const OPEN_STATUS: &str = "NEW";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EFaktura {
+    issuer_name: Option<String>,
+    status: Option<String>,
+    kid: Option<String>,
+    original_due_date: Option<String>,
+    updated_due_date: Option<String>,
+    original_amount: Option<f64>,
+    updated_amount: Option<f64>,
+    minimum_amount: Option<f64>,
+}
+
+impl EFaktura {
+    fn issuer(&self) -> Option<&str> {
+        self.issuer_name
+            .as_deref()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+    }
+
+    fn is_open(&self) -> bool {
+        self.status.as_deref() == Some(OPEN_STATUS)
+    }
+
+    fn due_date(&self) -> Option<&str> {
+        self.updated_due_date
+            .as_deref()
+            .or_else(|| self.original_due_date.as_deref())
+            .map(|date| date.get(..10).unwrap_or(date))
+    }
+
+    fn amount(&self) -> Option<f64> {
+        self.updated_amount.or(self.original_amount)
+    }
+}
+
+#[derive(Deserialize)]
+struct BillRead {
+    id: String,
+    attributes: BillAttributes,
+}
+
+#[derive(Deserialize)]
+struct BillAttributes {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct BillStore<'a> {
+    name: &'a str,
+    amount_min: String,
+    amount_max: String,
+    date: &'a str,
+    repeat_freq: &'a str,
+    currency_code: &'a str,
+    active: bool,
+}
+
+/// Firefly bills of the issuers of eFakturas, which payments are linked to through their KID.
+#[derive(Default)]
+pub struct Bills {
+    /// Id of the bill for each KID of an eFaktura.
+    by_reference: HashMap<String, String>,
+}
+
+async fn list_bills(bridge: &Bridge) -> Result<HashMap<String, String>> {
+    let mut ids = HashMap::new();
+    for page_number in 1.. {
+        let path = format!("/bills?page={}", page_number);
+        let page: Page<BillRead> = firefly::get(&bridge.firefly_config, &path)
+            .await?
+            .unwrap_or(Page { data: Vec::new() });
+        if page.data.is_empty() {
+            break;
+        }
+        ids.extend(
+            page.data
+                .into_iter()
+                .map(|bill| (bill.attributes.name, bill.id)),
+        );
+    }
+    Ok(ids)
+}
+
+impl Bills {
+    /// Create or update a bill for each issuer with an open eFaktura, expecting its amount on its
+    /// due date, and remember which bill every eFaktura belongs to.
+    pub async fn sync(&mut self, bridge: &Bridge, plan: &mut Plan) -> Result<()> {
+        let efakturas: Vec<EFaktura> =
+            bank::get_items(bridge, "/api/v1/EFakturas", &[("status", "ALL".into())])
+                .await
+                .context("unable to get efakturas")?;
+        let mut ids = list_bills(bridge)
+            .await
+            .context("unable to get existing bills")?;
+
+        // The open eFaktura which is due first is the next payment to each issuer
+        let mut next: HashMap<&str, &EFaktura> = HashMap::new();
+        for efaktura in efakturas.iter().filter(|efaktura| efaktura.is_open()) {
+            let (issuer, due_date) = match (efaktura.issuer(), efaktura.due_date()) {
+                (Some(issuer), Some(due_date)) => (issuer, due_date),
+                _ => continue,
+            };
+            let earlier = next
+                .get(issuer)
+                .and_then(|other| other.due_date())
+                .map_or(true, |other| due_date < other);
+            if earlier {
+                next.insert(issuer, efaktura);
+            }
+        }
+
+        for (issuer, efaktura) in next {
+            let amount = match efaktura.amount() {
+                Some(amount) => Money::from_f64(amount)?.abs(),
+                None => continue,
+            };
+            let minimum = match efaktura.minimum_amount {
+                Some(minimum) => Money::from_f64(minimum)?.abs(),
+                None => amount,
+            };
+            let due_date = efaktura.due_date().required("efaktura due date")?;
+
+            let existing = ids.get(issuer).cloned();
+            info!(
+                "{} bill {}: {} due {}",
+                if existing.is_some() {
+                    "Updating"
+                } else {
+                    "Creating"
+                },
+                issuer,
+                amount,
+                due_date
+            );
+            if bridge.dry_run {
+                plan.bills
+                    .push(format!("{}: {} due {}", issuer, amount, due_date));
+                continue;
+            }
+
+            let bill = BillStore {
+                name: issuer,
+                amount_min: std::cmp::min(minimum, amount).to_string(),
+                amount_max: amount.to_string(),
+                date: due_date,
+                repeat_freq: "monthly",
+                currency_code: CURRENCY_CODE,
+                active: true,
+            };
+            let stored: Single<BillRead> = match &existing {
+                Some(id) => {
+                    firefly::send(
+                        &bridge.firefly_config,
+                        Method::PUT,
+                        &format!("/bills/{}", id),
+                        &bill,
+                    )
+                    .await
+                }
+                None => firefly::send(&bridge.firefly_config, Method::POST, "/bills", &bill).await,
+            }
+            .with_context(|| format!("unable to store bill '{}'", issuer))?;
+            ids.insert(issuer.into(), stored.data.id);
+        }
+
+        for efaktura in &efakturas {
+            if let (Some(issuer), Some(kid)) = (efaktura.issuer(), efaktura.kid.as_deref()) {
+                if let Some(id) = ids.get(issuer) {
+                    self.by_reference.insert(kid.trim().into(), id.clone());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Id of the bill which the payment pays, found through the KID of its eFaktura.
+    pub fn bill_of(&self, transaction: &TransactionV1) -> Option<&str> {
+        let reference = payment_reference(transaction)?;
+        self.by_reference.get(&reference).map(String::as_str)
+    }
+}
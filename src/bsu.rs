@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Context, Result};
+use reqwest::Method;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use tracing::info;
+
+use crate::firefly::{self, Page, Single};
+use crate::money::Money;
+use crate::sync::Bridge;
+
+/// Sbanken account type of BSU (boligsparing for ungdom) accounts.
+pub const BSU_ACCOUNT_TYPE: &str = "BSU account";
+
+/// Most which may be saved in a BSU account each year.
+pub const DEFAULT_YEARLY_CAP: u32 = 27_500;
+
+/// Target of the piggy banks of savings accounts which have no goal of their own.
+pub const DEFAULT_SAVINGS_GOAL: u32 = 100_000;
+
+#[derive(Deserialize)]
+struct PiggyBankRead {
+    id: String,
+    attributes: PiggyBankAttributes,
+}
+
+#[derive(Deserialize)]
+struct PiggyBankAttributes {
+    name: String,
+    current_amount: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PiggyBankStore<'a> {
+    name: &'a str,
+    account_id: &'a str,
+    target_amount: String,
+    start_date: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_date: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PiggyBankAmount {
+    current_amount: String,
+}
+
+/// Firefly piggy banks tracking the yearly deposits into BSU accounts, named after the account
+/// and year (e.g. "BSU 2024"), and the balance of savings accounts, named after the account.
+#[derive(Default)]
+pub struct PiggyBanks {
+    /// Id of the piggy bank of each name, loaded from Firefly on first use.
+    ids: Option<HashMap<String, String>>,
+}
+
+async fn list_piggy_banks(bridge: &Bridge) -> Result<HashMap<String, String>> {
+    let mut ids = HashMap::new();
+    for page_number in 1.. {
+        let path = format!("/piggy_banks?page={}", page_number);
+        let page: Page<PiggyBankRead> = firefly::get(&bridge.firefly_config, &path)
+            .await?
+            .unwrap_or(Page { data: Vec::new() });
+        if page.data.is_empty() {
+            break;
+        }
+        ids.extend(
+            page.data
+                .into_iter()
+                .map(|piggy_bank| (piggy_bank.attributes.name, piggy_bank.id)),
+        );
+    }
+    Ok(ids)
+}
+
+impl PiggyBanks {
+    /// Id of the piggy bank for deposits into the BSU account `account_id` in `year`, which is
+    /// created with the yearly cap as target if it does not exist.
+    pub async fn for_year(
+        &mut self,
+        bridge: &Bridge,
+        account_id: &str,
+        account_name: &str,
+        year: i32,
+    ) -> Result<String> {
+        let name = format!("{} {}", account_name, year);
+        self.find_or_create(
+            bridge,
+            PiggyBankStore {
+                name: &name,
+                account_id,
+                target_amount: format!("{}.00", bridge.bsu_yearly_cap),
+                start_date: format!("{}-01-01", year),
+                target_date: Some(format!("{}-12-31", year)),
+            },
+        )
+        .await
+    }
+
+    /// Id of the piggy bank tracking the balance of the savings account `account_id`, which is
+    /// created with `goal` as target if it does not exist.
+    pub async fn for_savings_account(
+        &mut self,
+        bridge: &Bridge,
+        account_id: &str,
+        account_name: &str,
+        goal: u32,
+    ) -> Result<String> {
+        let today = chrono::Local::today().naive_local();
+        self.find_or_create(
+            bridge,
+            PiggyBankStore {
+                name: account_name,
+                account_id,
+                target_amount: format!("{}.00", goal),
+                start_date: today.format(crate::DATE_FORMAT).to_string(),
+                target_date: None,
+            },
+        )
+        .await
+    }
+
+    async fn find_or_create(
+        &mut self,
+        bridge: &Bridge,
+        store: PiggyBankStore<'_>,
+    ) -> Result<String> {
+        if self.ids.is_none() {
+            self.ids = Some(
+                list_piggy_banks(bridge)
+                    .await
+                    .context("unable to get existing piggy banks")?,
+            );
+        }
+        let ids = self.ids.as_mut().unwrap();
+
+        if let Some(id) = ids.get(store.name) {
+            return Ok(id.clone());
+        }
+
+        let created: Single<PiggyBankRead> =
+            firefly::send(&bridge.firefly_config, Method::POST, "/piggy_banks", &store)
+                .await
+                .with_context(|| format!("unable to create piggy bank '{}'", store.name))?;
+        info!(
+            "Created piggy bank {} <piggy bank {}>",
+            store.name, created.data.id
+        );
+
+        ids.insert(store.name.into(), created.data.id.clone());
+        Ok(created.data.id)
+    }
+}
+
+/// Add `amount` to the piggy bank `piggy_bank_id`, or take it out if negative, for money which
+/// is moved without a transfer that Firefly would track by itself.
+pub async fn adjust_piggy_bank(bridge: &Bridge, piggy_bank_id: &str, amount: Money) -> Result<()> {
+    let path = format!("/piggy_banks/{}", piggy_bank_id);
+    let piggy_bank: Single<PiggyBankRead> = firefly::get(&bridge.firefly_config, &path)
+        .await?
+        .ok_or_else(|| anyhow!("piggy bank {} not found in firefly", piggy_bank_id))?;
+    let current = match piggy_bank.data.attributes.current_amount.as_deref() {
+        Some(current) => Decimal::from_str(current)
+            .with_context(|| format!("invalid amount '{}' in piggy bank", current))?,
+        None => Decimal::new(0, 2),
+    };
+    let updated = std::cmp::max(current + amount.decimal(), Decimal::new(0, 2));
+
+    let _: Single<PiggyBankRead> = firefly::send(
+        &bridge.firefly_config,
+        Method::PUT,
+        &path,
+        &PiggyBankAmount {
+            current_amount: Money::from_decimal(updated).to_string(),
+        },
+    )
+    .await
+    .with_context(|| {
+        format!(
+            "unable to update piggy bank '{}'",
+            piggy_bank.data.attributes.name
+        )
+    })?;
+    Ok(())
+}
@@ -0,0 +1,444 @@
+use anyhow::{anyhow, Context, Result};
+use secrecy::Secret;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::convert::{DateField, IMPORT_TAG};
+use crate::crypto::StateKey;
+use crate::log_file::Rotation;
+use crate::logging::LogFormat;
+use crate::state::{FIREFLY_STATE_TAG, LEGACY_STATE_FILE, STATE_FILE};
+use crate::summary::RUN_SUMMARY_FILE;
+use crate::transactions::{ApiVersion, Chunk};
+
+/// Settings read from the configuration file.
+///
+/// Every value is optional, as flags and environment variables take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub sbanken_client_id: Option<Secret<String>>,
+    pub sbanken_client_secret: Option<Secret<String>>,
+    /// Found through the customers endpoint of Sbanken if not set.
+    pub sbanken_customer_id: Option<Secret<String>>,
+    pub sbanken_auth_url: Option<String>,
+    pub sbanken_base_url: Option<String>,
+    pub firefly_base_url: Option<String>,
+    pub firefly_access_token: Option<Secret<String>>,
+    pub delay_days: Option<i64>,
+    pub first_year: Option<i32>,
+    /// Book transactions on their `accounting` or `interest` date.
+    pub date_field: Option<DateField>,
+    /// Version of the Sbanken transactions endpoint, `v1` or `v2`.
+    pub sbanken_api: Option<ApiVersion>,
+    /// File with rules for categorizing transactions and naming merchants, defaults to the built-in
+    /// rules.
+    pub rules_file: Option<PathBuf>,
+    /// Tag on every transaction created by the bridge, defaults to `sbanken-bridge`.
+    pub import_tag: Option<String>,
+    /// Keep the transaction as Sbanken sent it in the notes of the Firefly transaction.
+    pub raw_notes: bool,
+    /// Append the KID or payment reference of giro payments to the description.
+    pub kid_in_description: bool,
+    /// Let Firefly run its rules on every imported transaction.
+    pub apply_firefly_rules: bool,
+    /// Let Firefly trigger its webhooks for imported transactions, defaults to `true`.
+    pub fire_webhooks: Option<bool>,
+    /// Attach the transaction as Sbanken sent it as a JSON file to every imported transaction.
+    pub attach_source: bool,
+    /// Format of the log, `text` or `json`, defaults to `text`.
+    pub log_format: Option<LogFormat>,
+    /// File to also write the log to, without colors, nothing is written if not set.
+    pub log_file: Option<PathBuf>,
+    /// When the log file is rotated, `hourly`, `daily` or `never`, defaults to `daily`.
+    pub log_rotation: Option<Rotation>,
+    /// Size in MiB at which the log file is rotated, defaults to 10.
+    pub log_max_size: Option<u64>,
+    /// Rotated log files which are kept, defaults to 7.
+    pub log_max_files: Option<usize>,
+    /// DSN of the Sentry project to report panics and failures to, nothing is reported if not set.
+    pub sentry_dsn: Option<String>,
+    /// OTLP collector to export the spans of syncs to as traces, e.g. `http://tempo:4317`.
+    pub otlp_endpoint: Option<String>,
+    /// Address to serve Prometheus metrics on in daemon mode, e.g. `0.0.0.0:9898`.
+    pub metrics_address: Option<SocketAddr>,
+    /// Address to serve `/healthz` and `/readyz` on in daemon mode, e.g. `0.0.0.0:9899`.
+    pub health_address: Option<SocketAddr>,
+    /// Hours within which the last sync must have succeeded for `/readyz` to report ready,
+    /// defaults to twice the interval of the daemon.
+    pub ready_within: Option<u64>,
+    /// File to write Prometheus metrics to after every sync, for the textfile collector of the
+    /// node exporter.
+    pub metrics_file: Option<PathBuf>,
+    /// Times a request which failed for a transient reason is made before giving up.
+    pub max_attempts: Option<u32>,
+    /// Most requests per second made to Sbanken, unlimited if not set.
+    pub sbanken_requests_per_second: Option<f64>,
+    /// Most requests per second made to Firefly, unlimited if not set.
+    pub firefly_requests_per_second: Option<f64>,
+    /// Seconds to wait for a connection to Sbanken or Firefly, defaults to 10.
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for a request to Sbanken or Firefly to finish, defaults to 60.
+    pub request_timeout: Option<u64>,
+    /// Most idle connections kept open to each host, unlimited if not set.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Seconds between TCP keep-alive probes on open connections, none if not set.
+    pub keep_alive: Option<u64>,
+    /// Proxy for all requests to Sbanken and Firefly, from the environment if not set.
+    pub proxy: Option<String>,
+    /// Seconds the Firefly accounts listed by an earlier run are reused for, defaults to 3600, 0 to
+    /// list them on every run.
+    pub accounts_cache_ttl: Option<u64>,
+    /// Size of the windows which transactions are fetched and transfers are paired in, `month`
+    /// or `year`, defaults to `month`.
+    pub chunk: Option<Chunk>,
+    /// Most accounts whose transactions are fetched and stored at the same time, defaults to 1.
+    pub jobs: Option<usize>,
+    /// Most years which are synced at the same time during a backfill, each pairing the
+    /// transfers within it, defaults to 1.
+    pub parallel_years: Option<usize>,
+    /// Most transactions stored in Firefly at the same time, defaults to 1.
+    pub concurrent_uploads: Option<usize>,
+    /// Always get a new Sbanken token instead of reusing the one cached in the state database.
+    pub force_reauth: bool,
+    /// Import card reservations as pending transactions until they are booked.
+    pub import_reservations: bool,
+    /// Mirror eFakturas as Firefly bills and link their payments to them.
+    pub efaktura_bills: bool,
+    /// Import scheduled payments as future transactions until they are booked.
+    pub scheduled_payments: bool,
+    /// Mirror standing orders as recurring transactions.
+    pub standing_orders: bool,
+    /// Track the deposits into BSU accounts in a Firefly piggy bank for each year.
+    pub bsu_piggy_bank: bool,
+    /// Most which may be saved in a BSU account each year, used as target of the piggy banks.
+    pub bsu_yearly_cap: Option<u32>,
+    /// Track the balance of savings accounts in a Firefly piggy bank for each account.
+    pub savings_piggy_banks: bool,
+    /// Target of the piggy banks of savings accounts without a goal of their own.
+    pub savings_goal: Option<u32>,
+    /// Number of days the two legs of an internal transfer may be booked apart.
+    pub transfer_date_tolerance: Option<i64>,
+    /// Sbanken transaction types which are internal transfers, e.g. `["OVFNETTB", "MOB.B.OVF"]`.
+    pub transfer_types: Vec<String>,
+    /// Number of days to wait for the other leg of a transfer before importing a leg on its own.
+    pub transfer_pending_days: Option<i64>,
+    /// Import the legs of internal transfers as a linked withdrawal and deposit.
+    pub transfer_legs_separately: bool,
+    /// Only sync accounts with these names or account numbers.
+    pub only_accounts: Vec<String>,
+    /// Never sync accounts with these names or account numbers.
+    pub exclude_accounts: Vec<String>,
+    /// Bring the name, account number and role of existing Firefly accounts up to date with
+    /// Sbanken and the account overrides.
+    pub update_accounts: bool,
+    /// Mark the Firefly accounts of closed Sbanken accounts as inactive.
+    pub archive_closed_accounts: bool,
+    /// Abort the sync on accounts of unknown types instead of mirroring them as asset accounts.
+    pub strict_account_types: bool,
+    /// Role of asset accounts of unknown types, defaults to `default`.
+    pub default_account_role: Option<AccountRole>,
+    /// Firefly object group to place the accounts of the bridge in, e.g. `Sbanken`.
+    pub account_group: Option<String>,
+    /// Overrides for single accounts, keyed by Sbanken account number.
+    pub accounts: HashMap<String, AccountConfig>,
+    /// Sbanken customers to sync in the same run, instead of only the one of the credentials
+    /// above.
+    pub customers: Vec<CustomerConfig>,
+    /// Database which keeps track of the sync progress, overrides `state_dir`.
+    pub state_file: Option<PathBuf>,
+    /// Directory for the state database, defaults to `$XDG_STATE_HOME/sbanken-firefly-bridge`.
+    pub state_dir: Option<PathBuf>,
+    /// Keep the sync progress in a tag in Firefly instead of in the state file.
+    pub state_in_firefly: bool,
+    /// Base64 encoded 32 byte key for encrypting sensitive data in the state file, the log file
+    /// and the run summary. The metrics file is left readable for the node exporter.
+    pub state_encryption_key: Option<Secret<String>>,
+    /// JSON file with a summary of every run, defaults to `run-summary.json` next to the state
+    /// database.
+    pub run_summary: Option<PathBuf>,
+    /// Named profiles, each overriding the settings above.
+    pub profiles: HashMap<String, Config>,
+    /// Name of the selected profile.
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+/// A Sbanken customer which is synced together with others, using the shared credentials for
+/// those which are not set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomerConfig {
+    /// Name of the customer, which the names of its Firefly accounts are prefixed with.
+    pub name: String,
+    pub sbanken_client_id: Option<Secret<String>>,
+    pub sbanken_client_secret: Option<Secret<String>>,
+    /// Found through the customers endpoint of Sbanken if not set.
+    pub sbanken_customer_id: Option<Secret<String>>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountConfig {
+    /// Name to use for the account in Firefly instead of the Sbanken name.
+    pub name: Option<String>,
+    /// Kind of Firefly account to create instead of the one following from the Sbanken type.
+    #[serde(rename = "type")]
+    pub account_type: Option<AccountType>,
+    /// Role of an asset account instead of the one following from the Sbanken type.
+    pub role: Option<AccountRole>,
+    /// Target of the piggy bank of a savings account in kroner.
+    pub savings_goal: Option<u32>,
+}
+
+/// Kind of Firefly account a Sbanken account is mirrored as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountType {
+    Asset,
+    Debt,
+    Loan,
+    Mortgage,
+}
+
+/// Role of a Firefly asset account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountRole {
+    Default,
+    Savings,
+    Shared,
+    CreditCard,
+    CashWallet,
+}
+
+impl Config {
+    /// Default location of the configuration file, i.e. `$XDG_CONFIG_HOME/sbanken-firefly-bridge/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("sbanken-firefly-bridge").join("config.toml"))
+    }
+
+    /// Load configuration from `path`, or from the default path if none is given.
+    ///
+    /// A missing file at the default path results in an empty configuration.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => match Config::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("unable to read config file '{}'", path.display()))?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&contents)
+                .with_context(|| format!("invalid yaml in config file '{}'", path.display())),
+            _ => toml::from_str(&contents)
+                .with_context(|| format!("invalid toml in config file '{}'", path.display())),
+        }
+    }
+
+    /// Apply the settings of profile `name` on top of the shared settings.
+    pub fn select_profile(mut self, name: &str) -> Result<Config> {
+        let profile = self
+            .profiles
+            .remove(name)
+            .ok_or_else(|| anyhow!("no profile named '{}' in config file", name))?;
+
+        let mut accounts = self.accounts;
+        accounts.extend(profile.accounts);
+
+        Ok(Config {
+            sbanken_client_id: profile.sbanken_client_id.or(self.sbanken_client_id),
+            sbanken_client_secret: profile.sbanken_client_secret.or(self.sbanken_client_secret),
+            sbanken_customer_id: profile.sbanken_customer_id.or(self.sbanken_customer_id),
+            sbanken_auth_url: profile.sbanken_auth_url.or(self.sbanken_auth_url),
+            sbanken_base_url: profile.sbanken_base_url.or(self.sbanken_base_url),
+            firefly_base_url: profile.firefly_base_url.or(self.firefly_base_url),
+            firefly_access_token: profile.firefly_access_token.or(self.firefly_access_token),
+            delay_days: profile.delay_days.or(self.delay_days),
+            first_year: profile.first_year.or(self.first_year),
+            date_field: profile.date_field.or(self.date_field),
+            sbanken_api: profile.sbanken_api.or(self.sbanken_api),
+            rules_file: profile.rules_file.or(self.rules_file),
+            import_tag: profile.import_tag.or(self.import_tag),
+            raw_notes: profile.raw_notes || self.raw_notes,
+            kid_in_description: profile.kid_in_description || self.kid_in_description,
+            apply_firefly_rules: profile.apply_firefly_rules || self.apply_firefly_rules,
+            fire_webhooks: profile.fire_webhooks.or(self.fire_webhooks),
+            attach_source: profile.attach_source || self.attach_source,
+            log_format: profile.log_format.or(self.log_format),
+            log_file: profile.log_file.or(self.log_file),
+            log_rotation: profile.log_rotation.or(self.log_rotation),
+            log_max_size: profile.log_max_size.or(self.log_max_size),
+            log_max_files: profile.log_max_files.or(self.log_max_files),
+            sentry_dsn: profile.sentry_dsn.or(self.sentry_dsn),
+            otlp_endpoint: profile.otlp_endpoint.or(self.otlp_endpoint),
+            metrics_address: profile.metrics_address.or(self.metrics_address),
+            health_address: profile.health_address.or(self.health_address),
+            ready_within: profile.ready_within.or(self.ready_within),
+            metrics_file: profile.metrics_file.or(self.metrics_file),
+            max_attempts: profile.max_attempts.or(self.max_attempts),
+            sbanken_requests_per_second: profile
+                .sbanken_requests_per_second
+                .or(self.sbanken_requests_per_second),
+            firefly_requests_per_second: profile
+                .firefly_requests_per_second
+                .or(self.firefly_requests_per_second),
+            connect_timeout: profile.connect_timeout.or(self.connect_timeout),
+            request_timeout: profile.request_timeout.or(self.request_timeout),
+            pool_max_idle_per_host: profile
+                .pool_max_idle_per_host
+                .or(self.pool_max_idle_per_host),
+            keep_alive: profile.keep_alive.or(self.keep_alive),
+            proxy: profile.proxy.or(self.proxy),
+            accounts_cache_ttl: profile.accounts_cache_ttl.or(self.accounts_cache_ttl),
+            chunk: profile.chunk.or(self.chunk),
+            jobs: profile.jobs.or(self.jobs),
+            parallel_years: profile.parallel_years.or(self.parallel_years),
+            concurrent_uploads: profile.concurrent_uploads.or(self.concurrent_uploads),
+            force_reauth: profile.force_reauth || self.force_reauth,
+            import_reservations: profile.import_reservations || self.import_reservations,
+            efaktura_bills: profile.efaktura_bills || self.efaktura_bills,
+            scheduled_payments: profile.scheduled_payments || self.scheduled_payments,
+            standing_orders: profile.standing_orders || self.standing_orders,
+            bsu_piggy_bank: profile.bsu_piggy_bank || self.bsu_piggy_bank,
+            bsu_yearly_cap: profile.bsu_yearly_cap.or(self.bsu_yearly_cap),
+            savings_piggy_banks: profile.savings_piggy_banks || self.savings_piggy_banks,
+            savings_goal: profile.savings_goal.or(self.savings_goal),
+            update_accounts: profile.update_accounts || self.update_accounts,
+            archive_closed_accounts: profile.archive_closed_accounts
+                || self.archive_closed_accounts,
+            strict_account_types: profile.strict_account_types || self.strict_account_types,
+            default_account_role: profile.default_account_role.or(self.default_account_role),
+            account_group: profile.account_group.or(self.account_group),
+            transfer_date_tolerance: profile
+                .transfer_date_tolerance
+                .or(self.transfer_date_tolerance),
+            transfer_types: if profile.transfer_types.is_empty() {
+                self.transfer_types
+            } else {
+                profile.transfer_types
+            },
+            transfer_pending_days: profile.transfer_pending_days.or(self.transfer_pending_days),
+            transfer_legs_separately: profile.transfer_legs_separately
+                || self.transfer_legs_separately,
+            only_accounts: if profile.only_accounts.is_empty() {
+                self.only_accounts
+            } else {
+                profile.only_accounts
+            },
+            exclude_accounts: if profile.exclude_accounts.is_empty() {
+                self.exclude_accounts
+            } else {
+                profile.exclude_accounts
+            },
+            accounts,
+            customers: if profile.customers.is_empty() {
+                self.customers
+            } else {
+                profile.customers
+            },
+            state_file: profile.state_file,
+            state_dir: profile.state_dir.or(self.state_dir),
+            state_in_firefly: profile.state_in_firefly || self.state_in_firefly,
+            state_encryption_key: profile.state_encryption_key.or(self.state_encryption_key),
+            run_summary: profile.run_summary,
+            profiles: HashMap::new(),
+            profile: Some(name.into()),
+        })
+    }
+
+    /// Default directory for the state database, i.e. `$XDG_STATE_HOME/sbanken-firefly-bridge`.
+    pub fn default_state_dir() -> Option<PathBuf> {
+        std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| {
+                if cfg!(target_os = "linux") {
+                    dirs::home_dir().map(|home| home.join(".local").join("state"))
+                } else {
+                    dirs::data_local_dir()
+                }
+            })
+            .map(|dir| dir.join("sbanken-firefly-bridge"))
+    }
+
+    /// Location of the state database.
+    ///
+    /// Unless the state file is set explicitly, each profile gets its own file in the state
+    /// directory.
+    pub fn state_file(&self) -> PathBuf {
+        if let Some(state_file) = &self.state_file {
+            return state_file.clone();
+        }
+
+        match self.state_dir.clone().or_else(Config::default_state_dir) {
+            Some(dir) => dir.join(self.local_state_file()),
+            None => self.local_state_file(),
+        }
+    }
+
+    pub fn state_key(&self) -> Result<Option<StateKey>> {
+        self.state_encryption_key
+            .as_ref()
+            .map(StateKey::from_base64)
+            .transpose()
+    }
+
+    /// State database in the current directory, where older versions of the bridge put it.
+    pub fn local_state_file(&self) -> PathBuf {
+        match &self.profile {
+            Some(name) => format!("{}-{}.db", STATE_FILE.trim_end_matches(".db"), name).into(),
+            None => STATE_FILE.into(),
+        }
+    }
+
+    /// File with the summary of every run, next to the state database and separate for each
+    /// profile unless configured.
+    pub fn run_summary_file(&self) -> PathBuf {
+        if let Some(run_summary) = &self.run_summary {
+            return run_summary.clone();
+        }
+
+        let name: PathBuf = match &self.profile {
+            Some(name) => format!(
+                "{}-{}.json",
+                RUN_SUMMARY_FILE.trim_end_matches(".json"),
+                name
+            )
+            .into(),
+            None => RUN_SUMMARY_FILE.into(),
+        };
+        match self.state_file().parent() {
+            Some(dir) => dir.join(name),
+            None => name,
+        }
+    }
+
+    /// State file which would have been used by older versions of the bridge.
+    pub fn legacy_state_file(&self) -> PathBuf {
+        match &self.profile {
+            Some(name) => format!("{}-{}", LEGACY_STATE_FILE, name).into(),
+            None => LEGACY_STATE_FILE.into(),
+        }
+    }
+
+    /// Tag in Firefly which holds the sync progress, separate for each profile.
+    pub fn firefly_state_tag(&self) -> String {
+        match &self.profile {
+            Some(name) => format!("{}-{}", FIREFLY_STATE_TAG, name),
+            None => FIREFLY_STATE_TAG.into(),
+        }
+    }
+
+    /// Tag on every transaction created by the bridge.
+    pub fn import_tag(&self) -> String {
+        self.import_tag.clone().unwrap_or_else(|| IMPORT_TAG.into())
+    }
+}
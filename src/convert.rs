@@ -0,0 +1,676 @@
+use anyhow::{anyhow, Result};
+use chrono::NaiveDate;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::str::FromStr;
+use tracing::warn;
+
+use crate::config::{AccountConfig, AccountRole, AccountType};
+use crate::error::{Item, Required, SyncError};
+use crate::money::Money;
+use crate::payments::{Payment, SCHEDULED_TAG};
+use crate::rules::Rules;
+use crate::transactions::{CardDetails, TransactionDetails};
+use crate::transfer::{TransferRequest, RECORDED_TAG};
+use crate::DATE_FORMAT;
+
+/// Default tag on every transaction created by the bridge, so that they can be found again.
+pub const IMPORT_TAG: &str = "sbanken-bridge";
+
+/// Currency of every Sbanken account, set explicitly so that the default currency of the Firefly
+/// instance does not matter.
+pub const CURRENCY_CODE: &str = "NOK";
+
+/// Which date of a Sbanken transaction it is booked on in Firefly and matched on as a transfer.
+///
+/// The sync cursors keep following the accounting date, as that is what Sbanken filters on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DateField {
+    Accounting,
+    Interest,
+}
+
+impl Default for DateField {
+    fn default() -> Self {
+        DateField::Accounting
+    }
+}
+
+impl FromStr for DateField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "accounting" => Ok(DateField::Accounting),
+            "interest" => Ok(DateField::Interest),
+            _ => Err(anyhow!(
+                "unknown date field '{}', expected 'accounting' or 'interest'",
+                s
+            )),
+        }
+    }
+}
+
+impl DateField {
+    /// Date part (YYYY-MM-DD) of the chosen timestamp of `transaction`, falling back to the
+    /// accounting date if it has no interest date.
+    pub fn date(self, transaction: &sbanken::models::TransactionV1) -> Result<&str> {
+        let date = match self {
+            DateField::Interest => transaction
+                .interest_date
+                .as_deref()
+                .or(transaction.accounting_date.as_deref()),
+            DateField::Accounting => transaction.accounting_date.as_deref(),
+        };
+        date.and_then(|date| date.get(..10))
+            .ok_or_else(|| anyhow!("transaction is missing accounting date"))
+    }
+
+    pub fn day(self, transaction: &sbanken::models::TransactionV1) -> Result<NaiveDate> {
+        Ok(NaiveDate::parse_from_str(self.date(transaction)?, DATE_FORMAT)?)
+    }
+}
+
+/// Settings for converting Sbanken transactions.
+#[derive(Debug, Clone)]
+pub struct Conversion {
+    pub date_field: DateField,
+    pub rules: Rules,
+    /// Tag on every transaction created by the bridge.
+    pub import_tag: String,
+    /// Tag telling which run created a transaction, e.g. "sbanken-run-2024-05-01T06:00".
+    pub run_tag: Option<String>,
+    /// Keep the transaction as Sbanken sent it in the notes.
+    pub raw_notes: bool,
+    /// Append the KID or payment reference to the description.
+    pub kid_in_description: bool,
+    /// Let Firefly run its rules on the created transactions.
+    pub apply_rules: bool,
+}
+
+impl Default for Conversion {
+    fn default() -> Self {
+        Conversion {
+            date_field: DateField::default(),
+            rules: Rules::default(),
+            import_tag: IMPORT_TAG.into(),
+            run_tag: None,
+            raw_notes: false,
+            kid_in_description: false,
+            apply_rules: false,
+        }
+    }
+}
+
+/// Tag for a run starting now.
+pub fn run_tag() -> String {
+    chrono::Local::now()
+        .format("sbanken-run-%Y-%m-%dT%H:%M")
+        .to_string()
+}
+
+/// Name of the other party of a transaction, made from its text.
+///
+/// The result is a part of `desc` or one of the merchant names of `rules`, so nothing is copied.
+pub fn cleanup_description<'a>(desc: &'a str, rules: &'a Rules) -> &'a str {
+    lazy_static! {
+        static ref START_DATE: Regex = Regex::new(r"^\d{2}\.\d{2}\s").unwrap();
+        static ref VISA_VARE_EXTRACT: Regex =
+            Regex::new(r"(?i)^\*\d{4}\s\d{2}\.\d{2}\s\w{3}\s\d+.\d{2}\s(.+?)\sKurs:\s\d+.\d+$")
+                .unwrap();
+        static ref PAY_DATE: Regex = Regex::new(r"Betalt:\s\d{2}\.\d{2}\.\d{2}$").unwrap();
+    }
+
+    // Remove leading date (e.g. "12.02 KIWI ...")
+    let desc = match START_DATE.find(desc) {
+        Some(date) => &desc[date.end()..],
+        None => desc,
+    };
+
+    // Remove trailing pay date (e.g. "KIWI ... Betalt: 12.03.20")
+    let desc = match PAY_DATE.find(desc) {
+        Some(date) => &desc[..date.start()],
+        None => desc,
+    };
+
+    // Remove leading "Fra: " and "Til: "
+    let desc = desc.trim_start_matches("Til: ");
+    let desc = desc.trim_start_matches("Fra: ");
+
+    // Extract name of company from VISA_VARE description
+    // (e.g. "*6227 26.02 NOK 30.00 COCA-COLA ENTERPRISES NOR Kurs: 1.0000")
+    let desc = VISA_VARE_EXTRACT
+        .captures(desc)
+        .and_then(|c| c.get(1))
+        .map_or(desc, |m| m.as_str());
+
+    // Use the canonical name of known merchants (e.g. "STARBUCKS OSLO S" -> "Starbucks")
+    let desc = rules.merchant(desc).unwrap_or(desc);
+
+    desc.trim()
+}
+
+/// Original currency and amount of a card transaction in a foreign currency, from a VISA VARE
+/// description (e.g. "*6227 26.02 EUR 12.50 CAFE DE FLORE Kurs: 10.4520").
+fn foreign_amount(desc: &str) -> Option<(String, String)> {
+    lazy_static! {
+        static ref VISA_VARE_AMOUNT: Regex =
+            Regex::new(r"(?i)^\*\d{4}\s\d{2}\.\d{2}\s(\w{3})\s(\d+\.\d{2})\s.+?\sKurs:\s\d+.\d+$")
+                .unwrap();
+    }
+
+    let captures = VISA_VARE_AMOUNT.captures(desc.trim())?;
+    let currency = captures[1].to_uppercase();
+    // Purchases in kroner carry the amount as well, which is nothing foreign
+    if currency == CURRENCY_CODE {
+        return None;
+    }
+
+    Some((currency, captures[2].into()))
+}
+
+/// Original currency and amount of a card payment in a foreign currency.
+fn card_foreign_amount(card: &CardDetails) -> Result<Option<(String, String)>> {
+    let (currency, amount) = match (&card.original_currency_code, card.currency_amount) {
+        (Some(currency), Some(amount)) => (currency.trim().to_uppercase(), amount),
+        _ => return Ok(None),
+    };
+    if currency.is_empty() || currency == CURRENCY_CODE {
+        return Ok(None);
+    }
+
+    Ok(Some((currency, Money::from_f64(amount)?.abs().to_string())))
+}
+
+/// Original currency and amount of a transaction in a foreign currency, preferring the card
+/// details as they are more reliable than what can be read from the text.
+fn original_amount(
+    sbanken_transaction: &sbanken::models::TransactionV1,
+    details: Option<&TransactionDetails>,
+) -> Result<Option<(String, String)>> {
+    let card_foreign_amount = match details.and_then(|details| details.card_details.as_ref()) {
+        Some(card) => card_foreign_amount(card)?,
+        None => None,
+    };
+    let text = sbanken_transaction.text.as_deref();
+    Ok(card_foreign_amount.or_else(|| text.and_then(foreign_amount)))
+}
+
+/// Foreign currency the transaction was made in, if any.
+pub fn foreign_currency(
+    sbanken_transaction: &sbanken::models::TransactionV1,
+    details: Option<&TransactionDetails>,
+) -> Result<Option<String>> {
+    Ok(original_amount(sbanken_transaction, details)?.map(|(currency, _)| currency))
+}
+
+/// KID or other payment reference of a giro payment.
+pub fn payment_reference(transaction: &sbanken::models::TransactionV1) -> Option<String> {
+    let detail = transaction.transaction_detail.as_ref()?;
+    detail
+        .cid
+        .as_deref()
+        .map(str::trim)
+        .filter(|cid| !cid.is_empty())
+        .map(String::from)
+        .or_else(|| detail.numeric_reference.map(|reference| reference.to_string()))
+}
+
+/// Compute a stable fingerprint for each of the transactions of an account.
+///
+/// Otherwise identical transactions (same day, amount and text) are told apart by the order in
+/// which they are listed. A transaction without a date or amount is an error naming it and
+/// `account_name`.
+pub fn fingerprints(
+    account_id: &str,
+    account_name: &str,
+    transactions: &[sbanken::models::TransactionV1],
+) -> Result<Vec<String>> {
+    let mut occurrences = HashMap::new();
+
+    transactions
+        .iter()
+        .map(|t| {
+            let key = DateField::Accounting
+                .date(t)
+                .and_then(|date| {
+                    Ok(format!(
+                        "{}|{}|{}|{}",
+                        account_id,
+                        date,
+                        Money::of(t)?,
+                        t.text.as_deref().unwrap_or("")
+                    ))
+                })
+                .map_err(|source| SyncError::Conversion {
+                    item: Item::new(account_name, t),
+                    source,
+                })?;
+            let occurrence = occurrences.entry(key.clone()).or_insert(0);
+            *occurrence += 1;
+
+            Ok(format!(
+                "{:x}",
+                Sha256::digest(format!("{}|{}", key, occurrence).as_bytes())
+            ))
+        })
+        .collect()
+}
+
+/// Lines for the notes of a transaction with the details from the v2 endpoint.
+fn details_notes(details: &TransactionDetails) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    if let Some(card) = &details.card_details {
+        if let Some(merchant) = &card.merchant_name {
+            lines.push(match &card.merchant_city {
+                Some(city) => format!("Merchant: {}, {}", merchant.trim(), city.trim()),
+                None => format!("Merchant: {}", merchant.trim()),
+            });
+        }
+        if let Some(category) = &card.merchant_category_description {
+            lines.push(format!("Merchant category: {}", category.trim()));
+        }
+    }
+    if let Some(id) = &details.transaction_id {
+        lines.push(format!("Sbanken transaction id: {}", id));
+    }
+
+    lines
+}
+
+/// Convert a Sbanken transaction, using its fingerprint as the external id in Firefly.
+///
+/// The `details` from the v2 endpoint name the other party and fill the notes, they are not known
+/// for transfers and for pending transfer legs carried over from an earlier run.
+pub fn convert_transaction(
+    main_account: &firefly_iii::models::AccountRead,
+    sbanken_transaction: &sbanken::models::TransactionV1,
+    details: Option<&TransactionDetails>,
+    other_account: Option<&firefly_iii::models::AccountRead>,
+    fingerprint: &str,
+    conversion: &Conversion,
+) -> Result<firefly_iii::models::Transaction> {
+    use firefly_iii::models::{
+        transaction_split::Type as TransactionType, Transaction, TransactionSplit,
+    };
+
+    let amount = Money::of(sbanken_transaction)?;
+    let text = sbanken_transaction
+        .text
+        .as_deref()
+        .required("transaction text")?;
+
+    let reference = payment_reference(sbanken_transaction);
+    let description = match &reference {
+        Some(reference) if conversion.kid_in_description => {
+            format!("{} (KID {})", text, reference)
+        }
+        _ => text.to_owned(),
+    };
+
+    let mut split = TransactionSplit::new(
+        // Extract date part of timestamp (YYYY-MM-DDTHH:MM:SS)
+        conversion.date_field.date(sbanken_transaction)?.into(),
+        amount.abs().to_string(),
+        description,
+        None,
+        None,
+    );
+
+    split.currency_code = Some(CURRENCY_CODE.into());
+    split.category_name = conversion.rules.category(sbanken_transaction, details);
+    split.tags = Some(
+        std::iter::once(conversion.import_tag.clone())
+            .chain(conversion.run_tag.clone())
+            .collect(),
+    );
+    split.external_id = Some(fingerprint.into());
+    split.internal_reference = reference;
+
+    let mut notes = details.map(details_notes).unwrap_or_default();
+    if conversion.raw_notes {
+        notes.push(format!(
+            "```json\n{}\n```",
+            serde_json::to_string_pretty(sbanken_transaction)?
+        ));
+    }
+    if !notes.is_empty() {
+        split.notes = Some(notes.join("\n\n"));
+    }
+
+    if let Some((currency, foreign_amount)) = original_amount(sbanken_transaction, details)? {
+        split.foreign_currency_code = Some(currency);
+        split.foreign_amount = Some(foreign_amount);
+    }
+
+    // The name of the merchant or payee is better than what can be made of the text, either is
+    // only copied if the transaction is not a transfer
+    let counterparty = details
+        .and_then(TransactionDetails::counterparty)
+        .map(|name| conversion.rules.merchant(name).unwrap_or(name))
+        .unwrap_or_else(|| cleanup_description(text, &conversion.rules));
+
+    if amount.is_negative() {
+        split.source_id = main_account.id.parse().ok();
+        if let Some(to_account) = other_account {
+            split._type = Some(TransactionType::Transfer);
+            split.destination_id = to_account.id.parse().ok();
+        } else {
+            split._type = Some(TransactionType::Withdrawal);
+            split.destination_name = Some(counterparty.into());
+        }
+    } else {
+        split.destination_id = main_account.id.parse().ok();
+        if let Some(to_account) = other_account {
+            split._type = Some(TransactionType::Transfer);
+            split.source_id = to_account.id.parse().ok();
+        } else {
+            split._type = Some(TransactionType::Deposit);
+            split.source_name = Some(counterparty.into());
+        }
+    }
+
+    // Budgets only apply to withdrawals in Firefly
+    let withdrawal = split._type == Some(TransactionType::Withdrawal);
+    let budget = |category: Option<&str>| {
+        if withdrawal {
+            conversion
+                .rules
+                .budget(sbanken_transaction, details, category)
+        } else {
+            None
+        }
+    };
+    split.budget_name = budget(split.category_name.as_deref());
+
+    // Split the transaction into several parts in Firefly (e.g. rent and utilities)
+    let splits = match conversion.rules.split(sbanken_transaction, details) {
+        Some(rule) => {
+            split.foreign_amount = None;
+            split.foreign_currency_code = None;
+
+            rule.amounts(amount)?
+                .into_iter()
+                .zip(&rule.parts)
+                .map(|(part_amount, part)| {
+                    let mut part_split = split.clone();
+                    part_split.amount = part_amount.to_string();
+                    if let Some(description) = &part.description {
+                        part_split.description = description.clone();
+                    }
+                    if let Some(category) = &part.category {
+                        part_split.category_name = Some(category.clone());
+                        part_split.budget_name = budget(Some(category));
+                    }
+                    part_split
+                })
+                .collect()
+        }
+        None => vec![split],
+    };
+
+    let group_title = if splits.len() > 1 {
+        Some(text.into())
+    } else {
+        None
+    };
+
+    let mut transaction = Transaction::new(splits);
+    transaction.group_title = group_title;
+    transaction.error_if_duplicate_hash = Some(true);
+    transaction.apply_rules = Some(conversion.apply_rules);
+
+    Ok(transaction)
+}
+
+/// Convert a scheduled payment from `main_account` into a withdrawal on its due date, tagged as
+/// scheduled.
+pub fn convert_payment(
+    main_account: &firefly_iii::models::AccountRead,
+    payment: &Payment,
+    conversion: &Conversion,
+) -> Result<firefly_iii::models::Transaction> {
+    use firefly_iii::models::{
+        transaction_split::Type as TransactionType, Transaction, TransactionSplit,
+    };
+
+    let beneficiary = payment
+        .beneficiary_name
+        .clone()
+        .or_else(|| payment.recipient_account_number.clone());
+    let description = payment
+        .text
+        .clone()
+        .filter(|text| !text.trim().is_empty())
+        .or_else(|| {
+            beneficiary
+                .as_ref()
+                .map(|name| format!("Payment to {}", name))
+        })
+        .unwrap_or_else(|| "Scheduled payment".into());
+
+    let mut split = TransactionSplit::new(
+        payment.due_day()?.format(DATE_FORMAT).to_string(),
+        payment.amount()?.to_string(),
+        description,
+        main_account.id.parse().ok(),
+        None,
+    );
+    split._type = Some(TransactionType::Withdrawal);
+    split.destination_name = beneficiary;
+    split.currency_code = Some(CURRENCY_CODE.into());
+    split.category_name = Some("Bills".into());
+    split.tags = Some(vec![conversion.import_tag.clone(), SCHEDULED_TAG.into()]);
+    split.external_id = Some(format!("sbanken-payment-{}", payment.payment_id));
+    split.internal_reference = payment.kid.clone();
+
+    let mut transaction = Transaction::new(vec![split]);
+    transaction.apply_rules = Some(conversion.apply_rules);
+    Ok(transaction)
+}
+
+/// Convert a transfer made with the `transfer` command, which is booked on `day`.
+pub fn convert_transfer_request(
+    from_account: &firefly_iii::models::AccountRead,
+    to_account: &firefly_iii::models::AccountRead,
+    request: &TransferRequest,
+    day: NaiveDate,
+    conversion: &Conversion,
+) -> Result<firefly_iii::models::Transaction> {
+    use firefly_iii::models::{
+        transaction_split::Type as TransactionType, Transaction, TransactionSplit,
+    };
+
+    let description = request
+        .message
+        .clone()
+        .filter(|message| !message.trim().is_empty())
+        .unwrap_or_else(|| format!("Transfer to {}", to_account.attributes.name));
+
+    let mut split = TransactionSplit::new(
+        day.format(DATE_FORMAT).to_string(),
+        request.amount.to_string(),
+        description,
+        from_account.id.parse().ok(),
+        to_account.id.parse().ok(),
+    );
+    split._type = Some(TransactionType::Transfer);
+    split.currency_code = Some(CURRENCY_CODE.into());
+    split.tags = Some(vec![conversion.import_tag.clone(), RECORDED_TAG.into()]);
+    // Tells it apart from the booked transfer, which usually has the message as its text
+    split.external_id = Some(format!(
+        "{}-{}-{}-{}",
+        RECORDED_TAG, request.from_account_id, request.to_account_id, day
+    ));
+
+    let mut transaction = Transaction::new(vec![split]);
+    transaction.apply_rules = Some(conversion.apply_rules);
+    Ok(transaction)
+}
+
+/// Let a new account start out with `balance` on `day`.
+pub fn set_opening_balance(
+    firefly_account: &mut firefly_iii::models::Account,
+    balance: Money,
+    day: NaiveDate,
+) {
+    use firefly_iii::models::account::Type;
+
+    let day = day.format(DATE_FORMAT).to_string();
+    if matches!(firefly_account._type, Type::Liability) {
+        // Liabilities track the debt, which is the negative balance
+        if balance.is_negative() {
+            firefly_account.liability_amount = Some(balance.abs().to_string());
+        }
+        firefly_account.liability_start_date = Some(day);
+    } else {
+        firefly_account.opening_balance = Some(balance.to_string());
+        firefly_account.opening_balance_date = Some(day);
+    }
+}
+
+/// Convert a Sbanken account, applying the overrides in `config`.
+///
+/// Accounts of unknown types become asset accounts with `fallback_role`, or are an error if there
+/// is no fallback.
+pub fn convert_account(
+    sbanken_account: &sbanken::models::AccountV1,
+    config: Option<&AccountConfig>,
+    fallback_role: Option<AccountRole>,
+) -> Result<firefly_iii::models::Account> {
+    use firefly_iii::models::account::{
+        Account, AccountRole as Role, InterestPeriod, LiabilityType, Type,
+    };
+
+    let name = match config.and_then(|config| config.name.clone()) {
+        Some(name) => name,
+        None => sbanken_account.name.clone().required("account name")?,
+    };
+    let sbanken_type = sbanken_account
+        .account_type
+        .as_deref()
+        .required("account type")?;
+    let is_credit_card = matches!(sbanken_type, "Creditcard account" | "Credit card account");
+
+    let (account_type, role) = match sbanken_type {
+        "High interest account" => (AccountType::Asset, AccountRole::Savings),
+        "Standard account" => (AccountType::Asset, AccountRole::Default),
+        "BSU account" => (AccountType::Asset, AccountRole::Savings),
+        _ if is_credit_card => (AccountType::Debt, AccountRole::CreditCard),
+        _ if sbanken_type.to_lowercase().contains("mortgage") => {
+            (AccountType::Mortgage, AccountRole::Default)
+        }
+        _ if sbanken_type.to_lowercase().contains("loan") => {
+            (AccountType::Loan, AccountRole::Default)
+        }
+        // Unknown types can still be mirrored if the configuration says how
+        _ => match (config.and_then(|config| config.account_type), fallback_role) {
+            (Some(account_type), _) => (account_type, AccountRole::Default),
+            (None, Some(role)) => {
+                warn!(
+                    "Unknown type '{}' of account '{}', mirroring it as an asset account",
+                    sbanken_type, name
+                );
+                (AccountType::Asset, role)
+            }
+            (None, None) => {
+                return Err(anyhow!(
+                    "conversion not implemented for account type '{}', set its type in the \
+                     config file",
+                    sbanken_type
+                ))
+            }
+        },
+    };
+    let account_type = config
+        .and_then(|config| config.account_type)
+        .unwrap_or(account_type);
+    let role = config.and_then(|config| config.role).unwrap_or(role);
+
+    let liability_type = match account_type {
+        AccountType::Asset => None,
+        AccountType::Debt => Some(LiabilityType::Debt),
+        AccountType::Loan => Some(LiabilityType::Loan),
+        AccountType::Mortgage => Some(LiabilityType::Mortgage),
+    };
+    let mut firefly_account = match liability_type {
+        None => {
+            let mut firefly_account = Account::new(name, Type::Asset);
+            firefly_account.account_role = Some(match role {
+                AccountRole::Default => Role::DefaultAsset,
+                AccountRole::Savings => Role::SavingAsset,
+                AccountRole::Shared => Role::SharedAsset,
+                AccountRole::CreditCard => Role::CcAsset,
+                AccountRole::CashWallet => Role::CashWalletAsset,
+            });
+            firefly_account
+        }
+        // Spending (or interest) is a withdrawal from a liability, which increases the debt,
+        // while paying it down is a deposit into it or a transfer to it. The debt is built up
+        // from the imported history.
+        Some(liability_type) => {
+            let mut firefly_account = Account::new(name, Type::Liability);
+            firefly_account.liability_type = Some(liability_type);
+            firefly_account.liability_amount = Some("0".into());
+            firefly_account.liability_start_date =
+                Some(chrono::Local::today().format(DATE_FORMAT).to_string());
+            firefly_account.interest = Some("0".into());
+            firefly_account.interest_period = Some(InterestPeriod::Monthly);
+            firefly_account
+        }
+    };
+
+    if is_credit_card {
+        if let Some(credit_limit) = sbanken_account.credit_limit {
+            firefly_account.notes =
+                Some(format!("Credit limit: {}", Money::from_f64(credit_limit)?));
+        }
+    }
+    // The account number is what maps the Firefly account to the Sbanken account
+    firefly_account.account_number = Some(
+        sbanken_account
+            .account_number
+            .clone()
+            .required("account number")?,
+    );
+    firefly_account.currency_code = Some(CURRENCY_CODE.into());
+
+    Ok(firefly_account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_foreign_amount_of_card_purchase() {
+        assert_eq!(
+            foreign_amount("*6227 26.02 EUR 12.50 CAFE DE FLORE Kurs: 10.4520"),
+            Some(("EUR".into(), "12.50".into()))
+        );
+        assert_eq!(
+            foreign_amount("*6227 03.11 usd 3.00 SHOP.EXAMPLE.COM Kurs: 9.1234 "),
+            Some(("USD".into(), "3.00".into()))
+        );
+    }
+
+    #[test]
+    fn purchase_in_kroner_has_no_foreign_amount() {
+        assert_eq!(
+            foreign_amount("*6227 26.02 NOK 30.00 COCA-COLA ENTERPRISES NOR Kurs: 1.0000"),
+            None
+        );
+    }
+
+    #[test]
+    fn other_texts_have_no_foreign_amount() {
+        assert_eq!(foreign_amount("12.02 KIWI 505 BISLETT"), None);
+        assert_eq!(foreign_amount("Til: Sparekonto"), None);
+        assert_eq!(foreign_amount(""), None);
+    }
+}
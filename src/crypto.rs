@@ -0,0 +1,145 @@
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use secrecy::{ExposeSecret, Secret};
+use std::io::{BufRead, Write};
+
+/// Prefix of encrypted values, which tells them apart from values written without encryption.
+const PREFIX: &str = "enc:v1:";
+
+const NONCE_LEN: usize = 12;
+
+/// Key for encrypting sensitive data in local files with ChaCha20-Poly1305.
+#[derive(Clone)]
+pub struct StateKey {
+    cipher: ChaCha20Poly1305,
+}
+
+impl std::fmt::Debug for StateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("StateKey([REDACTED])")
+    }
+}
+
+impl StateKey {
+    /// Parse a base64 encoded 32 byte key, e.g. as generated by `head -c 32 /dev/urandom | base64`.
+    pub fn from_base64(key: &Secret<String>) -> Result<StateKey> {
+        let key = base64::decode(key.expose_secret().trim())
+            .context("state encryption key is not valid base64")?;
+        if key.len() != 32 {
+            return Err(anyhow!(
+                "state encryption key must be 32 bytes, got {}",
+                key.len()
+            ));
+        }
+
+        Ok(StateKey {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut data = nonce.to_vec();
+        data.extend(
+            self.cipher
+                .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+                .map_err(|_| anyhow!("unable to encrypt state"))?,
+        );
+
+        Ok(format!("{}{}", PREFIX, base64::encode(data)))
+    }
+
+    /// Decrypt a value written by `encrypt`.
+    pub fn decrypt(&self, value: &str) -> Result<String> {
+        let data = match value.strip_prefix(PREFIX) {
+            Some(data) => base64::decode(data).context("invalid encrypted state")?,
+            None => return Ok(value.into()),
+        };
+        if data.len() < NONCE_LEN {
+            return Err(anyhow!("invalid encrypted state"));
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                anyhow!("unable to decrypt state, is the state encryption key correct?")
+            })?;
+
+        String::from_utf8(plaintext).context("invalid encrypted state")
+    }
+}
+
+/// Read a value which may have been encrypted, which requires a key if so.
+pub fn decrypt(key: Option<&StateKey>, value: &str) -> Result<String> {
+    match key {
+        Some(key) => key.decrypt(value),
+        None if value.starts_with(PREFIX) => Err(anyhow!(
+            "state is encrypted, set the state encryption key to read it"
+        )),
+        None => Ok(value.into()),
+    }
+}
+
+/// Copy the lines of `reader` to `writer`, decrypting those which were written encrypted, e.g. by
+/// the log file or the run summary.
+pub fn decrypt_lines(key: &StateKey, reader: impl BufRead, mut writer: impl Write) -> Result<()> {
+    for line in reader.lines() {
+        let line = line.context("unable to read encrypted file")?;
+        writeln!(writer, "{}", key.decrypt(&line)?)?;
+    }
+    Ok(())
+}
+
+/// Encrypt a value if a key is given.
+pub fn encrypt(key: Option<&StateKey>, value: &str) -> Result<String> {
+    match key {
+        Some(key) => key.encrypt(value),
+        None => Ok(value.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> StateKey {
+        StateKey::from_base64(&Secret::new(base64::encode([byte; 32]))).unwrap()
+    }
+
+    #[test]
+    fn decrypts_what_it_encrypted() {
+        let key = key(7);
+        let encrypted = key.encrypt("{\"accounts\":{}}").unwrap();
+        assert!(encrypted.starts_with(PREFIX));
+        assert_eq!(key.decrypt(&encrypted).unwrap(), "{\"accounts\":{}}");
+    }
+
+    #[test]
+    fn uses_a_new_nonce_every_time() {
+        let key = key(7);
+        assert_ne!(key.encrypt("state").unwrap(), key.encrypt("state").unwrap());
+    }
+
+    #[test]
+    fn wrong_key_is_an_error() {
+        let encrypted = key(7).encrypt("state").unwrap();
+        assert!(key(8).decrypt(&encrypted).is_err());
+        assert!(decrypt(None, &encrypted).is_err());
+    }
+
+    #[test]
+    fn reads_values_written_without_encryption() {
+        assert_eq!(key(7).decrypt("plain").unwrap(), "plain");
+        assert_eq!(decrypt(None, "plain").unwrap(), "plain");
+        assert_eq!(encrypt(None, "plain").unwrap(), "plain");
+    }
+
+    #[test]
+    fn key_must_be_32_bytes() {
+        assert!(StateKey::from_base64(&Secret::new(base64::encode([0; 16]))).is_err());
+        assert!(StateKey::from_base64(&Secret::new("not base64!".into())).is_err());
+    }
+}
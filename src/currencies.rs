@@ -0,0 +1,105 @@
+//! Currencies of imported transactions, which Firefly only accepts if they exist and are enabled.
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::info;
+
+use crate::firefly::{self, Resource, Single};
+use crate::plan::Plan;
+use crate::sync::Bridge;
+
+#[derive(Deserialize)]
+struct CurrencyRead {
+    attributes: CurrencyAttributes,
+}
+
+#[derive(Deserialize)]
+struct CurrencyAttributes {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct CurrencyStore<'a> {
+    enabled: bool,
+    code: &'a str,
+    name: &'a str,
+    symbol: &'a str,
+    decimal_places: u8,
+}
+
+/// Currencies which are known to be usable in Firefly.
+#[derive(Default)]
+pub struct Currencies {
+    checked: HashSet<String>,
+}
+
+impl Currencies {
+    /// Make sure the currency with the ISO 4217 `code` exists and is enabled in Firefly, creating
+    /// or enabling it if not.
+    ///
+    /// During a dry run the currency is only noted in the plan.
+    pub async fn ensure(&mut self, bridge: &Bridge, code: &str, plan: &mut Plan) -> Result<()> {
+        if self.checked.contains(code) {
+            return Ok(());
+        }
+
+        let path = format!("/currencies/{}", code);
+        let currency: Option<Single<CurrencyRead>> = firefly::get(&bridge.firefly_config, &path)
+            .await
+            .with_context(|| format!("unable to look up currency {} in firefly", code))?;
+
+        match currency {
+            Some(currency) if currency.data.attributes.enabled => {}
+            Some(_) if bridge.dry_run => plan.currencies.push(format!("{} (enable)", code)),
+            Some(_) => {
+                info!("Currency {} is disabled in Firefly, enabling...", code);
+                let _: Single<Resource> = firefly::send(
+                    &bridge.firefly_config,
+                    Method::POST,
+                    &format!("{}/enable", path),
+                    &(),
+                )
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "currency {} is disabled in firefly and could not be enabled, enable it \
+                         under 'Currencies' in firefly: {:#}",
+                        code,
+                        e
+                    )
+                })?;
+            }
+            None if bridge.dry_run => plan.currencies.push(format!("{} (create)", code)),
+            None => {
+                info!("Currency {} does not exist in Firefly, creating...", code);
+                let _: Single<Resource> = firefly::send(
+                    &bridge.firefly_config,
+                    Method::POST,
+                    "/currencies",
+                    &CurrencyStore {
+                        enabled: true,
+                        code,
+                        // The full name and symbol are not known, but can be changed in Firefly
+                        name: code,
+                        symbol: code,
+                        decimal_places: 2,
+                    },
+                )
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "currency {} does not exist in firefly and could not be created, create it \
+                         under 'Currencies' in firefly: {:#}",
+                        code,
+                        e
+                    )
+                })?;
+            }
+        }
+
+        self.checked.insert(code.to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+use chrono::NaiveDate;
+use sbanken::models::TransactionV1;
+use std::collections::BTreeMap;
+
+use crate::money::Money;
+
+/// Sbanken transaction types of internal bank transfers, which show up on both accounts.
+pub const DEFAULT_TRANSFER_TYPES: &[&str] = &["OVFNETTB", "MOB.B.OVF", "TILBAKEF."];
+
+/// Settings for pairing up the legs of internal transfers.
+#[derive(Debug, Clone)]
+pub struct TransferMatching {
+    /// Number of days the two legs of a transfer may be booked apart, e.g. over a weekend.
+    pub date_tolerance: i64,
+    /// Transaction types which are treated as internal transfers.
+    pub types: Vec<String>,
+    /// Number of days to wait for the other leg of a transfer before importing a leg on its own.
+    pub pending_days: i64,
+    /// Import the legs of a transfer as a withdrawal and a deposit which are linked to each other,
+    /// instead of as a single transfer.
+    pub separate_legs: bool,
+}
+
+impl Default for TransferMatching {
+    fn default() -> Self {
+        TransferMatching {
+            date_tolerance: 0,
+            pending_days: 7,
+            separate_legs: false,
+            types: DEFAULT_TRANSFER_TYPES.iter().map(|&t| t.into()).collect(),
+        }
+    }
+}
+
+impl TransferMatching {
+    /// Internal bank transfers show up on both accounts and have to be deduplicated.
+    pub fn is_internal_transfer(&self, transaction: &TransactionV1) -> bool {
+        match transaction.transaction_type.as_deref() {
+            Some(transaction_type) => self.types.iter().any(|t| t == transaction_type),
+            None => false,
+        }
+    }
+}
+
+/// A leg of an internal transfer together with the Sbanken account id it was found on.
+pub struct TransferLeg<'a> {
+    pub account_id: &'a str,
+    pub fingerprint: String,
+    pub amount: Money,
+    /// Day the leg is booked on, see `DateField`.
+    pub day: NaiveDate,
+    pub transaction: TransactionV1,
+}
+
+pub struct Transfers<'a> {
+    /// Sending and receiving leg of each transfer.
+    pub pairs: Vec<(TransferLeg<'a>, TransferLeg<'a>)>,
+    /// Legs without a counterpart, ordered by date.
+    pub unmatched: Vec<TransferLeg<'a>>,
+}
+
+/// What both legs of a transfer have in common: the amount without sign.
+type MatchKey = Money;
+
+/// Prefix of the sending leg of a transfer, followed by the account it goes to, e.g. "Til:
+/// Sparekonto".
+const TO_PREFIX: &str = "til:";
+
+/// Prefix of the receiving leg of a transfer, followed by the account it comes from, e.g. "Fra:
+/// Brukskonto".
+const FROM_PREFIX: &str = "fra:";
+
+/// Prefixes which tell the direction of a transfer.
+const DIRECTION_PREFIXES: &[&str] = &[FROM_PREFIX, TO_PREFIX];
+
+/// Text of a transfer leg with the known differences between the two legs removed.
+fn normalize_text(text: &str) -> String {
+    let text = text.trim().to_lowercase();
+    let text = DIRECTION_PREFIXES
+        .iter()
+        .find_map(|prefix| text.strip_prefix(prefix))
+        .unwrap_or(&text);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Which of the direction prefixes the text starts with, if any.
+fn direction(text: &str) -> Option<&'static str> {
+    let text = text.trim().to_lowercase();
+    DIRECTION_PREFIXES
+        .iter()
+        .copied()
+        .find(|prefix| text.starts_with(prefix))
+}
+
+/// Whether the texts of a sending and a receiving leg can belong to the same transfer.
+///
+/// Legs which tell the direction of the transfer name the other account, so their texts differ
+/// and cannot be compared. They only match when the sender tells where the money went and the
+/// receiver where it came from, never when only one of them has a direction.
+fn texts_match(sender: &str, receiver: &str) -> bool {
+    match (direction(sender), direction(receiver)) {
+        (None, None) => normalize_text(sender) == normalize_text(receiver),
+        (Some(TO_PREFIX), Some(FROM_PREFIX)) => true,
+        _ => false,
+    }
+}
+
+impl<'a> TransferLeg<'a> {
+    fn text(&self) -> &str {
+        self.transaction.text.as_deref().unwrap_or_default()
+    }
+
+    fn match_key(&self) -> MatchKey {
+        self.amount.abs()
+    }
+
+    /// Order which does not depend on the order in which Sbanken returned the legs.
+    fn sort_key(&self) -> (NaiveDate, MatchKey, &str, &str, &str) {
+        (
+            self.day,
+            self.match_key(),
+            self.text(),
+            self.account_id,
+            &self.fingerprint,
+        )
+    }
+}
+
+/// Try to find a receiver for `sender`, moving previously paired senders on to other receivers
+/// where that frees up a candidate (an augmenting path).
+fn augment(
+    sender: usize,
+    candidates: &[Vec<usize>],
+    sender_of: &mut [Option<usize>],
+    visited: &mut [bool],
+) -> bool {
+    for &receiver in &candidates[sender] {
+        if visited[receiver] {
+            continue;
+        }
+        visited[receiver] = true;
+
+        let free = match sender_of[receiver] {
+            Some(other) => augment(other, candidates, sender_of, visited),
+            None => true,
+        };
+        if free {
+            sender_of[receiver] = Some(sender);
+            return true;
+        }
+    }
+    false
+}
+
+/// Pair up sender and receiver legs of internal transfers.
+///
+/// A sending leg can be paired with a receiving leg with the same amount and a matching text (see
+/// `texts_match`) on another account, booked within the date tolerance. Within each amount, as
+/// many legs as possible are paired, so that several identical transfers between different
+/// accounts on the same day cannot block each other. Each sender prefers the leg booked closest
+/// in time, then one with the same text. Legs are considered in order of date, text, account id
+/// and fingerprint, so the result is the same on every run.
+pub fn pair_transfers<'a>(
+    legs: Vec<TransferLeg<'a>>,
+    matching: &TransferMatching,
+) -> Transfers<'a> {
+    let mut groups: BTreeMap<MatchKey, (Vec<TransferLeg>, Vec<TransferLeg>)> = BTreeMap::new();
+    for leg in legs {
+        let (senders, receivers) = groups.entry(leg.match_key()).or_default();
+        if leg.amount.is_negative() {
+            senders.push(leg);
+        } else {
+            receivers.push(leg);
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let mut unmatched = Vec::new();
+
+    for (_, (mut senders, mut receivers)) in groups {
+        senders.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+        receivers.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+
+        // Receivers each sender may be paired with, most preferred first
+        let candidates: Vec<Vec<usize>> = senders
+            .iter()
+            .map(|sender| {
+                let mut candidates: Vec<_> = receivers
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, receiver)| {
+                        receiver.account_id != sender.account_id
+                            && texts_match(sender.text(), receiver.text())
+                    })
+                    .map(|(i, receiver)| {
+                        let days_apart = (receiver.day - sender.day).num_days().abs();
+                        let text_differs =
+                            normalize_text(sender.text()) != normalize_text(receiver.text());
+                        (days_apart, text_differs, i)
+                    })
+                    .filter(|&(days_apart, _, _)| days_apart <= matching.date_tolerance)
+                    .collect();
+                candidates.sort();
+                candidates.into_iter().map(|(_, _, i)| i).collect()
+            })
+            .collect();
+
+        let mut sender_of = vec![None; receivers.len()];
+        for sender in 0..senders.len() {
+            let mut visited = vec![false; receivers.len()];
+            augment(sender, &candidates, &mut sender_of, &mut visited);
+        }
+
+        let mut receiver_of = vec![None; senders.len()];
+        for (receiver, sender) in sender_of.iter().enumerate() {
+            if let Some(sender) = *sender {
+                receiver_of[sender] = Some(receiver);
+            }
+        }
+
+        let mut receivers: Vec<_> = receivers.into_iter().map(Some).collect();
+        for (sender, receiver) in senders.into_iter().zip(receiver_of) {
+            match receiver.and_then(|i| receivers[i].take()) {
+                Some(receiver) => pairs.push((sender, receiver)),
+                None => unmatched.push(sender),
+            }
+        }
+        unmatched.extend(receivers.into_iter().flatten());
+    }
+
+    pairs.sort_by(|(a, _), (b, _)| a.sort_key().cmp(&b.sort_key()));
+    unmatched.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+
+    Transfers { pairs, unmatched }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::DATE_FORMAT;
+
+    fn leg<'a>(
+        account_id: &'a str,
+        fingerprint: &str,
+        amount: f64,
+        day: &str,
+        text: &str,
+    ) -> TransferLeg<'a> {
+        let transaction = serde_json::from_value(json!({
+            "accountingDate": format!("{}T00:00:00", day),
+            "amount": amount,
+            "text": text,
+            "transactionType": "OVFNETTB",
+        }))
+        .unwrap();
+        TransferLeg {
+            account_id,
+            fingerprint: fingerprint.into(),
+            amount: Money::from_f64(amount).unwrap(),
+            day: NaiveDate::parse_from_str(day, DATE_FORMAT).unwrap(),
+            transaction,
+        }
+    }
+
+    /// Fingerprints of the sender and receiver of each pair, and of the unmatched legs.
+    fn fingerprints(transfers: &Transfers) -> (Vec<(String, String)>, Vec<String>) {
+        (
+            transfers
+                .pairs
+                .iter()
+                .map(|(from, to)| (from.fingerprint.clone(), to.fingerprint.clone()))
+                .collect(),
+            transfers
+                .unmatched
+                .iter()
+                .map(|leg| leg.fingerprint.clone())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn pairs_three_identical_transfers_on_one_day() {
+        let legs = vec![
+            leg("a", "a1", -100.0, "2020-03-02", "Overføring"),
+            leg("a", "a2", -100.0, "2020-03-02", "Overføring"),
+            leg("b", "b1", -100.0, "2020-03-02", "Overføring"),
+            leg("b", "b2", 100.0, "2020-03-02", "Overføring"),
+            leg("c", "c1", 100.0, "2020-03-02", "Overføring"),
+            leg("c", "c2", 100.0, "2020-03-02", "Overføring"),
+        ];
+
+        let transfers = pair_transfers(legs, &TransferMatching::default());
+
+        assert_eq!(transfers.pairs.len(), 3);
+        assert!(transfers.unmatched.is_empty());
+        for (from, to) in &transfers.pairs {
+            assert_ne!(from.account_id, to.account_id);
+        }
+    }
+
+    #[test]
+    fn moves_a_paired_sender_to_free_a_receiver() {
+        // The sender on `a` prefers the receiver on `b`, which is the only one the sender on `c`
+        // can be paired with
+        let legs = vec![
+            leg("a", "a1", -50.0, "2020-03-02", "Overføring"),
+            leg("c", "c1", -50.0, "2020-03-02", "Overføring"),
+            leg("b", "b1", 50.0, "2020-03-02", "Overføring"),
+            leg("c", "c2", 50.0, "2020-03-02", "Overføring"),
+        ];
+
+        let transfers = pair_transfers(legs, &TransferMatching::default());
+
+        assert_eq!(
+            fingerprints(&transfers),
+            (
+                vec![("a1".into(), "c2".into()), ("c1".into(), "b1".into())],
+                vec![]
+            )
+        );
+    }
+
+    #[test]
+    fn pairs_legs_within_date_tolerance() {
+        let matching = TransferMatching {
+            date_tolerance: 1,
+            ..TransferMatching::default()
+        };
+        let legs = vec![
+            leg("a", "a1", -10.0, "2020-03-06", "Overføring"),
+            leg("b", "b1", 10.0, "2020-03-07", "Overføring"),
+            leg("a", "a2", -20.0, "2020-03-06", "Overføring"),
+            leg("b", "b2", 20.0, "2020-03-08", "Overføring"),
+        ];
+
+        let transfers = pair_transfers(legs, &matching);
+
+        assert_eq!(
+            fingerprints(&transfers),
+            (
+                vec![("a1".into(), "b1".into())],
+                vec!["a2".into(), "b2".into()]
+            )
+        );
+    }
+
+    #[test]
+    fn does_not_pair_legs_on_other_days_without_tolerance() {
+        let legs = vec![
+            leg("a", "a1", -10.0, "2020-03-06", "Overføring"),
+            leg("b", "b1", 10.0, "2020-03-07", "Overføring"),
+        ];
+
+        let transfers = pair_transfers(legs, &TransferMatching::default());
+
+        assert!(transfers.pairs.is_empty());
+        assert_eq!(transfers.unmatched.len(), 2);
+    }
+
+    #[test]
+    fn pairing_does_not_depend_on_order_of_legs() {
+        let legs = || {
+            vec![
+                leg("a", "a1", -100.0, "2020-03-02", "Overføring"),
+                leg("a", "a2", -100.0, "2020-03-02", "Overføring"),
+                leg("b", "b1", -100.0, "2020-03-02", "Overføring"),
+                leg("c", "c1", -100.0, "2020-03-03", "Overføring"),
+                leg("b", "b2", 100.0, "2020-03-02", "Overføring"),
+                leg("c", "c2", 100.0, "2020-03-02", "Overføring"),
+                leg("c", "c3", 100.0, "2020-03-02", "Overføring"),
+                leg("a", "a3", 100.0, "2020-03-03", "Overføring"),
+                leg("b", "b3", 100.0, "2020-03-04", "Overføring"),
+            ]
+        };
+        let matching = TransferMatching {
+            date_tolerance: 1,
+            ..TransferMatching::default()
+        };
+
+        let expected = fingerprints(&pair_transfers(legs(), &matching));
+        let mut reversed = legs();
+        reversed.reverse();
+        assert_eq!(fingerprints(&pair_transfers(reversed, &matching)), expected);
+        let mut rotated = legs();
+        rotated.rotate_left(4);
+        assert_eq!(fingerprints(&pair_transfers(rotated, &matching)), expected);
+    }
+
+    #[test]
+    fn matches_legs_with_opposite_directions() {
+        let legs = vec![
+            leg("a", "a1", -10.0, "2020-03-02", "Til: Sparekonto"),
+            leg("b", "b1", 10.0, "2020-03-02", "Fra: Brukskonto"),
+            leg("a", "a2", -20.0, "2020-03-02", "Til: Sparekonto"),
+            leg("b", "b2", 20.0, "2020-03-02", "Husleie"),
+            leg("a", "a3", -30.0, "2020-03-02", "Fra: Sparekonto"),
+            leg("b", "b3", 30.0, "2020-03-02", "Fra: Brukskonto"),
+        ];
+
+        let transfers = pair_transfers(legs, &TransferMatching::default());
+
+        assert_eq!(
+            fingerprints(&transfers),
+            (
+                vec![("a1".into(), "b1".into())],
+                vec!["b2".into(), "a2".into(), "b3".into(), "a3".into()]
+            )
+        );
+    }
+}
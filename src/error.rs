@@ -0,0 +1,103 @@
+//! Errors of the steps of a sync, which name the account and transaction they concern so that a
+//! failure can be acted upon from the log alone.
+
+use chrono::NaiveDate;
+use sbanken::models::TransactionV1;
+use std::fmt;
+use thiserror::Error;
+
+use crate::money::Money;
+
+/// What identifies a transaction in an error: the account it is on, its date, amount and text.
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub account: String,
+    pub date: String,
+    pub amount: String,
+    pub text: String,
+}
+
+impl Item {
+    pub fn new(account: &str, transaction: &TransactionV1) -> Item {
+        Item {
+            account: account.into(),
+            date: transaction
+                .accounting_date
+                .as_deref()
+                .and_then(|date| date.get(..10))
+                .unwrap_or("<no date>")
+                .into(),
+            amount: Money::of(transaction)
+                .map_or_else(|_| "<no amount>".into(), |amount| amount.to_string()),
+            text: transaction.text.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {} {}",
+            self.account, self.date, self.amount, self.text
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SyncError {
+    /// Sbanken or Firefly refused the credentials.
+    #[error("{service} refused the credentials: {reason}")]
+    Auth {
+        service: &'static str,
+        reason: String,
+    },
+    /// The transactions of an account could not be fetched from Sbanken.
+    #[error("unable to fetch transactions of {account} ({from} - {to})")]
+    Fetch {
+        account: String,
+        from: NaiveDate,
+        to: NaiveDate,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// A Sbanken transaction could not be turned into a Firefly transaction.
+    #[error("unable to convert transaction ({item})")]
+    Conversion {
+        item: Item,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// Firefly did not accept a transaction.
+    #[error("unable to store transaction ({item})")]
+    Store {
+        item: Item,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// Sbanken left out a field which it always sends.
+    #[error("missing {field} in response from sbanken")]
+    Missing { field: &'static str },
+}
+
+impl SyncError {
+    pub fn is_auth(&self) -> bool {
+        matches!(self, SyncError::Auth { .. })
+    }
+
+    /// One line with the error and all its causes, as kept in the failures of a run.
+    pub fn report(self) -> String {
+        format!("{:#}", anyhow::Error::from(self))
+    }
+}
+
+/// Turning a field which Sbanken always sends into an error instead of a panic if it is missing.
+pub trait Required<T> {
+    fn required(self, field: &'static str) -> Result<T, SyncError>;
+}
+
+impl<T> Required<T> for Option<T> {
+    fn required(self, field: &'static str) -> Result<T, SyncError> {
+        self.ok_or(SyncError::Missing { field })
+    }
+}
@@ -0,0 +1,71 @@
+use sbanken::models::AccountV1;
+
+use crate::sync::normalize_account_number;
+
+/// Selects which Sbanken accounts to sync, matching on account name or account number.
+#[derive(Debug, Default, Clone)]
+pub struct AccountFilter {
+    /// Only sync these accounts, all accounts are synced if empty.
+    pub only: Vec<String>,
+    /// Never sync these accounts.
+    pub exclude: Vec<String>,
+}
+
+/// Account numbers match however they are formatted, e.g. "9710.12.34567" and "97101234567".
+fn matches(account: &AccountV1, patterns: &[String]) -> bool {
+    let account_number = account
+        .account_number
+        .as_deref()
+        .map(normalize_account_number)
+        .filter(|number| !number.is_empty());
+    patterns.iter().any(|pattern| {
+        account.name.as_deref() == Some(pattern.as_str())
+            || account_number.as_deref() == Some(normalize_account_number(pattern).as_str())
+    })
+}
+
+impl AccountFilter {
+    pub fn includes(&self, account: &AccountV1) -> bool {
+        (self.only.is_empty() || matches(account, &self.only)) && !matches(account, &self.exclude)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn account(name: &str, account_number: &str) -> AccountV1 {
+        serde_json::from_value(json!({ "name": name, "accountNumber": account_number })).unwrap()
+    }
+
+    fn filter(only: &[&str], exclude: &[&str]) -> AccountFilter {
+        AccountFilter {
+            only: only.iter().map(|&pattern| pattern.into()).collect(),
+            exclude: exclude.iter().map(|&pattern| pattern.into()).collect(),
+        }
+    }
+
+    #[test]
+    fn matches_account_numbers_however_they_are_formatted() {
+        let account = account("Brukskonto", "97101234567");
+        assert!(filter(&["9710.12.34567"], &[]).includes(&account));
+        assert!(filter(&["9710 12 34567"], &[]).includes(&account));
+        assert!(!filter(&[], &["9710.12.34567"]).includes(&account));
+        assert!(!filter(&["9710.12.34568"], &[]).includes(&account));
+    }
+
+    #[test]
+    fn matches_account_names_exactly() {
+        let account = account("Brukskonto", "97101234567");
+        assert!(filter(&["Brukskonto"], &[]).includes(&account));
+        assert!(!filter(&["brukskonto"], &[]).includes(&account));
+        assert!(filter(&[], &[]).includes(&account));
+    }
+
+    #[test]
+    fn does_not_match_missing_account_numbers() {
+        let account = account("Sparekonto", "");
+        assert!(!filter(&["..."], &[]).includes(&account));
+    }
+}
@@ -0,0 +1,333 @@
+//! Direct access to Firefly endpoints which are not covered by the generated client.
+
+use anyhow::{anyhow, Context, Result};
+use firefly_iii::apis::configuration::Configuration as FireflyConfiguration;
+use firefly_iii::models::Transaction;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use secrecy::{ExposeSecret, Secret};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tracing::{info_span, warn, Instrument};
+
+use crate::http;
+use crate::retry::{self, Transient};
+use crate::throttle::Api;
+
+/// Oldest Firefly API version the bridge works with, which has `error_if_duplicate_hash`, object
+/// groups and transaction links as used here.
+const MIN_API_VERSION: (u32, u32, u32) = (1, 4, 0);
+
+/// Major version of the Firefly API after which endpoints may have changed incompatibly.
+const MAX_API_MAJOR: u32 = 2;
+
+/// A page of objects as returned by the list endpoints.
+#[derive(Debug, Deserialize)]
+pub struct Page<T> {
+    pub data: Vec<T>,
+}
+
+/// A single object as returned when creating or reading it.
+#[derive(Debug, Deserialize)]
+pub struct Single<T> {
+    pub data: T,
+}
+
+/// Any object, when only its id is of interest.
+#[derive(Debug, Deserialize)]
+pub struct Resource {
+    pub id: String,
+}
+
+pub fn configuration(base_url: &str, access_token: &Secret<String>) -> FireflyConfiguration {
+    FireflyConfiguration {
+        base_path: base_url.into(),
+        oauth_access_token: Some(access_token.expose_secret().into()),
+        client: http::client(),
+        ..FireflyConfiguration::default()
+    }
+}
+
+/// Path of the tag `tag`, which may contain characters that are not allowed in a URL.
+pub fn tag_path(tag: &str) -> String {
+    format!(
+        "/tags/{}",
+        percent_encoding::utf8_percent_encode(tag, percent_encoding::NON_ALPHANUMERIC)
+    )
+}
+
+pub fn request(config: &FireflyConfiguration, method: Method, path: &str) -> RequestBuilder {
+    let mut builder = config
+        .client
+        .request(method, &format!("{}/api/v1{}", config.base_path, path))
+        .header(reqwest::header::ACCEPT, "application/json");
+    if let Some(token) = &config.oauth_access_token {
+        builder = builder.bearer_auth(token);
+    }
+    builder
+}
+
+/// Span of a request which changes something in Firefly.
+fn write_span(method: &Method, path: &str) -> tracing::Span {
+    info_span!("firefly_write", method = %method, path)
+}
+
+/// GET `path` and deserialize the response, returning `None` if it was not found.
+pub async fn get<T: DeserializeOwned>(
+    config: &FireflyConfiguration,
+    path: &str,
+) -> Result<Option<T>> {
+    let response = retry::send(Api::Firefly, request(config, Method::GET, path))
+        .await
+        .with_context(|| format!("unable to GET '{}' from firefly", path))?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let response = response
+        .error_for_status()
+        .with_context(|| format!("firefly returned an error for GET '{}'", path))?;
+
+    Ok(Some(response.json().await.with_context(|| {
+        format!("invalid response from firefly for GET '{}'", path)
+    })?))
+}
+
+/// Send `body` to `path` using the given method, e.g. `POST` or `PUT`.
+pub async fn send<B: Serialize, T: DeserializeOwned>(
+    config: &FireflyConfiguration,
+    method: Method,
+    path: &str,
+    body: &B,
+) -> Result<T> {
+    let response = retry::send(
+        Api::Firefly,
+        request(config, method.clone(), path).json(body),
+    )
+    .instrument(write_span(&method, path))
+    .await
+    .with_context(|| format!("unable to {} '{}' to firefly", method, path))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        return Err(anyhow!(
+            "firefly returned {} for {} '{}': {}",
+            status,
+            method,
+            path,
+            message
+        ));
+    }
+
+    response
+        .json()
+        .await
+        .with_context(|| format!("invalid response from firefly for {} '{}'", method, path))
+}
+
+/// Upload `body` as the contents of the file at `path`.
+pub async fn upload(config: &FireflyConfiguration, path: &str, body: Vec<u8>) -> Result<()> {
+    let upload = request(config, Method::POST, path)
+        .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+        .body(body);
+    retry::send(Api::Firefly, upload)
+        .instrument(write_span(&Method::POST, path))
+        .await
+        .with_context(|| format!("unable to upload '{}' to firefly", path))?
+        .error_for_status()
+        .with_context(|| format!("firefly returned an error for uploading '{}'", path))?;
+    Ok(())
+}
+
+/// DELETE `path`, returning whether it existed.
+pub async fn delete(config: &FireflyConfiguration, path: &str) -> Result<bool> {
+    let response = retry::send(Api::Firefly, request(config, Method::DELETE, path))
+        .instrument(write_span(&Method::DELETE, path))
+        .await
+        .with_context(|| format!("unable to DELETE '{}' from firefly", path))?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+
+    response
+        .error_for_status()
+        .with_context(|| format!("firefly returned an error for DELETE '{}'", path))?;
+
+    Ok(true)
+}
+
+#[derive(Deserialize)]
+struct TransactionRead {
+    attributes: TransactionAttributes,
+}
+
+#[derive(Deserialize)]
+struct TransactionAttributes {
+    transactions: Vec<SplitRead>,
+}
+
+#[derive(Deserialize)]
+struct SplitRead {
+    transaction_journal_id: String,
+}
+
+/// Id of the journal of the first split of the transaction `transaction_id`, which is what links
+/// and attachments refer to.
+pub async fn journal_id(config: &FireflyConfiguration, transaction_id: &str) -> Result<String> {
+    let path = format!("/transactions/{}", transaction_id);
+    let transaction: Single<TransactionRead> = get(config, &path)
+        .await?
+        .ok_or_else(|| anyhow!("transaction {} not found in firefly", transaction_id))?;
+    transaction
+        .data
+        .attributes
+        .transactions
+        .into_iter()
+        .next()
+        .map(|split| split.transaction_journal_id)
+        .ok_or_else(|| anyhow!("transaction {} has no splits", transaction_id))
+}
+
+/// Versions of a Firefly instance, as returned by `/about`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct About {
+    pub version: String,
+    pub api_version: String,
+}
+
+/// Parse a version like "1.4.0" or "v1.5.2-dev", ignoring any suffix.
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = version
+        .trim_start_matches('v')
+        .split(|c: char| !c.is_ascii_digit())
+        .map(str::parse);
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().and_then(|part| part.ok()).unwrap_or(0);
+    let patch = parts.next().and_then(|part| part.ok()).unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+/// Check that the API of Firefly is one the bridge works with, before anything is written to it.
+///
+/// Older versions are refused, newer major versions and versions which cannot be parsed only give
+/// a warning.
+pub async fn check_version(config: &FireflyConfiguration) -> Result<About> {
+    let about: Single<About> = get(config, "/about")
+        .await
+        .context("unable to get the version of firefly")?
+        .ok_or_else(|| anyhow!("firefly has no '/about' endpoint, is the base url right?"))?;
+    let about = about.data;
+
+    match parse_version(&about.api_version) {
+        Some(version) if version < MIN_API_VERSION => {
+            let (major, minor, patch) = MIN_API_VERSION;
+            return Err(anyhow!(
+                "firefly {} has API version {}, but at least {}.{}.{} is needed, upgrade firefly",
+                about.version,
+                about.api_version,
+                major,
+                minor,
+                patch
+            ));
+        }
+        Some((major, _, _)) if major > MAX_API_MAJOR => warn!(
+            "Firefly {} has API version {}, which is newer than the bridge is known to \
+             work with",
+            about.version, about.api_version
+        ),
+        Some(_) => {}
+        None => warn!(
+            "Unable to tell whether firefly API version '{}' is supported",
+            about.api_version
+        ),
+    }
+
+    Ok(about)
+}
+
+/// Firefly kept answering with a server error, e.g. because it is down for maintenance.
+#[derive(Debug)]
+pub struct Unavailable {
+    status: StatusCode,
+    message: String,
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "firefly returned {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+/// Whether the error means that Firefly could not be reached, rather than that it refused what
+/// was sent.
+pub fn is_unreachable(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.is::<Unavailable>()
+            || cause
+                .downcast_ref::<reqwest::Error>()
+                .map_or(false, Transient::is_transient)
+    })
+}
+
+/// Outcome of storing a transaction.
+pub enum Stored {
+    /// The transaction was created with the given id.
+    Created(String),
+    /// Firefly refused the transaction as a duplicate of an existing one.
+    Duplicate,
+}
+
+/// A transaction to store, with the parameters which the generated model does not have.
+#[derive(Serialize)]
+struct StoreTransaction<'a> {
+    #[serde(flatten)]
+    transaction: &'a Transaction,
+    fire_webhooks: bool,
+}
+
+/// Store a transaction, telling duplicates refused by Firefly apart from other errors.
+///
+/// Firefly only checks for duplicates if `error_if_duplicate_hash` is set on the transaction, and
+/// only triggers its webhooks for the transaction if `fire_webhooks` is set.
+pub async fn store_transaction(
+    config: &FireflyConfiguration,
+    transaction: &Transaction,
+    fire_webhooks: bool,
+) -> Result<Stored> {
+    // Firefly refuses the transaction as a duplicate if an attempt which timed out did store it
+    let store = request(config, Method::POST, "/transactions").json(&StoreTransaction {
+        transaction,
+        fire_webhooks,
+    });
+    let response = retry::send_repeatable(Api::Firefly, store)
+        .instrument(write_span(&Method::POST, "/transactions"))
+        .await
+        .context("unable to send transaction to firefly")?;
+
+    let status = response.status();
+    if status == StatusCode::UNPROCESSABLE_ENTITY {
+        let message = response.text().await.unwrap_or_default();
+        if message.contains("Duplicate of transaction") {
+            return Ok(Stored::Duplicate);
+        }
+        return Err(anyhow!("firefly refused transaction: {}", message));
+    }
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Unavailable { status, message }.into());
+        }
+        return Err(anyhow!("firefly returned {}: {}", status, message));
+    }
+
+    let created: Single<Resource> = response
+        .json()
+        .await
+        .context("invalid response from firefly when storing transaction")?;
+    Ok(Stored::Created(created.data.id))
+}
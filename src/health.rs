@@ -0,0 +1,65 @@
+//! Health checks of the daemon for container orchestrators and uptime monitors, `/healthz` answers
+//! as long as the process is alive and `/readyz` only if the last sync succeeded recently.
+
+use anyhow::{Context, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+lazy_static! {
+    /// When the last sync which succeeded finished.
+    static ref LAST_SUCCESS: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+/// Record that a sync of every customer succeeded.
+pub fn synced() {
+    *LAST_SUCCESS.lock().unwrap() = Some(Instant::now());
+}
+
+/// Serve `/healthz` and `/readyz` at `address` in the background, the bridge is ready as long as
+/// the last sync succeeded within `ready_within`.
+pub fn serve(address: SocketAddr, ready_within: Duration) -> Result<()> {
+    let server = Server::try_bind(&address)
+        .with_context(|| format!("unable to listen for health checks on {}", address))?
+        .serve(make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |request| respond(request, ready_within)))
+        }));
+    info!("Serving health checks on http://{}/healthz", address);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Health check server stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+async fn respond(
+    request: Request<Body>,
+    ready_within: Duration,
+) -> Result<Response<Body>, Infallible> {
+    let (status, body) = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") => (StatusCode::OK, "ok".to_string()),
+        (&Method::GET, "/readyz") => match *LAST_SUCCESS.lock().unwrap() {
+            Some(time) if time.elapsed() <= ready_within => (StatusCode::OK, "ok".to_string()),
+            Some(time) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!(
+                    "last successful sync was {} ago",
+                    humantime::format_duration(Duration::from_secs(time.elapsed().as_secs()))
+                ),
+            ),
+            None => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no sync has succeeded yet".to_string(),
+            ),
+        },
+        _ => (StatusCode::NOT_FOUND, String::new()),
+    };
+    let response = Response::builder().status(status).body(Body::from(body));
+    Ok(response.expect("responses are always valid"))
+}
@@ -0,0 +1,84 @@
+//! The HTTP client shared by the Sbanken and Firefly clients and the authentication, so that they
+//! share a connection pool and a hung connection cannot stall a sync forever.
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Seconds to wait for a connection to be established, unless configured otherwise.
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+
+/// Seconds to wait for a whole request to finish, unless configured otherwise.
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 60;
+
+/// User agent of every request, so that the bridge can be told apart in the logs of Firefly.
+pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Connection settings shared by all clients.
+#[derive(Debug, Clone)]
+pub struct HttpSettings {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    /// Most idle connections kept open to each host, unlimited if not set.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Interval of TCP keep-alive probes on open connections, none if not set.
+    pub keep_alive: Option<Duration>,
+    /// Proxy for every request, otherwise the proxy of the environment (e.g. `HTTPS_PROXY`) is
+    /// used.
+    pub proxy: Option<String>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT),
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT),
+            pool_max_idle_per_host: None,
+            keep_alive: None,
+            proxy: None,
+        }
+    }
+}
+
+lazy_static! {
+    static ref CLIENT: RwLock<Option<reqwest::Client>> = RwLock::new(None);
+}
+
+fn build(settings: &HttpSettings) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(settings.connect_timeout)
+        .timeout(settings.request_timeout)
+        .tcp_keepalive(settings.keep_alive);
+    if let Some(max_idle) = settings.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(proxy) = &settings.proxy {
+        builder = builder.proxy(
+            reqwest::Proxy::all(proxy).with_context(|| format!("invalid proxy '{}'", proxy))?,
+        );
+    }
+    builder
+        .build()
+        .context("unable to create http client with the configured settings")
+}
+
+/// Use `settings` for every client handed out from now on.
+pub fn configure(settings: HttpSettings) -> Result<()> {
+    *CLIENT.write().unwrap() = Some(build(&settings)?);
+    Ok(())
+}
+
+/// The shared client, with the configured timeouts and connection settings.
+///
+/// Clones of a client share its connection pool, so this is cheap and every request reuses the
+/// same connections.
+pub fn client() -> reqwest::Client {
+    if let Some(client) = CLIENT.read().unwrap().as_ref() {
+        return client.clone();
+    }
+
+    let client = build(&HttpSettings::default()).expect("unable to create http client");
+    CLIENT.write().unwrap().get_or_insert(client).clone()
+}
@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, NaiveDate};
+use secrecy::{ExposeSecret, Secret};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::auth::get_auth_token;
+use crate::config::Config;
+use crate::error::Required;
+use crate::state::StateStore;
+use crate::sync::{self, Bridge};
+use crate::DATE_FORMAT;
+
+const DEFAULT_SBANKEN_AUTH_URL: &str = "https://auth.sbanken.no/identityserver/connect/token";
+const DEFAULT_SBANKEN_BASE_URL: &str = "https://api.sbanken.no/exec.bank";
+
+/// The subset of `Config` which is written by the wizard.
+#[derive(Debug, Serialize)]
+struct InitConfig<'a> {
+    sbanken_client_id: &'a str,
+    sbanken_client_secret: &'a str,
+    sbanken_customer_id: &'a str,
+    sbanken_auth_url: &'a str,
+    sbanken_base_url: &'a str,
+    firefly_base_url: &'a str,
+    firefly_access_token: &'a str,
+    only_accounts: Vec<String>,
+}
+
+fn prompt(question: &str, default: Option<&str>) -> Result<String> {
+    match default {
+        Some(default) => eprint!("{} [{}]: ", question, default),
+        None => eprint!("{}: ", question),
+    }
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+
+    match (answer.is_empty(), default) {
+        (true, Some(default)) => Ok(default.into()),
+        (true, None) => prompt(question, default),
+        (false, _) => Ok(answer.into()),
+    }
+}
+
+fn prompt_secret(question: &str) -> Result<Secret<String>> {
+    let answer = rpassword::read_password_from_tty(Some(&format!("{}: ", question)))?;
+    if answer.is_empty() {
+        prompt_secret(question)
+    } else {
+        Ok(Secret::new(answer))
+    }
+}
+
+/// Interactively ask for credentials, verify them and write a configuration file to `path`
+/// together with the day to sync each account from to the state `config` points to.
+pub async fn init(path: &Path, config: &Config) -> Result<()> {
+    if path.exists()
+        && prompt(
+            &format!("'{}' already exists, overwrite? (y/n)", path.display()),
+            Some("n"),
+        )? != "y"
+    {
+        return Err(anyhow!("aborted, not overwriting '{}'", path.display()));
+    }
+
+    let sbanken_auth_url = prompt("Sbanken auth url", Some(DEFAULT_SBANKEN_AUTH_URL))?;
+    let sbanken_base_url = prompt("Sbanken base url", Some(DEFAULT_SBANKEN_BASE_URL))?;
+    let sbanken_client_id = prompt_secret("Sbanken client id")?;
+    let sbanken_client_secret = prompt_secret("Sbanken client secret")?;
+    let sbanken_customer_id = prompt_secret("Sbanken customer id (your national identity number)")?;
+
+    let sbanken_token = get_auth_token(
+        &sbanken_auth_url,
+        &sbanken_client_id,
+        &sbanken_client_secret,
+    )
+    .await
+    .context("unable to authenticate with sbanken, check the client id and secret")?;
+    println!("Sbanken authentication: ok");
+
+    let firefly_base_url = prompt("Firefly base url (e.g. https://firefly.example.com)", None)?;
+    let firefly_access_token = prompt_secret("Firefly personal access token")?;
+
+    let bridge = Bridge::new(
+        &sbanken_base_url,
+        &sbanken_token,
+        Secret::new(sbanken_customer_id.expose_secret().clone()),
+        &firefly_base_url,
+        &firefly_access_token,
+    );
+
+    let sbanken_accounts = sync::list_sbanken_accounts(&bridge).await?;
+    println!("Sbanken accounts: ok ({} found)", sbanken_accounts.len());
+
+    let firefly_accounts = sync::list_firefly_accounts(&bridge).await?;
+    println!("Firefly accounts: ok ({} found)", firefly_accounts.len());
+
+    for (i, account) in sbanken_accounts.iter().enumerate() {
+        println!(
+            "\t{}) {} ({})",
+            i + 1,
+            account.name.as_deref().unwrap_or("<unnamed>"),
+            account
+                .account_number
+                .as_deref()
+                .unwrap_or("<no account number>"),
+        );
+    }
+    let selection = prompt(
+        "Accounts to sync (comma separated numbers from the list above)",
+        Some("all"),
+    )?;
+    let selected_accounts: Vec<_> = if selection == "all" {
+        sbanken_accounts.iter().collect()
+    } else {
+        selection
+            .split(',')
+            .map(|i| {
+                i.trim()
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|i| sbanken_accounts.get(i.wrapping_sub(1)))
+                    .ok_or_else(|| anyhow!("invalid account selection '{}'", i.trim()))
+            })
+            .collect::<Result<_>>()?
+    };
+    let only_accounts = if selection == "all" {
+        Vec::new()
+    } else {
+        selected_accounts
+            .iter()
+            .filter_map(|account| account.account_number.clone())
+            .collect()
+    };
+
+    let first_day = prompt(
+        "Import transactions from (YYYY-MM-DD)",
+        Some(&format!("{}-01-01", chrono::Local::today().year())),
+    )?;
+    let first_day = NaiveDate::parse_from_str(&first_day, DATE_FORMAT)
+        .with_context(|| format!("invalid date '{}'", first_day))?;
+
+    let init_config = InitConfig {
+        sbanken_client_id: sbanken_client_id.expose_secret(),
+        sbanken_client_secret: sbanken_client_secret.expose_secret(),
+        sbanken_customer_id: sbanken_customer_id.expose_secret(),
+        sbanken_auth_url: &sbanken_auth_url,
+        sbanken_base_url: &sbanken_base_url,
+        firefly_base_url: &firefly_base_url,
+        firefly_access_token: firefly_access_token.expose_secret(),
+        only_accounts,
+    };
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("unable to create directory '{}'", dir.display()))?;
+    }
+    // The config file contains credentials, so only the owner should ever be able to read it
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("unable to write config file '{}'", path.display()))?;
+    // An existing file keeps its mode when opened
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(toml::to_string(&init_config)?.as_bytes())
+        .with_context(|| format!("unable to write config file '{}'", path.display()))?;
+    println!("Wrote configuration to '{}'", path.display());
+
+    let state_store = if config.state_in_firefly {
+        StateStore::Firefly {
+            config: bridge.firefly_config.clone(),
+            tag: config.firefly_state_tag(),
+        }
+    } else {
+        StateStore::File {
+            path: config.state_file(),
+            key: config.state_key()?,
+        }
+    };
+    let starts = selected_accounts
+        .iter()
+        .map(|account| {
+            Ok((
+                account.account_id.clone().required("account id")?,
+                first_day,
+            ))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+    state_store.start_accounts(starts).await?;
+    println!("Transactions will be imported from {}", first_day);
+
+    Ok(())
+}
@@ -0,0 +1,46 @@
+pub mod attachments;
+pub mod auth;
+pub mod bank;
+pub mod bills;
+pub mod bsu;
+pub mod config;
+pub mod convert;
+pub mod crypto;
+pub mod currencies;
+pub mod dedup;
+pub mod error;
+pub mod filter;
+pub mod firefly;
+pub mod health;
+pub mod http;
+pub mod init;
+pub mod links;
+pub mod lock;
+pub mod log_file;
+pub mod logging;
+pub mod metrics;
+pub mod money;
+pub mod payees;
+pub mod payments;
+pub mod plan;
+pub mod progress;
+pub mod purge;
+pub mod reapply;
+pub mod reporting;
+pub mod report;
+pub mod reservations;
+pub mod retry;
+pub mod rollback;
+pub mod rules;
+pub mod shutdown;
+pub mod standing_orders;
+pub mod state;
+pub mod summary;
+pub mod sync;
+pub mod throttle;
+pub mod transactions;
+pub mod transfer;
+
+pub use sync::{sync_accounts, sync_transactions, Bridge};
+
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
@@ -0,0 +1,90 @@
+//! Firefly transaction links, which tie together transactions that belong to each other, e.g. the
+//! two legs of a transfer that are imported separately.
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+
+use crate::firefly::{self, Page, Resource, Single};
+use crate::sync::Bridge;
+
+/// Name of the Firefly link type which is used, one of the types Firefly comes with.
+const LINK_TYPE: &str = "Related";
+
+#[derive(Deserialize)]
+struct LinkTypeRead {
+    id: String,
+    attributes: LinkTypeAttributes,
+}
+
+#[derive(Deserialize)]
+struct LinkTypeAttributes {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct LinkStore<'a> {
+    link_type_id: &'a str,
+    outward_id: &'a str,
+    inward_id: &'a str,
+    notes: &'a str,
+}
+
+/// Links between transactions, with the link type loaded from Firefly on first use.
+#[derive(Default)]
+pub struct TransactionLinks {
+    link_type_id: Option<String>,
+}
+
+impl TransactionLinks {
+    async fn link_type_id(&mut self, bridge: &Bridge) -> Result<String> {
+        if let Some(id) = &self.link_type_id {
+            return Ok(id.clone());
+        }
+
+        let page: Page<LinkTypeRead> = firefly::get(&bridge.firefly_config, "/link_types")
+            .await?
+            .unwrap_or(Page { data: Vec::new() });
+        let id = page
+            .data
+            .into_iter()
+            .find(|link_type| link_type.attributes.name == LINK_TYPE)
+            .map(|link_type| link_type.id)
+            .ok_or_else(|| anyhow!("firefly has no '{}' link type", LINK_TYPE))?;
+        self.link_type_id = Some(id.clone());
+        Ok(id)
+    }
+
+    /// Link the transaction `from_id` to the transaction `to_id`, both being ids of transactions
+    /// as returned when storing them.
+    pub async fn link(
+        &mut self,
+        bridge: &Bridge,
+        from_id: &str,
+        to_id: &str,
+        notes: &str,
+    ) -> Result<()> {
+        let link_type_id = self
+            .link_type_id(bridge)
+            .await
+            .context("unable to find link type")?;
+        // Links are made between journals rather than transactions
+        let outward_id = firefly::journal_id(&bridge.firefly_config, from_id).await?;
+        let inward_id = firefly::journal_id(&bridge.firefly_config, to_id).await?;
+
+        let _: Single<Resource> = firefly::send(
+            &bridge.firefly_config,
+            Method::POST,
+            "/transaction_links",
+            &LinkStore {
+                link_type_id: &link_type_id,
+                outward_id: &outward_id,
+                inward_id: &inward_id,
+                notes,
+            },
+        )
+        .await
+        .context("unable to link transactions")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use anyhow::{Context, Result};
+use rusqlite::{Connection, ErrorCode};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::info;
+
+/// Time between attempts when waiting for another run to finish.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Advisory lock which prevents concurrent runs against the same state.
+///
+/// The lock is an exclusive lock on an SQLite database next to the state file, so it is released
+/// by the operating system even if the process is killed.
+pub struct RunLock {
+    _conn: Connection,
+}
+
+/// Lock file belonging to the given state file.
+pub fn lock_file(state_file: &Path) -> PathBuf {
+    let mut path = state_file.as_os_str().to_owned();
+    path.push(".lock");
+    path.into()
+}
+
+impl RunLock {
+    /// Take the lock for `state_file`, returning `None` if another run is holding it.
+    pub fn try_acquire(state_file: &Path) -> Result<Option<RunLock>> {
+        let path = lock_file(state_file);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("unable to create state directory '{}'", dir.display()))?;
+        }
+
+        let conn = Connection::open(&path)
+            .with_context(|| format!("unable to open lock file '{}'", path.display()))?;
+        conn.busy_timeout(Duration::from_secs(0))?;
+
+        match conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE; BEGIN EXCLUSIVE;") {
+            Ok(()) => Ok(Some(RunLock { _conn: conn })),
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == ErrorCode::DatabaseBusy || e.code == ErrorCode::DatabaseLocked =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e).with_context(|| format!("unable to lock '{}'", path.display())),
+        }
+    }
+
+    /// Take the lock for `state_file`, waiting for other runs to finish.
+    pub async fn acquire(state_file: &Path) -> Result<RunLock> {
+        let mut waiting = false;
+        loop {
+            if let Some(lock) = RunLock::try_acquire(state_file)? {
+                return Ok(lock);
+            }
+
+            if !waiting {
+                info!("Another sync is running, waiting for it to finish...");
+                waiting = true;
+            }
+            tokio::time::delay_for(RETRY_INTERVAL).await;
+        }
+    }
+}
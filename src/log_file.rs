@@ -0,0 +1,215 @@
+//! Log file which is rotated when it grows too large or a new day or hour starts, keeping a
+//! bounded number of old files next to it as `<file>.1`, `<file>.2` and so on. With a state
+//! encryption key every line is written encrypted, as an `enc:v1:` value of its own, which the
+//! `decrypt` command prints as plain text again.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::crypto::StateKey;
+
+/// Size in MiB at which the log file is rotated, unless configured otherwise.
+pub const DEFAULT_MAX_SIZE: u64 = 10;
+
+/// Number of rotated log files which are kept, unless configured otherwise.
+pub const DEFAULT_MAX_FILES: usize = 7;
+
+/// When the log file is rotated, besides when it grows too large.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation::Daily
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            "never" => Ok(Rotation::Never),
+            _ => Err(anyhow!("unknown log rotation '{}'", s)),
+        }
+    }
+}
+
+impl Rotation {
+    /// The hour or day which `time` is in, the file is rotated when it changes.
+    fn period(self, time: DateTime<Local>) -> Option<String> {
+        match self {
+            Rotation::Hourly => Some(time.format("%Y-%m-%d %H").to_string()),
+            Rotation::Daily => Some(time.format("%Y-%m-%d").to_string()),
+            Rotation::Never => None,
+        }
+    }
+}
+
+/// Log file which is appended to, rotated by size and time.
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    /// Size in bytes at which the file is rotated.
+    max_size: u64,
+    /// Number of rotated files which are kept.
+    max_files: usize,
+    file: File,
+    size: u64,
+    period: Option<String>,
+    /// Key which every line is encrypted with, written as is if not set.
+    key: Option<StateKey>,
+}
+
+impl LogFile {
+    /// Append to the log file at `path`, rotating it when it grows beyond `max_size` MiB or when
+    /// the period of `rotation` changes, and keeping `max_files` rotated files. Every line is
+    /// encrypted with `key` if given.
+    pub fn open(
+        path: &Path,
+        rotation: Rotation,
+        max_size: u64,
+        max_files: usize,
+        key: Option<StateKey>,
+    ) -> Result<Self> {
+        let file = append(path).with_context(|| format!("unable to open '{}'", path.display()))?;
+        let metadata = file
+            .metadata()
+            .with_context(|| format!("unable to open '{}'", path.display()))?;
+        // A file left by an earlier run belongs to the period it was last written in
+        let modified = metadata
+            .modified()
+            .map(DateTime::<Local>::from)
+            .unwrap_or_else(|_| Local::now());
+        Ok(LogFile {
+            path: path.into(),
+            rotation,
+            max_size: max_size.saturating_mul(1024 * 1024).max(1),
+            max_files,
+            file,
+            size: metadata.len(),
+            period: rotation.period(modified),
+            key,
+        })
+    }
+
+    /// Share the file between the writers of every log line.
+    pub fn shared(self) -> impl Fn() -> SharedLogFile + Send + Sync + 'static {
+        let file = Arc::new(Mutex::new(self));
+        move || SharedLogFile(file.clone())
+    }
+
+    /// Move `<file>.1` to `<file>.2` and so on, dropping the oldest, and start a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let older = numbered(&self.path, index);
+                if older.exists() {
+                    fs::rename(&older, numbered(&self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = self.rotation.period(Local::now());
+        if self.size > 0 && (self.size + buf.len() as u64 > self.max_size || period != self.period)
+        {
+            self.rotate()?;
+        }
+        self.period = period;
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writer of a log line to the shared log file.
+pub struct SharedLogFile(Arc<Mutex<LogFile>>);
+
+impl Write for SharedLogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self.0.lock().unwrap();
+        match &file.key {
+            Some(key) => {
+                let line = String::from_utf8_lossy(buf);
+                let encrypted = key
+                    .encrypt(line.trim_end_matches('\n'))
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                file.write_all(format!("{}\n", encrypted).as_bytes())?;
+            }
+            None => file.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn numbered(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    name.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use secrecy::Secret;
+
+    #[test]
+    fn encrypted_lines_can_be_read_back() {
+        let key = StateKey::from_base64(&Secret::new(base64::encode([7; 32]))).unwrap();
+        let path = std::env::temp_dir().join(format!("bridge-log-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let log_file = LogFile::open(&path, Rotation::Never, 1, 0, Some(key.clone())).unwrap();
+        let writer = log_file.shared();
+        writer().write_all(b"first line\n").unwrap();
+        writer().write_all(b"second line\n").unwrap();
+
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(!written.contains("line"));
+
+        let mut read = Vec::new();
+        crypto::decrypt_lines(&key, written.as_bytes(), &mut read).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            String::from_utf8(read).unwrap(),
+            "first line\nsecond line\n"
+        );
+    }
+}
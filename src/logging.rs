@@ -0,0 +1,135 @@
+//! Logging of what the bridge is doing through `tracing`.
+//!
+//! Everything is written to stderr, above the progress bars if they are drawn, filtered by
+//! `RUST_LOG` which defaults to `info`. The log can also be written to a rotated file, and the
+//! spans of a sync exported as traces.
+
+use anyhow::{anyhow, Context, Result};
+use opentelemetry::sdk::Resource;
+use opentelemetry::KeyValue;
+use serde::Deserialize;
+use std::str::FromStr;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+use crate::log_file::LogFile;
+use crate::progress;
+
+const DEFAULT_FILTER: &str = "info";
+
+/// How events are written to stderr and the log file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human readable line per event.
+    Text,
+    /// One JSON object per event, with its fields and those of the spans it is in, so that the
+    /// log can be shipped to and queried in e.g. Loki or Elasticsearch.
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("unknown log format '{}'", s)),
+        }
+    }
+}
+
+/// Install the global subscriber, must be called once before anything is logged. Events are also
+/// written to `log_file` if it is set, and spans exported as OpenTelemetry traces to the OTLP
+/// collector at `otlp_endpoint` if it is set.
+pub fn init(
+    format: LogFormat,
+    otlp_endpoint: Option<&str>,
+    log_file: Option<LogFile>,
+) -> Result<()> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let (text, json) = match format {
+        LogFormat::Text => (
+            Some(
+                fmt::layer()
+                    .with_target(false)
+                    .with_writer(progress::log_writer),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(
+                fmt::layer()
+                    .with_target(false)
+                    .with_writer(std::io::stderr)
+                    .json()
+                    .flatten_event(true),
+            ),
+        ),
+    };
+    let log_file = log_file.map(LogFile::shared);
+    let (file_text, file_json) = match (log_file, format) {
+        (Some(writer), LogFormat::Text) => (
+            Some(
+                fmt::layer()
+                    .with_target(false)
+                    .with_ansi(false)
+                    .with_writer(writer),
+            ),
+            None,
+        ),
+        (Some(writer), LogFormat::Json) => (
+            None,
+            Some(
+                fmt::layer()
+                    .with_target(false)
+                    .with_writer(writer)
+                    .json()
+                    .flatten_event(true),
+            ),
+        ),
+        (None, _) => (None, None),
+    };
+    let traces = match otlp_endpoint {
+        Some(endpoint) => Some(tracing_opentelemetry::layer().with_tracer(tracer(endpoint)?)),
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .with(file_text)
+        .with(file_json)
+        .with(traces)
+        .init();
+    Ok(())
+}
+
+fn tracer(endpoint: &str) -> Result<opentelemetry::sdk::trace::Tracer> {
+    opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .with_trace_config(
+            opentelemetry::sdk::trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )])),
+        )
+        .install()
+        .with_context(|| format!("unable to export traces to '{}'", endpoint))
+}
+
+/// Send the spans which have not been exported yet, before the process exits.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
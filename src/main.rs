@@ -1,550 +1,1793 @@
 use anyhow::{anyhow, Context, Result};
-use chrono::Datelike;
-use firefly_iii::apis::{
-    client::APIClient as FireflyClient, configuration::Configuration as FireflyConfiguration,
-};
-use lazy_static::lazy_static;
-use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
-use regex::Regex;
-use sbanken::apis::{
-    client::APIClient as SbankenClient, configuration::Configuration as SbankenConfiguration,
+use chrono::NaiveDate;
+use firefly_iii::apis::configuration::Configuration as FireflyConfiguration;
+use sbanken_firefly_bridge::{
+    auth, bank,
+    config::{AccountRole, Config, CustomerConfig},
+    convert::{self, Conversion, DateField},
+    crypto,
+    dedup::TransferMatching,
+    error::SyncError,
+    filter::AccountFilter,
+    firefly, health,
+    http::{self, HttpSettings},
+    init,
+    lock::{lock_file, RunLock},
+    log_file::{self, LogFile, Rotation},
+    logging::{self, LogFormat},
+    metrics,
+    money::Money,
+    payments,
+    plan::Plan,
+    progress, purge, reapply,
+    report::{self, ReportGroup},
+    reporting, reservations, retry, rollback,
+    rules::Rules,
+    shutdown, standing_orders,
+    state::{Run, State, StateStore},
+    summary::{self, RunSummary},
+    sync,
+    throttle::{self, Api},
+    transactions::{ApiVersion, Chunk},
+    transfer, Bridge,
 };
 use secrecy::{ExposeSecret, Secret};
-use serde::Deserialize;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
+use tracing::{error, info, info_span, warn, Instrument};
+
+const DEFAULT_DELAY_DAYS: i64 = 10;
+const DEFAULT_FIRST_YEAR: i32 = 2019;
+
+/// Exit code when nothing could be done, e.g. because Sbanken or Firefly is unreachable.
+const EXIT_FAILURE: i32 = 1;
+/// Exit code when the run finished, but some transactions or accounts failed.
+const EXIT_PARTIAL_FAILURE: i32 = 2;
+/// Exit code when Sbanken or Firefly refused the credentials.
+const EXIT_AUTH_FAILURE: i32 = 3;
+
+/// A run which finished, but failed to import some transactions or accounts.
+#[derive(Debug)]
+struct PartialFailure(usize);
+
+impl std::fmt::Display for PartialFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} transaction(s) or account(s) failed, see the summary",
+            self.0
+        )
+    }
+}
 
-const DATE_FORMAT: &str = "%Y-%m-%d";
+impl std::error::Error for PartialFailure {}
+
+fn exit_code(error: &anyhow::Error) -> i32 {
+    let auth_failure = error.chain().any(|cause| {
+        cause
+            .downcast_ref::<SyncError>()
+            .map_or(false, SyncError::is_auth)
+            || cause
+                .downcast_ref::<reqwest::Error>()
+                .and_then(reqwest::Error::status)
+                .map_or(false, |status| {
+                    status == reqwest::StatusCode::UNAUTHORIZED
+                        || status == reqwest::StatusCode::FORBIDDEN
+                })
+    });
+
+    if auth_failure {
+        EXIT_AUTH_FAILURE
+    } else if error.is::<PartialFailure>() {
+        EXIT_PARTIAL_FAILURE
+    } else {
+        EXIT_FAILURE
+    }
+}
 
 #[derive(StructOpt, Debug)]
 #[structopt(about, author)]
 struct Opts {
+    /// Configuration file (TOML or YAML), defaults to `$XDG_CONFIG_HOME/sbanken-firefly-bridge/config.toml`
+    #[structopt(long, global = true, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Use the settings of this profile from the configuration file
+    #[structopt(long, global = true)]
+    profile: Option<String>,
+    /// Keep the sync progress in a tag in Firefly instead of in a local state file
+    #[structopt(long, global = true)]
+    state_in_firefly: bool,
+    /// Directory for the state database, defaults to `$XDG_STATE_HOME/sbanken-firefly-bridge`
+    #[structopt(long, global = true, parse(from_os_str))]
+    state_dir: Option<PathBuf>,
+    /// State database, overrides --state-dir
+    #[structopt(long, global = true, parse(from_os_str))]
+    state_file: Option<PathBuf>,
+    /// Base64 encoded 32 byte key for encrypting sensitive data in the state database, the log
+    /// file and the run summary (e.g. from `head -c 32 /dev/urandom | base64`), the metrics file
+    /// is left readable for the node exporter
+    #[structopt(long, global = true, env, hide_env_values = true)]
+    state_encryption_key: Option<Secret<String>>,
+    /// JSON file to write a summary of every run to [default: run-summary.json next to the state
+    /// database]
+    #[structopt(long, global = true, parse(from_os_str))]
+    run_summary: Option<PathBuf>,
+    /// Book transactions on their accounting or interest date [default: accounting]
+    #[structopt(long, global = true, possible_values = &["accounting", "interest"])]
+    date_field: Option<DateField>,
+    /// Version of the Sbanken transactions endpoint, v2 has details about card payments and
+    /// counterparties but needs a base url which serves it [default: v1]
+    #[structopt(long, global = true, possible_values = &["v1", "v2"])]
+    sbanken_api: Option<ApiVersion>,
+    /// File with rules for categorizing transactions and naming merchants (TOML or YAML), defaults
+    /// to the built-in rules
+    #[structopt(long, global = true, parse(from_os_str))]
+    rules_file: Option<PathBuf>,
+    /// Tag on every transaction created by the bridge [default: sbanken-bridge]
+    #[structopt(long, global = true)]
+    import_tag: Option<String>,
+    /// Keep the transaction as Sbanken sent it in the notes of the Firefly transaction
+    #[structopt(long, global = true)]
+    raw_notes: bool,
+    /// Append the KID or payment reference of giro payments to the description
+    #[structopt(long, global = true)]
+    kid_in_description: bool,
+    /// Let Firefly run its rules (budgets, categories, tags) on every imported transaction
+    #[structopt(long, global = true)]
+    apply_firefly_rules: bool,
+    /// Let Firefly trigger its webhooks for imported transactions, e.g. not during backfills
+    /// [default: true]
+    #[structopt(long, global = true)]
+    fire_webhooks: Option<bool>,
+    /// Attach the transaction as Sbanken sent it as a JSON file to every imported transaction
+    #[structopt(long, global = true)]
+    attach_source: bool,
+    /// Import card reservations as pending transactions until they are booked
+    #[structopt(long, global = true)]
+    import_reservations: bool,
+    /// Mirror eFakturas as Firefly bills and link their payments to them
+    #[structopt(long, global = true)]
+    efaktura_bills: bool,
+    /// Import scheduled payments as future transactions until they are booked
+    #[structopt(long, global = true)]
+    scheduled_payments: bool,
+    /// Mirror standing orders as recurring transactions
+    #[structopt(long, global = true)]
+    standing_orders: bool,
+    /// Track the deposits into BSU accounts in a Firefly piggy bank for each year
+    #[structopt(long, global = true)]
+    bsu_piggy_bank: bool,
+    /// Track the balance of savings accounts in a Firefly piggy bank for each account
+    #[structopt(long, global = true)]
+    savings_piggy_banks: bool,
+    /// Update the name, account number and role of existing Firefly accounts when they change
+    #[structopt(long, global = true)]
+    update_accounts: bool,
+    /// Mark the Firefly accounts of closed Sbanken accounts as inactive
+    #[structopt(long, global = true)]
+    archive_closed_accounts: bool,
+    /// Abort on accounts of unknown types instead of mirroring them as asset accounts
+    #[structopt(long, global = true)]
+    strict_account_types: bool,
+    /// Firefly object group to place the accounts of the bridge in, e.g. Sbanken
+    #[structopt(long, global = true)]
+    account_group: Option<String>,
+    /// Format of the log on stderr, json writes one event with its fields per line [default: text]
+    #[structopt(long, global = true, possible_values = &["text", "json"])]
+    log_format: Option<LogFormat>,
+    /// File to also write the log to, rotated and kept as <file>.1, <file>.2 and so on
+    /// [default: none]
+    #[structopt(long, global = true)]
+    log_file: Option<PathBuf>,
+    /// When the log file is rotated, besides when it grows beyond --log-max-size [default: daily]
+    #[structopt(long, global = true, possible_values = &["hourly", "daily", "never"])]
+    log_rotation: Option<Rotation>,
+    /// Size in MiB at which the log file is rotated [default: 10]
+    #[structopt(long, global = true)]
+    log_max_size: Option<u64>,
+    /// Rotated log files to keep, older ones are deleted [default: 7]
+    #[structopt(long, global = true)]
+    log_max_files: Option<usize>,
+    /// DSN of a Sentry project to report panics and failed transactions and syncs to, without
+    /// their text, amounts or account numbers [default: none]
+    #[structopt(long, global = true, env, hide_env_values = true)]
+    sentry_dsn: Option<String>,
+    /// OTLP collector to export traces of the syncs to, e.g. http://tempo:4317 [default: none]
+    #[structopt(long, global = true)]
+    otlp_endpoint: Option<String>,
+    /// Address to serve Prometheus metrics on in daemon mode, e.g. 0.0.0.0:9898
+    #[structopt(long, global = true)]
+    metrics_address: Option<SocketAddr>,
+    /// Address to serve /healthz and /readyz on in daemon mode, e.g. 0.0.0.0:9899, must differ
+    /// from --metrics-address
+    #[structopt(long, global = true)]
+    health_address: Option<SocketAddr>,
+    /// Hours within which the last sync must have succeeded for /readyz to report ready
+    /// [default: twice the interval]
+    #[structopt(long, global = true)]
+    ready_within: Option<u64>,
+    /// File to write Prometheus metrics to after every sync, e.g. for the textfile collector of
+    /// the node exporter
+    #[structopt(long, global = true)]
+    metrics_file: Option<PathBuf>,
+    /// Times a request which failed with a timeout, dropped connection or server error is made
+    /// before giving up, 1 disables retrying [default: 4]
+    #[structopt(long, global = true)]
+    max_attempts: Option<u32>,
+    /// Most requests per second to make to Sbanken, e.g. 5 [default: unlimited]
+    #[structopt(long, global = true)]
+    sbanken_requests_per_second: Option<f64>,
+    /// Most requests per second to make to Firefly, e.g. 20 [default: unlimited]
+    #[structopt(long, global = true)]
+    firefly_requests_per_second: Option<f64>,
+    /// Seconds to wait for a connection to Sbanken or Firefly [default: 10]
+    #[structopt(long, global = true)]
+    connect_timeout: Option<u64>,
+    /// Seconds to wait for a request to Sbanken or Firefly to finish [default: 60]
+    #[structopt(long, global = true)]
+    request_timeout: Option<u64>,
+    /// Most idle connections to keep open to each host [default: unlimited]
+    #[structopt(long, global = true)]
+    pool_max_idle_per_host: Option<usize>,
+    /// Seconds between TCP keep-alive probes on open connections [default: none]
+    #[structopt(long, global = true)]
+    keep_alive: Option<u64>,
+    /// Proxy for all requests to Sbanken and Firefly, e.g. http://proxy:3128 [default: from
+    /// HTTPS_PROXY or HTTP_PROXY]
+    #[structopt(long, global = true)]
+    proxy: Option<String>,
+    /// Seconds to reuse the Firefly accounts listed by an earlier run for, 0 to list them on every
+    /// run [default: 3600]
+    #[structopt(long, global = true)]
+    accounts_cache_ttl: Option<u64>,
+    /// Size of the windows which transactions are fetched and transfers are paired in, smaller
+    /// ones use less memory [default: month]
+    #[structopt(long, global = true, possible_values = &["month", "year"])]
+    chunk: Option<Chunk>,
+    /// Most accounts to fetch and store transactions of at the same time [default: 1]
+    #[structopt(long, global = true)]
+    jobs: Option<usize>,
+    /// Most years to sync at the same time, each pairing the transfers within it, which speeds up
+    /// backfills of many years, e.g. 3 [default: 1]
+    #[structopt(long, global = true)]
+    parallel_years: Option<usize>,
+    /// Most transactions to store in Firefly at the same time, e.g. 4 [default: 1]
+    #[structopt(long, global = true)]
+    concurrent_uploads: Option<usize>,
+    /// Get a new Sbanken token instead of reusing the one cached by an earlier run
+    #[structopt(long, global = true)]
+    force_reauth: bool,
+    /// Wait for another running sync to finish instead of exiting
+    #[structopt(long, global = true)]
+    wait_for_lock: bool,
+    /// Print accounts, status, check and dry runs as text or as JSON, one object per customer
+    /// and line
+    #[structopt(
+        long,
+        global = true,
+        default_value = "text",
+        possible_values = &["text", "json"]
+    )]
+    output: Output,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+/// How the informational commands print what they found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Text,
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => Err(anyhow!("unknown output format '{}'", s)),
+        }
+    }
+}
+
+/// Print `value` as JSON on a single line.
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!(
+        "{}",
+        serde_json::to_string(value).context("unable to serialize output")?
+    );
+    Ok(())
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Import new accounts and transactions from Sbanken into Firefly
+    Sync {
+        #[structopt(flatten)]
+        credentials: Credentials,
+        #[structopt(flatten)]
+        filter: FilterOpts,
+        #[structopt(flatten)]
+        transfers: TransferOpts,
+        #[structopt(long)]
+        delay_days: Option<i64>,
+        #[structopt(long)]
+        first_year: Option<i32>,
+        /// Print the changes which would be made without writing anything to Firefly
+        #[structopt(long)]
+        dry_run: bool,
+        /// Import transactions from this day (YYYY-MM-DD), ignoring and not updating the sync state
+        #[structopt(long)]
+        since: Option<NaiveDate>,
+        /// Import transactions until this day (YYYY-MM-DD), ignoring and not updating the sync state
+        #[structopt(long)]
+        until: Option<NaiveDate>,
+    },
+    /// Import historical transactions in a date range without touching the sync state
+    Backfill {
+        #[structopt(flatten)]
+        credentials: Credentials,
+        #[structopt(flatten)]
+        filter: FilterOpts,
+        #[structopt(flatten)]
+        transfers: TransferOpts,
+        /// First day to import (YYYY-MM-DD)
+        #[structopt(long)]
+        from: NaiveDate,
+        /// Last day to import (YYYY-MM-DD)
+        #[structopt(long)]
+        to: NaiveDate,
+        /// Print the changes which would be made without writing anything to Firefly
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Keep running and sync at a fixed interval
+    Daemon {
+        #[structopt(flatten)]
+        credentials: Credentials,
+        #[structopt(flatten)]
+        filter: FilterOpts,
+        #[structopt(flatten)]
+        transfers: TransferOpts,
+        #[structopt(long)]
+        delay_days: Option<i64>,
+        #[structopt(long)]
+        first_year: Option<i32>,
+        /// Time between each sync (e.g. "30m", "6h" or "1day")
+        #[structopt(long, default_value = "6h", parse(try_from_str = humantime::parse_duration))]
+        interval: Duration,
+    },
+    /// List Sbanken accounts and the Firefly accounts they are mapped to
+    Accounts {
+        #[structopt(flatten)]
+        credentials: Credentials,
+    },
+    /// Show how far transactions have been synced
+    Status {
+        #[structopt(flatten)]
+        credentials: Credentials,
+        #[structopt(long)]
+        delay_days: Option<i64>,
+    },
+    /// Show the monthly spending of the synced accounts as summed up by Firefly
+    Report {
+        #[structopt(flatten)]
+        credentials: Credentials,
+        /// Number of months to show, including the current one
+        #[structopt(long, default_value = "3")]
+        months: u32,
+        /// Sum up the spending by category or by account
+        #[structopt(long, default_value = "category", possible_values = &["category", "account"])]
+        by: ReportGroup,
+    },
+    /// Delete everything a sync run created in Firefly and rewind the sync state
+    Rollback {
+        #[structopt(flatten)]
+        credentials: Credentials,
+        /// Id of the run to undo, as listed by `status`
+        #[structopt(long = "run")]
+        run_id: i64,
+        /// Delete the accounts created by the run as well
+        #[structopt(long)]
+        accounts: bool,
+    },
+    /// Convert imported transactions again and update their description, category and
+    /// counterparty in Firefly where the current rules give a different result
+    Reapply {
+        #[structopt(flatten)]
+        credentials: Credentials,
+        /// List what would be updated without updating anything
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Store the transactions which Firefly refused in earlier runs again
+    RetryFailed {
+        #[structopt(flatten)]
+        credentials: Credentials,
+        /// List the failed transactions without storing anything
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Delete every transaction the bridge has created from Firefly and reset the sync state
+    Purge {
+        #[structopt(flatten)]
+        credentials: Credentials,
+        /// Delete the asset accounts mirroring Sbanken accounts as well
+        #[structopt(long)]
+        accounts: bool,
+        /// List what would be deleted without deleting anything
+        #[structopt(long)]
+        dry_run: bool,
+        /// Confirm that everything should be deleted
+        #[structopt(long)]
+        yes: bool,
+    },
+    /// Transfer money between two Sbanken accounts and record the transfer in Firefly
+    Transfer {
+        #[structopt(flatten)]
+        credentials: Credentials,
+        /// Account to transfer from (name or account number)
+        #[structopt(long)]
+        from: String,
+        /// Account to transfer to (name or account number)
+        #[structopt(long)]
+        to: String,
+        /// Amount in NOK with at most two decimals (e.g. 1500 or 99.50)
+        #[structopt(long)]
+        amount: Money,
+        /// Message on the transfer, at most 30 characters
+        #[structopt(long)]
+        message: Option<String>,
+        /// Look up the accounts without transferring anything
+        #[structopt(long)]
+        dry_run: bool,
+        /// Confirm that the money should be transferred
+        #[structopt(long)]
+        yes: bool,
+    },
+    /// Interactively create a configuration file
+    Init,
+    /// Print a log file or run summary which was written encrypted with the state encryption key
+    Decrypt {
+        /// File to print, e.g. the log file or the run summary
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Validate credentials and connectivity towards Sbanken and Firefly
+    Check {
+        #[structopt(flatten)]
+        credentials: Credentials,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+struct FilterOpts {
+    /// Only sync these accounts (comma separated names or account numbers)
+    #[structopt(long, use_delimiter = true)]
+    only_accounts: Vec<String>,
+    /// Never sync these accounts (comma separated names or account numbers)
+    #[structopt(long, use_delimiter = true)]
+    exclude_accounts: Vec<String>,
+}
+
+#[derive(StructOpt, Debug)]
+struct TransferOpts {
+    /// Number of days the two legs of an internal transfer may be booked apart [default: 0]
+    #[structopt(long)]
+    transfer_date_tolerance: Option<i64>,
+    /// Sbanken transaction types of internal transfers (comma separated) [default: OVFNETTB,MOB.B.OVF,TILBAKEF.]
+    #[structopt(long, use_delimiter = true)]
+    transfer_types: Vec<String>,
+    /// Number of days to wait for the other leg of an internal transfer before importing a leg on its own [default: 7]
+    #[structopt(long)]
+    transfer_pending_days: Option<i64>,
+    /// Import the legs of internal transfers as a withdrawal and a deposit linked to each other
+    #[structopt(long)]
+    transfer_legs_separately: bool,
+}
+
+impl TransferOpts {
+    fn into_matching(self, config: &Config) -> TransferMatching {
+        let mut matching = TransferMatching::default();
+        if let Some(date_tolerance) = self
+            .transfer_date_tolerance
+            .or(config.transfer_date_tolerance)
+        {
+            matching.date_tolerance = date_tolerance;
+        }
+        if let Some(pending_days) = self.transfer_pending_days.or(config.transfer_pending_days) {
+            matching.pending_days = pending_days;
+        }
+        matching.separate_legs = self.transfer_legs_separately || config.transfer_legs_separately;
+        if !self.transfer_types.is_empty() {
+            matching.types = self.transfer_types;
+        } else if !config.transfer_types.is_empty() {
+            matching.types = config.transfer_types.clone();
+        }
+        matching
+    }
+}
+
+impl FilterOpts {
+    fn into_filter(self, config: &Config) -> AccountFilter {
+        AccountFilter {
+            only: if self.only_accounts.is_empty() {
+                config.only_accounts.clone()
+            } else {
+                self.only_accounts
+            },
+            exclude: if self.exclude_accounts.is_empty() {
+                config.exclude_accounts.clone()
+            } else {
+                self.exclude_accounts
+            },
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct Credentials {
     #[structopt(long, env, hide_env_values = true)]
-    sbanken_client_id: Secret<String>,
+    sbanken_client_id: Option<Secret<String>>,
     #[structopt(long, env, hide_env_values = true)]
-    sbanken_client_secret: Secret<String>,
+    sbanken_client_secret: Option<Secret<String>>,
     #[structopt(long, env, hide_env_values = true)]
-    sbanken_customer_id: Secret<String>,
+    sbanken_customer_id: Option<Secret<String>>,
     #[structopt(long, env)]
-    sbanken_auth_url: String,
+    sbanken_auth_url: Option<String>,
     #[structopt(long, env)]
-    sbanken_base_url: String,
+    sbanken_base_url: Option<String>,
     #[structopt(long, env)]
-    firefly_base_url: String,
+    firefly_base_url: Option<String>,
     #[structopt(long, env, hide_env_values = true)]
-    firefly_access_token: Secret<String>,
-    #[structopt(long, default_value = "10")]
-    delay_days: i64,
-    #[structopt(long, default_value = "2019")]
-    first_year: i32,
+    firefly_access_token: Option<Secret<String>>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let opt = Opts::from_args();
+/// Pick the value from the command line (or environment) before the one from the config file.
+fn required<'a, T>(cli: &'a Option<T>, file: &'a Option<T>, name: &str) -> Result<&'a T> {
+    cli.as_ref().or_else(|| file.as_ref()).ok_or_else(|| {
+        anyhow!(
+            "missing '{}', set it with --{} or in the config file",
+            name,
+            name.replace('_', "-")
+        )
+    })
+}
 
-    let sbanken_token = get_auth_token(
-        &opt.sbanken_auth_url,
-        &opt.sbanken_client_id,
-        &opt.sbanken_client_secret,
-    )
-    .await
-    .context("unable to get sbanken auth token")?;
+impl Credentials {
+    /// Connect a bridge for every customer in the config file, or for the single customer of the
+    /// credentials if none are listed.
+    async fn connect(&self, config: &Config) -> Result<Vec<Bridge>> {
+        firefly::check_version(&self.firefly_configuration(config)?).await?;
 
-    let sbanken_client = SbankenClient::new(SbankenConfiguration {
-        base_path: opt.sbanken_base_url,
-        oauth_access_token: Some(sbanken_token.expose_secret().into()),
-        ..SbankenConfiguration::default()
-    });
+        if config.customers.is_empty() {
+            return Ok(vec![self.connect_customer(config, None).await?]);
+        }
 
-    let firefly_client = FireflyClient::new(FireflyConfiguration {
-        base_path: opt.firefly_base_url,
-        oauth_access_token: Some(opt.firefly_access_token.expose_secret().into()),
-        ..FireflyConfiguration::default()
-    });
+        let mut bridges = Vec::new();
+        for customer in &config.customers {
+            let bridge = self
+                .connect_customer(config, Some(customer))
+                .await
+                .with_context(|| format!("unable to connect customer '{}'", customer.name))?;
+            bridges.push(bridge);
+        }
+        Ok(bridges)
+    }
 
-    let sbanken_accounts = sbanken_client
-        .accounts_api()
-        .list_accounts(Some(opt.sbanken_customer_id.expose_secret()))
-        .await
-        .context("unable to fetch accounts from sbanken")?
-        .items
-        .unwrap();
-
-    let firefly_accounts = firefly_client
-        .accounts_api()
-        .list_account(
-            None,
-            None,
-            Some(firefly_iii::models::AccountTypeFilter::Asset),
-        )
-        .await
-        .context("unable to get existing accounts")?;
-
-    for sbanken_account in sbanken_accounts.iter().filter(|acc| {
-        !firefly_accounts.data.iter().any(|account_read| {
-            account_read
-                .attributes
-                .notes
-                .as_ref()
-                .map(|notes| notes == acc.account_id.as_ref().unwrap())
-                .unwrap_or(false)
-        })
-    }) {
-        eprintln!(
-            "Account '{}' does not already exist, creating...",
-            sbanken_account.name.as_ref().unwrap()
+    /// Connect a bridge for `customer`, whose credentials take precedence over the shared ones.
+    async fn connect_customer(
+        &self,
+        config: &Config,
+        customer: Option<&CustomerConfig>,
+    ) -> Result<Bridge> {
+        let sbanken_client_id = match customer.and_then(|c| c.sbanken_client_id.as_ref()) {
+            Some(client_id) => client_id,
+            None => required(
+                &self.sbanken_client_id,
+                &config.sbanken_client_id,
+                "sbanken_client_id",
+            )?,
+        };
+        let sbanken_client_secret = match customer.and_then(|c| c.sbanken_client_secret.as_ref()) {
+            Some(client_secret) => client_secret,
+            None => required(
+                &self.sbanken_client_secret,
+                &config.sbanken_client_secret,
+                "sbanken_client_secret",
+            )?,
+        };
+        let sbanken_auth_url = required(
+            &self.sbanken_auth_url,
+            &config.sbanken_auth_url,
+            "sbanken_auth_url",
+        )?;
+        let sbanken_base_url = required(
+            &self.sbanken_base_url,
+            &config.sbanken_base_url,
+            "sbanken_base_url",
+        )?;
+        let firefly_base_url = required(
+            &self.firefly_base_url,
+            &config.firefly_base_url,
+            "firefly_base_url",
+        )?;
+        let firefly_access_token = required(
+            &self.firefly_access_token,
+            &config.firefly_access_token,
+            "firefly_access_token",
+        )?;
+
+        let state_store = self.state_store(config)?;
+        let cached_token = if config.force_reauth {
+            None
+        } else {
+            state_store
+                .cached_token(sbanken_client_id)
+                .unwrap_or_else(|e| {
+                    warn!(
+                        "Unable to read cached sbanken token, getting a new one: {:#}",
+                        e
+                    );
+                    None
+                })
+        };
+        let sbanken_token = match cached_token {
+            Some(token) => token,
+            None => {
+                let token = auth::get_auth_token(
+                    sbanken_auth_url,
+                    sbanken_client_id,
+                    sbanken_client_secret,
+                )
+                .await
+                .context("unable to get sbanken auth token")?;
+                if let Err(e) = state_store.cache_token(sbanken_client_id, &token) {
+                    warn!("Unable to cache sbanken token: {:#}", e);
+                }
+                token
+            }
+        };
+
+        let mut bridge = Bridge::new(
+            sbanken_base_url,
+            &sbanken_token,
+            Secret::new(String::new()),
+            firefly_base_url,
+            firefly_access_token,
         );
-        firefly_client
-            .accounts_api()
-            .store_account(convert_account(&sbanken_account).context("unable to convert account")?)
-            .await
-            .context("unable to store account")?;
+        bridge.sbanken_login = Some(auth::Login {
+            auth_url: sbanken_auth_url.clone(),
+            client_id: sbanken_client_id.clone(),
+            client_secret: sbanken_client_secret.clone(),
+        });
+        let sbanken_customer_id = match customer {
+            Some(customer) => customer.sbanken_customer_id.as_ref(),
+            None => self
+                .sbanken_customer_id
+                .as_ref()
+                .or_else(|| config.sbanken_customer_id.as_ref()),
+        };
+        bridge.sbanken_customer_id = match sbanken_customer_id {
+            Some(customer_id) => Secret::new(customer_id.expose_secret().clone()),
+            None => bank::customer_id(&bridge)
+                .await
+                .context("unable to find the sbanken customer id")?,
+        };
+        bridge.customer = customer.map(|customer| customer.name.clone());
+        bridge.fire_webhooks = config.fire_webhooks.unwrap_or(true);
+        bridge.attach_source = config.attach_source;
+        bridge.accounts_cache_ttl = config
+            .accounts_cache_ttl
+            .unwrap_or(sync::ACCOUNTS_CACHE_TTL);
+        bridge.chunk = config.chunk.unwrap_or_default();
+        bridge.jobs = config.jobs.unwrap_or(1).max(1);
+        bridge.parallel_years = config.parallel_years.unwrap_or(1).max(1);
+        bridge.concurrent_uploads = config.concurrent_uploads.unwrap_or(1).max(1);
+        bridge.accounts = config.accounts.clone();
+        bridge.bsu_piggy_bank = config.bsu_piggy_bank;
+        bridge.standing_orders = config.standing_orders;
+        bridge.scheduled_payments = config.scheduled_payments;
+        bridge.efaktura_bills = config.efaktura_bills;
+        bridge.import_reservations = config.import_reservations;
+        bridge.update_accounts = config.update_accounts;
+        bridge.archive_closed_accounts = config.archive_closed_accounts;
+        bridge.account_group = config.account_group.clone();
+        bridge.fallback_account_role = if config.strict_account_types {
+            None
+        } else {
+            Some(config.default_account_role.unwrap_or(AccountRole::Default))
+        };
+        if let Some(cap) = config.bsu_yearly_cap {
+            bridge.bsu_yearly_cap = cap;
+        }
+        bridge.savings_piggy_banks = config.savings_piggy_banks;
+        if let Some(goal) = config.savings_goal {
+            bridge.savings_goal = goal;
+        }
+        bridge.sbanken_api = config.sbanken_api.unwrap_or_default();
+        bridge.conversion = Conversion {
+            date_field: config.date_field.unwrap_or_default(),
+            rules: Rules::load(config.rules_file.as_deref())?,
+            import_tag: config.import_tag(),
+            run_tag: Some(convert::run_tag()),
+            raw_notes: config.raw_notes,
+            kid_in_description: config.kid_in_description,
+            apply_rules: config.apply_firefly_rules,
+        };
+
+        Ok(bridge)
     }
 
-    let firefly_accounts = firefly_client
-        .accounts_api()
-        .list_account(
-            None,
-            None,
-            Some(firefly_iii::models::AccountTypeFilter::Asset),
-        )
-        .await
-        .context("unable to get existing accounts")?;
-
-    let first_sync_day = std::fs::read("firefly_last_sync")
-        .ok()
-        .map(|s| {
-            std::str::from_utf8(&s)
-                .context("invalid encoding in firefly_last_sync")
-                .and_then(|s| {
-                    chrono::NaiveDate::parse_from_str(s, DATE_FORMAT)
-                        .context("invalid date in firefly_last_sync")
-                })
+    /// Where to keep the sync progress, which only needs the Firefly credentials if it is kept in
+    /// Firefly.
+    fn state_store(&self, config: &Config) -> Result<StateStore> {
+        if !config.state_in_firefly {
+            return Ok(StateStore::File {
+                path: config.state_file(),
+                key: config.state_key()?,
+            });
+        }
+
+        Ok(StateStore::Firefly {
+            config: self.firefly_configuration(config)?,
+            tag: config.firefly_state_tag(),
         })
-        .transpose()?;
+    }
 
-    let last_sync_day = (chrono::Utc::today() - chrono::Duration::days(opt.delay_days)).naive_local();
+    /// Settings for talking to Firefly directly, which does not need the Sbanken credentials.
+    fn firefly_configuration(&self, config: &Config) -> Result<FireflyConfiguration> {
+        let firefly_base_url = required(
+            &self.firefly_base_url,
+            &config.firefly_base_url,
+            "firefly_base_url",
+        )?;
+        let firefly_access_token = required(
+            &self.firefly_access_token,
+            &config.firefly_access_token,
+            "firefly_access_token",
+        )?;
+
+        Ok(firefly::configuration(
+            firefly_base_url,
+            firefly_access_token,
+        ))
+    }
+}
 
-    if first_sync_day == Some(last_sync_day) {
-        eprintln!("Already updated everything until {}", last_sync_day);
-        return Ok(());
+/// Take the run lock belonging to the state file, returning `None` if another run is holding it
+/// and `wait` is not set.
+async fn take_lock(state_file: &Path, wait: bool) -> Result<Option<RunLock>> {
+    if wait {
+        RunLock::acquire(state_file).await.map(Some)
+    } else {
+        RunLock::try_acquire(state_file)
     }
+}
 
-    let actual_first_year = first_sync_day
-        .map(|day| day.year())
-        .unwrap_or(opt.first_year);
-    let actual_last_year = last_sync_day.year();
-
-    // Do one year at a time
-    for year in actual_first_year..=actual_last_year {
-        // Collect all transactions which need to be deduplicated, for each account in this vector
-        let mut needs_deduplication = Vec::new();
-
-        // Loop through all transactions for all accounts and add them to firefly
-        for sbanken_account in sbanken_accounts.iter() {
-            let account_id = sbanken_account.account_id.as_ref().unwrap();
-
-            let sbanken_transactions = sbanken_client
-                .transactions_api()
-                .get_transactions(
-                    &account_id,
-                    Some(&opt.sbanken_customer_id.expose_secret()),
-                    if year == actual_first_year {
-                        Some(
-                            first_sync_day
-                                .map(|day| day.format(DATE_FORMAT).to_string())
-                                .unwrap_or_else(|| format!("{}-01-01", year)),
-                        )
-                    } else {
-                        Some(format!("{}-01-01", year))
-                    },
-                    if year == actual_last_year {
-                        Some(last_sync_day.format(DATE_FORMAT).to_string())
-                    } else {
-                        Some(format!("{}-12-31", year))
-                    },
-                    None,
-                    Some(1000),
-                )
-                .await
-                .context("unable to get transactions for account")?;
+fn last_sync_target(delay_days: i64) -> NaiveDate {
+    (chrono::Utc::today() - chrono::Duration::days(delay_days)).naive_local()
+}
 
-            if sbanken_transactions.is_error.unwrap_or(true) {
-                eprintln!(
-                    "Error when accessing transaction, skipping: {}",
-                    sbanken_transactions.error_message.as_ref().unwrap()
-                );
-                continue;
-            }
+#[tokio::main]
+async fn main() {
+    shutdown::listen();
+    let result = run().await;
+    logging::shutdown();
+    if let Err(e) = result {
+        if e.is::<shutdown::Interrupted>() {
+            eprintln!("Stopped early, the progress so far has been saved");
+            std::process::exit(shutdown::EXIT_INTERRUPTED);
+        }
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
 
-            eprintln!(
-                "Found {} transaction(s) for account {}",
-                sbanken_transactions.available_items.unwrap(),
-                sbanken_account.name.as_ref().unwrap()
-            );
+async fn run() -> Result<()> {
+    let opts = Opts::from_args();
 
-            if let Some(firefly_account) = firefly_accounts.data.iter().find(|account_read| {
-                account_read
-                    .attributes
-                    .notes
-                    .as_ref()
-                    .map(|notes| notes == account_id)
-                    .unwrap_or(false)
-            }) {
-                eprintln!("Updating transactions...");
-
-                for sbanken_transaction in sbanken_transactions.items.unwrap() {
-                    if sbanken_transaction.transaction_type.as_deref() == Some("OVFNETTB")
-                        || sbanken_transaction.transaction_type.as_deref() == Some("MOB.B.OVF")
-                        || sbanken_transaction.transaction_type.as_deref() == Some("TILBAKEF.")
-                    {
-                        eprintln!(
-                            "{} {}: {} -- {} -- {} **internal transaction for dedup**",
-                            &sbanken_transaction.accounting_date.as_deref().unwrap()[..10],
-                            sbanken_transaction.transaction_type.as_deref().unwrap(),
-                            &firefly_account.attributes.name,
-                            sbanken_transaction.amount.unwrap(),
-                            sbanken_transaction.text.as_deref().unwrap(),
-                        );
-
-                        // Transaction is an internal bank transfer and has to be deduplicated.
-                        needs_deduplication.push((account_id, sbanken_transaction));
-                        continue;
-                    }
+    if let Command::Init = opts.command {
+        logging::init(opts.log_format.unwrap_or_default(), None, None)?;
+        let path = opts
+            .config
+            .or_else(Config::default_path)
+            .ok_or_else(|| anyhow!("unable to find a config directory, use --config"))?;
+        if opts.profile.is_some() {
+            return Err(anyhow!(
+                "init does not support profiles, add a [profiles.<name>] section manually"
+            ));
+        }
+        let config = Config {
+            state_file: opts.state_file,
+            state_dir: opts.state_dir,
+            state_in_firefly: opts.state_in_firefly,
+            state_encryption_key: opts.state_encryption_key,
+            ..Config::default()
+        };
+        return init::init(&path, &config).await;
+    }
 
-                    let firefly_transaction =
-                        convert_transaction(&firefly_account, &sbanken_transaction, None)
-                            .context("unable to convert transaction")?;
-
-                    let t = &firefly_transaction.transactions[0];
-                    eprintln!(
-                        "{} {}: {} -- {} --> {}",
-                        t.date,
-                        sbanken_transaction.transaction_type.as_deref().unwrap(),
-                        t.source_id
-                            .map(|id| format!("<account {}>", id))
-                            .or(t.source_name.clone())
-                            .unwrap_or("<missing>".into()),
-                        t.amount,
-                        t.destination_id
-                            .map(|id| format!("<account {}>", id))
-                            .or(t.destination_name.clone())
-                            .unwrap_or("<missing>".into()),
-                    );
+    let config = Config::load(opts.config.as_deref())?;
+    let mut config = match &opts.profile {
+        Some(profile) => config.select_profile(profile)?,
+        None => config,
+    };
+    config.state_file = opts.state_file.or(config.state_file);
+    config.state_dir = opts.state_dir.or(config.state_dir);
+    config.state_in_firefly = opts.state_in_firefly || config.state_in_firefly;
+    config.state_encryption_key = opts.state_encryption_key.or(config.state_encryption_key);
+    config.run_summary = opts.run_summary.or(config.run_summary);
+    config.date_field = opts.date_field.or(config.date_field);
+    config.sbanken_api = opts.sbanken_api.or(config.sbanken_api);
+    config.rules_file = opts.rules_file.or(config.rules_file);
+    config.import_tag = opts.import_tag.or(config.import_tag);
+    config.raw_notes = opts.raw_notes || config.raw_notes;
+    config.kid_in_description = opts.kid_in_description || config.kid_in_description;
+    config.apply_firefly_rules = opts.apply_firefly_rules || config.apply_firefly_rules;
+    config.fire_webhooks = opts.fire_webhooks.or(config.fire_webhooks);
+    config.attach_source = opts.attach_source || config.attach_source;
+    config.log_format = opts.log_format.or(config.log_format);
+    config.log_file = opts.log_file.or(config.log_file);
+    config.log_rotation = opts.log_rotation.or(config.log_rotation);
+    config.log_max_size = opts.log_max_size.or(config.log_max_size);
+    config.log_max_files = opts.log_max_files.or(config.log_max_files);
+    config.sentry_dsn = opts.sentry_dsn.or(config.sentry_dsn);
+    config.otlp_endpoint = opts.otlp_endpoint.or(config.otlp_endpoint);
+    config.metrics_address = opts.metrics_address.or(config.metrics_address);
+    config.health_address = opts.health_address.or(config.health_address);
+    config.ready_within = opts.ready_within.or(config.ready_within);
+    config.metrics_file = opts.metrics_file.or(config.metrics_file);
+    config.max_attempts = opts.max_attempts.or(config.max_attempts);
+    config.sbanken_requests_per_second = opts
+        .sbanken_requests_per_second
+        .or(config.sbanken_requests_per_second);
+    config.firefly_requests_per_second = opts
+        .firefly_requests_per_second
+        .or(config.firefly_requests_per_second);
+    config.connect_timeout = opts.connect_timeout.or(config.connect_timeout);
+    config.request_timeout = opts.request_timeout.or(config.request_timeout);
+    config.pool_max_idle_per_host = opts
+        .pool_max_idle_per_host
+        .or(config.pool_max_idle_per_host);
+    config.keep_alive = opts.keep_alive.or(config.keep_alive);
+    config.proxy = opts.proxy.or(config.proxy);
+    config.accounts_cache_ttl = opts.accounts_cache_ttl.or(config.accounts_cache_ttl);
+    config.chunk = opts.chunk.or(config.chunk);
+    config.jobs = opts.jobs.or(config.jobs);
+    config.parallel_years = opts.parallel_years.or(config.parallel_years);
+    config.concurrent_uploads = opts.concurrent_uploads.or(config.concurrent_uploads);
+    config.force_reauth = opts.force_reauth || config.force_reauth;
+    config.import_reservations = opts.import_reservations || config.import_reservations;
+    config.efaktura_bills = opts.efaktura_bills || config.efaktura_bills;
+    config.scheduled_payments = opts.scheduled_payments || config.scheduled_payments;
+    config.standing_orders = opts.standing_orders || config.standing_orders;
+    config.bsu_piggy_bank = opts.bsu_piggy_bank || config.bsu_piggy_bank;
+    config.savings_piggy_banks = opts.savings_piggy_banks || config.savings_piggy_banks;
+    config.update_accounts = opts.update_accounts || config.update_accounts;
+    config.archive_closed_accounts = opts.archive_closed_accounts || config.archive_closed_accounts;
+    config.strict_account_types = opts.strict_account_types || config.strict_account_types;
+    config.account_group = opts.account_group.or(config.account_group);
+    let log_file = config
+        .log_file
+        .as_deref()
+        .map(|path| {
+            LogFile::open(
+                path,
+                config.log_rotation.unwrap_or_default(),
+                config.log_max_size.unwrap_or(log_file::DEFAULT_MAX_SIZE),
+                config.log_max_files.unwrap_or(log_file::DEFAULT_MAX_FILES),
+                config.state_key()?,
+            )
+        })
+        .transpose()?;
+    logging::init(
+        config.log_format.unwrap_or_default(),
+        config.otlp_endpoint.as_deref(),
+        log_file,
+    )?;
+    // Kept until the end of the run, so that the reports are sent before the process exits
+    let _sentry = config
+        .sentry_dsn
+        .as_deref()
+        .map(reporting::init)
+        .transpose()?;
+    progress::set_enabled(config.log_format.unwrap_or_default() == LogFormat::Text);
+    retry::set_max_attempts(config.max_attempts.unwrap_or(retry::DEFAULT_MAX_ATTEMPTS));
+    throttle::set_rate(Api::Sbanken, config.sbanken_requests_per_second);
+    throttle::set_rate(Api::Firefly, config.firefly_requests_per_second);
+    http::configure(HttpSettings {
+        connect_timeout: Duration::from_secs(
+            config
+                .connect_timeout
+                .unwrap_or(http::DEFAULT_CONNECT_TIMEOUT),
+        ),
+        request_timeout: Duration::from_secs(
+            config
+                .request_timeout
+                .unwrap_or(http::DEFAULT_REQUEST_TIMEOUT),
+        ),
+        pool_max_idle_per_host: config.pool_max_idle_per_host,
+        keep_alive: config.keep_alive.map(Duration::from_secs),
+        proxy: config.proxy.clone(),
+    })?;
+
+    let state_file = config.state_file();
+    let state_in_firefly = config.state_in_firefly;
+
+    if !state_in_firefly && State::relocate(&state_file, config.local_state_file())? {
+        info!(
+            "Moved sync state from '{}' to '{}'",
+            config.local_state_file().display(),
+            state_file.display()
+        );
+    }
+    if !state_in_firefly && State::migrate_legacy(&state_file, config.legacy_state_file())? {
+        info!(
+            "Moved sync state from '{}' into '{}'",
+            config.legacy_state_file().display(),
+            state_file.display()
+        );
+    }
 
-                    let _ = firefly_client
-                        .transactions_api()
-                        .store_transaction(firefly_transaction.clone())
-                        .await
-                        .map_err(|e| {
-                            eprintln!("\tunable to store transaction, skipping: {}", e);
-                        });
-                }
+    // Only one sync may run at a time, as concurrent runs would import transactions twice
+    let _lock = match opts.command {
+        Command::Sync { .. }
+        | Command::Backfill { .. }
+        | Command::Rollback { .. }
+        | Command::Reapply { .. }
+        | Command::RetryFailed { .. }
+        | Command::Purge { .. }
+        | Command::Transfer { .. } => match take_lock(&state_file, opts.wait_for_lock).await? {
+            Some(lock) => Some(lock),
+            None => {
+                info!(
+                    "Another sync is already running ('{}' is locked), exiting",
+                    lock_file(&state_file).display()
+                );
+                return Ok(());
             }
-        }
-
-        needs_deduplication.sort_by(|(_, a), (_, b)| {
-            a.amount
-                .unwrap()
-                .abs()
-                .partial_cmp(&b.amount.unwrap().abs())
-                .expect("unreachable: amount was NaN")
-                .then_with(|| a.accounting_date.cmp(&b.accounting_date))
-                .then_with(|| a.text.cmp(&b.text))
-                .then_with(|| a.amount.unwrap().partial_cmp(&b.amount.unwrap()).unwrap())
-        });
+        },
+        _ => None,
+    };
 
-        // Find and fix identical transfers which are sorted after eachother
-        let flats: Vec<_> = needs_deduplication
-            .windows(2)
-            .map(|win| (win[0].1.amount.unwrap(), win[1].1.amount.unwrap()))
-            .scan(0, |state, (prev, cur)| {
-                let diff = cur - prev;
-
-                if diff > 0.0 {
-                    // rising "edge"
-                    *state = 0;
-                    Some(0)
-                } else if diff < 0.0 {
-                    // falling "edge"
-                    let prev_state = *state;
-                    *state = 0;
-                    Some(prev_state)
+    match opts.command {
+        Command::Sync {
+            credentials,
+            filter,
+            transfers,
+            delay_days,
+            first_year,
+            dry_run,
+            since,
+            until,
+        } => {
+            let delay_days = delay_days
+                .or(config.delay_days)
+                .unwrap_or(DEFAULT_DELAY_DAYS);
+            let first_year = first_year
+                .or(config.first_year)
+                .unwrap_or(DEFAULT_FIRST_YEAR);
+            let account_filter = filter.into_filter(&config);
+            let transfer_matching = transfers.into_matching(&config);
+            let state_store = credentials.state_store(&config)?;
+            let mut result = Ok(());
+            for mut bridge in credentials.connect(&config).await? {
+                bridge.dry_run = dry_run;
+                bridge.account_filter = account_filter.clone();
+                bridge.transfer_matching = transfer_matching.clone();
+                let customer = bridge.customer.clone();
+                let synced = if since.is_some() || until.is_some() {
+                    let first_day = since.unwrap_or_else(|| NaiveDate::from_ymd(first_year, 1, 1));
+                    let last_day = until.unwrap_or_else(|| last_sync_target(delay_days));
+                    run_range(bridge, &state_store, first_day, last_day, opts.output).await
                 } else {
-                    // flat
-                    *state += 1;
-                    Some(0)
+                    run_sync(bridge, &state_store, delay_days, first_year, opts.output).await
+                };
+                result = keep_first_error(customer.as_deref(), result, synced);
+                if shutdown::requested() {
+                    break;
                 }
-            })
-            .enumerate()
-            .filter(|&(_, flat_count)| flat_count > 0)
-            .collect(); // We have to collect to be able modify needs_deduplication
-
-        for (last_index, amount) in flats {
-            let consecutive_duplicates = amount + 1;
-
-            let first_index = (last_index + 1) - 2 * consecutive_duplicates;
+            }
+            export_results(&config);
+            result
+        }
+        Command::Backfill {
+            credentials,
+            filter,
+            transfers,
+            from,
+            to,
+            dry_run,
+        } => {
+            let account_filter = filter.into_filter(&config);
+            let transfer_matching = transfers.into_matching(&config);
+            let state_store = credentials.state_store(&config)?;
+            let mut result = Ok(());
+            for mut bridge in credentials.connect(&config).await? {
+                bridge.dry_run = dry_run;
+                bridge.account_filter = account_filter.clone();
+                bridge.transfer_matching = transfer_matching.clone();
+                let customer = bridge.customer.clone();
+                let synced = run_range(bridge, &state_store, from, to, opts.output).await;
+                result = keep_first_error(customer.as_deref(), result, synced);
+                if shutdown::requested() {
+                    break;
+                }
+            }
+            export_results(&config);
+            result
+        }
+        Command::Daemon {
+            credentials,
+            filter,
+            transfers,
+            delay_days,
+            first_year,
+            interval,
+        } => {
+            let delay_days = delay_days
+                .or(config.delay_days)
+                .unwrap_or(DEFAULT_DELAY_DAYS);
+            let first_year = first_year
+                .or(config.first_year)
+                .unwrap_or(DEFAULT_FIRST_YEAR);
+            let account_filter = filter.into_filter(&config);
+            let transfer_matching = transfers.into_matching(&config);
+            let state_store = credentials.state_store(&config)?;
+            if let Some(address) = config.metrics_address {
+                metrics::serve(address)?;
+            }
+            if let Some(address) = config.health_address {
+                let ready_within = config
+                    .ready_within
+                    .map(|hours| Duration::from_secs(hours * 60 * 60))
+                    .unwrap_or(interval * 2);
+                health::serve(address, ready_within)?;
+            }
+            run_daemon(
+                credentials,
+                config,
+                &state_store,
+                &state_file,
+                opts.wait_for_lock,
+                account_filter,
+                transfer_matching,
+                delay_days,
+                first_year,
+                interval,
+            )
+            .await
+        }
+        Command::Accounts { credentials } => {
+            for bridge in credentials.connect(&config).await? {
+                run_accounts(bridge, opts.output).await?;
+            }
+            Ok(())
+        }
+        Command::Status {
+            credentials,
+            delay_days,
+        } => {
+            run_status(
+                &credentials.state_store(&config)?,
+                delay_days
+                    .or(config.delay_days)
+                    .unwrap_or(DEFAULT_DELAY_DAYS),
+                opts.output,
+            )
+            .await
+        }
+        Command::Rollback {
+            credentials,
+            run_id,
+            accounts,
+        } => {
+            if state_in_firefly {
+                return Err(anyhow!(
+                    "rollback needs the journal in the local state database, which is not kept \
+                     when the state is kept in firefly"
+                ));
+            }
+            rollback::rollback(
+                &credentials.firefly_configuration(&config)?,
+                &state_file,
+                config.state_key()?.as_ref(),
+                run_id,
+                accounts,
+            )
+            .await
+        }
+        Command::Report {
+            credentials,
+            months,
+            by,
+        } => {
+            let bridges = credentials.connect(&config).await?;
+            let spending = report::spending(&bridges, months, by).await?;
+            if opts.output == Output::Json {
+                // The spending of every customer is summed up together, as they share Firefly
+                #[derive(Serialize)]
+                struct Report {
+                    spending: Vec<report::Spending>,
+                }
+                return print_json(&Report { spending });
+            }
+            println!("{}", report::table(&spending));
+            Ok(())
+        }
+        Command::Reapply {
+            credentials,
+            dry_run,
+        } => {
+            if state_in_firefly {
+                return Err(anyhow!(
+                    "reapply needs the journal in the local state database, which is not kept \
+                     when the state is kept in firefly"
+                ));
+            }
+            let bridges = credentials.connect(&config).await?;
+            let updated =
+                reapply::reapply(&bridges, &state_file, config.state_key()?.as_ref(), dry_run)
+                    .await?;
+            info!(
+                "{} {} transaction(s)",
+                if dry_run { "Would update" } else { "Updated" },
+                updated
+            );
+            Ok(())
+        }
+        Command::RetryFailed {
+            credentials,
+            dry_run,
+        } => {
+            let state_store = credentials.state_store(&config)?;
+            let mut state = state_store.load().await?;
+            if state.failed.is_empty() {
+                info!("No failed transactions to retry");
+                return Ok(());
+            }
+            let mut bridges = credentials.connect(&config).await?;
+            for bridge in &mut bridges {
+                bridge.dry_run = dry_run;
+            }
 
-            let shift_amount = if consecutive_duplicates % 2 == 1 {
-                consecutive_duplicates
+            let stored = sync::retry_failed(&bridges, &mut state).await?;
+            if dry_run {
+                return Ok(());
+            }
+            info!("Stored {} failed transaction(s)", stored);
+            log_summary(&state);
+            state_store.save(&mut state).await?;
+            check_failures(&state, Ok(()))
+        }
+        Command::Purge {
+            credentials,
+            accounts,
+            dry_run,
+            yes,
+        } => {
+            if !yes && !dry_run {
+                return Err(anyhow!(
+                    "this deletes everything the bridge has created in firefly, pass --yes to \
+                     confirm or --dry-run to list it"
+                ));
+            }
+            let firefly_config = credentials.firefly_configuration(&config)?;
+            let state_store = credentials.state_store(&config)?;
+
+            let transactions =
+                purge::purge_transactions(&firefly_config, &config.import_tag(), dry_run).await?;
+            let accounts = if accounts {
+                let mut accounts = 0;
+                for bridge in credentials.connect(&config).await? {
+                    accounts += purge::purge_accounts(&bridge, &firefly_config, dry_run).await?;
+                }
+                accounts
             } else {
-                consecutive_duplicates - 1
+                0
             };
 
-            let shifts = consecutive_duplicates / 2;
-
-            for s in 0..shifts {
-                let i = first_index + 1 + 2 * s;
-                needs_deduplication.swap(i, i + shift_amount);
+            if !dry_run {
+                state_store.reset().await?;
+            }
+            info!(
+                "{} {} transaction(s) and {} account(s)",
+                if dry_run { "Would delete" } else { "Deleted" },
+                transactions,
+                accounts
+            );
+            Ok(())
+        }
+        Command::Transfer {
+            credentials,
+            from,
+            to,
+            amount,
+            message,
+            dry_run,
+            yes,
+        } => {
+            if !yes && !dry_run {
+                return Err(anyhow!(
+                    "this transfers {} from '{}' to '{}' in sbanken, pass --yes to confirm or \
+                     --dry-run to check it",
+                    amount,
+                    from,
+                    to
+                ));
+            }
+            let bridges = credentials.connect(&config).await?;
+            let state_store = credentials.state_store(&config)?;
+            let mut state = state_store.load().await?;
+            transfer::transfer(&bridges, &mut state, &from, &to, amount, message, dry_run).await?;
+            if dry_run {
+                return Ok(());
             }
+            state_store.save(&mut state).await
         }
+        Command::Init => unreachable!("handled before loading config"),
+        Command::Decrypt { file } => {
+            let key = config
+                .state_key()?
+                .ok_or_else(|| anyhow!("set the state encryption key to decrypt files"))?;
+            let reader = std::fs::File::open(&file)
+                .with_context(|| format!("unable to open '{}'", file.display()))?;
+            let stdout = std::io::stdout();
+            crypto::decrypt_lines(&key, std::io::BufReader::new(reader), stdout.lock())
+        }
+        Command::Check { credentials } => {
+            for bridge in credentials.connect(&config).await? {
+                run_check(bridge, opts.output).await?;
+            }
+            Ok(())
+        }
+    }
+}
 
-        // Run deduplication on this list, which is now exactly sorted so that sender and receiver are in the same pairs
-        let mut dedup_chunks = needs_deduplication.chunks_exact(2);
-        for pair in &mut dedup_chunks {
-            let (from_ac, from_trans) = &pair[0];
-            let (to_ac, to_trans) = &pair[1];
-
-            let from_account = firefly_accounts
-                .data
-                .iter()
-                .find(|account_read| {
-                    account_read
-                        .attributes
-                        .notes
-                        .as_ref()
-                        .map(|notes| notes == *from_ac)
-                        .unwrap_or(false)
-                })
-                .unwrap();
-
-            let to_account = firefly_accounts
-                .data
-                .iter()
-                .find(|account_read| {
-                    account_read
-                        .attributes
-                        .notes
-                        .as_ref()
-                        .map(|notes| notes == *to_ac)
-                        .unwrap_or(false)
-                })
-                .unwrap();
-
-            eprintln!(
-                "{} ({}) : {} -- {:6.2} ({:6.2}) --> {} : {} ({})",
-                from_trans.accounting_date.as_ref().unwrap(),
-                to_trans.accounting_date.as_ref().unwrap(),
-                from_account.attributes.name,
-                from_trans.amount.unwrap(),
-                to_trans.amount.unwrap(),
-                to_account.attributes.name,
-                from_trans.text.as_ref().unwrap(),
-                to_trans.text.as_ref().unwrap(),
-            );
+/// Combine the result of syncing `customer` with those of the customers before it, logging its
+/// error if there are several customers so that the others are still synced.
+///
+/// A sync which failed completely takes precedence over one which failed partially.
+fn keep_first_error(customer: Option<&str>, result: Result<()>, synced: Result<()>) -> Result<()> {
+    if let (Some(customer), Err(e)) = (customer, &synced) {
+        error!("Sync of customer '{}' failed: {:?}", customer, e);
+    }
+    // Partial failures are reported for each transaction on their own
+    if let Err(e) = &synced {
+        if !e.is::<PartialFailure>() && !e.is::<shutdown::Interrupted>() {
+            reporting::run_failed(customer, e);
+        }
+    }
+    match (result, synced) {
+        (Err(first), Err(e)) if first.is::<PartialFailure>() && !e.is::<PartialFailure>() => Err(e),
+        // Stopping early is reported rather than an earlier customer failing
+        (Err(_), Err(e)) if e.is::<shutdown::Interrupted>() => Err(e),
+        (result, synced) => result.and(synced),
+    }
+}
 
-            if from_trans.amount == to_trans.amount.map(|f| -f)
-                && from_trans.text == to_trans.text
-                && from_trans.accounting_date == to_trans.accounting_date
-            {
-                let firefly_transaction =
-                    convert_transaction(&from_account, &from_trans, Some(&to_account))
-                        .context("unable to convert transaction")?;
-
-                let _ = firefly_client
-                    .transactions_api()
-                    .store_transaction(firefly_transaction.clone())
-                    .await
-                    .map_err(|e| {
-                        eprintln!("\tunable to store transaction, skipping: {}", e);
-                    });
-            } else {
-                eprintln!("\twarn: got unbalanced transaction (not equal amount/date/text), skipping")
+/// Log what the run created, skipped and failed in total and for each account, followed by
+/// every failure.
+fn log_summary(state: &State) {
+    info!("Transactions: {}", state.stats);
+    for (account, stats) in &state.account_stats {
+        info!(account = %account, "Transactions of {}: {}", account, stats);
+    }
+    if !state.queued.is_empty() {
+        warn!(
+            "Queued until Firefly is reachable again: {} transaction(s)",
+            state.queued.len()
+        );
+    }
+    if !state.failed.is_empty() {
+        warn!(
+            "Kept for retry-failed: {} transaction(s)",
+            state.failed.len()
+        );
+    }
+    for failure in &state.failures {
+        warn!("Failed: {}", failure);
+    }
+}
+
+/// Print what a dry run would have changed in Firefly.
+fn print_plan(customer: Option<&str>, plan: &Plan, output: Output) -> Result<()> {
+    match output {
+        Output::Text => {
+            print!("{}", plan);
+            Ok(())
+        }
+        Output::Json => {
+            #[derive(Serialize)]
+            struct DryRun<'a> {
+                customer: Option<&'a str>,
+                #[serde(flatten)]
+                plan: &'a Plan,
             }
+            print_json(&DryRun { customer, plan })
         }
+    }
+}
 
-        if let Some((from_ac, from_trans)) = &dedup_chunks.remainder().first() {
-            let from_account = firefly_accounts
-                .data
-                .iter()
-                .find(|account_read| {
-                    account_read
-                        .attributes
-                        .notes
-                        .as_ref()
-                        .map(|notes| notes == *from_ac)
-                        .unwrap_or(false)
-                })
-                .unwrap();
-
-            eprintln!(
-                "GOT A LEFTOVER TRANSACTION: {} : {} -- {:6.2} -->  : {}",
-                from_trans.accounting_date.as_ref().unwrap(),
-                from_account.attributes.name,
-                from_trans.amount.unwrap().abs(),
-                from_trans.text.as_ref().unwrap(),
-            );
+/// Write the run summary and the metrics if configured, a failure to do so does not fail the
+/// sync.
+fn export_results(config: &Config) {
+    let key = match config.state_key() {
+        Ok(key) => key,
+        Err(e) => {
+            warn!("{:#}", e);
+            return;
         }
+    };
+    if let Err(e) = summary::write(&config.run_summary_file(), key.as_ref()) {
+        warn!("{:#}", e);
     }
+    if let Some(path) = &config.metrics_file {
+        if let Err(e) = metrics::write_file(path) {
+            warn!("{:#}", e);
+        }
+    }
+}
 
-    std::fs::write(
-        "firefly_last_sync",
-        &last_sync_day.format(DATE_FORMAT).to_string(),
-    )?;
+/// Turn a run which went through but had failures into a partial failure.
+fn check_failures(state: &State, result: Result<()>) -> Result<()> {
+    if result.is_ok() && !state.failures.is_empty() {
+        return Err(PartialFailure(state.failures.len()).into());
+    }
+    result
+}
 
-    Ok(())
+async fn run_sync(
+    bridge: Bridge,
+    state_store: &StateStore,
+    delay_days: i64,
+    first_year: i32,
+    output: Output,
+) -> Result<()> {
+    let span = info_span!("sync", customer = tracing::field::Empty);
+    if let Some(customer) = &bridge.customer {
+        span.record("customer", &customer.as_str());
+    }
+    let first_day = NaiveDate::from_ymd(first_year, 1, 1);
+    let last_day = last_sync_target(delay_days);
+    let mut summary = RunSummary::new(bridge.customer.as_deref(), first_day, last_day);
+    let started = Instant::now();
+    let result = sync_customer(
+        bridge,
+        state_store,
+        first_day,
+        last_day,
+        output,
+        &mut summary,
+    )
+    .instrument(span)
+    .await;
+    metrics::run(started.elapsed(), result.is_ok());
+    summary::record(summary.finish(&result));
+    result
 }
 
-fn cleanup_description(desc: &str) -> String {
-    lazy_static! {
-        static ref START_DATE: Regex = Regex::new(r"^\d{2}\.\d{2}\s").unwrap();
-        static ref VISA_VARE_EXTRACT: Regex =
-            Regex::new(r"(?i)^\*\d{4}\s\d{2}\.\d{2}\s\w{3}\s\d+.\d{2}\s(.+?)\sKurs:\s\d+.\d+$")
-                .unwrap();
-        static ref PAY_DATE: Regex = Regex::new(r"Betalt:\s\d{2}\.\d{2}\.\d{2}$").unwrap();
+async fn sync_customer(
+    bridge: Bridge,
+    state_store: &StateStore,
+    first_day: NaiveDate,
+    last_day: NaiveDate,
+    output: Output,
+    summary: &mut RunSummary,
+) -> Result<()> {
+    if let Some(customer) = &bridge.customer {
+        info!("Syncing customer '{}'", customer);
+    }
+    let mut plan = Plan::default();
+    let mut state = state_store.load().await?;
+
+    let (sbanken_accounts, firefly_accounts) =
+        sync::sync_accounts(&bridge, &mut state, first_day, &mut plan).await?;
+
+    let mut result = sync::sync_transactions(
+        &bridge,
+        &sbanken_accounts,
+        &firefly_accounts,
+        &mut state,
+        state_store,
+        first_day,
+        last_day,
+        &mut plan,
+    )
+    .await;
+
+    if result.is_ok() && bridge.standing_orders {
+        result = standing_orders::sync_standing_orders(
+            &bridge,
+            &sbanken_accounts,
+            &firefly_accounts,
+            &mut state,
+            &mut plan,
+        )
+        .await;
     }
 
-    // Remove leading date (e.g. "12.02 KIWI ...")
-    let desc = START_DATE.replace(desc, "");
+    if result.is_ok() && bridge.scheduled_payments {
+        result = payments::sync_payments(
+            &bridge,
+            &sbanken_accounts,
+            &firefly_accounts,
+            &mut state,
+            last_day,
+            &mut plan,
+        )
+        .await;
+    }
 
-    // Remove trailing pay date (e.g. "KIWI ... Betalt: 12.03.20")
-    let desc = PAY_DATE.replace(&desc, "");
+    if result.is_ok() && bridge.import_reservations {
+        result = reservations::sync_reservations(
+            &bridge,
+            &sbanken_accounts,
+            &firefly_accounts,
+            &mut state,
+            last_day,
+            &mut plan,
+        )
+        .await;
+    }
 
-    // Remove leading "Fra: " and "Til: "
-    let desc = desc.trim_start_matches("Til: ");
-    let desc = desc.trim_start_matches("Fra: ");
+    summary.add_state(&state);
+    if bridge.dry_run {
+        print_plan(bridge.customer.as_deref(), &plan, output)?;
+        return result;
+    }
 
-    // Extract name of company from VISA_VARE description
-    // (e.g. "*6227 26.02 NOK 30.00 COCA-COLA ENTERPRISES NOR Kurs: 1.0000")
-    let desc = VISA_VARE_EXTRACT
-        .captures(&desc)
-        .and_then(|c| c.get(1))
-        .map(|m| m.as_str())
-        .unwrap_or(&desc);
+    log_summary(&state);
 
-    let desc = if desc.to_lowercase().starts_with("skimore") { "Skimore" } else { desc };
+    // Keep the progress of accounts which were synced, even if the run failed
+    state_store.save(&mut state).await?;
 
-    let desc = if desc.to_lowercase().starts_with("starbucks") { "Starbucks" } else { desc };
+    check_failures(&state, result)
+}
 
-    let desc = if desc.to_lowercase().starts_with("steam") { "Steam" } else { desc };
+/// Import all transactions in the given range without reading or updating how far each account
+/// has been synced.
+///
+/// Transactions which have been imported before are still skipped, and created objects are
+/// recorded in the journal.
+async fn run_range(
+    bridge: Bridge,
+    state_store: &StateStore,
+    first_day: NaiveDate,
+    last_day: NaiveDate,
+    output: Output,
+) -> Result<()> {
+    let mut summary = RunSummary::new(bridge.customer.as_deref(), first_day, last_day);
+    let result = sync_range(
+        bridge,
+        state_store,
+        first_day,
+        last_day,
+        output,
+        &mut summary,
+    )
+    .await;
+    summary::record(summary.finish(&result));
+    result
+}
 
-    let desc = if desc.to_lowercase().starts_with("domeneshop") { "Domeneshop" } else { desc };
+async fn sync_range(
+    bridge: Bridge,
+    state_store: &StateStore,
+    first_day: NaiveDate,
+    last_day: NaiveDate,
+    output: Output,
+    summary: &mut RunSummary,
+) -> Result<()> {
+    if first_day > last_day {
+        return Err(anyhow!(
+            "start of range ({}) is after end of range ({})",
+            first_day,
+            last_day
+        ));
+    }
 
-    let desc = if desc.to_lowercase().starts_with("hokksund sushi og thai") { "Hokksund Sushi og Thai" } else { desc };
+    let mut plan = Plan::default();
+    let mut state = state_store.load().await?;
 
-    let desc = if desc.to_lowercase().starts_with("tekna") { "TEKNA" } else { desc };
+    // Start every account at the beginning of the range and keep the stored progress untouched
+    state.freeze_cursors();
 
-    return desc.trim().to_string();
-}
+    let (sbanken_accounts, firefly_accounts) =
+        sync::sync_accounts(&bridge, &mut state, first_day, &mut plan).await?;
 
-fn convert_transaction(
-    main_account: &firefly_iii::models::AccountRead,
-    sbanken_transaction: &sbanken::models::TransactionV1,
-    other_account: Option<&firefly_iii::models::AccountRead>,
-) -> Result<firefly_iii::models::Transaction> {
-    use firefly_iii::models::{
-        transaction_split::Type as TransactionType, Transaction, TransactionSplit,
-    };
+    let result = sync::sync_transactions(
+        &bridge,
+        &sbanken_accounts,
+        &firefly_accounts,
+        &mut state,
+        state_store,
+        first_day,
+        last_day,
+        &mut plan,
+    )
+    .await;
 
-    let amount = sbanken_transaction.amount.unwrap();
+    summary.add_state(&state);
+    if bridge.dry_run {
+        print_plan(bridge.customer.as_deref(), &plan, output)?;
+        return result;
+    }
 
-    let mut split = TransactionSplit::new(
-        // Extract date part of timestamp (YYYY-MM-DDTHH:MM:SS)
-        sbanken_transaction.accounting_date.as_ref().unwrap()[0..10].into(),
-        format!("{:.2}", amount.abs()),
-        sbanken_transaction.text.as_ref().unwrap().clone(),
-        None,
-        None,
-    );
+    log_summary(&state);
 
-    split.category_name = sbanken_transaction.transaction_type.clone();
+    state_store.save(&mut state).await?;
 
-    if amount < 0.0 {
-        split.source_id = main_account.id.clone().parse().ok();
-        if let Some(to_account) = other_account {
-            split._type = Some(TransactionType::Transfer);
-            split.destination_id = to_account.id.clone().parse().ok();
-        } else {
-            split._type = Some(TransactionType::Withdrawal);
-            split.destination_name = sbanken_transaction.text.as_deref().map(cleanup_description);
+    check_failures(&state, result)
+}
+
+async fn run_daemon(
+    credentials: Credentials,
+    config: Config,
+    state_store: &StateStore,
+    state_file: &Path,
+    wait_for_lock: bool,
+    account_filter: AccountFilter,
+    transfer_matching: TransferMatching,
+    delay_days: i64,
+    first_year: i32,
+    interval: Duration,
+) -> Result<()> {
+    loop {
+        info!("Starting sync at {}", chrono::Local::now().to_rfc3339());
+
+        let result = match take_lock(state_file, wait_for_lock).await {
+            Ok(Some(_lock)) => {
+                // Connect on every cycle to get a fresh Sbanken token
+                match credentials.connect(&config).await {
+                    Ok(bridges) => {
+                        let mut result = Ok(());
+                        for mut bridge in bridges {
+                            bridge.account_filter = account_filter.clone();
+                            bridge.transfer_matching = transfer_matching.clone();
+                            let customer = bridge.customer.clone();
+                            let synced =
+                                run_sync(bridge, state_store, delay_days, first_year, Output::Text)
+                                    .await;
+                            result = keep_first_error(customer.as_deref(), result, synced);
+                            if shutdown::requested() {
+                                break;
+                            }
+                        }
+                        result
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Ok(None) => Err(anyhow!(
+                "another sync is already running ('{}' is locked)",
+                lock_file(state_file).display()
+            )),
+            Err(e) => Err(e),
+        };
+
+        export_results(&config);
+        if shutdown::requested() {
+            return result;
         }
-    } else {
-        split.destination_id = main_account.id.clone().parse().ok();
-        if let Some(to_account) = other_account {
-            split._type = Some(TransactionType::Transfer);
-            split.source_id = to_account.id.clone().parse().ok();
-        } else {
-            split._type = Some(TransactionType::Deposit);
-            split.source_name = sbanken_transaction.text.as_deref().map(cleanup_description);
+        if result.is_ok() {
+            health::synced();
+        }
+        match result {
+            Ok(()) => info!(
+                "Sync finished, next sync in {}",
+                humantime::format_duration(interval)
+            ),
+            Err(e) => error!(
+                "Sync failed, retrying in {}: {:?}",
+                humantime::format_duration(interval),
+                e
+            ),
+        }
+
+        tokio::select! {
+            _ = tokio::time::delay_for(interval) => {},
+            _ = shutdown::wait() => return Ok(()),
         }
     }
+}
 
-    Ok(Transaction::new(vec![split]))
+/// An Sbanken account and the Firefly account it is mapped to, as listed by `accounts`.
+#[derive(Serialize)]
+struct AccountListing<'a> {
+    name: Option<&'a str>,
+    account_number: Option<&'a str>,
+    account_type: Option<&'a str>,
+    firefly_account: Option<MappedAccount<'a>>,
 }
 
-fn convert_account(
-    sbanken_account: &sbanken::models::AccountV1,
-) -> Result<firefly_iii::models::Account> {
-    use firefly_iii::models::account::*;
-    let account_role = match &**sbanken_account.account_type.as_ref().unwrap() {
-        "High interest account" => AccountRole::SavingAsset,
-        "Standard account" => AccountRole::DefaultAsset,
-        "BSU account" => AccountRole::SavingAsset,
-        _ => {
-            return Err(anyhow!(
-                "conversion not implemented for account type '{}'",
-                sbanken_account.account_type.as_ref().unwrap()
-            ))
+#[derive(Serialize)]
+struct MappedAccount<'a> {
+    id: &'a str,
+    name: &'a str,
+}
+
+async fn run_accounts(bridge: Bridge, output: Output) -> Result<()> {
+    let sbanken_accounts = sync::list_sbanken_accounts(&bridge).await?;
+    let firefly_accounts = sync::list_firefly_accounts(&bridge).await?;
+
+    let accounts: Vec<_> = sbanken_accounts
+        .iter()
+        .map(|sbanken_account| AccountListing {
+            name: sbanken_account.name.as_deref(),
+            account_number: sbanken_account.account_number.as_deref(),
+            account_type: sbanken_account.account_type.as_deref(),
+            firefly_account: sync::find_account(&firefly_accounts, sbanken_account).map(
+                |account| MappedAccount {
+                    id: &account.id,
+                    name: &account.attributes.name,
+                },
+            ),
+        })
+        .collect();
+
+    if output == Output::Json {
+        #[derive(Serialize)]
+        struct Accounts<'a> {
+            customer: Option<&'a str>,
+            accounts: Vec<AccountListing<'a>>,
         }
+        return print_json(&Accounts {
+            customer: bridge.customer.as_deref(),
+            accounts,
+        });
+    }
+
+    if let Some(customer) = &bridge.customer {
+        println!("{}:", customer);
+    }
+    for account in &accounts {
+        println!(
+            "{} ({}, {}) --> {}",
+            account.name.unwrap_or("<unnamed>"),
+            account.account_number.unwrap_or("<no account number>"),
+            account.account_type.unwrap_or("<unknown type>"),
+            account
+                .firefly_account
+                .as_ref()
+                .map(|account| format!("{} <account {}>", account.name, account.id))
+                .unwrap_or_else(|| "<not mapped>".into()),
+        );
+    }
+
+    Ok(())
+}
+
+/// How far an account has been synced, as shown by `status`.
+#[derive(Serialize)]
+struct AccountStatus<'a> {
+    /// Sbanken account id, or none for accounts without their own state.
+    account_id: Option<&'a str>,
+    synced_until: NaiveDate,
+    days_behind: i64,
+}
+
+async fn run_status(state_store: &StateStore, delay_days: i64, output: Output) -> Result<()> {
+    let last_sync_target = last_sync_target(delay_days);
+
+    let state = state_store.load().await?;
+
+    let status = |account_id, day: NaiveDate| AccountStatus {
+        account_id,
+        synced_until: day,
+        days_behind: std::cmp::max((last_sync_target - day).num_days(), 0),
+    };
+    let mut accounts: Vec<_> = state
+        .legacy
+        .map(|day| status(None, day))
+        .into_iter()
+        .collect();
+    let mut synced: Vec<_> = state.accounts.iter().collect();
+    synced.sort();
+    accounts.extend(
+        synced
+            .into_iter()
+            .map(|(account_id, &day)| status(Some(account_id.as_str()), day)),
+    );
+
+    let runs = match state_store {
+        StateStore::File { path, .. } => State::recent_runs(path, 5)?,
+        _ => Vec::new(),
     };
-    let mut firefly_account = Account::new(sbanken_account.name.clone().unwrap(), Type::Asset);
-    firefly_account.account_role = Some(account_role);
-    firefly_account.account_number = Some(sbanken_account.account_number.clone().unwrap());
-    firefly_account.notes = Some(sbanken_account.account_id.clone().unwrap());
-
-    Ok(firefly_account)
-}
-
-async fn get_auth_token(
-    auth_url: &str,
-    client_id: &Secret<String>,
-    client_secret: &Secret<String>,
-) -> Result<Secret<String>> {
-    #[derive(Debug, Deserialize)]
-    struct AuthSuccess {
-        access_token: Secret<String>,
-    }
-    #[derive(Debug, Deserialize)]
-    struct AuthError {
-        error: String,
-    }
-    #[derive(Debug, Deserialize)]
-    #[serde(untagged)]
-    enum AuthResponse {
-        Success(AuthSuccess),
-        Error(AuthError),
-    }
-
-    let auth_response: AuthResponse = reqwest::Client::new()
-        .post(auth_url)
-        .header(reqwest::header::ACCEPT, "application/json")
-        .basic_auth(
-            utf8_percent_encode(client_id.expose_secret(), NON_ALPHANUMERIC),
-            Some(utf8_percent_encode(
-                client_secret.expose_secret(),
-                NON_ALPHANUMERIC,
-            )),
-        )
-        .form(&[("grant_type", "client_credentials")])
-        .send()
-        .await?
-        .json()
-        .await?;
 
-    match auth_response {
-        AuthResponse::Success(AuthSuccess { access_token }) => Ok(access_token),
-        AuthResponse::Error(AuthError { error }) => {
-            Err(anyhow!("received error from api: {}", error))
+    if output == Output::Json {
+        #[derive(Serialize)]
+        struct Status<'a> {
+            last_sync_target: NaiveDate,
+            accounts: Vec<AccountStatus<'a>>,
+            recent_runs: Vec<Run>,
         }
+        return print_json(&Status {
+            last_sync_target,
+            accounts,
+            recent_runs: runs,
+        });
+    }
+
+    if accounts.is_empty() {
+        println!("Never synced");
+    }
+    for account in &accounts {
+        let name = account.account_id.unwrap_or("Accounts without own state");
+        if account.days_behind == 0 {
+            println!(
+                "{}: synced until {} (up to date)",
+                name, account.synced_until
+            );
+        } else {
+            println!(
+                "{}: synced until {} ({} day(s) behind {})",
+                name, account.synced_until, account.days_behind, last_sync_target
+            );
+        }
+    }
+
+    if !runs.is_empty() {
+        println!("\nRecent runs:");
+    }
+    for run in runs {
+        println!(
+            "\t{}: {} - {}, {}",
+            run.id, run.started_at, run.finished_at, run.stats
+        );
     }
+
+    Ok(())
+}
+
+/// What `check` found, once every check has passed.
+#[derive(Serialize)]
+struct CheckReport<'a> {
+    customer: Option<&'a str>,
+    sbanken_accounts: usize,
+    firefly: firefly::About,
+    firefly_accounts: usize,
+}
+
+async fn run_check(bridge: Bridge, output: Output) -> Result<()> {
+    let text = output == Output::Text;
+    if let (true, Some(customer)) = (text, &bridge.customer) {
+        println!("{}:", customer);
+    }
+    if text {
+        println!("Sbanken authentication: ok");
+    }
+
+    let sbanken_accounts = sync::list_sbanken_accounts(&bridge).await?;
+    if text {
+        println!("Sbanken accounts: ok ({} found)", sbanken_accounts.len());
+    }
+
+    let about = firefly::check_version(&bridge.firefly_config).await?;
+    if text {
+        println!(
+            "Firefly version: ok ({}, API {})",
+            about.version, about.api_version
+        );
+    }
+
+    let firefly_accounts = sync::list_firefly_accounts(&bridge).await?;
+    if text {
+        println!("Firefly accounts: ok ({} found)", firefly_accounts.len());
+        return Ok(());
+    }
+
+    print_json(&CheckReport {
+        customer: bridge.customer.as_deref(),
+        sbanken_accounts: sbanken_accounts.len(),
+        firefly: about,
+        firefly_accounts: firefly_accounts.len(),
+    })
 }
@@ -0,0 +1,135 @@
+//! Prometheus metrics of the syncs, served on `/metrics` in daemon mode or written to a file for
+//! the textfile collector of the node exporter after a single run.
+
+use anyhow::{Context, Result};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::state::Outcome;
+use crate::throttle::Api;
+
+lazy_static! {
+    static ref TRANSACTIONS: IntCounterVec = register_int_counter_vec!(
+        "sbanken_bridge_transactions_total",
+        "Transactions handled by syncs, by whether they were created, skipped as duplicates or \
+         failed",
+        &["outcome"]
+    )
+    .unwrap();
+    static ref TRANSFERS_MATCHED: IntCounter = register_int_counter!(
+        "sbanken_bridge_transfers_matched_total",
+        "Internal transfers whose two legs were paired"
+    )
+    .unwrap();
+    static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "sbanken_bridge_request_duration_seconds",
+        "Time taken by each attempt of a request to Sbanken or Firefly",
+        &["api"]
+    )
+    .unwrap();
+    static ref RUN_DURATION: Histogram = register_histogram!(
+        "sbanken_bridge_run_duration_seconds",
+        "Time taken by the sync of a customer",
+        vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0]
+    )
+    .unwrap();
+    static ref RUNS: IntCounterVec = register_int_counter_vec!(
+        "sbanken_bridge_runs_total",
+        "Syncs of a customer, by whether they succeeded",
+        &["result"]
+    )
+    .unwrap();
+    static ref LAST_SUCCESS: IntGauge = register_int_gauge!(
+        "sbanken_bridge_last_success_timestamp_seconds",
+        "Time of the last sync which succeeded, as a Unix timestamp"
+    )
+    .unwrap();
+}
+
+/// Count a transaction with what became of it.
+pub fn transaction(outcome: Outcome) {
+    TRANSACTIONS
+        .with_label_values(&[&outcome.to_string()])
+        .inc();
+}
+
+/// Count internal transfers whose legs were paired.
+pub fn transfers_matched(count: usize) {
+    TRANSFERS_MATCHED.inc_by(count as i64);
+}
+
+/// Record the time taken by an attempt of a request to `api`.
+pub fn request(api: Api, elapsed: Duration) {
+    REQUEST_DURATION
+        .with_label_values(&[api.name()])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Record the time taken by a sync and whether it succeeded.
+pub fn run(elapsed: Duration, succeeded: bool) {
+    RUN_DURATION.observe(elapsed.as_secs_f64());
+    RUNS.with_label_values(&[if succeeded { "success" } else { "failure" }])
+        .inc();
+    if succeeded {
+        LAST_SUCCESS.set(chrono::Utc::now().timestamp());
+    }
+}
+
+/// All metrics in the text format of Prometheus.
+pub fn render() -> Vec<u8> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("metrics are always encodable");
+    buffer
+}
+
+/// Write all metrics to `path`, replacing it at once so that a collector never reads half a file.
+/// The file is never encrypted, as the collector has to read it, and holds only counts and times.
+pub fn write_file(path: &Path) -> Result<()> {
+    let partial = path.with_extension("prom.partial");
+    std::fs::write(&partial, render())
+        .with_context(|| format!("unable to write metrics to '{}'", partial.display()))?;
+    std::fs::rename(&partial, path)
+        .with_context(|| format!("unable to write metrics to '{}'", path.display()))
+}
+
+/// Serve the metrics on `/metrics` at `address` in the background.
+pub fn serve(address: SocketAddr) -> Result<()> {
+    let server = Server::try_bind(&address)
+        .with_context(|| format!("unable to listen for metrics on {}", address))?
+        .serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(respond))
+        }));
+    info!("Serving metrics on http://{}/metrics", address);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Metrics server stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+async fn respond(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Body::from(render())),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.expect("responses are always valid"))
+}
@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Context, Result};
+use rust_decimal::Decimal;
+use sbanken::models::TransactionV1;
+use serde::de::{self, Deserializer, Visitor};
+use serde::ser::{Error as _, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::fmt;
+use std::str::FromStr;
+
+/// An amount in kroner with exactly two decimals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(Decimal);
+
+impl Money {
+    /// Convert an amount as Sbanken returns it, which has at most two decimals.
+    pub fn from_f64(amount: f64) -> Result<Money> {
+        if !amount.is_finite() {
+            return Err(anyhow!("invalid amount {}", amount));
+        }
+
+        // Formatting rounds away the binary representation error, e.g. 0.1 + 0.2
+        let amount = Decimal::from_str(&format!("{:.2}", amount))
+            .with_context(|| format!("invalid amount {}", amount))?;
+        Ok(Money(amount))
+    }
+
+    /// Amount of a Sbanken transaction.
+    pub fn of(transaction: &TransactionV1) -> Result<Money> {
+        let amount = transaction
+            .amount
+            .ok_or_else(|| anyhow!("transaction is missing amount"))?;
+        Money::from_f64(amount)
+    }
+
+    pub fn from_decimal(amount: Decimal) -> Money {
+        // Adding a zero with two decimals keeps e.g. 500 from being written without them
+        Money((amount + Decimal::new(0, 2)).round_dp(2))
+    }
+
+    pub fn decimal(&self) -> Decimal {
+        self.0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0.is_sign_negative() && !self.0.is_zero()
+    }
+
+    pub fn abs(&self) -> Money {
+        Money(self.0.abs())
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&self.0.to_string())
+    }
+}
+
+impl FromStr for Money {
+    type Err = anyhow::Error;
+
+    /// Parse an amount such as "1500" or "99.50", which may not have more than two decimals.
+    fn from_str(s: &str) -> Result<Money> {
+        let amount =
+            Decimal::from_str(s.trim()).with_context(|| format!("invalid amount '{}'", s))?;
+        if amount.round_dp(2) != amount {
+            return Err(anyhow!("amount '{}' has more than two decimals", s));
+        }
+        Ok(Money::from_decimal(amount))
+    }
+}
+
+impl Serialize for Money {
+    /// Written as a JSON number with exactly two decimals, e.g. `1500.00`, so that no rounding of
+    /// a float comes in between.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RawValue::from_string(self.to_string())
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    /// Read from a number or a string, as amounts written by earlier versions are floats.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Money, D::Error> {
+        struct MoneyVisitor;
+
+        impl<'de> Visitor<'de> for MoneyVisitor {
+            type Value = Money;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an amount")
+            }
+
+            fn visit_f64<E: de::Error>(self, amount: f64) -> Result<Money, E> {
+                Money::from_f64(amount).map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, amount: i64) -> Result<Money, E> {
+                Ok(Money::from_decimal(Decimal::from(amount)))
+            }
+
+            fn visit_u64<E: de::Error>(self, amount: u64) -> Result<Money, E> {
+                Ok(Money::from_decimal(Decimal::from(amount)))
+            }
+
+            fn visit_str<E: de::Error>(self, amount: &str) -> Result<Money, E> {
+                Money::from_str(amount).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(MoneyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_away_binary_representation_error() {
+        assert_eq!(Money::from_f64(0.1 + 0.2).unwrap().to_string(), "0.30");
+        assert_eq!(Money::from_f64(-1234.5).unwrap().to_string(), "-1234.50");
+    }
+
+    #[test]
+    fn rejects_amounts_which_are_not_finite() {
+        assert!(Money::from_f64(f64::NAN).is_err());
+        assert!(Money::from_f64(f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn always_has_two_decimals() {
+        assert_eq!(
+            Money::from_decimal(Decimal::new(500, 0)).to_string(),
+            "500.00"
+        );
+        assert_eq!(
+            Money::from_decimal(Decimal::new(123456, 4)).to_string(),
+            "12.35"
+        );
+    }
+
+    #[test]
+    fn zero_is_not_negative() {
+        let zero = Money::from_f64(-0.0).unwrap();
+        assert!(!zero.is_negative());
+        assert!(Money::from_f64(-0.01).unwrap().is_negative());
+        assert_eq!(Money::from_f64(-42.0).unwrap().abs().to_string(), "42.00");
+    }
+
+    #[test]
+    fn parses_amounts_with_at_most_two_decimals() {
+        assert_eq!(Money::from_str("1500").unwrap().to_string(), "1500.00");
+        assert_eq!(Money::from_str(" 99.5 ").unwrap().to_string(), "99.50");
+        assert!(Money::from_str("100.005").is_err());
+        assert!(Money::from_str("1,5").is_err());
+    }
+
+    #[test]
+    fn serializes_with_two_decimals() {
+        let amount = Money::from_str("100.5").unwrap();
+        assert_eq!(serde_json::to_string(&amount).unwrap(), "100.50");
+        assert_eq!(serde_json::from_str::<Money>("100.5").unwrap(), amount);
+        assert_eq!(serde_json::from_str::<Money>("\"100.50\"").unwrap(), amount);
+    }
+
+    #[test]
+    fn transaction_without_amount_is_an_error() {
+        let transaction: TransactionV1 =
+            serde_json::from_value(serde_json::json!({ "text": "KIWI" })).unwrap();
+        assert!(Money::of(&transaction).is_err());
+    }
+}
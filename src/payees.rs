@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use firefly_iii::models::AccountRead;
+use reqwest::Method;
+use serde::Serialize;
+use tracing::info;
+
+use crate::firefly::{self, Page, Single};
+use crate::sync::{normalize_account_number, Bridge};
+
+/// Firefly expense accounts of the payees of outgoing payments, which are told apart by their
+/// account number rather than by the description of the payment.
+#[derive(Default)]
+pub struct Payees {
+    /// Loaded from Firefly on first use.
+    accounts: Option<Vec<AccountRead>>,
+}
+
+#[derive(Serialize)]
+struct ExpenseAccount<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    account_type: &'a str,
+    account_number: &'a str,
+}
+
+async fn list_expense_accounts(bridge: &Bridge) -> Result<Vec<AccountRead>> {
+    let mut accounts = Vec::new();
+    for page_number in 1.. {
+        let path = format!("/accounts?type=expense&page={}", page_number);
+        let page: Page<AccountRead> = firefly::get(&bridge.firefly_config, &path)
+            .await?
+            .unwrap_or(Page { data: Vec::new() });
+        if page.data.is_empty() {
+            break;
+        }
+        accounts.extend(page.data);
+    }
+    Ok(accounts)
+}
+
+impl Payees {
+    /// Id of the expense account with `account_number`, creating it as `name` if there is none.
+    ///
+    /// An expense account named `name` without an account number gets `account_number`, so that
+    /// earlier payments to the payee are grouped with the new ones.
+    pub async fn expense_account(
+        &mut self,
+        bridge: &Bridge,
+        name: &str,
+        account_number: &str,
+    ) -> Result<String> {
+        if self.accounts.is_none() {
+            self.accounts = Some(
+                list_expense_accounts(bridge)
+                    .await
+                    .context("unable to get existing expense accounts")?,
+            );
+        }
+        let accounts = self.accounts.as_mut().unwrap();
+        let number = normalize_account_number(account_number);
+
+        if let Some(account) = accounts.iter().find(|account| {
+            account
+                .attributes
+                .account_number
+                .as_deref()
+                .map_or(false, |other| normalize_account_number(other) == number)
+        }) {
+            return Ok(account.id.clone());
+        }
+
+        let unnumbered = accounts.iter_mut().find(|account| {
+            account.attributes.name.eq_ignore_ascii_case(name)
+                && account
+                    .attributes
+                    .account_number
+                    .as_deref()
+                    .map_or(true, str::is_empty)
+        });
+        if let Some(account) = unnumbered {
+            let _: serde_json::Value = firefly::send(
+                &bridge.firefly_config,
+                Method::PUT,
+                &format!("/accounts/{}", account.id),
+                &ExpenseAccount {
+                    name: &account.attributes.name,
+                    account_type: "expense",
+                    account_number,
+                },
+            )
+            .await
+            .with_context(|| format!("unable to update expense account '{}'", name))?;
+            account.attributes.account_number = Some(account_number.into());
+            return Ok(account.id.clone());
+        }
+
+        // Names are unique, so a different payee with the same name needs its own
+        let name = if accounts
+            .iter()
+            .any(|account| account.attributes.name.eq_ignore_ascii_case(name))
+        {
+            format!("{} ({})", name, account_number)
+        } else {
+            name.into()
+        };
+
+        let created: Single<AccountRead> = firefly::send(
+            &bridge.firefly_config,
+            Method::POST,
+            "/accounts",
+            &ExpenseAccount {
+                name: &name,
+                account_type: "expense",
+                account_number,
+            },
+        )
+        .await
+        .with_context(|| format!("unable to create expense account '{}'", name))?;
+        info!(
+            "Created expense account {} <account {}>",
+            name, created.data.id
+        );
+
+        let id = created.data.id.clone();
+        accounts.push(created.data);
+        Ok(id)
+    }
+}
@@ -0,0 +1,217 @@
+//! Scheduled payments, which are imported as future transactions and removed once the real
+//! transaction is synced.
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use sbanken::models::{AccountV1, TransactionV1};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::bank;
+use crate::convert::{convert_payment, payment_reference};
+use crate::error::Required;
+use crate::firefly::{self, Stored};
+use crate::money::Money;
+use crate::plan::Plan;
+use crate::state::{ScheduledPayment, State};
+use crate::sync::{AccountMap, Bridge};
+use crate::DATE_FORMAT;
+
+/// Tag on the transactions of scheduled payments.
+pub const SCHEDULED_TAG: &str = "sbanken-scheduled";
+
+/// Most days a payment takes to be booked after its due date, after which it is considered
+/// cancelled.
+const MAX_BOOKING_DAYS: i64 = 7;
+
+/// A payment which is due in the future, as listed by Sbanken.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Payment {
+    pub payment_id: String,
+    pub recipient_account_number: Option<String>,
+    pub beneficiary_name: Option<String>,
+    pub amount: Option<f64>,
+    pub due_date: Option<String>,
+    pub kid: Option<String>,
+    pub text: Option<String>,
+    pub is_active: Option<bool>,
+}
+
+impl Payment {
+    pub fn due_day(&self) -> Result<NaiveDate> {
+        let due_date = self.due_date.as_deref().unwrap_or_default();
+        NaiveDate::parse_from_str(due_date.get(..10).unwrap_or(due_date), DATE_FORMAT)
+            .with_context(|| format!("invalid due date of payment {}", self.payment_id))
+    }
+
+    pub fn amount(&self) -> Result<Money> {
+        Money::from_f64(self.amount.unwrap_or_default()).map(|amount| amount.abs())
+    }
+}
+
+/// Take the scheduled payment which `booked` is the real transaction of, if any.
+///
+/// Payments are matched on account and amount, and on the KID if both have one. The payment due
+/// closest to the booked transaction is taken.
+pub fn take_paid(
+    bridge: &Bridge,
+    state: &mut State,
+    account_id: &str,
+    booked: &TransactionV1,
+) -> Result<Option<ScheduledPayment>> {
+    let amount = Money::of(booked)?;
+    if !amount.is_negative() {
+        return Ok(None);
+    }
+    let day = bridge.conversion.date_field.day(booked)?;
+    let reference = payment_reference(booked);
+
+    let mut best: Option<(usize, i64)> = None;
+    for (index, scheduled) in state.scheduled.iter().enumerate() {
+        let payment = &scheduled.payment;
+        if scheduled.account_id != account_id || payment.amount()? != amount.abs() {
+            continue;
+        }
+        if let (Some(kid), Some(reference)) = (payment.kid.as_deref(), reference.as_deref()) {
+            if kid.trim() != reference {
+                continue;
+            }
+        }
+        // Payments due on a holiday are booked on the day before or after
+        let days = (day - payment.due_day()?).num_days();
+        if days < -3 || days > MAX_BOOKING_DAYS {
+            continue;
+        }
+        if best.map_or(true, |(_, best_days)| days.abs() < best_days) {
+            best = Some((index, days.abs()));
+        }
+    }
+
+    Ok(best.map(|(index, _)| state.scheduled.remove(index)))
+}
+
+/// Delete the transaction of a scheduled payment from Firefly. Errors are only logged, as the
+/// transaction can be deleted by hand.
+pub async fn remove(bridge: &Bridge, scheduled: &ScheduledPayment) {
+    if bridge.dry_run {
+        return;
+    }
+
+    let path = format!("/transactions/{}", scheduled.firefly_id);
+    if let Err(e) = firefly::delete(&bridge.firefly_config, &path).await {
+        warn!(
+            "Unable to delete scheduled transaction <transaction {}>: {:?}",
+            scheduled.firefly_id, e
+        );
+    }
+}
+
+/// Import the scheduled payments of all accounts as future transactions, replace those of
+/// payments which changed and remove those of payments which were not booked on or before
+/// `last_day` in time.
+pub async fn sync_payments(
+    bridge: &Bridge,
+    sbanken_accounts: &[AccountV1],
+    firefly_accounts: &AccountMap,
+    state: &mut State,
+    last_day: NaiveDate,
+    plan: &mut Plan,
+) -> Result<()> {
+    let mut expired = Vec::new();
+    for scheduled in std::mem::take(&mut state.scheduled) {
+        if (last_day - scheduled.payment.due_day()?).num_days() > MAX_BOOKING_DAYS {
+            expired.push(scheduled);
+        } else {
+            state.scheduled.push(scheduled);
+        }
+    }
+    for scheduled in expired {
+        info!(
+            "Payment '{}' was never booked, removing its scheduled transaction",
+            scheduled.payment.text.as_deref().unwrap_or_default()
+        );
+        remove(bridge, &scheduled).await;
+    }
+
+    for sbanken_account in sbanken_accounts {
+        let firefly_account = match firefly_accounts.mirror(sbanken_account) {
+            Some(firefly_account) => firefly_account,
+            None => continue,
+        };
+        let account_id = sbanken_account
+            .account_id
+            .as_deref()
+            .required("account id")?;
+
+        let payments: Vec<Payment> =
+            bank::get_items(bridge, &format!("/api/v1/Payments/{}", account_id), &[])
+                .await
+                .with_context(|| {
+                    format!(
+                        "unable to get payments of account {}",
+                        sbanken_account.name.as_deref().required("account name")?
+                    )
+                })?;
+
+        for payment in payments {
+            if payment.is_active == Some(false) {
+                continue;
+            }
+
+            let known = state
+                .scheduled
+                .iter()
+                .position(|scheduled| scheduled.payment.payment_id == payment.payment_id);
+            if let Some(index) = known {
+                if state.scheduled[index].payment == payment {
+                    continue;
+                }
+                // Changed amount or due date, the transaction is created again below
+                let changed = state.scheduled.remove(index);
+                info!(
+                    "Payment '{}' has changed, replacing its scheduled transaction",
+                    payment.text.as_deref().unwrap_or_default()
+                );
+                remove(bridge, &changed).await;
+            }
+
+            let firefly_transaction =
+                convert_payment(firefly_account, &payment, &bridge.conversion)
+                    .context("unable to convert payment")?;
+
+            info!(
+                "{} : {} -- {:>6} : {} **scheduled payment**",
+                payment.due_day()?,
+                firefly_account.attributes.name,
+                payment.amount()?,
+                payment.text.as_deref().unwrap_or_default(),
+            );
+
+            if bridge.dry_run {
+                plan.transactions.push(firefly_transaction);
+                continue;
+            }
+
+            match firefly::store_transaction(
+                &bridge.firefly_config,
+                &firefly_transaction,
+                bridge.fire_webhooks,
+            )
+            .await
+            {
+                Ok(Stored::Created(id)) => state.scheduled.push(ScheduledPayment {
+                    account_id: account_id.into(),
+                    firefly_id: id,
+                    payment,
+                }),
+                Ok(Stored::Duplicate) => {
+                    info!("Duplicate of a transaction in firefly, skipping");
+                }
+                Err(e) => warn!("Unable to store scheduled payment, skipping: {}", e),
+            }
+        }
+    }
+
+    Ok(())
+}
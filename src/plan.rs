@@ -0,0 +1,106 @@
+use firefly_iii::models::{Account, Transaction};
+use serde::Serialize;
+use std::fmt;
+
+/// Changes which would have been written to Firefly during a dry run.
+#[derive(Debug, Default, Serialize)]
+pub struct Plan {
+    pub accounts: Vec<Account>,
+    /// Changes to existing accounts, one line per account.
+    pub account_updates: Vec<String>,
+    /// Bills for eFakturas, one line per bill.
+    pub bills: Vec<String>,
+    /// Currencies to create or enable, one line per currency.
+    pub currencies: Vec<String>,
+    /// Recurring transactions for standing orders, one line per recurrence.
+    pub recurrences: Vec<String>,
+    pub transactions: Vec<Transaction>,
+    pub transfers: Vec<Transaction>,
+    pub skipped: Vec<String>,
+}
+
+fn describe(transaction: &Transaction) -> String {
+    transaction
+        .transactions
+        .iter()
+        .map(|t| {
+            format!(
+                "{} {}: {} -- {} --> {}",
+                t.date,
+                t.category_name.as_deref().unwrap_or("<none>"),
+                t.source_id
+                    .map(|id| format!("<account {}>", id))
+                    .or(t.source_name.clone())
+                    .unwrap_or("<missing>".into()),
+                t.amount,
+                t.destination_id
+                    .map(|id| format!("<account {}>", id))
+                    .or(t.destination_name.clone())
+                    .unwrap_or("<missing>".into()),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\t")
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Accounts to create ({}):", self.accounts.len())?;
+        for account in &self.accounts {
+            writeln!(
+                f,
+                "\t{} ({})",
+                account.name,
+                account
+                    .account_number
+                    .as_deref()
+                    .unwrap_or("<no account number>")
+            )?;
+        }
+
+        writeln!(f, "Accounts to update ({}):", self.account_updates.len())?;
+        for update in &self.account_updates {
+            writeln!(f, "\t{}", update)?;
+        }
+
+        writeln!(f, "Bills to create or update ({}):", self.bills.len())?;
+        for bill in &self.bills {
+            writeln!(f, "\t{}", bill)?;
+        }
+
+        writeln!(
+            f,
+            "Currencies to create or enable ({}):",
+            self.currencies.len()
+        )?;
+        for currency in &self.currencies {
+            writeln!(f, "\t{}", currency)?;
+        }
+
+        writeln!(
+            f,
+            "Recurring transactions to create, update or delete ({}):",
+            self.recurrences.len()
+        )?;
+        for recurrence in &self.recurrences {
+            writeln!(f, "\t{}", recurrence)?;
+        }
+
+        writeln!(f, "Transactions to create ({}):", self.transactions.len())?;
+        for transaction in &self.transactions {
+            writeln!(f, "\t{}", describe(transaction))?;
+        }
+
+        writeln!(f, "Transfers to create ({}):", self.transfers.len())?;
+        for transaction in &self.transfers {
+            writeln!(f, "\t{}", describe(transaction))?;
+        }
+
+        writeln!(f, "Skipped ({}):", self.skipped.len())?;
+        for reason in &self.skipped {
+            writeln!(f, "\t{}", reason)?;
+        }
+
+        Ok(())
+    }
+}
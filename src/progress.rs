@@ -0,0 +1,140 @@
+//! Progress bars of the transactions handled during a sync, so that a backfill of many years shows
+//! how far it has come.
+//!
+//! The bars are only drawn when stderr is a terminal and the log is written as text, in which case
+//! log lines are printed above them. Otherwise the log is all there is.
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::thread::JoinHandle;
+
+const TEMPLATE: &str = "{prefix:>24} [{bar:40}] {pos}/{len} {msg}";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Bar which log lines are printed above while the bars are drawn.
+    static ref ACTIVE: RwLock<Option<ProgressBar>> = RwLock::new(None);
+}
+
+/// Draw progress bars during syncs if stderr is a terminal.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(
+        enabled && console::user_attended_stderr(),
+        Ordering::Relaxed,
+    );
+}
+
+/// Progress of the transactions of every account and of all of them together, where the total of
+/// a bar grows as the chunks of its account are fetched.
+pub struct Progress {
+    chunks: usize,
+    overall: ProgressBar,
+    accounts: HashMap<String, ProgressBar>,
+    drawing: Option<JoinHandle<()>>,
+}
+
+impl Progress {
+    /// Start the bars of a sync of `accounts`, as their id and name, over `chunks` chunks.
+    pub fn new<'a>(chunks: usize, accounts: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Progress {
+                chunks,
+                overall: ProgressBar::hidden(),
+                accounts: HashMap::new(),
+                drawing: None,
+            };
+        }
+
+        let style = ProgressStyle::default_bar()
+            .template(TEMPLATE)
+            .progress_chars("=> ");
+        let multi = MultiProgress::new();
+        let overall = multi.add(ProgressBar::new(0));
+        overall.set_style(style.clone());
+        overall.set_prefix("All accounts");
+        let accounts = accounts
+            .into_iter()
+            .map(|(account_id, name)| {
+                let bar = multi.add(ProgressBar::new(0));
+                bar.set_style(style.clone());
+                bar.set_prefix(name);
+                (account_id.to_string(), bar)
+            })
+            .collect();
+
+        *ACTIVE.write().unwrap() = Some(overall.clone());
+        let drawing = std::thread::spawn(move || {
+            let _ = multi.join_and_clear();
+        });
+        Progress {
+            chunks,
+            overall,
+            accounts,
+            drawing: Some(drawing),
+        }
+    }
+
+    /// Show that the chunk at `index` is being handled.
+    pub fn chunk(&self, index: usize) {
+        self.overall
+            .set_message(&format!("chunk {}/{}", index + 1, self.chunks));
+    }
+
+    /// Add the `count` transactions fetched for an account to its total.
+    pub fn fetched(&self, account_id: &str, count: usize) {
+        self.overall.inc_length(count as u64);
+        if let Some(bar) = self.accounts.get(account_id) {
+            bar.inc_length(count as u64);
+        }
+    }
+
+    /// Count a transaction of an account as handled.
+    pub fn handled(&self, account_id: &str) {
+        self.overall.inc(1);
+        if let Some(bar) = self.accounts.get(account_id) {
+            bar.inc(1);
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        *ACTIVE.write().unwrap() = None;
+        self.overall.finish_and_clear();
+        for bar in self.accounts.values() {
+            bar.finish_and_clear();
+        }
+        if let Some(drawing) = self.drawing.take() {
+            let _ = drawing.join();
+        }
+    }
+}
+
+/// Writer of log lines, above the progress bars while they are drawn and to stderr otherwise.
+pub struct LogWriter;
+
+/// Make a writer for a log line.
+pub fn log_writer() -> LogWriter {
+    LogWriter
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &*ACTIVE.read().unwrap() {
+            Some(bar) => {
+                bar.println(String::from_utf8_lossy(buf).trim_end());
+                Ok(buf.len())
+            }
+            None => io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::Result;
+use firefly_iii::apis::configuration::Configuration as FireflyConfiguration;
+use std::collections::HashSet;
+use tracing::info;
+
+use crate::firefly::{self, Page, Resource};
+use crate::sync::{self, Bridge};
+
+/// Delete every transaction in Firefly carrying the import tag, returning how many there were.
+pub async fn purge_transactions(
+    firefly_config: &FireflyConfiguration,
+    import_tag: &str,
+    dry_run: bool,
+) -> Result<usize> {
+    let mut seen = HashSet::new();
+    let mut page_number = 1;
+
+    loop {
+        let path = format!(
+            "{}/transactions?page={}",
+            firefly::tag_path(import_tag),
+            page_number
+        );
+        let page: Page<Resource> = match firefly::get(firefly_config, &path).await? {
+            Some(page) => page,
+            None => break,
+        };
+        let ids: Vec<_> = page
+            .data
+            .into_iter()
+            .map(|transaction| transaction.id)
+            .filter(|id| !seen.contains(id))
+            .collect();
+        if ids.is_empty() {
+            break;
+        }
+
+        for id in ids {
+            if dry_run {
+                println!("Would delete transaction {}", id);
+            } else {
+                firefly::delete(firefly_config, &format!("/transactions/{}", id)).await?;
+                info!("Deleted transaction {}", id);
+            }
+            seen.insert(id);
+        }
+
+        // Deleting moves the remaining transactions onto the first page
+        if dry_run {
+            page_number += 1;
+        }
+    }
+
+    Ok(seen.len())
+}
+
+/// Delete the Firefly asset accounts which mirror Sbanken accounts, returning how many there were.
+///
+/// Firefly deletes the transactions of an account together with it.
+pub async fn purge_accounts(
+    bridge: &Bridge,
+    firefly_config: &FireflyConfiguration,
+    dry_run: bool,
+) -> Result<usize> {
+    let sbanken_accounts = sync::list_sbanken_accounts(bridge).await?;
+    let firefly_accounts = sync::list_firefly_accounts(bridge).await?;
+
+    let mut deleted = 0;
+    for sbanken_account in &sbanken_accounts {
+        let firefly_account = match sync::find_account(&firefly_accounts, sbanken_account) {
+            Some(firefly_account) => firefly_account,
+            None => continue,
+        };
+
+        if dry_run {
+            println!(
+                "Would delete account {} <account {}>",
+                firefly_account.attributes.name, firefly_account.id
+            );
+        } else {
+            firefly::delete(firefly_config, &format!("/accounts/{}", firefly_account.id)).await?;
+            info!(
+                "Deleted account {} <account {}>",
+                firefly_account.attributes.name, firefly_account.id
+            );
+        }
+        deleted += 1;
+    }
+
+    Ok(deleted)
+}
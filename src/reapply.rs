@@ -0,0 +1,199 @@
+//! Bring transactions imported earlier in line with the current conversion, e.g. after the rules
+//! for cleaning up descriptions or assigning categories have been improved.
+
+use anyhow::{Context, Result};
+use firefly_iii::models::AccountRead;
+use reqwest::Method;
+use sbanken::models::TransactionV1;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::info;
+
+use crate::convert::convert_transaction;
+use crate::crypto::StateKey;
+use crate::firefly::{self, Resource, Single};
+use crate::state::State;
+use crate::sync::{self, Bridge};
+
+#[derive(Deserialize)]
+struct TransactionRead {
+    attributes: TransactionAttributes,
+}
+
+#[derive(Deserialize)]
+struct TransactionAttributes {
+    transactions: Vec<SplitRead>,
+}
+
+#[derive(Deserialize)]
+struct SplitRead {
+    transaction_journal_id: String,
+    #[serde(rename = "type")]
+    transaction_type: String,
+    description: String,
+    category_name: Option<String>,
+    source_name: Option<String>,
+    destination_name: Option<String>,
+    external_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TransactionUpdate<'a> {
+    transactions: [SplitUpdate<'a>; 1],
+}
+
+#[derive(Serialize)]
+struct SplitUpdate<'a> {
+    transaction_journal_id: &'a str,
+    description: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination_name: Option<&'a str>,
+}
+
+/// Firefly account mirroring each Sbanken account of the customers of `bridges`, keyed by Sbanken
+/// account id.
+async fn mapped_accounts(bridges: &[Bridge]) -> Result<HashMap<String, AccountRead>> {
+    let mut mapped = HashMap::new();
+    for bridge in bridges {
+        let firefly_accounts = sync::list_firefly_accounts(bridge).await?;
+        for sbanken_account in sync::list_sbanken_accounts(bridge).await? {
+            if let (Some(account_id), Some(firefly_account)) = (
+                sbanken_account.account_id.clone(),
+                sync::find_account(&firefly_accounts, &sbanken_account),
+            ) {
+                mapped.insert(account_id, firefly_account.clone());
+            }
+        }
+    }
+    Ok(mapped)
+}
+
+/// Convert the transactions imported by the bridge again from the Sbanken data kept in the
+/// journal, and update the description, category and counterparty of those which come out
+/// differently.
+///
+/// Transfers and split transactions are left as they are, and so is the counterparty of payments
+/// to an account number, which is an expense account shared by all payments to it.
+pub async fn reapply(
+    bridges: &[Bridge],
+    state_file: &Path,
+    key: Option<&StateKey>,
+    dry_run: bool,
+) -> Result<usize> {
+    let bridge = match bridges.first() {
+        Some(bridge) => bridge,
+        None => return Ok(0),
+    };
+    let entries = State::created_transactions(state_file, key)?;
+    let accounts = mapped_accounts(bridges).await?;
+
+    let mut updated = 0;
+    for entry in &entries {
+        let firefly_account = match accounts.get(&entry.account_id) {
+            Some(account) => account,
+            None => continue,
+        };
+        let source: TransactionV1 =
+            serde_json::from_str(&entry.source).context("invalid transaction in journal")?;
+
+        let path = format!("/transactions/{}", entry.firefly_id);
+        let current: Single<TransactionRead> = match firefly::get(&bridge.firefly_config, &path)
+            .await
+            .with_context(|| format!("unable to get transaction {}", entry.firefly_id))?
+        {
+            Some(current) => current,
+            // Deleted in Firefly since it was imported
+            None => continue,
+        };
+        let current = match current.data.attributes.transactions.as_slice() {
+            [split] if split.transaction_type != "transfer" => split,
+            _ => continue,
+        };
+
+        let fingerprint = current.external_id.as_deref().unwrap_or_default();
+        let converted = convert_transaction(
+            firefly_account,
+            &source,
+            None,
+            None,
+            fingerprint,
+            &bridge.conversion,
+        )
+        .with_context(|| format!("unable to convert transaction {}", entry.firefly_id))?;
+        let wanted = match converted.transactions.as_slice() {
+            [split] => split,
+            _ => continue,
+        };
+
+        let grouped_payee = source
+            .other_account_number
+            .as_deref()
+            .map_or(false, |number| !number.trim().is_empty());
+        let deposit = current.transaction_type == "deposit";
+        let (current_counterparty, wanted_counterparty) = if grouped_payee {
+            (None, None)
+        } else if deposit {
+            (
+                current.source_name.as_deref(),
+                wanted.source_name.as_deref(),
+            )
+        } else {
+            (
+                current.destination_name.as_deref(),
+                wanted.destination_name.as_deref(),
+            )
+        };
+
+        let mut changes = Vec::new();
+        if current.description != wanted.description {
+            changes.push(format!(
+                "description '{}' -> '{}'",
+                current.description, wanted.description
+            ));
+        }
+        if current.category_name != wanted.category_name {
+            changes.push(format!(
+                "category {} -> {}",
+                current.category_name.as_deref().unwrap_or("<none>"),
+                wanted.category_name.as_deref().unwrap_or("<none>")
+            ));
+        }
+        if wanted_counterparty.is_some() && current_counterparty != wanted_counterparty {
+            changes.push(format!(
+                "counterparty {} -> {}",
+                current_counterparty.unwrap_or("<none>"),
+                wanted_counterparty.unwrap_or("<none>")
+            ));
+        }
+        if changes.is_empty() {
+            continue;
+        }
+
+        info!("Transaction {}: {}", entry.firefly_id, changes.join(", "));
+        updated += 1;
+        if dry_run {
+            continue;
+        }
+
+        let update = TransactionUpdate {
+            transactions: [SplitUpdate {
+                transaction_journal_id: &current.transaction_journal_id,
+                description: &wanted.description,
+                category_name: wanted.category_name.as_deref(),
+                source_name: wanted_counterparty.filter(|_| deposit),
+                destination_name: wanted_counterparty.filter(|_| !deposit),
+            }],
+        };
+        let _: Single<Resource> =
+            firefly::send(&bridge.firefly_config, Method::PUT, &path, &update)
+                .await
+                .with_context(|| format!("unable to update transaction {}", entry.firefly_id))?;
+    }
+
+    Ok(updated)
+}
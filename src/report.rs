@@ -0,0 +1,185 @@
+//! Monthly spending of the synced accounts, as summed up by the insight endpoints of Firefly.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, NaiveDate};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::firefly;
+use crate::money::Money;
+use crate::sync::{self, Bridge};
+use crate::DATE_FORMAT;
+
+/// What the spending is summed up by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportGroup {
+    Category,
+    Account,
+}
+
+impl FromStr for ReportGroup {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "category" => Ok(ReportGroup::Category),
+            "account" => Ok(ReportGroup::Account),
+            _ => Err(anyhow!("unknown report grouping '{}'", s)),
+        }
+    }
+}
+
+impl ReportGroup {
+    fn path(self) -> &'static str {
+        match self {
+            ReportGroup::Category => "/insight/expense/category",
+            ReportGroup::Account => "/insight/expense/asset",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct InsightEntry {
+    name: String,
+    difference: String,
+    currency_code: String,
+}
+
+/// The spending on a category or from an account in a month.
+#[derive(Debug, Serialize)]
+pub struct Spending {
+    /// Month as YYYY-MM.
+    pub month: String,
+    /// Name of the category or account.
+    pub name: String,
+    pub amount: Decimal,
+    pub currency: String,
+}
+
+/// First day of the month `months` before the month of `day`.
+fn month_start(day: NaiveDate, months: i32) -> NaiveDate {
+    let index = day.year() * 12 + day.month0() as i32 - months;
+    NaiveDate::from_ymd(index.div_euclid(12), index.rem_euclid(12) as u32 + 1, 1)
+}
+
+/// Firefly ids of the accounts mirroring the Sbanken accounts of `bridges`.
+async fn synced_accounts(bridges: &[Bridge]) -> Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for bridge in bridges {
+        let firefly_accounts = sync::list_firefly_accounts(bridge).await?;
+        for sbanken_account in sync::list_sbanken_accounts(bridge).await? {
+            if let Some(firefly_account) = sync::find_account(&firefly_accounts, &sbanken_account) {
+                ids.push(firefly_account.id.clone());
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// Spending of the synced accounts in each of the last `months` months (including the current
+/// one), summed up by category or account.
+pub async fn spending(
+    bridges: &[Bridge],
+    months: u32,
+    group: ReportGroup,
+) -> Result<Vec<Spending>> {
+    let bridge = match bridges.first() {
+        Some(bridge) => bridge,
+        None => return Ok(Vec::new()),
+    };
+    let accounts = synced_accounts(bridges).await?;
+    if accounts.is_empty() {
+        return Err(anyhow!(
+            "no sbanken accounts are mirrored in firefly, sync first"
+        ));
+    }
+    let accounts: String = accounts
+        .iter()
+        .map(|id| format!("&accounts%5B%5D={}", id))
+        .collect();
+
+    let today = chrono::Local::today().naive_local();
+    let mut spending = Vec::new();
+    for month in (0..months as i32).rev() {
+        let start = month_start(today, month);
+        let end = month_start(today, month - 1).pred();
+        let path = format!(
+            "{}?start={}&end={}{}",
+            group.path(),
+            start.format(DATE_FORMAT),
+            end.format(DATE_FORMAT),
+            accounts
+        );
+        let entries: Vec<InsightEntry> = firefly::get(&bridge.firefly_config, &path)
+            .await
+            .with_context(|| format!("unable to get spending of {}", start.format("%Y-%m")))?
+            .unwrap_or_default();
+
+        for entry in entries {
+            let amount: Decimal = entry
+                .difference
+                .parse()
+                .with_context(|| format!("invalid amount '{}' from firefly", entry.difference))?;
+            spending.push(Spending {
+                month: start.format("%Y-%m").to_string(),
+                name: entry.name,
+                amount: amount.abs(),
+                currency: entry.currency_code,
+            });
+        }
+    }
+
+    Ok(spending)
+}
+
+/// Format the spending as a table with a row for each category or account and a column for each
+/// month.
+pub fn table(spending: &[Spending]) -> String {
+    let months: Vec<&str> = {
+        let mut months: Vec<_> = spending.iter().map(|s| s.month.as_str()).collect();
+        months.dedup();
+        months
+    };
+    let mut rows: BTreeMap<(&str, &str), BTreeMap<&str, Decimal>> = BTreeMap::new();
+    for s in spending {
+        *rows
+            .entry((s.name.as_str(), s.currency.as_str()))
+            .or_default()
+            .entry(s.month.as_str())
+            .or_default() += s.amount;
+    }
+
+    let name_width = rows
+        .keys()
+        .map(|(name, currency)| name.chars().count() + currency.len() + 3)
+        .max()
+        .unwrap_or(0);
+    let mut lines = vec![format!(
+        "{:name_width$}{}",
+        "",
+        months
+            .iter()
+            .map(|month| format!("{:>12}", month))
+            .collect::<String>(),
+        name_width = name_width
+    )];
+    for ((name, currency), amounts) in &rows {
+        let cells: String = months
+            .iter()
+            .map(|month| {
+                let amount = amounts.get(month).copied().unwrap_or_default();
+                format!("{:>12}", Money::from_decimal(amount).to_string())
+            })
+            .collect();
+        lines.push(format!(
+            "{:name_width$}{}",
+            format!("{} ({})", name, currency),
+            cells,
+            name_width = name_width
+        ));
+    }
+
+    lines.join("\n")
+}
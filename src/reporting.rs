@@ -0,0 +1,63 @@
+//! Opt-in reporting of panics, failed transactions and failed runs to Sentry, so that a sync which
+//! runs unattended does not fail silently.
+//!
+//! Reports never contain the text, amount or account numbers of a transaction.
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use sentry::{ClientInitGuard, ClientOptions, Level};
+
+use crate::error::Item;
+
+/// Start reporting to the Sentry project of `dsn` until the returned guard is dropped. Panics are
+/// reported from then on.
+pub fn init(dsn: &str) -> Result<ClientInitGuard> {
+    let dsn = dsn.parse().context("invalid sentry dsn")?;
+    Ok(sentry::init(ClientOptions {
+        dsn: Some(dsn),
+        release: sentry::release_name!(),
+        send_default_pii: false,
+        ..ClientOptions::default()
+    }))
+}
+
+/// Report a transaction which could not be stored in Firefly.
+pub fn transaction_failed(item: &Item, action: &str, error: &anyhow::Error) {
+    let message = scrub(&format!("{:#}", error), &item.text);
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("action", action);
+            scope.set_tag("date", &item.date);
+        },
+        || sentry::capture_message(&message, Level::Error),
+    );
+}
+
+/// Report a sync which failed as a whole.
+pub fn run_failed(customer: Option<&str>, error: &anyhow::Error) {
+    let message = scrub(&format!("{:#}", error), "");
+    sentry::with_scope(
+        |scope| {
+            if let Some(customer) = customer {
+                scope.set_tag("customer", customer);
+            }
+        },
+        || sentry::capture_message(&message, Level::Error),
+    );
+}
+
+/// Remove the text of the transaction and anything which looks like an account, card or KID
+/// number from `message`.
+fn scrub(message: &str, text: &str) -> String {
+    lazy_static! {
+        static ref NUMBER: Regex = Regex::new(r"\d[\d. ]{4,}\d").unwrap();
+    }
+
+    let message = if text.trim().is_empty() {
+        message.to_string()
+    } else {
+        message.replace(text, "<text>")
+    };
+    NUMBER.replace_all(&message, "<number>").into_owned()
+}
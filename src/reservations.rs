@@ -0,0 +1,194 @@
+//! Card reservations, which are imported as pending transactions and replaced by the booked
+//! transaction once it is synced.
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use sbanken::models::{AccountV1, TransactionV1};
+use tracing::{info, warn};
+
+use crate::convert::{convert_transaction, fingerprints, DateField};
+use crate::error::{Item, Required, SyncError};
+use crate::firefly::{self, Stored};
+use crate::money::Money;
+use crate::plan::Plan;
+use crate::state::{Reservation, State};
+use crate::sync::{AccountMap, Bridge};
+use crate::transactions;
+
+/// Tag on the pending transactions of reservations.
+pub const PENDING_TAG: &str = "sbanken-pending";
+
+/// Most days a reservation takes to be booked, after which it is considered cancelled.
+const MAX_BOOKING_DAYS: i64 = 14;
+
+pub fn is_reservation(transaction: &TransactionV1) -> bool {
+    transaction.is_reservation == Some(true)
+}
+
+/// Take the reservation which `booked` is the booked transaction of, if any.
+///
+/// Reservations are matched on account and amount, as the text usually changes when booked, and
+/// the one closest in time is taken.
+pub fn take_booked(
+    bridge: &Bridge,
+    state: &mut State,
+    account_id: &str,
+    booked: &TransactionV1,
+) -> Result<Option<Reservation>> {
+    let amount = Money::of(booked)?;
+    let day = bridge.conversion.date_field.day(booked)?;
+
+    let mut best: Option<(usize, i64)> = None;
+    for (index, reservation) in state.reservations.iter().enumerate() {
+        if reservation.account_id != account_id || Money::of(&reservation.transaction)? != amount {
+            continue;
+        }
+        let days = (day - bridge.conversion.date_field.day(&reservation.transaction)?).num_days();
+        if days < 0 || days > MAX_BOOKING_DAYS {
+            continue;
+        }
+        if best.map_or(true, |(_, best_days)| days < best_days) {
+            best = Some((index, days));
+        }
+    }
+
+    Ok(best.map(|(index, _)| state.reservations.remove(index)))
+}
+
+/// Delete the pending transaction of a reservation from Firefly. Errors are only logged, as the
+/// pending transaction can be deleted by hand.
+pub async fn remove(bridge: &Bridge, reservation: &Reservation) {
+    if bridge.dry_run {
+        return;
+    }
+
+    let path = format!("/transactions/{}", reservation.firefly_id);
+    if let Err(e) = firefly::delete(&bridge.firefly_config, &path).await {
+        warn!(
+            "Unable to delete pending transaction <transaction {}>: {:?}",
+            reservation.firefly_id, e
+        );
+    }
+}
+
+/// Import the current reservations of all accounts as pending transactions, and remove those of
+/// reservations which were not booked on or before `last_day` in time.
+pub async fn sync_reservations(
+    bridge: &Bridge,
+    sbanken_accounts: &[AccountV1],
+    firefly_accounts: &AccountMap,
+    state: &mut State,
+    last_day: NaiveDate,
+    plan: &mut Plan,
+) -> Result<()> {
+    let today = chrono::Local::today().naive_local();
+
+    let mut expired = Vec::new();
+    for reservation in std::mem::take(&mut state.reservations) {
+        let day = bridge.conversion.date_field.day(&reservation.transaction)?;
+        if (last_day - day).num_days() > MAX_BOOKING_DAYS {
+            expired.push(reservation);
+        } else {
+            state.reservations.push(reservation);
+        }
+    }
+    for reservation in expired {
+        info!(
+            "Reservation '{}' was never booked, removing its pending transaction",
+            reservation.transaction.text.as_deref().unwrap_or_default()
+        );
+        remove(bridge, &reservation).await;
+    }
+
+    for sbanken_account in sbanken_accounts {
+        let firefly_account = match firefly_accounts.mirror(sbanken_account) {
+            Some(firefly_account) => firefly_account,
+            None => continue,
+        };
+        let account_id = sbanken_account
+            .account_id
+            .as_deref()
+            .required("account id")?;
+
+        let start = today - chrono::Duration::days(MAX_BOOKING_DAYS);
+        let (reservations, details): (Vec<_>, Vec<_>) =
+            transactions::fetch(bridge, account_id, start, today)
+                .await
+                .with_context(|| {
+                    format!(
+                        "unable to get reservations of account {}",
+                        sbanken_account.name.as_deref().required("account name")?
+                    )
+                })?
+                .into_iter()
+                .filter(|(transaction, _)| is_reservation(transaction))
+                .unzip();
+        let fingerprints =
+            fingerprints(account_id, &firefly_account.attributes.name, &reservations)?;
+
+        for ((reservation, details), fingerprint) in
+            reservations.into_iter().zip(details).zip(fingerprints)
+        {
+            if state
+                .reservations
+                .iter()
+                .any(|known| known.fingerprint == fingerprint)
+            {
+                continue;
+            }
+
+            let mut firefly_transaction = convert_transaction(
+                firefly_account,
+                &reservation,
+                details.as_ref(),
+                None,
+                &fingerprint,
+                &bridge.conversion,
+            )
+            .map_err(|source| SyncError::Conversion {
+                item: Item::new(&firefly_account.attributes.name, &reservation),
+                source,
+            })?;
+            for split in &mut firefly_transaction.transactions {
+                split
+                    .tags
+                    .get_or_insert_with(Vec::new)
+                    .push(PENDING_TAG.into());
+            }
+
+            info!(
+                "{} : {} -- {:>6} : {} **reservation**",
+                DateField::Accounting.date(&reservation)?,
+                firefly_account.attributes.name,
+                Money::of(&reservation)?,
+                reservation.text.as_deref().unwrap_or_default(),
+            );
+
+            if bridge.dry_run {
+                plan.transactions.push(firefly_transaction);
+                continue;
+            }
+
+            match firefly::store_transaction(
+                &bridge.firefly_config,
+                &firefly_transaction,
+                bridge.fire_webhooks,
+            )
+            .await
+            {
+                Ok(Stored::Created(id)) => state.reservations.push(Reservation {
+                    account_id: account_id.into(),
+                    fingerprint,
+                    firefly_id: id,
+                    transaction: reservation,
+                }),
+                Ok(Stored::Duplicate) => {
+                    info!("Duplicate of a transaction in firefly, skipping");
+                }
+                Err(e) => warn!("Unable to store reservation, skipping: {}", e),
+            }
+        }
+    }
+
+    Ok(())
+}
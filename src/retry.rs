@@ -0,0 +1,229 @@
+//! Retrying of requests which failed for reasons that are likely to go away by themselves, like
+//! timeouts, dropped connections, server errors and throttling.
+
+use rand::Rng;
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::metrics;
+use crate::throttle::{self, Api};
+
+/// Number of times a request is made before giving up, unless configured otherwise.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 4;
+
+/// Wait before the first retry, which doubles for every following one.
+const BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest wait between two attempts.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Longest wait asked for by a throttled response which is honored, rather than giving up.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+static MAX_ATTEMPTS: AtomicU32 = AtomicU32::new(DEFAULT_MAX_ATTEMPTS);
+
+/// Make every request at most `attempts` times, where 1 disables retrying.
+pub fn set_max_attempts(attempts: u32) {
+    MAX_ATTEMPTS.store(attempts.max(1), Ordering::Relaxed);
+}
+
+/// Errors which may go away when the request is made again.
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for reqwest::Error {
+    fn is_transient(&self) -> bool {
+        if self.is_timeout() || self.is_connect() {
+            return true;
+        }
+        if let Some(status) = self.status() {
+            return is_transient_status(status);
+        }
+
+        // Connections which are reset or closed halfway show up as I/O errors further down
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            if let Some(io) = error.downcast_ref::<std::io::Error>() {
+                use std::io::ErrorKind::*;
+                return matches!(
+                    io.kind(),
+                    ConnectionReset | ConnectionAborted | BrokenPipe | UnexpectedEof | TimedOut
+                );
+            }
+            source = error.source();
+        }
+        false
+    }
+}
+
+impl<T> Transient for firefly_iii::apis::Error<T> {
+    fn is_transient(&self) -> bool {
+        match self {
+            firefly_iii::apis::Error::Reqwest(e) => e.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+impl<T> Transient for sbanken::apis::Error<T> {
+    fn is_transient(&self) -> bool {
+        match self {
+            sbanken::apis::Error::Reqwest(e) => e.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Whether making a request with `method` twice has the same effect as making it once.
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+    )
+}
+
+/// How long a throttled response asks to wait before the next request, if it says so in seconds.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds: u64 = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds).min(MAX_RETRY_AFTER))
+}
+
+/// Wait before attempt `attempt + 1`, growing exponentially with a random part so that clients
+/// which failed together do not retry together.
+fn backoff(attempt: u32) -> Duration {
+    let exponential = BASE_DELAY
+        .checked_mul(2u32.saturating_pow(attempt - 1))
+        .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY));
+    let half = exponential / 2;
+    half + half.mul_f64(rand::thread_rng().gen_range(0.0, 1.0))
+}
+
+/// Run `request` to `api` until it succeeds, fails with an error which is not transient or has
+/// been made the configured number of times.
+pub async fn retry<T, E, F, Fut>(api: Api, what: &str, mut request: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Transient + Display,
+{
+    let max_attempts = MAX_ATTEMPTS.load(Ordering::Relaxed);
+    let mut attempt = 1;
+    loop {
+        throttle::acquire(api).await;
+        let started = Instant::now();
+        let result = request().await;
+        metrics::request(api, started.elapsed());
+        match result {
+            Err(e) if attempt < max_attempts && e.is_transient() => {
+                let delay = backoff(attempt);
+                warn!(
+                    "{} failed ({}), retrying in {:.1}s...",
+                    what,
+                    e,
+                    delay.as_secs_f64()
+                );
+                tokio::time::delay_for(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Send the request to `api`, sending it again after a transient error, a server error or being
+/// throttled, in which case the wait asked for in `Retry-After` is honored.
+///
+/// A request which is not idempotent, like a `POST`, could have been applied by an attempt which
+/// timed out or got a server error, so it is only sent again if it never reached the server or was
+/// throttled. Requests with a streaming body cannot be repeated and are sent only once.
+pub async fn send(api: Api, builder: RequestBuilder) -> reqwest::Result<Response> {
+    send_with(api, builder, false).await
+}
+
+/// Send the request to `api` like `send`, but send it again after any transient failure whatever
+/// its method, as the server refuses or ignores a repeated request, e.g. a transaction which
+/// Firefly checks for duplicates.
+pub async fn send_repeatable(api: Api, builder: RequestBuilder) -> reqwest::Result<Response> {
+    send_with(api, builder, true).await
+}
+
+async fn send_with(
+    api: Api,
+    builder: RequestBuilder,
+    repeatable: bool,
+) -> reqwest::Result<Response> {
+    let max_attempts = MAX_ATTEMPTS.load(Ordering::Relaxed);
+    let repeatable = repeatable
+        || builder
+            .try_clone()
+            .and_then(|request| request.build().ok())
+            .map_or(false, |request| is_idempotent(request.method()));
+    let mut attempt = 1;
+    loop {
+        throttle::acquire(api).await;
+        let started = Instant::now();
+        let request = match builder.try_clone() {
+            Some(request) if attempt < max_attempts => request,
+            _ => {
+                let result = builder.send().await;
+                metrics::request(api, started.elapsed());
+                return result;
+            }
+        };
+        let result = request.send().await;
+        metrics::request(api, started.elapsed());
+        let (reason, delay) = match result {
+            Ok(response)
+                if response.status() == StatusCode::TOO_MANY_REQUESTS
+                    || (repeatable && is_transient_status(response.status())) =>
+            {
+                (
+                    response.status().to_string(),
+                    retry_after(&response).unwrap_or_else(|| backoff(attempt)),
+                )
+            }
+            Err(e) if e.is_connect() || (repeatable && e.is_transient()) => {
+                (e.to_string(), backoff(attempt))
+            }
+            result => return result,
+        };
+
+        warn!(
+            "Request failed ({}), retrying in {:.1}s...",
+            reason,
+            delay.as_secs_f64()
+        );
+        tokio::time::delay_for(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_repeats_idempotent_methods() {
+        assert!(is_idempotent(&Method::GET));
+        assert!(is_idempotent(&Method::PUT));
+        assert!(is_idempotent(&Method::DELETE));
+        assert!(!is_idempotent(&Method::POST));
+        assert!(!is_idempotent(&Method::PATCH));
+    }
+}
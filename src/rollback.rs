@@ -0,0 +1,103 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDate;
+use firefly_iii::apis::configuration::Configuration as FireflyConfiguration;
+use sbanken::models::TransactionV1;
+use std::collections::BTreeSet;
+use std::path::Path;
+use tracing::info;
+
+use crate::crypto::StateKey;
+use crate::firefly;
+use crate::state::{ObjectKind, State};
+use crate::DATE_FORMAT;
+
+/// Delete the objects created by run `run_id` from Firefly and rewind the sync state, so that
+/// the next sync imports the transactions again.
+///
+/// Accounts are only deleted if `include_accounts` is set. As Firefly deletes the transactions of
+/// an account together with it, this is refused if a later run imported into the same account.
+pub async fn rollback(
+    firefly_config: &FireflyConfiguration,
+    state_file: &Path,
+    key: Option<&StateKey>,
+    run_id: i64,
+    include_accounts: bool,
+) -> Result<()> {
+    let entries = State::journal(state_file, run_id, key)?;
+    if entries.is_empty() {
+        return Err(anyhow!(
+            "nothing to roll back, run {} did not create anything in firefly",
+            run_id
+        ));
+    }
+
+    let mut state = State::load(state_file)?;
+
+    let mut transactions = BTreeSet::new();
+    let mut accounts = Vec::new();
+    for entry in &entries {
+        match entry.kind {
+            ObjectKind::Transaction => {
+                transactions.insert(&entry.firefly_id);
+
+                let source: TransactionV1 = serde_json::from_str(&entry.source)
+                    .context("invalid transaction in journal")?;
+                let day = source
+                    .accounting_date
+                    .as_deref()
+                    .and_then(|date| date.get(..10))
+                    .ok_or_else(|| anyhow!("transaction in journal is missing accounting date"))?;
+                let day = NaiveDate::parse_from_str(day, DATE_FORMAT)?;
+
+                // Start the next sync at the earliest transaction which is removed
+                if let Some(last_sync) = state.last_sync(&entry.account_id) {
+                    state
+                        .accounts
+                        .insert(entry.account_id.clone(), std::cmp::min(last_sync, day));
+                }
+            }
+            ObjectKind::Account if include_accounts => {
+                let later_runs = State::later_runs(state_file, run_id, &entry.account_id)?;
+                if let Some(later_run) = later_runs.last() {
+                    return Err(anyhow!(
+                        "run {} imported into account <account {}> as well, roll it back first",
+                        later_run,
+                        entry.firefly_id
+                    ));
+                }
+                accounts.push(entry);
+            }
+            ObjectKind::Account => {}
+        }
+    }
+
+    for id in &transactions {
+        if firefly::delete(firefly_config, &format!("/transactions/{}", id)).await? {
+            info!("Deleted transaction {}", id);
+        } else {
+            info!("Transaction {} was already deleted", id);
+        }
+    }
+
+    let mut removed_accounts = Vec::new();
+    for entry in accounts {
+        if firefly::delete(firefly_config, &format!("/accounts/{}", entry.firefly_id)).await? {
+            info!("Deleted account {}", entry.firefly_id);
+        } else {
+            info!("Account {} was already deleted", entry.firefly_id);
+        }
+        state.accounts.remove(&entry.account_id);
+        removed_accounts.push(entry.account_id.clone());
+    }
+
+    state.forget_run(state_file, run_id, &removed_accounts)?;
+
+    info!(
+        "Rolled back run {}: deleted {} transaction(s) and {} account(s)",
+        run_id,
+        transactions.len(),
+        removed_accounts.len()
+    );
+
+    Ok(())
+}
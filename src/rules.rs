@@ -0,0 +1,430 @@
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use rust_decimal::Decimal;
+use sbanken::models::TransactionV1;
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::money::Money;
+use crate::transactions::TransactionDetails;
+
+/// Rules used when no rules file is given.
+const DEFAULT_RULES: &str = include_str!("default_rules.toml");
+
+/// Which Sbanken transactions a rule applies to, every transaction if empty.
+#[derive(Debug, Clone)]
+struct TransactionMatch {
+    /// Sbanken transaction type, e.g. "VARE" or "AVTGIRO".
+    transaction_type: Option<String>,
+    /// Pattern the transaction text has to match.
+    pattern: Option<Regex>,
+    /// Merchant category codes of card payments, e.g. "5411" for groceries, of which one has to
+    /// match. Only known for transactions from the v2 endpoint.
+    merchant_category_codes: Vec<String>,
+}
+
+impl TransactionMatch {
+    fn new(
+        transaction_type: Option<String>,
+        pattern: Option<String>,
+        merchant_category_codes: Vec<String>,
+    ) -> Result<TransactionMatch> {
+        Ok(TransactionMatch {
+            transaction_type,
+            pattern: compile(pattern)?,
+            merchant_category_codes,
+        })
+    }
+
+    fn matches(&self, transaction: &TransactionV1, details: Option<&TransactionDetails>) -> bool {
+        let text = transaction.text.as_deref().unwrap_or_default();
+        let merchant_category_code = details
+            .and_then(|details| details.card_details.as_ref())
+            .and_then(|card| card.merchant_category_code.as_deref());
+
+        self.transaction_type
+            .as_deref()
+            .map_or(true, |t| Some(t) == transaction.transaction_type.as_deref())
+            && self
+                .pattern
+                .as_ref()
+                .map_or(true, |pattern| pattern.is_match(text))
+            && (self.merchant_category_codes.is_empty()
+                || merchant_category_code.map_or(false, |code| {
+                    self.merchant_category_codes
+                        .iter()
+                        .any(|other| other.trim() == code.trim())
+                }))
+    }
+}
+
+/// A rule assigning a Firefly category to matching Sbanken transactions.
+#[derive(Debug, Clone)]
+pub struct CategoryRule {
+    matches: TransactionMatch,
+    category: String,
+}
+
+/// A rule assigning a Firefly budget to matching Sbanken withdrawals.
+#[derive(Debug, Clone)]
+pub struct BudgetRule {
+    matches: TransactionMatch,
+    /// Category the withdrawal has to be given.
+    category: Option<String>,
+    budget: String,
+}
+
+/// A rule splitting matching Sbanken transactions into several parts in Firefly.
+#[derive(Debug, Clone)]
+pub struct SplitRule {
+    matches: TransactionMatch,
+    pub parts: Vec<SplitPart>,
+}
+
+/// One part of a split transaction.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitPart {
+    /// Description of the part, defaults to the description of the transaction.
+    pub description: Option<String>,
+    pub category: Option<String>,
+    /// Fraction of the amount, e.g. 0.7.
+    share: Option<Decimal>,
+    /// Fixed amount.
+    amount: Option<Decimal>,
+}
+
+/// A rule giving matching merchants a canonical name.
+#[derive(Debug, Clone)]
+pub struct MerchantRule {
+    /// Start of the description, compared case insensitively.
+    prefix: Option<String>,
+    /// Pattern the description has to match.
+    pattern: Option<Regex>,
+    name: String,
+}
+
+/// User editable rules for turning Sbanken transactions into meaningful Firefly transactions.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    categories: Vec<CategoryRule>,
+    budgets: Vec<BudgetRule>,
+    merchants: Vec<MerchantRule>,
+    splits: Vec<SplitRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    category: Vec<CategoryRuleFile>,
+    #[serde(default)]
+    budget: Vec<BudgetRuleFile>,
+    #[serde(default)]
+    merchant: Vec<MerchantRuleFile>,
+    #[serde(default)]
+    split: Vec<SplitRuleFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CategoryRuleFile {
+    #[serde(rename = "type")]
+    transaction_type: Option<String>,
+    pattern: Option<String>,
+    #[serde(default)]
+    mcc: Vec<String>,
+    category: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BudgetRuleFile {
+    #[serde(rename = "type")]
+    transaction_type: Option<String>,
+    pattern: Option<String>,
+    #[serde(default)]
+    mcc: Vec<String>,
+    category: Option<String>,
+    budget: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SplitRuleFile {
+    #[serde(rename = "type")]
+    transaction_type: Option<String>,
+    pattern: Option<String>,
+    #[serde(default)]
+    mcc: Vec<String>,
+    parts: Vec<SplitPart>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MerchantRuleFile {
+    prefix: Option<String>,
+    pattern: Option<String>,
+    name: String,
+}
+
+fn compile(pattern: Option<String>) -> Result<Option<Regex>> {
+    match pattern {
+        Some(pattern) => Regex::new(&pattern)
+            .map(Some)
+            .with_context(|| format!("invalid pattern '{}' in rule", pattern)),
+        None => Ok(None),
+    }
+}
+
+impl Rules {
+    /// Load rules from `path` (TOML or YAML), or the built-in rules if no path is given.
+    pub fn load(path: Option<&Path>) -> Result<Rules> {
+        let file: RulesFile = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("unable to read rules file '{}'", path.display()))?;
+                match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("yaml") | Some("yml") => {
+                        serde_yaml::from_str(&contents).with_context(|| {
+                            format!("invalid yaml in rules file '{}'", path.display())
+                        })?
+                    }
+                    _ => toml::from_str(&contents).with_context(|| {
+                        format!("invalid toml in rules file '{}'", path.display())
+                    })?,
+                }
+            }
+            None => toml::from_str(DEFAULT_RULES).context("invalid built-in rules")?,
+        };
+
+        let categories = file
+            .category
+            .into_iter()
+            .map(|rule| {
+                Ok(CategoryRule {
+                    matches: TransactionMatch::new(rule.transaction_type, rule.pattern, rule.mcc)?,
+                    category: rule.category,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let budgets = file
+            .budget
+            .into_iter()
+            .map(|rule| {
+                Ok(BudgetRule {
+                    matches: TransactionMatch::new(rule.transaction_type, rule.pattern, rule.mcc)?,
+                    category: rule.category,
+                    budget: rule.budget,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let merchants = file
+            .merchant
+            .into_iter()
+            .map(|rule| {
+                Ok(MerchantRule {
+                    prefix: rule.prefix.map(|prefix| prefix.to_lowercase()),
+                    pattern: compile(rule.pattern)?,
+                    name: rule.name,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let splits = file
+            .split
+            .into_iter()
+            .map(|rule| {
+                if rule.parts.len() < 2 {
+                    return Err(anyhow!("split rule needs at least two parts"));
+                }
+                if rule
+                    .parts
+                    .iter()
+                    .any(|part| part.share.is_some() && part.amount.is_some())
+                {
+                    return Err(anyhow!(
+                        "part of split rule has both a share and an amount, use only one"
+                    ));
+                }
+                let remainders = rule
+                    .parts
+                    .iter()
+                    .filter(|part| part.share.is_none() && part.amount.is_none())
+                    .count();
+                if remainders > 1 {
+                    return Err(anyhow!(
+                        "split rule has {} parts without share or amount, only one can take the \
+                         rest",
+                        remainders
+                    ));
+                }
+
+                Ok(SplitRule {
+                    matches: TransactionMatch::new(rule.transaction_type, rule.pattern, rule.mcc)?,
+                    parts: rule.parts,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Rules {
+            categories,
+            budgets,
+            merchants,
+            splits,
+        })
+    }
+
+    /// First split rule matching the transaction, if any.
+    pub fn split(
+        &self,
+        transaction: &TransactionV1,
+        details: Option<&TransactionDetails>,
+    ) -> Option<&SplitRule> {
+        self.splits
+            .iter()
+            .find(|rule| rule.matches.matches(transaction, details))
+    }
+
+    /// Canonical name of the first merchant rule matching the description, if any.
+    pub fn merchant(&self, desc: &str) -> Option<&str> {
+        let lowercase = desc.to_lowercase();
+
+        self.merchants
+            .iter()
+            .find(|rule| {
+                rule.prefix
+                    .as_deref()
+                    .map_or(true, |prefix| lowercase.starts_with(prefix))
+                    && rule
+                        .pattern
+                        .as_ref()
+                        .map_or(true, |pattern| pattern.is_match(desc))
+            })
+            .map(|rule| rule.name.as_str())
+    }
+
+    /// Category of the first rule matching the transaction, falling back to its transaction type.
+    pub fn category(
+        &self,
+        transaction: &TransactionV1,
+        details: Option<&TransactionDetails>,
+    ) -> Option<String> {
+        self.categories
+            .iter()
+            .find(|rule| rule.matches.matches(transaction, details))
+            .map(|rule| rule.category.clone())
+            .or_else(|| transaction.transaction_type.clone())
+    }
+
+    /// Budget of the first rule matching the transaction and its assigned `category`, if any.
+    pub fn budget(
+        &self,
+        transaction: &TransactionV1,
+        details: Option<&TransactionDetails>,
+        category: Option<&str>,
+    ) -> Option<String> {
+        self.budgets
+            .iter()
+            .find(|rule| {
+                rule.matches.matches(transaction, details)
+                    && rule
+                        .category
+                        .as_deref()
+                        .map_or(true, |wanted| Some(wanted) == category)
+            })
+            .map(|rule| rule.budget.clone())
+    }
+}
+
+impl SplitRule {
+    /// Amount of each part of `total` (without sign), in the order of the parts.
+    ///
+    /// The part without share or amount takes what is left, otherwise the last part absorbs the
+    /// rounding of the shares.
+    pub fn amounts(&self, total: Money) -> Result<Vec<Money>> {
+        let total = total.abs().decimal();
+        let mut amounts: Vec<Decimal> = self
+            .parts
+            .iter()
+            .map(|part| match (part.share, part.amount) {
+                (Some(share), _) => (total * share).round_dp(2),
+                (_, Some(amount)) => amount,
+                (None, None) => Decimal::new(0, 2),
+            })
+            .collect();
+
+        let rest = total - amounts.iter().sum::<Decimal>();
+        let rest_index = self
+            .parts
+            .iter()
+            .position(|part| part.share.is_none() && part.amount.is_none())
+            .unwrap_or(amounts.len() - 1);
+        amounts[rest_index] += rest;
+
+        if amounts.iter().any(|amount| *amount < Decimal::new(0, 0)) {
+            return Err(anyhow!(
+                "split rule does not fit an amount of {}, the parts add up to more",
+                total
+            ));
+        }
+
+        Ok(amounts.into_iter().map(Money::from_decimal).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn split(parts: &[(Option<&str>, Option<&str>)]) -> SplitRule {
+        SplitRule {
+            matches: TransactionMatch::new(None, None, Vec::new()).unwrap(),
+            parts: parts
+                .iter()
+                .map(|(share, amount)| SplitPart {
+                    description: None,
+                    category: None,
+                    share: share.map(|share| Decimal::from_str(share).unwrap()),
+                    amount: amount.map(|amount| Decimal::from_str(amount).unwrap()),
+                })
+                .collect(),
+        }
+    }
+
+    fn amounts(rule: &SplitRule, total: f64) -> Vec<String> {
+        rule.amounts(Money::from_f64(total).unwrap())
+            .unwrap()
+            .iter()
+            .map(Money::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn part_without_share_or_amount_takes_the_rest() {
+        let rule = split(&[(Some("0.7"), None), (None, None)]);
+        assert_eq!(amounts(&rule, -1000.0), vec!["700.00", "300.00"]);
+
+        let rule = split(&[(None, None), (None, Some("1200"))]);
+        assert_eq!(amounts(&rule, -1500.0), vec!["300.00", "1200.00"]);
+    }
+
+    #[test]
+    fn last_part_absorbs_rounding_of_shares() {
+        let rule = split(&[
+            (Some("0.3333"), None),
+            (Some("0.3333"), None),
+            (Some("0.3333"), None),
+        ]);
+        assert_eq!(amounts(&rule, 10.0), vec!["3.33", "3.33", "3.34"]);
+    }
+
+    #[test]
+    fn parts_may_not_add_up_to_more_than_the_total() {
+        let rule = split(&[(None, Some("150")), (None, None)]);
+        assert!(rule.amounts(Money::from_f64(-100.0).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+//! Stopping a run early on SIGINT or SIGTERM without losing the progress made so far.
+//!
+//! The first signal asks the run to stop after the request in flight, so that the state can be
+//! saved and a summary printed. A second signal stops the process right away.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Exit code of a run which was stopped by a signal.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// A run which was stopped early by a signal.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "stopped by signal before the run finished")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Whether the run has been asked to stop.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Fail with `Interrupted` if the run has been asked to stop.
+pub fn check() -> Result<()> {
+    if requested() {
+        return Err(Interrupted.into());
+    }
+    Ok(())
+}
+
+/// Wait until the run is asked to stop.
+pub async fn wait() {
+    while !requested() {
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+    }
+}
+
+#[cfg(unix)]
+async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = terminate.recv() => {},
+            }
+        }
+        Err(e) => {
+            warn!("Unable to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Listen for SIGINT and SIGTERM in the background.
+pub fn listen() {
+    tokio::spawn(async {
+        signal().await;
+        info!("Stopping after the current request, send the signal again to stop right away");
+        REQUESTED.store(true, Ordering::Relaxed);
+
+        signal().await;
+        info!("Stopping right away");
+        std::process::exit(EXIT_INTERRUPTED);
+    });
+}
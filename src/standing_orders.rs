@@ -0,0 +1,328 @@
+//! Standing orders (faste oppdrag), which are mirrored as recurring transactions in Firefly.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, NaiveDate};
+use firefly_iii::models::AccountRead;
+use reqwest::Method;
+use sbanken::models::AccountV1;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::{info, warn};
+
+use crate::bank;
+use crate::convert::CURRENCY_CODE;
+use crate::error::Required;
+use crate::firefly::{self, Resource, Single};
+use crate::money::Money;
+use crate::payees::Payees;
+use crate::plan::Plan;
+use crate::state::{MirroredStandingOrder, State};
+use crate::sync::{AccountMap, Bridge};
+use crate::DATE_FORMAT;
+
+/// A standing order as listed by Sbanken.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StandingOrder {
+    pub standing_order_id: String,
+    pub beneficiary_name: Option<String>,
+    pub credit_account_number: Option<String>,
+    pub amount: Option<f64>,
+    /// E.g. "MONTHLY" or "WEEKLY".
+    pub frequency: Option<String>,
+    pub next_due_date: Option<String>,
+    pub cid: Option<String>,
+    pub free_terms: Option<String>,
+}
+
+impl StandingOrder {
+    fn next_due_day(&self) -> Result<NaiveDate> {
+        let date = self.next_due_date.as_deref().unwrap_or_default();
+        NaiveDate::parse_from_str(date.get(..10).unwrap_or(date), DATE_FORMAT).with_context(|| {
+            format!(
+                "invalid next due date of standing order {}",
+                self.standing_order_id
+            )
+        })
+    }
+
+    fn beneficiary(&self) -> String {
+        self.beneficiary_name
+            .clone()
+            .filter(|name| !name.trim().is_empty())
+            .or_else(|| self.credit_account_number.clone())
+            .unwrap_or_else(|| "Standing order".into())
+    }
+}
+
+#[derive(Serialize)]
+struct Repetition {
+    #[serde(rename = "type")]
+    repetition_type: &'static str,
+    moment: String,
+    skip: u32,
+    /// Move to the previous weekday if due on a weekend, as the bank does.
+    weekend: u32,
+}
+
+#[derive(Serialize)]
+struct RecurrenceTransaction {
+    description: String,
+    amount: String,
+    currency_code: &'static str,
+    source_id: String,
+    destination_id: String,
+}
+
+#[derive(Serialize)]
+struct RecurrenceStore {
+    #[serde(rename = "type")]
+    recurrence_type: &'static str,
+    title: String,
+    first_date: String,
+    apply_rules: bool,
+    active: bool,
+    notes: String,
+    repetitions: Vec<Repetition>,
+    transactions: Vec<RecurrenceTransaction>,
+}
+
+/// How often a standing order is paid, in terms of a Firefly repetition.
+fn repetition(order: &StandingOrder, due: NaiveDate) -> Result<Repetition> {
+    let frequency = order.frequency.as_deref().unwrap_or_default();
+    let (repetition_type, skip) = match frequency.to_uppercase().as_str() {
+        "WEEKLY" => ("weekly", 0),
+        "EVERY_OTHER_WEEK" | "BIWEEKLY" => ("weekly", 1),
+        "MONTHLY" => ("monthly", 0),
+        "EVERY_OTHER_MONTH" | "BIMONTHLY" => ("monthly", 1),
+        "QUARTERLY" => ("monthly", 2),
+        "HALF_YEARLY" | "SEMIANNUALLY" => ("monthly", 5),
+        "YEARLY" | "ANNUALLY" => ("yearly", 0),
+        _ => return Err(anyhow!("unknown frequency '{}'", frequency)),
+    };
+    let moment = match repetition_type {
+        "weekly" => due.weekday().number_from_monday().to_string(),
+        "monthly" => due.day().to_string(),
+        _ => due.format(DATE_FORMAT).to_string(),
+    };
+
+    Ok(Repetition {
+        repetition_type,
+        moment,
+        skip,
+        weekend: 3,
+    })
+}
+
+/// Mirror the standing orders of all accounts as recurring transactions in Firefly, updating
+/// those which changed and deleting those which were cancelled.
+pub async fn sync_standing_orders(
+    bridge: &Bridge,
+    sbanken_accounts: &[AccountV1],
+    firefly_accounts: &AccountMap,
+    state: &mut State,
+    plan: &mut Plan,
+) -> Result<()> {
+    let mut payees = Payees::default();
+    let mut synced_accounts = HashSet::new();
+    let mut seen = HashSet::new();
+
+    for sbanken_account in sbanken_accounts {
+        let firefly_account = match firefly_accounts.mirror(sbanken_account) {
+            Some(firefly_account) => firefly_account,
+            None => continue,
+        };
+        let account_id = sbanken_account
+            .account_id
+            .as_deref()
+            .required("account id")?;
+
+        let orders: Vec<StandingOrder> = match bank::get_items(
+            bridge,
+            &format!("/api/v1/StandingOrders/{}", account_id),
+            &[],
+        )
+        .await
+        {
+            Ok(orders) => orders,
+            Err(e) => {
+                warn!(
+                    "Unable to get standing orders of account {}, skipping: {:#}",
+                    sbanken_account.name.as_deref().required("account name")?,
+                    e
+                );
+                continue;
+            }
+        };
+        synced_accounts.insert(account_id);
+
+        for order in orders {
+            seen.insert(order.standing_order_id.clone());
+
+            let known = state
+                .standing_orders
+                .iter()
+                .find(|mirrored| mirrored.order.standing_order_id == order.standing_order_id);
+            if known.map_or(false, |mirrored| mirrored.order == order) {
+                continue;
+            }
+            let recurrence_id = known.map(|mirrored| mirrored.recurrence_id.clone());
+
+            let title = format!(
+                "{} ({})",
+                order.beneficiary(),
+                firefly_account.attributes.name
+            );
+            info!(
+                "{} recurring transaction {}",
+                if recurrence_id.is_some() {
+                    "Updating"
+                } else {
+                    "Creating"
+                },
+                title
+            );
+            if bridge.dry_run {
+                plan.recurrences.push(title);
+                continue;
+            }
+
+            let recurrence = match recurrence(
+                bridge,
+                &mut payees,
+                firefly_account,
+                firefly_accounts,
+                &order,
+                title,
+            )
+            .await
+            {
+                Ok(recurrence) => recurrence,
+                Err(e) => {
+                    warn!("Unable to convert standing order, skipping: {:#}", e);
+                    continue;
+                }
+            };
+
+            let stored: Single<Resource> = match &recurrence_id {
+                Some(id) => {
+                    firefly::send(
+                        &bridge.firefly_config,
+                        Method::PUT,
+                        &format!("/recurrences/{}", id),
+                        &recurrence,
+                    )
+                    .await
+                }
+                None => {
+                    firefly::send(
+                        &bridge.firefly_config,
+                        Method::POST,
+                        "/recurrences",
+                        &recurrence,
+                    )
+                    .await
+                }
+            }
+            .with_context(|| {
+                format!(
+                    "unable to store recurring transaction '{}'",
+                    recurrence.title
+                )
+            })?;
+
+            state
+                .standing_orders
+                .retain(|mirrored| mirrored.order.standing_order_id != order.standing_order_id);
+            state.standing_orders.push(MirroredStandingOrder {
+                account_id: account_id.into(),
+                recurrence_id: stored.data.id,
+                order,
+            });
+        }
+    }
+
+    // Standing orders which are gone from an account which was listed have been cancelled
+    let (cancelled, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut state.standing_orders)
+        .into_iter()
+        .partition(|mirrored| {
+            synced_accounts.contains(mirrored.account_id.as_str())
+                && !seen.contains(&mirrored.order.standing_order_id)
+        });
+    state.standing_orders = kept;
+
+    for mirrored in cancelled {
+        info!(
+            "Standing order to {} was cancelled, deleting its recurring transaction",
+            mirrored.order.beneficiary()
+        );
+        if bridge.dry_run {
+            plan.recurrences
+                .push(format!("{} (delete)", mirrored.order.beneficiary()));
+            continue;
+        }
+        let path = format!("/recurrences/{}", mirrored.recurrence_id);
+        if let Err(e) = firefly::delete(&bridge.firefly_config, &path).await {
+            warn!("Unable to delete recurring transaction: {:?}", e);
+            state.standing_orders.push(mirrored);
+        }
+    }
+
+    Ok(())
+}
+
+/// Recurring transaction of a standing order, which is a transfer if it pays into another
+/// mirrored account and a withdrawal to the expense account of the beneficiary otherwise.
+async fn recurrence(
+    bridge: &Bridge,
+    payees: &mut Payees,
+    firefly_account: &AccountRead,
+    firefly_accounts: &AccountMap,
+    order: &StandingOrder,
+    title: String,
+) -> Result<RecurrenceStore> {
+    let due = order.next_due_day()?;
+    let amount = Money::from_f64(order.amount.unwrap_or_default())?.abs();
+    let credit_account_number = order
+        .credit_account_number
+        .as_deref()
+        .ok_or_else(|| anyhow!("standing order has no account to pay to"))?;
+
+    let own_account = firefly_accounts.by_number(credit_account_number);
+    let (recurrence_type, destination_id) = match own_account {
+        Some(account) => ("transfer", account.id.clone()),
+        None => (
+            "withdrawal",
+            payees
+                .expense_account(bridge, &order.beneficiary(), credit_account_number)
+                .await?,
+        ),
+    };
+
+    let description = order
+        .free_terms
+        .clone()
+        .filter(|terms| !terms.trim().is_empty())
+        .unwrap_or_else(|| order.beneficiary());
+    let mut notes = format!("Sbanken standing order {}", order.standing_order_id);
+    if let Some(cid) = &order.cid {
+        notes.push_str(&format!("\nKID: {}", cid));
+    }
+
+    Ok(RecurrenceStore {
+        recurrence_type,
+        title,
+        first_date: due.format(DATE_FORMAT).to_string(),
+        apply_rules: true,
+        active: true,
+        notes,
+        repetitions: vec![repetition(order, due)?],
+        transactions: vec![RecurrenceTransaction {
+            description,
+            amount: amount.to_string(),
+            currency_code: CURRENCY_CODE,
+            source_id: firefly_account.id.clone(),
+            destination_id,
+        }],
+    })
+}
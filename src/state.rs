@@ -0,0 +1,1495 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use firefly_iii::apis::configuration::Configuration as FireflyConfiguration;
+use firefly_iii::models::{AccountRead, Transaction};
+use reqwest::Method;
+use rusqlite::{params, Connection, OptionalExtension};
+use sbanken::models::TransactionV1;
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::auth::AuthToken;
+use crate::crypto::{self, StateKey};
+use crate::firefly;
+use crate::metrics;
+use crate::payments::Payment;
+use crate::standing_orders::StandingOrder;
+use crate::transfer::TransferRequest;
+use crate::DATE_FORMAT;
+
+pub const STATE_FILE: &str = "sbanken-firefly-bridge.db";
+
+/// State file used by older versions of the bridge.
+pub const LEGACY_STATE_FILE: &str = "firefly_last_sync";
+
+/// Tag in Firefly which holds the sync cursors when the state is kept in Firefly.
+pub const FIREFLY_STATE_TAG: &str = "sbanken-firefly-bridge";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS cursors (
+        account_id TEXT PRIMARY KEY,
+        last_sync TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        started_at TEXT NOT NULL,
+        finished_at TEXT NOT NULL,
+        created INTEGER NOT NULL,
+        duplicates INTEGER NOT NULL,
+        failed INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS fingerprints (
+        fingerprint TEXT PRIMARY KEY,
+        run_id INTEGER NOT NULL REFERENCES runs(id)
+    );
+    CREATE TABLE IF NOT EXISTS journal (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        run_id INTEGER NOT NULL REFERENCES runs(id),
+        kind TEXT NOT NULL,
+        firefly_id TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        source TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS pending_transfers (
+        fingerprint TEXT PRIMARY KEY,
+        account_id TEXT NOT NULL,
+        source TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS reservations (
+        fingerprint TEXT PRIMARY KEY,
+        account_id TEXT NOT NULL,
+        firefly_id TEXT NOT NULL,
+        source TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS scheduled_payments (
+        payment_id TEXT PRIMARY KEY,
+        account_id TEXT NOT NULL,
+        firefly_id TEXT NOT NULL,
+        source TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS standing_orders (
+        standing_order_id TEXT PRIMARY KEY,
+        account_id TEXT NOT NULL,
+        recurrence_id TEXT NOT NULL,
+        source TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS recorded_transfers (
+        firefly_id TEXT PRIMARY KEY,
+        day TEXT NOT NULL,
+        source TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS queued_transactions (
+        id INTEGER PRIMARY KEY,
+        source TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS failed_transactions (
+        id INTEGER PRIMARY KEY,
+        source TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS sbanken_tokens (
+        client TEXT PRIMARY KEY,
+        token TEXT NOT NULL,
+        expires_at TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS known_accounts (
+        account_id TEXT PRIMARY KEY,
+        account_number TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS account_customers (
+        account_id TEXT PRIMARY KEY,
+        customer TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS firefly_accounts (
+        id INTEGER PRIMARY KEY,
+        fetched_at TEXT NOT NULL,
+        source TEXT NOT NULL
+    );
+";
+
+/// Counters for a single run.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct RunStats {
+    pub created: u64,
+    pub duplicates: u64,
+    pub failed: u64,
+}
+
+/// What became of a transaction during a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Created,
+    Duplicate,
+    Failed,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Outcome::Created => "created",
+            Outcome::Duplicate => "duplicate",
+            Outcome::Failed => "failed",
+        })
+    }
+}
+
+impl RunStats {
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Created => self.created += 1,
+            Outcome::Duplicate => self.duplicates += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+}
+
+impl std::fmt::Display for RunStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} created, {} duplicate(s) skipped, {} failed",
+            self.created, self.duplicates, self.failed
+        )
+    }
+}
+
+/// Kind of object created in Firefly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Account,
+    Transaction,
+}
+
+impl ObjectKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ObjectKind::Account => "account",
+            ObjectKind::Transaction => "transaction",
+        }
+    }
+
+    fn parse(kind: &str) -> rusqlite::Result<ObjectKind> {
+        match kind {
+            "account" => Ok(ObjectKind::Account),
+            "transaction" => Ok(ObjectKind::Transaction),
+            _ => Err(rusqlite::Error::InvalidColumnType(
+                0,
+                kind.into(),
+                rusqlite::types::Type::Text,
+            )),
+        }
+    }
+}
+
+/// An object created in Firefly together with the Sbanken data it was created from.
+///
+/// A transfer results in one entry for each of its legs, which share the same Firefly id.
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    pub kind: ObjectKind,
+    pub firefly_id: String,
+    /// Sbanken account id the source data belongs to.
+    pub account_id: String,
+    pub created_at: DateTime<Utc>,
+    /// The Sbanken account or transaction as JSON.
+    pub source: String,
+}
+
+/// A leg of an internal transfer whose counterpart has not been seen yet, which is matched again
+/// on the next sync.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingTransfer {
+    /// Sbanken account id the leg was found on.
+    pub account_id: String,
+    pub fingerprint: String,
+    pub transaction: TransactionV1,
+}
+
+/// A converted transaction which could not be stored because Firefly was unreachable, and is
+/// stored at the start of the next run instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedTransaction {
+    /// Name of the Firefly account the transaction is counted for.
+    pub account: String,
+    /// The Sbanken transactions it was converted from, one for each leg of a transfer.
+    pub legs: Vec<QueuedLeg>,
+    pub transaction: Transaction,
+}
+
+/// A converted transaction which Firefly refused to store, kept so that it can be stored again
+/// with `retry-failed` instead of syncing its account all over.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedTransaction {
+    /// Why Firefly refused it the last time.
+    pub reason: String,
+    pub item: QueuedTransaction,
+}
+
+/// A Sbanken transaction which a queued transaction was converted from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedLeg {
+    /// Sbanken account id the transaction was found on.
+    pub account_id: String,
+    pub fingerprint: String,
+    pub transaction: TransactionV1,
+}
+
+/// A card reservation which is in Firefly as a pending transaction until it is booked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reservation {
+    /// Sbanken account id the reservation was found on.
+    pub account_id: String,
+    pub fingerprint: String,
+    /// Id of the pending transaction in Firefly.
+    pub firefly_id: String,
+    pub transaction: TransactionV1,
+}
+
+/// A scheduled payment which is in Firefly as a future transaction until it is booked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledPayment {
+    /// Sbanken account id the payment is made from.
+    pub account_id: String,
+    /// Id of the transaction in Firefly.
+    pub firefly_id: String,
+    pub payment: Payment,
+}
+
+/// A transfer made with the `transfer` command, which is in Firefly until the synced transfer
+/// replaces it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedTransfer {
+    /// Id of the transaction in Firefly.
+    pub firefly_id: String,
+    /// Day the transfer was made.
+    pub day: NaiveDate,
+    pub request: TransferRequest,
+}
+
+/// A standing order which is mirrored as a recurring transaction in Firefly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirroredStandingOrder {
+    /// Sbanken account id the standing order pays from.
+    pub account_id: String,
+    /// Id of the recurring transaction in Firefly.
+    pub recurrence_id: String,
+    /// The standing order as it was when last mirrored.
+    pub order: StandingOrder,
+}
+
+/// The Firefly accounts of the bridge as an earlier run listed them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedAccounts {
+    pub fetched_at: DateTime<Utc>,
+    pub accounts: Vec<AccountRead>,
+}
+
+/// Summary of a finished run.
+#[derive(Debug, Serialize)]
+pub struct Run {
+    pub id: i64,
+    pub started_at: String,
+    pub finished_at: String,
+    pub stats: RunStats,
+}
+
+/// Progress of the sync which is kept between runs in an SQLite database.
+///
+/// The state is read into memory when loaded and only written back on `checkpoint` and `save`, so
+/// that dry runs can use it without touching the database.
+#[derive(Debug)]
+pub struct State {
+    /// Day up to which transactions have been synced, keyed by Sbanken account id.
+    pub accounts: HashMap<String, NaiveDate>,
+    /// Day from an old state file which only tracked a single date for all accounts, used for
+    /// accounts without their own entry.
+    pub legacy: Option<NaiveDate>,
+    pub stats: RunStats,
+    /// Stats of the current run for each Firefly account.
+    pub account_stats: BTreeMap<String, RunStats>,
+    /// What failed during the current run, one line per transaction or account.
+    pub failures: Vec<String>,
+    /// Internal transfers whose two legs were paired during the current run.
+    pub transfers_matched: u64,
+    /// Transfer legs which are still waiting for their counterpart.
+    pub pending: Vec<PendingTransfer>,
+    /// Reservations which are imported as pending transactions.
+    pub reservations: Vec<Reservation>,
+    /// Scheduled payments which are imported as future transactions.
+    pub scheduled: Vec<ScheduledPayment>,
+    /// Standing orders which are mirrored as recurring transactions.
+    pub standing_orders: Vec<MirroredStandingOrder>,
+    /// Transfers made with the `transfer` command which are not synced yet.
+    pub recorded_transfers: Vec<RecordedTransfer>,
+    /// Transactions waiting for Firefly to be reachable again.
+    pub queued: Vec<QueuedTransaction>,
+    /// Transactions which Firefly refused to store.
+    pub failed: Vec<FailedTransaction>,
+    /// Account number of every Sbanken account seen so far, keyed by Sbanken account id, to
+    /// notice when one is closed.
+    pub account_numbers: HashMap<String, String>,
+    /// Customer every Sbanken account belongs to when syncing several, as its `customer_key`,
+    /// keyed by Sbanken account id.
+    pub account_customers: HashMap<String, String>,
+    /// Firefly accounts as last listed, so that they need not be listed on every run. Only kept
+    /// in a state database on disk.
+    pub firefly_accounts: Option<CachedAccounts>,
+    started_at: DateTime<Utc>,
+    fingerprints: HashSet<String>,
+    imported: Vec<String>,
+    journal: Vec<JournalEntry>,
+    /// Id of the current run once it has been written to the database.
+    run_id: Option<i64>,
+    /// Never write the cursors back, see `freeze_cursors`.
+    frozen_cursors: bool,
+    /// Key used to encrypt the source data of created objects.
+    pub key: Option<StateKey>,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State {
+            accounts: HashMap::new(),
+            legacy: None,
+            stats: RunStats::default(),
+            account_stats: BTreeMap::new(),
+            failures: Vec::new(),
+            transfers_matched: 0,
+            pending: Vec::new(),
+            reservations: Vec::new(),
+            scheduled: Vec::new(),
+            standing_orders: Vec::new(),
+            recorded_transfers: Vec::new(),
+            queued: Vec::new(),
+            failed: Vec::new(),
+            account_numbers: HashMap::new(),
+            account_customers: HashMap::new(),
+            firefly_accounts: None,
+            started_at: Utc::now(),
+            fingerprints: HashSet::new(),
+            imported: Vec::new(),
+            journal: Vec::new(),
+            run_id: None,
+            frozen_cursors: false,
+            key: None,
+        }
+    }
+}
+
+/// Sync cursors as they are serialized outside of the database.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cursors {
+    #[serde(default)]
+    accounts: HashMap<String, NaiveDate>,
+    legacy: Option<NaiveDate>,
+    #[serde(default)]
+    pending: Vec<PendingTransfer>,
+    #[serde(default)]
+    reservations: Vec<Reservation>,
+    #[serde(default)]
+    scheduled: Vec<ScheduledPayment>,
+    #[serde(default)]
+    standing_orders: Vec<MirroredStandingOrder>,
+    #[serde(default)]
+    recorded_transfers: Vec<RecordedTransfer>,
+    #[serde(default)]
+    queued: Vec<QueuedTransaction>,
+    #[serde(default)]
+    failed: Vec<FailedTransaction>,
+    #[serde(default)]
+    account_numbers: HashMap<String, String>,
+    #[serde(default)]
+    account_customers: HashMap<String, String>,
+}
+
+/// Where the state is kept between runs.
+pub enum StateStore {
+    /// An SQLite database on disk, with sensitive data encrypted if a key is given.
+    File {
+        path: PathBuf,
+        key: Option<StateKey>,
+    },
+    /// The description of a tag in Firefly, which makes the bridge stateless on disk.
+    ///
+    /// Only the sync cursors, pending transfer legs, reservations, scheduled payments, standing
+    /// orders and recorded transfers are kept, not the imported transactions, created objects or
+    /// run statistics.
+    Firefly {
+        config: FireflyConfiguration,
+        tag: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct TagResponse {
+    data: TagRead,
+}
+
+#[derive(Deserialize)]
+struct TagRead {
+    attributes: TagAttributes,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TagAttributes {
+    tag: String,
+    description: Option<String>,
+}
+
+impl StateStore {
+    pub async fn load(&self) -> Result<State> {
+        match self {
+            StateStore::File { path, key } => Ok(State {
+                pending: State::pending_transfers(path, key.as_ref())?,
+                reservations: State::reservations(path, key.as_ref())?,
+                scheduled: State::scheduled_payments(path, key.as_ref())?,
+                standing_orders: State::standing_orders(path, key.as_ref())?,
+                recorded_transfers: State::recorded_transfers(path, key.as_ref())?,
+                queued: State::queued_transactions(path, key.as_ref())?,
+                failed: State::failed_transactions(path, key.as_ref())?,
+                account_numbers: State::known_accounts(path, key.as_ref())?,
+                account_customers: State::account_customers(path)?,
+                firefly_accounts: State::firefly_accounts(path, key.as_ref())?,
+                key: key.clone(),
+                ..State::load(path)?
+            }),
+            StateStore::Firefly { config, tag } => {
+                let path = firefly::tag_path(tag);
+                let description = firefly::get::<TagResponse>(config, &path)
+                    .await?
+                    .and_then(|response| response.data.attributes.description);
+                let cursors = match description {
+                    Some(description) => serde_json::from_str(&description).with_context(|| {
+                        format!("invalid sync state in description of firefly tag '{}'", tag)
+                    })?,
+                    None => Cursors::default(),
+                };
+
+                Ok(State {
+                    accounts: cursors.accounts,
+                    legacy: cursors.legacy,
+                    pending: cursors.pending,
+                    reservations: cursors.reservations,
+                    scheduled: cursors.scheduled,
+                    standing_orders: cursors.standing_orders,
+                    recorded_transfers: cursors.recorded_transfers,
+                    queued: cursors.queued,
+                    failed: cursors.failed,
+                    account_numbers: cursors.account_numbers,
+                    account_customers: cursors.account_customers,
+                    ..State::default()
+                })
+            }
+        }
+    }
+
+    /// Persist the progress made so far, so that an interrupted run does not start over.
+    pub async fn checkpoint(&self, state: &mut State) -> Result<()> {
+        match self {
+            StateStore::File { path, .. } => state.checkpoint(path),
+            StateStore::Firefly { config, tag } => {
+                let path = firefly::tag_path(tag);
+                let existing = firefly::get::<TagResponse>(config, &path).await?;
+                let mut cursors = Cursors {
+                    accounts: state.accounts.clone(),
+                    legacy: state.legacy,
+                    pending: state.pending.clone(),
+                    reservations: state.reservations.clone(),
+                    scheduled: state.scheduled.clone(),
+                    standing_orders: state.standing_orders.clone(),
+                    recorded_transfers: state.recorded_transfers.clone(),
+                    queued: state.queued.clone(),
+                    failed: state.failed.clone(),
+                    account_numbers: state.account_numbers.clone(),
+                    account_customers: state.account_customers.clone(),
+                };
+                if state.frozen_cursors {
+                    // Keep the cursors of the regular sync, but everything else of this run
+                    let stored = match existing
+                        .as_ref()
+                        .and_then(|response| response.data.attributes.description.as_deref())
+                    {
+                        Some(description) => serde_json::from_str::<Cursors>(description)
+                            .with_context(|| {
+                                format!(
+                                    "invalid sync state in description of firefly tag '{}'",
+                                    tag
+                                )
+                            })?,
+                        None => Cursors::default(),
+                    };
+                    cursors.accounts = stored.accounts;
+                    cursors.legacy = stored.legacy;
+                }
+                let body = TagAttributes {
+                    tag: tag.clone(),
+                    description: Some(serde_json::to_string(&cursors)?),
+                };
+
+                let _: TagResponse = if existing.is_some() {
+                    firefly::send(config, Method::PUT, &path, &body).await
+                } else {
+                    firefly::send(config, Method::POST, "/tags", &body).await
+                }
+                .with_context(|| format!("unable to store sync state in firefly tag '{}'", tag))?;
+
+                state.imported.clear();
+                state.journal.clear();
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Sync the accounts in `starts` from the given days, leaving the rest of the state as it is.
+    pub async fn start_accounts(&self, starts: HashMap<String, NaiveDate>) -> Result<()> {
+        match self {
+            StateStore::File { path, .. } => {
+                let state = State {
+                    accounts: starts,
+                    ..State::default()
+                };
+                let mut conn = open(path)?;
+                let tx = conn.transaction()?;
+                state.write_cursors(&tx)?;
+                tx.commit().context("unable to write state database")
+            }
+            StateStore::Firefly { .. } => {
+                let mut state = self.load().await?;
+                state.accounts.extend(starts);
+                self.checkpoint(&mut state).await
+            }
+        }
+    }
+
+    /// Forget all progress, so that the next sync imports everything again.
+    pub async fn reset(&self) -> Result<()> {
+        match self {
+            StateStore::File { path, .. } => State::reset(path),
+            StateStore::Firefly { .. } => self.save(&mut State::default()).await,
+        }
+    }
+
+    /// Persist the state and finish the current run.
+    pub async fn save(&self, state: &mut State) -> Result<()> {
+        self.checkpoint(state).await?;
+        state.finish_run();
+        Ok(())
+    }
+
+    /// Sbanken token which an earlier run got for the client `client_id`, unless it is about to
+    /// run out.
+    ///
+    /// Tokens are only cached in a state database on disk, never in Firefly.
+    pub fn cached_token(&self, client_id: &Secret<String>) -> Result<Option<AuthToken>> {
+        let (path, key) = match self {
+            StateStore::File { path, key } => (path, key),
+            StateStore::Firefly { .. } => return Ok(None),
+        };
+        let cached = open(path)?
+            .query_row(
+                "SELECT token, expires_at FROM sbanken_tokens WHERE client = ?1",
+                params![token_client(client_id)],
+                |row| Ok((row.get::<_, String>(0)?, parse_time(row.get(1)?)?)),
+            )
+            .optional()
+            .context("unable to read cached sbanken token")?;
+
+        let (token, expires_at) = match cached {
+            Some(cached) => cached,
+            None => return Ok(None),
+        };
+        let token = AuthToken {
+            access_token: Secret::new(crypto::decrypt(key.as_ref(), &token)?),
+            expires_at: Some(expires_at),
+        };
+        Ok(Some(token).filter(|token| !token.is_expiring()))
+    }
+
+    /// Keep `token` for the client `client_id` for later runs, if it is known when it runs out.
+    pub fn cache_token(&self, client_id: &Secret<String>, token: &AuthToken) -> Result<()> {
+        let (path, key, expires_at) = match (self, token.expires_at) {
+            (StateStore::File { path, key }, Some(expires_at)) => (path, key, expires_at),
+            _ => return Ok(()),
+        };
+        open(path)?
+            .execute(
+                "INSERT OR REPLACE INTO sbanken_tokens (client, token, expires_at) \
+                 VALUES (?1, ?2, ?3)",
+                params![
+                    token_client(client_id),
+                    crypto::encrypt(key.as_ref(), token.access_token.expose_secret())?,
+                    expires_at.to_rfc3339(),
+                ],
+            )
+            .context("unable to cache sbanken token")?;
+        Ok(())
+    }
+}
+
+/// Key of the cached token of a Sbanken client, which does not reveal the client id.
+fn token_client(client_id: &Secret<String>) -> String {
+    format!("{:x}", Sha256::digest(client_id.expose_secret().as_bytes()))
+}
+
+/// Key of a customer in the state, which does not reveal who the customer is.
+pub fn customer_key(customer: &str) -> String {
+    format!("{:x}", Sha256::digest(customer.as_bytes()))
+}
+
+fn open(path: &Path) -> Result<Connection> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("unable to create state directory '{}'", dir.display()))?;
+    }
+
+    let conn = Connection::open(path)
+        .with_context(|| format!("unable to open state database '{}'", path.display()))?;
+    conn.execute_batch(SCHEMA)
+        .context("unable to create state database schema")?;
+    Ok(conn)
+}
+
+fn parse_time(time: String) -> rusqlite::Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(&time)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })
+}
+
+fn parse_day(day: String) -> rusqlite::Result<NaiveDate> {
+    NaiveDate::parse_from_str(&day, DATE_FORMAT).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+impl State {
+    /// Read state from the database at `path`, which is created if it does not exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<State> {
+        let conn = open(path.as_ref())?;
+
+        let accounts = conn
+            .prepare("SELECT account_id, last_sync FROM cursors")?
+            .query_map(params![], |row| Ok((row.get(0)?, parse_day(row.get(1)?)?)))?
+            .collect::<rusqlite::Result<_>>()
+            .context("unable to read sync cursors")?;
+
+        let legacy = conn
+            .query_row(
+                "SELECT value FROM meta WHERE key = 'legacy_last_sync'",
+                params![],
+                |row| parse_day(row.get(0)?),
+            )
+            .optional()
+            .context("unable to read legacy sync cursor")?;
+
+        let fingerprints = conn
+            .prepare("SELECT fingerprint FROM fingerprints")?
+            .query_map(params![], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()
+            .context("unable to read imported transactions")?;
+
+        Ok(State {
+            accounts,
+            legacy,
+            fingerprints,
+            ..State::default()
+        })
+    }
+
+    /// Import progress from a state file written by an older version of the bridge, which either
+    /// contains a single date or the sync cursors as JSON.
+    ///
+    /// Cursors which are already present are kept.
+    pub fn import_legacy(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("unable to read state file '{}'", path.display()))?;
+
+        let legacy_state = match NaiveDate::parse_from_str(contents.trim(), DATE_FORMAT) {
+            Ok(day) => Cursors {
+                legacy: Some(day),
+                ..Cursors::default()
+            },
+            Err(_) => serde_json::from_str(&contents)
+                .with_context(|| format!("invalid state file '{}'", path.display()))?,
+        };
+
+        for (account_id, day) in legacy_state.accounts {
+            self.accounts.entry(account_id).or_insert(day);
+        }
+        self.legacy = self.legacy.or(legacy_state.legacy);
+
+        Ok(())
+    }
+
+    /// Move the progress from a state file of an older version of the bridge at `legacy_path`
+    /// into the database at `path`, renaming the old file afterwards.
+    ///
+    /// Returns whether there was anything to migrate.
+    pub fn migrate_legacy(path: impl AsRef<Path>, legacy_path: impl AsRef<Path>) -> Result<bool> {
+        let legacy_path = legacy_path.as_ref();
+        if !legacy_path.exists() {
+            return Ok(false);
+        }
+
+        let mut state = State::load(&path)?;
+        state.import_legacy(legacy_path)?;
+
+        let mut conn = open(path.as_ref())?;
+        let tx = conn.transaction()?;
+        state.write_cursors(&tx)?;
+        tx.commit().context("unable to write state database")?;
+
+        let mut migrated = legacy_path.as_os_str().to_owned();
+        migrated.push(".migrated");
+        std::fs::rename(legacy_path, &migrated).with_context(|| {
+            format!(
+                "unable to rename old state file '{}'",
+                legacy_path.display()
+            )
+        })?;
+
+        Ok(true)
+    }
+
+    /// Move a state database from `old_path` to `path`, unless there already is one at `path`.
+    ///
+    /// Returns whether the database was moved.
+    pub fn relocate(path: impl AsRef<Path>, old_path: impl AsRef<Path>) -> Result<bool> {
+        let (path, old_path) = (path.as_ref(), old_path.as_ref());
+        if path == old_path || path.exists() || !old_path.exists() {
+            return Ok(false);
+        }
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("unable to create state directory '{}'", dir.display()))?;
+        }
+
+        // Renaming fails across file systems, in which case the file is copied instead
+        if std::fs::rename(old_path, path).is_err() {
+            std::fs::copy(old_path, path).with_context(|| {
+                format!(
+                    "unable to move state file '{}' to '{}'",
+                    old_path.display(),
+                    path.display()
+                )
+            })?;
+            std::fs::remove_file(old_path).with_context(|| {
+                format!("unable to remove old state file '{}'", old_path.display())
+            })?;
+        }
+
+        Ok(true)
+    }
+
+    /// Let every account start from scratch and never write the cursors back, which is used to
+    /// import explicit date ranges without affecting the regular sync.
+    pub fn freeze_cursors(&mut self) {
+        self.accounts.clear();
+        self.legacy = None;
+        self.frozen_cursors = true;
+    }
+
+    fn write_cursors(&self, tx: &rusqlite::Transaction) -> Result<()> {
+        if self.frozen_cursors {
+            return Ok(());
+        }
+
+        for (account_id, day) in &self.accounts {
+            tx.execute(
+                "INSERT OR REPLACE INTO cursors (account_id, last_sync) VALUES (?1, ?2)",
+                params![account_id, day.format(DATE_FORMAT).to_string()],
+            )?;
+        }
+
+        if let Some(day) = self.legacy {
+            tx.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('legacy_last_sync', ?1)",
+                params![day.format(DATE_FORMAT).to_string()],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the cursors, imported transactions, created objects and statistics of this run to the
+    /// database, without finishing the run.
+    pub fn checkpoint(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let mut conn = open(path.as_ref())?;
+        let tx = conn.transaction()?;
+
+        self.write_cursors(&tx)?;
+
+        let run_id = match self.run_id {
+            Some(run_id) => {
+                tx.execute(
+                    "UPDATE runs SET finished_at = ?2, created = ?3, duplicates = ?4, failed = ?5
+                     WHERE id = ?1",
+                    params![
+                        run_id,
+                        Utc::now().to_rfc3339(),
+                        self.stats.created as i64,
+                        self.stats.duplicates as i64,
+                        self.stats.failed as i64,
+                    ],
+                )?;
+                run_id
+            }
+            None => {
+                tx.execute(
+                    "INSERT INTO runs (started_at, finished_at, created, duplicates, failed)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        self.started_at.to_rfc3339(),
+                        Utc::now().to_rfc3339(),
+                        self.stats.created as i64,
+                        self.stats.duplicates as i64,
+                        self.stats.failed as i64,
+                    ],
+                )?;
+                tx.last_insert_rowid()
+            }
+        };
+
+        for fingerprint in &self.imported {
+            tx.execute(
+                "INSERT OR IGNORE INTO fingerprints (fingerprint, run_id) VALUES (?1, ?2)",
+                params![fingerprint, run_id],
+            )?;
+        }
+
+        tx.execute("DELETE FROM pending_transfers", params![])?;
+        for leg in &self.pending {
+            let source = serde_json::to_string(&leg.transaction)
+                .context("unable to serialize pending transfer")?;
+            tx.execute(
+                "INSERT OR REPLACE INTO pending_transfers (fingerprint, account_id, source)
+                 VALUES (?1, ?2, ?3)",
+                params![
+                    leg.fingerprint,
+                    leg.account_id,
+                    crypto::encrypt(self.key.as_ref(), &source)?,
+                ],
+            )?;
+        }
+
+        tx.execute("DELETE FROM reservations", params![])?;
+        for reservation in &self.reservations {
+            let source = serde_json::to_string(&reservation.transaction)
+                .context("unable to serialize reservation")?;
+            tx.execute(
+                "INSERT OR REPLACE INTO reservations (fingerprint, account_id, firefly_id, source)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    reservation.fingerprint,
+                    reservation.account_id,
+                    reservation.firefly_id,
+                    crypto::encrypt(self.key.as_ref(), &source)?,
+                ],
+            )?;
+        }
+
+        tx.execute("DELETE FROM scheduled_payments", params![])?;
+        for scheduled in &self.scheduled {
+            let source = serde_json::to_string(&scheduled.payment)
+                .context("unable to serialize scheduled payment")?;
+            tx.execute(
+                "INSERT OR REPLACE INTO scheduled_payments
+                 (payment_id, account_id, firefly_id, source) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    scheduled.payment.payment_id,
+                    scheduled.account_id,
+                    scheduled.firefly_id,
+                    crypto::encrypt(self.key.as_ref(), &source)?,
+                ],
+            )?;
+        }
+
+        tx.execute("DELETE FROM standing_orders", params![])?;
+        for mirrored in &self.standing_orders {
+            let source = serde_json::to_string(&mirrored.order)
+                .context("unable to serialize standing order")?;
+            tx.execute(
+                "INSERT OR REPLACE INTO standing_orders
+                 (standing_order_id, account_id, recurrence_id, source) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    mirrored.order.standing_order_id,
+                    mirrored.account_id,
+                    mirrored.recurrence_id,
+                    crypto::encrypt(self.key.as_ref(), &source)?,
+                ],
+            )?;
+        }
+
+        tx.execute("DELETE FROM recorded_transfers", params![])?;
+        for recorded in &self.recorded_transfers {
+            let source = serde_json::to_string(&recorded.request)
+                .context("unable to serialize recorded transfer")?;
+            tx.execute(
+                "INSERT OR REPLACE INTO recorded_transfers (firefly_id, day, source)
+                 VALUES (?1, ?2, ?3)",
+                params![
+                    recorded.firefly_id,
+                    recorded.day.format(DATE_FORMAT).to_string(),
+                    crypto::encrypt(self.key.as_ref(), &source)?,
+                ],
+            )?;
+        }
+
+        tx.execute("DELETE FROM queued_transactions", params![])?;
+        for queued in &self.queued {
+            let source =
+                serde_json::to_string(queued).context("unable to serialize queued transaction")?;
+            tx.execute(
+                "INSERT INTO queued_transactions (source) VALUES (?1)",
+                params![crypto::encrypt(self.key.as_ref(), &source)?],
+            )?;
+        }
+
+        tx.execute("DELETE FROM failed_transactions", params![])?;
+        for failed in &self.failed {
+            let source =
+                serde_json::to_string(failed).context("unable to serialize failed transaction")?;
+            tx.execute(
+                "INSERT INTO failed_transactions (source) VALUES (?1)",
+                params![crypto::encrypt(self.key.as_ref(), &source)?],
+            )?;
+        }
+
+        for (account_id, account_number) in &self.account_numbers {
+            tx.execute(
+                "INSERT OR REPLACE INTO known_accounts (account_id, account_number) VALUES (?1, ?2)",
+                params![
+                    account_id,
+                    crypto::encrypt(self.key.as_ref(), account_number)?,
+                ],
+            )?;
+        }
+
+        tx.execute("DELETE FROM account_customers", params![])?;
+        for (account_id, customer) in &self.account_customers {
+            tx.execute(
+                "INSERT INTO account_customers (account_id, customer) VALUES (?1, ?2)",
+                params![account_id, customer],
+            )?;
+        }
+
+        tx.execute("DELETE FROM firefly_accounts", params![])?;
+        if let Some(cached) = &self.firefly_accounts {
+            let source = serde_json::to_string(&cached.accounts)
+                .context("unable to serialize firefly accounts")?;
+            tx.execute(
+                "INSERT INTO firefly_accounts (id, fetched_at, source) VALUES (0, ?1, ?2)",
+                params![
+                    cached.fetched_at.to_rfc3339(),
+                    crypto::encrypt(self.key.as_ref(), &source)?,
+                ],
+            )?;
+        }
+
+        for entry in &self.journal {
+            tx.execute(
+                "INSERT INTO journal (run_id, kind, firefly_id, account_id, created_at, source)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    run_id,
+                    entry.kind.as_str(),
+                    entry.firefly_id,
+                    entry.account_id,
+                    entry.created_at.to_rfc3339(),
+                    crypto::encrypt(self.key.as_ref(), &entry.source)?,
+                ],
+            )?;
+        }
+
+        tx.commit().context("unable to write state database")?;
+
+        self.run_id = Some(run_id);
+        self.imported.clear();
+        self.journal.clear();
+
+        Ok(())
+    }
+
+    /// Write everything to the database and finish the current run.
+    pub fn save(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.checkpoint(path)?;
+        self.finish_run();
+        Ok(())
+    }
+
+    fn finish_run(&mut self) {
+        self.run_id = None;
+        self.stats = RunStats::default();
+        self.started_at = Utc::now();
+    }
+
+    /// Remove the cursors and imported transactions from the database, keeping the run history.
+    pub fn reset(path: impl AsRef<Path>) -> Result<()> {
+        let mut conn = open(path.as_ref())?;
+        let tx = conn.transaction()?;
+        tx.execute_batch(
+            "DELETE FROM cursors;
+             DELETE FROM meta WHERE key = 'legacy_last_sync';
+             DELETE FROM fingerprints;
+             DELETE FROM journal;
+             DELETE FROM pending_transfers;
+             DELETE FROM reservations;
+             DELETE FROM scheduled_payments;
+             DELETE FROM standing_orders;
+             DELETE FROM recorded_transfers;
+             DELETE FROM queued_transactions;
+             DELETE FROM failed_transactions;
+             DELETE FROM known_accounts;
+             DELETE FROM account_customers;
+             DELETE FROM firefly_accounts;",
+        )?;
+        tx.commit().context("unable to write state database")?;
+        Ok(())
+    }
+
+    /// Transfer legs waiting for their counterpart, decrypting them with `key`.
+    pub fn pending_transfers(
+        path: impl AsRef<Path>,
+        key: Option<&StateKey>,
+    ) -> Result<Vec<PendingTransfer>> {
+        let conn = open(path.as_ref())?;
+        let rows = conn
+            .prepare("SELECT fingerprint, account_id, source FROM pending_transfers")?
+            .query_map(params![], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, String, String)>>>()
+            .context("unable to read pending transfers")?;
+
+        rows.into_iter()
+            .map(|(fingerprint, account_id, source)| {
+                let source = crypto::decrypt(key, &source)?;
+                Ok(PendingTransfer {
+                    account_id,
+                    fingerprint,
+                    transaction: serde_json::from_str(&source)
+                        .context("invalid pending transfer in state")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Reservations which are in Firefly as pending transactions, decrypting them with `key`.
+    pub fn reservations(
+        path: impl AsRef<Path>,
+        key: Option<&StateKey>,
+    ) -> Result<Vec<Reservation>> {
+        let conn = open(path.as_ref())?;
+        let rows = conn
+            .prepare("SELECT fingerprint, account_id, firefly_id, source FROM reservations")?
+            .query_map(params![], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, String, String, String)>>>()
+            .context("unable to read reservations")?;
+
+        rows.into_iter()
+            .map(|(fingerprint, account_id, firefly_id, source)| {
+                let source = crypto::decrypt(key, &source)?;
+                Ok(Reservation {
+                    account_id,
+                    fingerprint,
+                    firefly_id,
+                    transaction: serde_json::from_str(&source)
+                        .context("invalid reservation in state")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Scheduled payments which are in Firefly as future transactions, decrypting them with `key`.
+    pub fn scheduled_payments(
+        path: impl AsRef<Path>,
+        key: Option<&StateKey>,
+    ) -> Result<Vec<ScheduledPayment>> {
+        let conn = open(path.as_ref())?;
+        let rows = conn
+            .prepare("SELECT account_id, firefly_id, source FROM scheduled_payments")?
+            .query_map(params![], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, String, String)>>>()
+            .context("unable to read scheduled payments")?;
+
+        rows.into_iter()
+            .map(|(account_id, firefly_id, source)| {
+                let source = crypto::decrypt(key, &source)?;
+                Ok(ScheduledPayment {
+                    account_id,
+                    firefly_id,
+                    payment: serde_json::from_str(&source)
+                        .context("invalid scheduled payment in state")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Standing orders which are mirrored as recurring transactions, decrypting them with `key`.
+    pub fn standing_orders(
+        path: impl AsRef<Path>,
+        key: Option<&StateKey>,
+    ) -> Result<Vec<MirroredStandingOrder>> {
+        let conn = open(path.as_ref())?;
+        let rows = conn
+            .prepare("SELECT account_id, recurrence_id, source FROM standing_orders")?
+            .query_map(params![], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, String, String)>>>()
+            .context("unable to read standing orders")?;
+
+        rows.into_iter()
+            .map(|(account_id, recurrence_id, source)| {
+                let source = crypto::decrypt(key, &source)?;
+                Ok(MirroredStandingOrder {
+                    account_id,
+                    recurrence_id,
+                    order: serde_json::from_str(&source)
+                        .context("invalid standing order in state")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Transfers made with the `transfer` command which are not synced yet, decrypting them with
+    /// `key`.
+    pub fn recorded_transfers(
+        path: impl AsRef<Path>,
+        key: Option<&StateKey>,
+    ) -> Result<Vec<RecordedTransfer>> {
+        let conn = open(path.as_ref())?;
+        let rows = conn
+            .prepare("SELECT firefly_id, day, source FROM recorded_transfers")?
+            .query_map(params![], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, String, String)>>>()
+            .context("unable to read recorded transfers")?;
+
+        rows.into_iter()
+            .map(|(firefly_id, day, source)| {
+                let source = crypto::decrypt(key, &source)?;
+                Ok(RecordedTransfer {
+                    firefly_id,
+                    day: NaiveDate::parse_from_str(&day, DATE_FORMAT)
+                        .context("invalid day of recorded transfer in state")?,
+                    request: serde_json::from_str(&source)
+                        .context("invalid recorded transfer in state")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Transactions waiting for Firefly to be reachable again in the order they were queued,
+    /// decrypting them with `key`.
+    pub fn queued_transactions(
+        path: impl AsRef<Path>,
+        key: Option<&StateKey>,
+    ) -> Result<Vec<QueuedTransaction>> {
+        let conn = open(path.as_ref())?;
+        let rows = conn
+            .prepare("SELECT source FROM queued_transactions ORDER BY id")?
+            .query_map(params![], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .context("unable to read queued transactions")?;
+
+        rows.into_iter()
+            .map(|source| {
+                let source = crypto::decrypt(key, &source)?;
+                serde_json::from_str(&source).context("invalid queued transaction in state")
+            })
+            .collect()
+    }
+
+    /// Transactions which Firefly refused to store in the order they failed, decrypting them with
+    /// `key`.
+    pub fn failed_transactions(
+        path: impl AsRef<Path>,
+        key: Option<&StateKey>,
+    ) -> Result<Vec<FailedTransaction>> {
+        let conn = open(path.as_ref())?;
+        let rows = conn
+            .prepare("SELECT source FROM failed_transactions ORDER BY id")?
+            .query_map(params![], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .context("unable to read failed transactions")?;
+
+        rows.into_iter()
+            .map(|source| {
+                let source = crypto::decrypt(key, &source)?;
+                serde_json::from_str(&source).context("invalid failed transaction in state")
+            })
+            .collect()
+    }
+
+    /// Account numbers of the Sbanken accounts seen so far, decrypting them with `key`.
+    pub fn known_accounts(
+        path: impl AsRef<Path>,
+        key: Option<&StateKey>,
+    ) -> Result<HashMap<String, String>> {
+        let conn = open(path.as_ref())?;
+        let rows = conn
+            .prepare("SELECT account_id, account_number FROM known_accounts")?
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()
+            .context("unable to read known accounts")?;
+
+        rows.into_iter()
+            .map(|(account_id, account_number)| {
+                Ok((account_id, crypto::decrypt(key, &account_number)?))
+            })
+            .collect()
+    }
+
+    /// Customer of every Sbanken account which was synced as part of several customers.
+    pub fn account_customers(path: impl AsRef<Path>) -> Result<HashMap<String, String>> {
+        let conn = open(path.as_ref())?;
+        let rows = conn
+            .prepare("SELECT account_id, customer FROM account_customers")?
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<String, String>>>()
+            .context("unable to read account customers")?;
+        Ok(rows)
+    }
+
+    /// Firefly accounts as an earlier run listed them, decrypting them with `key`.
+    pub fn firefly_accounts(
+        path: impl AsRef<Path>,
+        key: Option<&StateKey>,
+    ) -> Result<Option<CachedAccounts>> {
+        let conn = open(path.as_ref())?;
+        let row = conn
+            .query_row(
+                "SELECT fetched_at, source FROM firefly_accounts WHERE id = 0",
+                params![],
+                |row| Ok((parse_time(row.get(0)?)?, row.get::<_, String>(1)?)),
+            )
+            .optional()
+            .context("unable to read firefly accounts")?;
+
+        row.map(|(fetched_at, source)| {
+            let source = crypto::decrypt(key, &source)?;
+            Ok(CachedAccounts {
+                fetched_at,
+                accounts: serde_json::from_str(&source)
+                    .context("invalid firefly accounts in state")?,
+            })
+        })
+        .transpose()
+    }
+
+    /// The most recent runs, newest first.
+    pub fn recent_runs(path: impl AsRef<Path>, limit: u32) -> Result<Vec<Run>> {
+        let conn = open(path.as_ref())?;
+        let runs = conn
+            .prepare(
+                "SELECT id, started_at, finished_at, created, duplicates, failed
+                 FROM runs ORDER BY id DESC LIMIT ?1",
+            )?
+            .query_map(params![limit], |row| {
+                Ok(Run {
+                    id: row.get(0)?,
+                    started_at: row.get(1)?,
+                    finished_at: row.get(2)?,
+                    stats: RunStats {
+                        created: row.get::<_, i64>(3)? as u64,
+                        duplicates: row.get::<_, i64>(4)? as u64,
+                        failed: row.get::<_, i64>(5)? as u64,
+                    },
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .context("unable to read runs")?;
+        Ok(runs)
+    }
+
+    /// Objects created in Firefly by the run `run_id`, decrypting the source data with `key`.
+    pub fn journal(
+        path: impl AsRef<Path>,
+        run_id: i64,
+        key: Option<&StateKey>,
+    ) -> Result<Vec<JournalEntry>> {
+        let conn = open(path.as_ref())?;
+        let entries = conn
+            .prepare(
+                "SELECT kind, firefly_id, account_id, created_at, source
+                 FROM journal WHERE run_id = ?1 ORDER BY id",
+            )?
+            .query_map(params![run_id], |row| {
+                Ok(JournalEntry {
+                    kind: ObjectKind::parse(&row.get::<_, String>(0)?)?,
+                    firefly_id: row.get(1)?,
+                    account_id: row.get(2)?,
+                    created_at: parse_time(row.get(3)?)?,
+                    source: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("unable to read journal")?;
+
+        entries
+            .into_iter()
+            .map(|entry| {
+                Ok(JournalEntry {
+                    source: crypto::decrypt(key, &entry.source)?,
+                    ..entry
+                })
+            })
+            .collect()
+    }
+
+    /// Transactions created in Firefly by any run, decrypting the source data with `key`.
+    pub fn created_transactions(
+        path: impl AsRef<Path>,
+        key: Option<&StateKey>,
+    ) -> Result<Vec<JournalEntry>> {
+        let conn = open(path.as_ref())?;
+        let entries = conn
+            .prepare(
+                "SELECT kind, firefly_id, account_id, created_at, source
+                 FROM journal WHERE kind = ?1 ORDER BY id",
+            )?
+            .query_map(params![ObjectKind::Transaction.as_str()], |row| {
+                Ok(JournalEntry {
+                    kind: ObjectKind::parse(&row.get::<_, String>(0)?)?,
+                    firefly_id: row.get(1)?,
+                    account_id: row.get(2)?,
+                    created_at: parse_time(row.get(3)?)?,
+                    source: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("unable to read journal")?;
+
+        entries
+            .into_iter()
+            .map(|entry| {
+                Ok(JournalEntry {
+                    source: crypto::decrypt(key, &entry.source)?,
+                    ..entry
+                })
+            })
+            .collect()
+    }
+
+    /// Runs after `run_id` which created objects from the Sbanken account `account_id`.
+    pub fn later_runs(path: impl AsRef<Path>, run_id: i64, account_id: &str) -> Result<Vec<i64>> {
+        let conn = open(path.as_ref())?;
+        let runs = conn
+            .prepare(
+                "SELECT DISTINCT run_id FROM journal
+                 WHERE run_id > ?1 AND account_id = ?2 ORDER BY run_id",
+            )?
+            .query_map(params![run_id, account_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()
+            .context("unable to read journal")?;
+        Ok(runs)
+    }
+
+    /// Forget the transactions imported by run `run_id` after they have been deleted from Firefly,
+    /// writing the (rewound) cursors of this state.
+    ///
+    /// The accounts in `removed_accounts` have been deleted as well, so their cursors are removed.
+    pub fn forget_run(
+        &self,
+        path: impl AsRef<Path>,
+        run_id: i64,
+        removed_accounts: &[String],
+    ) -> Result<()> {
+        let mut conn = open(path.as_ref())?;
+        let tx = conn.transaction()?;
+
+        self.write_cursors(&tx)?;
+        tx.execute(
+            "DELETE FROM journal WHERE run_id = ?1 AND kind = ?2",
+            params![run_id, ObjectKind::Transaction.as_str()],
+        )?;
+        for account_id in removed_accounts {
+            tx.execute(
+                "DELETE FROM cursors WHERE account_id = ?1",
+                params![account_id],
+            )?;
+            tx.execute(
+                "DELETE FROM journal WHERE run_id = ?1 AND kind = ?2 AND account_id = ?3",
+                params![run_id, ObjectKind::Account.as_str(), account_id],
+            )?;
+        }
+        tx.execute(
+            "DELETE FROM fingerprints WHERE run_id = ?1",
+            params![run_id],
+        )?;
+
+        tx.commit().context("unable to write state database")?;
+
+        Ok(())
+    }
+
+    /// Day up to which transactions for the given account have been synced.
+    pub fn last_sync(&self, account_id: &str) -> Option<NaiveDate> {
+        self.accounts.get(account_id).copied().or(self.legacy)
+    }
+
+    /// Whether a transaction with this fingerprint has been imported before.
+    pub fn is_imported(&self, fingerprint: &str) -> bool {
+        self.fingerprints.contains(fingerprint)
+    }
+
+    /// Record an object which was created in Firefly from the Sbanken `source`.
+    pub fn record_created(
+        &mut self,
+        kind: ObjectKind,
+        firefly_id: String,
+        account_id: &str,
+        source: &impl Serialize,
+    ) -> Result<()> {
+        self.journal.push(JournalEntry {
+            kind,
+            firefly_id,
+            account_id: account_id.into(),
+            created_at: Utc::now(),
+            source: serde_json::to_string(source).context("unable to serialize source data")?,
+        });
+        Ok(())
+    }
+
+    /// Count a transaction of the Firefly account `account` in the stats of the run.
+    pub fn count(&mut self, account: &str, outcome: Outcome) {
+        metrics::transaction(outcome);
+        self.stats.add(outcome);
+        self.account_stats
+            .entry(account.to_string())
+            .or_default()
+            .add(outcome);
+    }
+
+    pub fn mark_imported(&mut self, fingerprint: String) {
+        if self.fingerprints.insert(fingerprint.clone()) {
+            self.imported.push(fingerprint);
+        }
+    }
+}
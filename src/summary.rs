@@ -0,0 +1,109 @@
+//! Summary of what each run did, written as JSON so that dashboards and notifications do not
+//! have to scrape the log.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::crypto::{self, StateKey};
+use crate::state::{RunStats, State};
+
+/// Name of the summary file next to the state database, unless configured otherwise.
+pub const RUN_SUMMARY_FILE: &str = "run-summary.json";
+
+/// What the sync of a customer did.
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub customer: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// First day of the range which was synced.
+    pub first_day: NaiveDate,
+    /// Last day of the range which was synced.
+    pub last_day: NaiveDate,
+    pub succeeded: bool,
+    /// Transactions created, skipped as duplicates and failed, in total.
+    pub totals: RunStats,
+    /// Transactions created, skipped as duplicates and failed, for each Firefly account.
+    pub accounts: BTreeMap<String, RunStats>,
+    /// Internal transfers whose two legs were paired.
+    pub transfers_matched: u64,
+    /// Transactions queued until Firefly is reachable again.
+    pub queued: usize,
+    /// Transactions kept for `retry-failed`.
+    pub kept_for_retry: usize,
+    /// What failed, one line per transaction or account, followed by the error which stopped the
+    /// run if any.
+    pub errors: Vec<String>,
+}
+
+impl RunSummary {
+    pub fn new(customer: Option<&str>, first_day: NaiveDate, last_day: NaiveDate) -> Self {
+        RunSummary {
+            customer: customer.map(Into::into),
+            started_at: Utc::now(),
+            finished_at: None,
+            first_day,
+            last_day,
+            succeeded: false,
+            totals: RunStats::default(),
+            accounts: BTreeMap::new(),
+            transfers_matched: 0,
+            queued: 0,
+            kept_for_retry: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Take the counts of the run from `state`.
+    pub fn add_state(&mut self, state: &State) {
+        self.totals = state.stats;
+        self.accounts = state.account_stats.clone();
+        self.transfers_matched = state.transfers_matched;
+        self.queued = state.queued.len();
+        self.kept_for_retry = state.failed.len();
+        self.errors = state.failures.clone();
+    }
+
+    /// Complete the summary with the result of the run.
+    pub fn finish(mut self, result: &Result<()>) -> Self {
+        self.finished_at = Some(Utc::now());
+        self.succeeded = result.is_ok();
+        if let Err(e) = result {
+            self.errors.push(format!("{:#}", e));
+        }
+        self
+    }
+}
+
+lazy_static! {
+    /// Summaries of the runs since the summary file was last written.
+    static ref RUNS: Mutex<Vec<RunSummary>> = Mutex::new(Vec::new());
+}
+
+/// Keep the summary of a run until the summary file is written.
+pub fn record(summary: RunSummary) {
+    RUNS.lock().unwrap().push(summary);
+}
+
+/// Write the summaries of the runs of every customer since the last time to `path`, replacing it
+/// at once so that a reader never sees half a file. The file is encrypted with `key` if given, and
+/// then read with the `decrypt` command.
+pub fn write(path: &Path, key: Option<&StateKey>) -> Result<()> {
+    let runs = std::mem::take(&mut *RUNS.lock().unwrap());
+    if runs.is_empty() {
+        return Ok(());
+    }
+
+    let json = serde_json::to_string_pretty(&runs).context("unable to serialize run summary")?;
+    let json = crypto::encrypt(key, &json).context("unable to encrypt run summary")?;
+    let partial = path.with_extension("json.partial");
+    std::fs::write(&partial, json)
+        .with_context(|| format!("unable to write run summary to '{}'", partial.display()))?;
+    std::fs::rename(&partial, path)
+        .with_context(|| format!("unable to write run summary to '{}'", path.display()))
+}
@@ -0,0 +1,2926 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, NaiveDate, Utc};
+use firefly_iii::apis::{
+    client::APIClient as FireflyClient, configuration::Configuration as FireflyConfiguration,
+};
+use firefly_iii::models::account::LiabilityType;
+use firefly_iii::models::{Account, AccountRead, Transaction};
+use futures::lock::Mutex;
+use futures::stream::{self, Peekable, Stream, StreamExt, TryStreamExt};
+use reqwest::Method;
+use rust_decimal::Decimal;
+use sbanken::apis::{
+    client::APIClient as SbankenClient, configuration::Configuration as SbankenConfiguration,
+};
+use sbanken::models::{AccountV1, TransactionV1};
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::attachments;
+use crate::auth::{AuthToken, Login, Unauthorized};
+use crate::bills::Bills;
+use crate::bsu::{self, PiggyBanks};
+use crate::config::{AccountConfig, AccountRole};
+use crate::convert::{
+    convert_account, convert_transaction, fingerprints, foreign_currency, set_opening_balance,
+    Conversion, DateField, CURRENCY_CODE,
+};
+use crate::currencies::Currencies;
+use crate::dedup::{pair_transfers, TransferLeg, TransferMatching};
+use crate::error::{Item, Required, SyncError};
+use crate::filter::AccountFilter;
+use crate::firefly::{self, Page, Single, Stored};
+use crate::http;
+use crate::links::TransactionLinks;
+use crate::metrics;
+use crate::money::Money;
+use crate::payees::Payees;
+use crate::payments;
+use crate::plan::Plan;
+use crate::progress::Progress;
+use crate::reporting;
+use crate::reservations;
+use crate::retry;
+use crate::shutdown;
+use crate::state::{
+    customer_key, CachedAccounts, FailedTransaction, ObjectKind, Outcome, PendingTransfer,
+    QueuedLeg, QueuedTransaction, RecordedTransfer, Reservation, ScheduledPayment, State,
+    StateStore,
+};
+use crate::throttle::{self, Api};
+use crate::transactions::{self, ApiVersion, Chunk, TransactionDetails};
+use crate::transfer;
+use crate::DATE_FORMAT;
+
+/// Seconds the Firefly accounts listed by an earlier run are reused for by default.
+pub const ACCOUNTS_CACHE_TTL: u64 = 3600;
+
+pub struct Bridge {
+    /// Use `sbanken()` instead, which has the current token.
+    sbanken: RefCell<Rc<SbankenClient>>,
+    /// Use `sbanken_config()` instead, which has the current token.
+    sbanken_config: RefCell<SbankenConfiguration>,
+    sbanken_token: RefCell<AuthToken>,
+    /// How to get a new Sbanken token when the current one runs out, if it can be renewed.
+    pub sbanken_login: Option<Login>,
+    /// Version of the Sbanken transactions endpoint to use.
+    pub sbanken_api: ApiVersion,
+    pub firefly: FireflyClient,
+    /// Settings for the Firefly endpoints which are not covered by the generated client.
+    pub firefly_config: FireflyConfiguration,
+    /// Customer the Sbanken requests are made on behalf of, empty until it is known.
+    pub sbanken_customer_id: Secret<String>,
+    /// Record changes in a `Plan` instead of writing them to Firefly.
+    pub dry_run: bool,
+    /// Account overrides keyed by Sbanken account number.
+    pub accounts: HashMap<String, AccountConfig>,
+    pub account_filter: AccountFilter,
+    pub transfer_matching: TransferMatching,
+    pub conversion: Conversion,
+    /// Track the deposits into BSU accounts in a piggy bank for each year.
+    pub bsu_piggy_bank: bool,
+    /// Target of the BSU piggy banks in kroner.
+    pub bsu_yearly_cap: u32,
+    /// Bring the name, account number and role of existing Firefly accounts up to date.
+    pub update_accounts: bool,
+    /// Mark the Firefly accounts of closed Sbanken accounts as inactive.
+    pub archive_closed_accounts: bool,
+    /// Role of accounts of unknown types, which abort the sync if there is none.
+    pub fallback_account_role: Option<AccountRole>,
+    /// Firefly object group which the accounts of the bridge are placed in.
+    pub account_group: Option<String>,
+    /// Import card reservations as pending transactions until they are booked.
+    pub import_reservations: bool,
+    /// Mirror eFakturas as Firefly bills and link their payments to them.
+    pub efaktura_bills: bool,
+    /// Import scheduled payments as future transactions until they are booked.
+    pub scheduled_payments: bool,
+    /// Mirror standing orders as recurring transactions.
+    pub standing_orders: bool,
+    /// Name of the customer when syncing several, which namespaces its accounts.
+    pub customer: Option<String>,
+    /// Let Firefly trigger its webhooks for the created transactions.
+    pub fire_webhooks: bool,
+    /// Attach the transactions as Sbanken sent them to the created transactions.
+    pub attach_source: bool,
+    /// Track the balance of savings accounts in a piggy bank for each account.
+    pub savings_piggy_banks: bool,
+    /// Target of the savings piggy banks in kroner, unless the account has its own goal.
+    pub savings_goal: u32,
+    /// Seconds the Firefly accounts listed by an earlier run are reused for, 0 to list them on
+    /// every run.
+    pub accounts_cache_ttl: u64,
+    /// Size of the windows which transactions are fetched and transfers are paired in.
+    pub chunk: Chunk,
+    /// Most accounts whose transactions are fetched and stored at the same time.
+    pub jobs: usize,
+    /// Most years which are synced at the same time during a backfill.
+    pub parallel_years: usize,
+    /// Most transactions of an account which are being stored in Firefly at the same time.
+    pub concurrent_uploads: usize,
+}
+
+impl Bridge {
+    pub fn new(
+        sbanken_base_url: &str,
+        sbanken_token: &AuthToken,
+        sbanken_customer_id: Secret<String>,
+        firefly_base_url: &str,
+        firefly_access_token: &Secret<String>,
+    ) -> Bridge {
+        let firefly_config = firefly::configuration(firefly_base_url, firefly_access_token);
+        let sbanken_config = SbankenConfiguration {
+            base_path: sbanken_base_url.into(),
+            oauth_access_token: Some(sbanken_token.access_token.expose_secret().into()),
+            client: http::client(),
+            ..SbankenConfiguration::default()
+        };
+
+        Bridge {
+            sbanken: RefCell::new(Rc::new(SbankenClient::new(sbanken_config.clone()))),
+            sbanken_config: RefCell::new(sbanken_config),
+            sbanken_token: RefCell::new(sbanken_token.clone()),
+            sbanken_login: None,
+            sbanken_api: ApiVersion::default(),
+            firefly: FireflyClient::new(firefly_config.clone()),
+            firefly_config,
+            sbanken_customer_id,
+            dry_run: false,
+            accounts: HashMap::new(),
+            account_filter: AccountFilter::default(),
+            transfer_matching: TransferMatching::default(),
+            conversion: Conversion::default(),
+            bsu_piggy_bank: false,
+            bsu_yearly_cap: bsu::DEFAULT_YEARLY_CAP,
+            update_accounts: false,
+            archive_closed_accounts: false,
+            fallback_account_role: Some(AccountRole::Default),
+            account_group: None,
+            import_reservations: false,
+            efaktura_bills: false,
+            scheduled_payments: false,
+            standing_orders: false,
+            customer: None,
+            fire_webhooks: true,
+            attach_source: false,
+            savings_piggy_banks: false,
+            savings_goal: bsu::DEFAULT_SAVINGS_GOAL,
+            accounts_cache_ttl: ACCOUNTS_CACHE_TTL,
+            chunk: Chunk::default(),
+            jobs: 1,
+            parallel_years: 1,
+            concurrent_uploads: 1,
+        }
+    }
+
+    /// The generated Sbanken client, with the current token.
+    pub fn sbanken(&self) -> Rc<SbankenClient> {
+        self.sbanken.borrow().clone()
+    }
+
+    /// Settings for the Sbanken endpoints which are not covered by the generated client, with the
+    /// current token.
+    pub fn sbanken_config(&self) -> SbankenConfiguration {
+        self.sbanken_config.borrow().clone()
+    }
+
+    /// Make the Sbanken requests with `token` from now on.
+    pub fn set_sbanken_token(&self, token: AuthToken) {
+        let config = SbankenConfiguration {
+            oauth_access_token: Some(token.access_token.expose_secret().clone()),
+            ..self.sbanken_config()
+        };
+        *self.sbanken.borrow_mut() = Rc::new(SbankenClient::new(config.clone()));
+        *self.sbanken_config.borrow_mut() = config;
+        *self.sbanken_token.borrow_mut() = token;
+    }
+
+    async fn renew_sbanken_token(&self, login: &Login) -> Result<()> {
+        let token = login
+            .token()
+            .await
+            .context("unable to renew sbanken auth token")?;
+        self.set_sbanken_token(token);
+        Ok(())
+    }
+
+    /// Get a new Sbanken token if the current one is about to run out.
+    pub async fn refresh_sbanken_token(&self) -> Result<()> {
+        let expiring = self.sbanken_token.borrow().is_expiring();
+        match &self.sbanken_login {
+            Some(login) if expiring => {
+                info!("Sbanken token is about to run out, renewing it...");
+                self.renew_sbanken_token(login).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Make `request` to Sbanken with a valid token, renewing it first if it is about to run out
+    /// and making the request once more with a new token if Sbanken rejects it anyway.
+    pub async fn with_sbanken_token<T, E, F, Fut>(&self, mut request: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Unauthorized + std::error::Error + Send + Sync + 'static,
+    {
+        self.refresh_sbanken_token().await?;
+        match (request().await, &self.sbanken_login) {
+            (Err(e), Some(login)) if e.is_unauthorized() => {
+                info!("Sbanken rejected the token, renewing it...");
+                self.renew_sbanken_token(login).await?;
+                Ok(request().await?)
+            }
+            (result, _) => Ok(result?),
+        }
+    }
+}
+
+/// Account number without the separators people tend to add (e.g. "9710.05.12345").
+pub fn normalize_account_number(account_number: &str) -> String {
+    account_number
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect()
+}
+
+/// Find the Firefly account which mirrors the given Sbanken account by its account number.
+///
+/// Accounts created by older versions of the bridge are found by the Sbanken account id in their
+/// notes instead, see `is_legacy_mapping`.
+pub fn find_account<'a>(
+    firefly_accounts: &'a [AccountRead],
+    sbanken_account: &AccountV1,
+) -> Option<&'a AccountRead> {
+    let account_number = sbanken_account
+        .account_number
+        .as_deref()
+        .map(normalize_account_number);
+
+    firefly_accounts
+        .iter()
+        .find(|account_read| {
+            account_number.is_some()
+                && account_read
+                    .attributes
+                    .account_number
+                    .as_deref()
+                    .map(normalize_account_number)
+                    == account_number
+        })
+        .or_else(|| {
+            firefly_accounts
+                .iter()
+                .find(|account_read| is_legacy_mapping(account_read, sbanken_account))
+        })
+}
+
+/// Whether `firefly_account` is a loan or mortgage, which payments are transferred to.
+fn is_loan(firefly_account: &AccountRead) -> bool {
+    matches!(
+        firefly_account.attributes.liability_type,
+        Some(LiabilityType::Loan) | Some(LiabilityType::Mortgage)
+    )
+}
+
+/// The Firefly accounts, indexed by the Sbanken account each of them mirrors so that they are
+/// looked up without scanning all of them.
+pub struct AccountMap {
+    /// Every asset account in Firefly.
+    accounts: Vec<AccountRead>,
+    /// Position in `accounts` of the account with each normalized account number.
+    by_number: HashMap<String, usize>,
+    /// Position in `accounts` of the account mirroring each Sbanken account, keyed by Sbanken
+    /// account id.
+    mirrors: HashMap<String, usize>,
+    /// Names of the Sbanken accounts which no Firefly account mirrors.
+    unmapped: Vec<String>,
+}
+
+impl AccountMap {
+    /// Find the Firefly account mirroring each of `sbanken_accounts`, the same way as
+    /// `find_account`.
+    pub fn new(sbanken_accounts: &[AccountV1], firefly_accounts: Vec<AccountRead>) -> AccountMap {
+        let mut by_number = HashMap::new();
+        for (i, account) in firefly_accounts.iter().enumerate() {
+            if let Some(account_number) = account.attributes.account_number.as_deref() {
+                by_number
+                    .entry(normalize_account_number(account_number))
+                    .or_insert(i);
+            }
+        }
+
+        let mut mirrors = HashMap::new();
+        let mut unmapped = Vec::new();
+        for sbanken_account in sbanken_accounts {
+            let account_id = match &sbanken_account.account_id {
+                Some(account_id) => account_id,
+                None => continue,
+            };
+            let mirror = sbanken_account
+                .account_number
+                .as_deref()
+                .and_then(|number| by_number.get(&normalize_account_number(number)).copied())
+                .or_else(|| {
+                    firefly_accounts
+                        .iter()
+                        .position(|account| is_legacy_mapping(account, sbanken_account))
+                });
+            match mirror {
+                Some(i) => {
+                    mirrors.insert(account_id.clone(), i);
+                }
+                None => unmapped.push(
+                    sbanken_account
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| account_id.clone()),
+                ),
+            }
+        }
+
+        AccountMap {
+            accounts: firefly_accounts,
+            by_number,
+            mirrors,
+            unmapped,
+        }
+    }
+
+    /// The Firefly account mirroring the Sbanken account `sbanken_account_id`.
+    pub fn get(&self, sbanken_account_id: &str) -> Option<&AccountRead> {
+        self.mirrors
+            .get(sbanken_account_id)
+            .map(|&i| &self.accounts[i])
+    }
+
+    /// The Firefly account mirroring the Sbanken account `sbanken_account_id`, which must be
+    /// mapped.
+    pub fn require(&self, sbanken_account_id: &str) -> Result<&AccountRead> {
+        self.get(sbanken_account_id).ok_or_else(|| {
+            anyhow!(
+                "sbanken account {} is not mirrored by a firefly account",
+                sbanken_account_id
+            )
+        })
+    }
+
+    /// The Firefly account mirroring `sbanken_account`.
+    pub fn mirror(&self, sbanken_account: &AccountV1) -> Option<&AccountRead> {
+        self.get(sbanken_account.account_id.as_deref()?)
+    }
+
+    /// The Firefly account with the account number, whether it mirrors a Sbanken account or not.
+    pub fn by_number(&self, account_number: &str) -> Option<&AccountRead> {
+        self.by_number
+            .get(&normalize_account_number(account_number))
+            .map(|&i| &self.accounts[i])
+    }
+
+    /// The other account in Firefly of a transaction on `firefly_account`, by the account number
+    /// which it was paid to or from.
+    pub fn counterpart(
+        &self,
+        firefly_account: &AccountRead,
+        transaction: &TransactionV1,
+    ) -> Option<&AccountRead> {
+        let account_number = transaction.other_account_number.as_deref()?;
+        self.by_number(account_number)
+            .filter(|account| account.id != firefly_account.id)
+    }
+
+    /// Whether `firefly_account` mirrors a Sbanken account which is synced.
+    pub fn is_mirror(&self, firefly_account: &AccountRead) -> bool {
+        self.mirrors
+            .values()
+            .any(|&i| self.accounts[i].id == firefly_account.id)
+    }
+
+    /// Whether a Firefly account mirrors the Sbanken account `sbanken_account_id`.
+    pub fn is_mapped(&self, sbanken_account_id: &str) -> bool {
+        self.mirrors.contains_key(sbanken_account_id)
+    }
+
+    /// Every Sbanken account id with the Firefly account mirroring it.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &AccountRead)> {
+        self.mirrors
+            .iter()
+            .map(move |(account_id, &i)| (account_id.as_str(), &self.accounts[i]))
+    }
+
+    /// Names of the Sbanken accounts which no Firefly account mirrors.
+    pub fn unmapped(&self) -> &[String] {
+        &self.unmapped
+    }
+}
+
+/// Whether the Firefly account is mapped to the Sbanken account through the Sbanken account id in
+/// its notes, which breaks as soon as the notes are edited.
+fn is_legacy_mapping(firefly_account: &AccountRead, sbanken_account: &AccountV1) -> bool {
+    firefly_account.attributes.notes.is_some()
+        && firefly_account.attributes.notes == sbanken_account.account_id
+}
+
+/// Move a mapping through the notes over to the account number of the Firefly account.
+async fn upgrade_legacy_mapping(
+    bridge: &Bridge,
+    firefly_account: &AccountRead,
+    sbanken_account: &AccountV1,
+) -> Result<()> {
+    #[derive(Serialize)]
+    struct AccountUpdate<'a> {
+        name: &'a str,
+        account_number: Option<&'a str>,
+        notes: &'a str,
+    }
+
+    let _: serde_json::Value = firefly::send(
+        &bridge.firefly_config,
+        Method::PUT,
+        &format!("/accounts/{}", firefly_account.id),
+        &AccountUpdate {
+            name: &firefly_account.attributes.name,
+            account_number: sbanken_account.account_number.as_deref(),
+            notes: "",
+        },
+    )
+    .await
+    .with_context(|| {
+        format!(
+            "unable to move mapping of account '{}' to its account number",
+            firefly_account.attributes.name
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Differences between the Firefly account and how the bridge would create it today, e.g. after
+/// the account was renamed in Sbanken.
+fn account_changes(firefly_account: &AccountRead, wanted: &Account) -> Vec<String> {
+    let current = &firefly_account.attributes;
+    let mut changes = Vec::new();
+
+    if current.name != wanted.name {
+        changes.push(format!("name '{}' -> '{}'", current.name, wanted.name));
+    }
+    if current
+        .account_number
+        .as_deref()
+        .map(normalize_account_number)
+        != wanted
+            .account_number
+            .as_deref()
+            .map(normalize_account_number)
+    {
+        changes.push(format!(
+            "account number {} -> {}",
+            current.account_number.as_deref().unwrap_or("<none>"),
+            wanted.account_number.as_deref().unwrap_or("<none>"),
+        ));
+    }
+    // Liabilities have no role
+    if wanted.account_role.is_some() && current.account_role != wanted.account_role {
+        changes.push(format!(
+            "role {:?} -> {:?}",
+            current.account_role, wanted.account_role
+        ));
+    }
+
+    changes
+}
+
+async fn update_account(
+    bridge: &Bridge,
+    firefly_account: &AccountRead,
+    wanted: &Account,
+) -> Result<()> {
+    #[derive(Serialize)]
+    struct AccountUpdate<'a> {
+        name: &'a str,
+        account_number: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account_role: Option<&'a firefly_iii::models::account::AccountRole>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        object_group_title: Option<&'a str>,
+    }
+
+    let _: serde_json::Value = firefly::send(
+        &bridge.firefly_config,
+        Method::PUT,
+        &format!("/accounts/{}", firefly_account.id),
+        &AccountUpdate {
+            name: &wanted.name,
+            account_number: wanted.account_number.as_deref(),
+            account_role: wanted.account_role.as_ref(),
+            object_group_title: bridge.account_group.as_deref(),
+        },
+    )
+    .await
+    .with_context(|| {
+        format!(
+            "unable to update account '{}'",
+            firefly_account.attributes.name
+        )
+    })?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct GroupedAccount {
+    attributes: GroupedAccountAttributes,
+}
+
+#[derive(Deserialize)]
+struct GroupedAccountAttributes {
+    object_group_title: Option<String>,
+}
+
+/// Title of the object group of the Firefly account, which the generated client does not know.
+async fn account_group(bridge: &Bridge, firefly_account: &AccountRead) -> Result<Option<String>> {
+    let account: Option<Single<GroupedAccount>> = firefly::get(
+        &bridge.firefly_config,
+        &format!("/accounts/{}", firefly_account.id),
+    )
+    .await?;
+    Ok(account.and_then(|account| account.data.attributes.object_group_title))
+}
+
+/// Place a newly created Firefly account in the object group of the bridge.
+async fn set_account_group(
+    bridge: &Bridge,
+    firefly_account: &AccountRead,
+    group: &str,
+) -> Result<()> {
+    #[derive(Serialize)]
+    struct AccountUpdate<'a> {
+        name: &'a str,
+        object_group_title: &'a str,
+    }
+
+    let _: serde_json::Value = firefly::send(
+        &bridge.firefly_config,
+        Method::PUT,
+        &format!("/accounts/{}", firefly_account.id),
+        &AccountUpdate {
+            name: &firefly_account.attributes.name,
+            object_group_title: group,
+        },
+    )
+    .await
+    .with_context(|| {
+        format!(
+            "unable to place account '{}' in group '{}'",
+            firefly_account.attributes.name, group
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Make sure the Firefly account is kept in kroner, as the amounts from Sbanken would be wrong in
+/// any other currency.
+fn check_currency(firefly_account: &AccountRead) -> Result<()> {
+    match firefly_account.attributes.currency_code.as_deref() {
+        // Accounts without a currency use the default currency of Firefly, which is unknown here
+        None | Some(CURRENCY_CODE) => Ok(()),
+        Some(currency) => Err(anyhow!(
+            "account '{}' is kept in {} in Firefly, but Sbanken accounts are in {}",
+            firefly_account.attributes.name,
+            currency,
+            CURRENCY_CODE
+        )),
+    }
+}
+
+/// Mark the Firefly account of a closed Sbanken account as inactive, noting when the closure was
+/// noticed.
+async fn archive_account(bridge: &Bridge, firefly_account: &AccountRead) -> Result<()> {
+    #[derive(Serialize)]
+    struct AccountUpdate<'a> {
+        name: &'a str,
+        active: bool,
+        notes: String,
+    }
+
+    let closed = format!(
+        "Closed in Sbanken, noticed on {}.",
+        chrono::Local::today().format(DATE_FORMAT)
+    );
+    let notes = match firefly_account.attributes.notes.as_deref() {
+        Some(notes) if !notes.is_empty() => format!("{}\n\n{}", notes, closed),
+        _ => closed,
+    };
+
+    let _: serde_json::Value = firefly::send(
+        &bridge.firefly_config,
+        Method::PUT,
+        &format!("/accounts/{}", firefly_account.id),
+        &AccountUpdate {
+            name: &firefly_account.attributes.name,
+            active: false,
+            notes,
+        },
+    )
+    .await
+    .with_context(|| {
+        format!(
+            "unable to archive account '{}'",
+            firefly_account.attributes.name
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Look for Sbanken accounts which were seen before but are gone now, and archive their Firefly
+/// accounts if enabled.
+///
+/// Returns whether any account was archived.
+async fn closed_accounts(
+    bridge: &Bridge,
+    state: &State,
+    sbanken_accounts: &[AccountV1],
+    firefly_accounts: &[AccountRead],
+    plan: &mut Plan,
+) -> Result<bool> {
+    // An empty list is more likely a hiccup at Sbanken than every account being closed
+    if sbanken_accounts.is_empty() {
+        return Ok(false);
+    }
+
+    let open: HashSet<&str> = sbanken_accounts
+        .iter()
+        .filter_map(|account| account.account_id.as_deref())
+        .collect();
+
+    let mut archived = false;
+    for (account_id, account_number) in &state.account_numbers {
+        // Accounts of other customers are not in the list of this one
+        let customer = state.account_customers.get(account_id);
+        if open.contains(account_id.as_str())
+            || customer != bridge.customer.as_deref().map(customer_key).as_ref()
+        {
+            continue;
+        }
+        let number = normalize_account_number(account_number);
+        let firefly_account = firefly_accounts.iter().find(|account| {
+            account
+                .attributes
+                .account_number
+                .as_deref()
+                .map_or(false, |other| normalize_account_number(other) == number)
+        });
+        let firefly_account = match firefly_account {
+            Some(account) if account.attributes.active.unwrap_or(true) => account,
+            // Never mirrored or already archived
+            _ => continue,
+        };
+
+        info!(
+            "Account '{}' no longer exists in Sbanken and is not synced anymore",
+            firefly_account.attributes.name
+        );
+        if !bridge.archive_closed_accounts {
+            continue;
+        }
+        if bridge.dry_run {
+            plan.account_updates.push(format!(
+                "{}: archive, closed in Sbanken",
+                firefly_account.attributes.name
+            ));
+        } else {
+            archive_account(bridge, firefly_account).await?;
+            info!("Archived account '{}'", firefly_account.attributes.name);
+            archived = true;
+        }
+    }
+
+    Ok(archived)
+}
+
+#[derive(Deserialize)]
+struct ListedTransaction {
+    attributes: ListedAttributes,
+}
+
+#[derive(Deserialize)]
+struct ListedAttributes {
+    transactions: Vec<ListedSplit>,
+}
+
+#[derive(Deserialize)]
+struct ListedSplit {
+    date: String,
+    amount: String,
+    description: String,
+    external_id: Option<String>,
+}
+
+/// Transactions which Firefly already has, e.g. because they were imported before the local state
+/// was lost, fetched once for every account and window which is synced.
+#[derive(Default)]
+struct Existing {
+    /// Windows of days which are fetched, by the id of the Firefly account.
+    windows: HashMap<String, Vec<(NaiveDate, NaiveDate)>>,
+    /// Splits of the fetched transactions, by the id of the Firefly account and their day.
+    splits: HashMap<(String, NaiveDate), Vec<ListedSplit>>,
+}
+
+impl Existing {
+    /// Fetch the transactions of `firefly_account` from `start` to `end`, unless they already are.
+    async fn fetch(
+        &mut self,
+        bridge: &Bridge,
+        firefly_account: &AccountRead,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<()> {
+        if self.covers(&firefly_account.id, start, end) {
+            return Ok(());
+        }
+
+        let splits = Existing::fetch_window(bridge, firefly_account, start, end).await?;
+        self.insert(&firefly_account.id, (start, end), splits);
+        Ok(())
+    }
+
+    /// Whether the transactions of the Firefly account from `start` to `end` are fetched.
+    fn covers(&self, firefly_account_id: &str, start: NaiveDate, end: NaiveDate) -> bool {
+        self.windows
+            .get(firefly_account_id)
+            .into_iter()
+            .flatten()
+            .any(|&(from, to)| from <= start && end <= to)
+    }
+
+    /// Keep the `splits` fetched from the Firefly account for the `window` of days.
+    fn insert(
+        &mut self,
+        firefly_account_id: &str,
+        window: (NaiveDate, NaiveDate),
+        splits: Vec<(NaiveDate, ListedSplit)>,
+    ) {
+        for (day, split) in splits {
+            self.splits
+                .entry((firefly_account_id.into(), day))
+                .or_default()
+                .push(split);
+        }
+        self.windows
+            .entry(firefly_account_id.into())
+            .or_default()
+            .push(window);
+    }
+
+    /// The splits of the transactions of `firefly_account` from `start` to `end` with their day.
+    async fn fetch_window(
+        bridge: &Bridge,
+        firefly_account: &AccountRead,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<Vec<(NaiveDate, ListedSplit)>> {
+        let mut splits = Vec::new();
+        for page_number in 1.. {
+            let path = format!(
+                "/accounts/{}/transactions?start={}&end={}&page={}",
+                firefly_account.id,
+                start.format(DATE_FORMAT),
+                end.format(DATE_FORMAT),
+                page_number
+            );
+            let page: Page<ListedTransaction> = firefly::get(&bridge.firefly_config, &path)
+                .await
+                .context("unable to look for transactions which are already in firefly")?
+                .unwrap_or(Page { data: Vec::new() });
+            if page.data.is_empty() {
+                break;
+            }
+            for split in page
+                .data
+                .into_iter()
+                .flat_map(|t| t.attributes.transactions)
+            {
+                let day = split
+                    .date
+                    .get(..10)
+                    .and_then(|date| NaiveDate::parse_from_str(date, DATE_FORMAT).ok())
+                    .ok_or_else(|| anyhow!("invalid date '{}' from firefly", split.date))?;
+                splits.push((day, split));
+            }
+        }
+        Ok(splits)
+    }
+
+    /// Whether Firefly already has this transaction, fetching the day it is on if that has not
+    /// been done yet.
+    async fn contains(
+        &mut self,
+        bridge: &Bridge,
+        firefly_account: &AccountRead,
+        transaction: &Transaction,
+    ) -> Result<bool> {
+        let date = &transaction.transactions[0].date;
+        let day = NaiveDate::parse_from_str(date, DATE_FORMAT)
+            .with_context(|| format!("invalid date '{}' of converted transaction", date))?;
+        self.fetch(bridge, firefly_account, day, day).await?;
+        Ok(self.has(&firefly_account.id, day, transaction))
+    }
+
+    /// Transactions on the same account with the same date and amount are considered the same if
+    /// they have the same external id, or if they have no external id (as when imported by older
+    /// versions) and the same description.
+    fn has(&self, firefly_account_id: &str, day: NaiveDate, transaction: &Transaction) -> bool {
+        let split = &transaction.transactions[0];
+        self.splits
+            .get(&(firefly_account_id.into(), day))
+            .into_iter()
+            .flatten()
+            .filter(|existing| same_amount(&existing.amount, &split.amount))
+            .any(|existing| match &existing.external_id {
+                Some(external_id) if !external_id.is_empty() => {
+                    Some(external_id) == split.external_id.as_ref()
+                }
+                _ => existing.description == split.description,
+            })
+    }
+}
+
+/// Whether two amounts are the same, as Firefly returns them with more decimals than they are
+/// stored with.
+fn same_amount(a: &str, b: &str) -> bool {
+    match (a.parse::<Decimal>(), b.parse::<Decimal>()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+pub async fn list_sbanken_accounts(bridge: &Bridge) -> Result<Vec<AccountV1>> {
+    let customer_id = bridge.sbanken_customer_id.expose_secret();
+    Ok(bridge
+        .with_sbanken_token(|| async {
+            let sbanken = bridge.sbanken();
+            retry::retry(Api::Sbanken, "listing sbanken accounts", || {
+                sbanken.accounts_api().list_accounts(Some(customer_id))
+            })
+            .await
+        })
+        .instrument(info_span!("list_accounts", api = "sbanken"))
+        .await
+        .context("unable to fetch accounts from sbanken")?
+        .items
+        .required("accounts")?)
+}
+
+pub async fn list_firefly_accounts(bridge: &Bridge) -> Result<Vec<AccountRead>> {
+    use firefly_iii::models::AccountTypeFilter;
+
+    // Credit cards are mirrored as liabilities, every other account as an asset
+    let mut accounts = Vec::new();
+    for account_type in vec![AccountTypeFilter::Asset, AccountTypeFilter::Liability] {
+        accounts.extend(
+            retry::retry(Api::Firefly, "listing firefly accounts", || {
+                bridge
+                    .firefly
+                    .accounts_api()
+                    .list_account(None, None, Some(account_type.clone()))
+            })
+            .instrument(info_span!("list_accounts", api = "firefly"))
+            .await
+            .context("unable to get existing accounts")?
+            .data,
+        );
+    }
+    Ok(accounts)
+}
+
+/// List the Firefly accounts of the bridge and keep them in `state` for later runs.
+async fn refresh_firefly_accounts(bridge: &Bridge, state: &mut State) -> Result<Vec<AccountRead>> {
+    let accounts = list_firefly_accounts(bridge).await?;
+    state.firefly_accounts = Some(CachedAccounts {
+        fetched_at: Utc::now(),
+        accounts: accounts.clone(),
+    });
+    Ok(accounts)
+}
+
+/// The Firefly accounts which an earlier run listed, unless they are older than
+/// `bridge.accounts_cache_ttl`, one of `sbanken_accounts` has no account among them or one of
+/// the accounts which mirror them is gone from Firefly.
+///
+/// The accounts which mirror `sbanken_accounts` are read again one by one, so that they are as
+/// current as if they had been listed.
+async fn cached_firefly_accounts(
+    bridge: &Bridge,
+    state: &State,
+    sbanken_accounts: &[AccountV1],
+) -> Result<Option<Vec<AccountRead>>> {
+    let cached = match &state.firefly_accounts {
+        Some(cached) => cached,
+        None => return Ok(None),
+    };
+    let age = (Utc::now() - cached.fetched_at).num_seconds();
+    if age < 0 || age as u64 >= bridge.accounts_cache_ttl {
+        return Ok(None);
+    }
+
+    let mapped: HashSet<String> = {
+        let accounts = AccountMap::new(sbanken_accounts, cached.accounts.clone());
+        if !accounts.unmapped().is_empty() {
+            info!("Found new Sbanken accounts, listing the Firefly accounts again...");
+            return Ok(None);
+        }
+        accounts
+            .iter()
+            .map(|(_, account)| account.id.clone())
+            .collect()
+    };
+
+    let mut accounts = cached.accounts.clone();
+    for account in accounts
+        .iter_mut()
+        .filter(|account| mapped.contains(&account.id))
+    {
+        let path = format!("/accounts/{}", account.id);
+        match firefly::get::<Single<AccountRead>>(&bridge.firefly_config, &path).await? {
+            Some(current) => *account = current.data,
+            None => {
+                warn!(
+                    "Account '{}' is gone from Firefly, listing the accounts again...",
+                    account.attributes.name
+                );
+                return Ok(None);
+            }
+        }
+    }
+
+    // An account which was changed in Firefly may no longer mirror its Sbanken account
+    if !AccountMap::new(sbanken_accounts, accounts.clone())
+        .unmapped()
+        .is_empty()
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(accounts))
+}
+
+/// Balance of a Sbanken account at the start of `first_day`, i.e. its current balance without the
+/// transactions booked since.
+async fn opening_balance(
+    bridge: &Bridge,
+    sbanken_account: &AccountV1,
+    first_day: NaiveDate,
+) -> Result<Money> {
+    let account_id = sbanken_account
+        .account_id
+        .as_deref()
+        .required("account id")?;
+    let today = chrono::Local::today().naive_local();
+    let mut balance = Money::from_f64(sbanken_account.balance.unwrap_or_default())?.decimal();
+
+    // Transactions are fetched one chunk at a time, like when syncing
+    for (start, end) in bridge.chunk.windows(first_day, today) {
+        let transactions = transactions::fetch(bridge, account_id, start, end).await?;
+
+        // Reservations are not part of the balance yet
+        for (transaction, _) in transactions {
+            if transaction.is_reservation != Some(true) {
+                balance -= Money::of(&transaction)?.decimal();
+            }
+        }
+    }
+
+    Ok(Money::from_decimal(balance))
+}
+
+/// The Firefly account which `sbanken_account` is mirrored as, with the account overrides applied
+/// and its name prefixed by the customer when syncing several.
+fn wanted_account(bridge: &Bridge, sbanken_account: &AccountV1) -> Result<Account> {
+    let config = sbanken_account
+        .account_number
+        .as_ref()
+        .and_then(|number| bridge.accounts.get(number));
+    let mut account = convert_account(sbanken_account, config, bridge.fallback_account_role)?;
+    if let Some(customer) = &bridge.customer {
+        if config.and_then(|config| config.name.as_ref()).is_none() {
+            account.name = format!("{}: {}", customer, account.name);
+        }
+    }
+    Ok(account)
+}
+
+/// Create Firefly accounts for all Sbanken accounts which are not mirrored yet.
+///
+/// New accounts get the balance they had before the first day which is synced as opening
+/// balance.
+///
+/// Returns the Sbanken accounts selected by the account filter together with the up-to-date
+/// Firefly accounts mirroring them.
+pub async fn sync_accounts(
+    bridge: &Bridge,
+    state: &mut State,
+    first_day: NaiveDate,
+    plan: &mut Plan,
+) -> Result<(Vec<AccountV1>, AccountMap)> {
+    let all_accounts = list_sbanken_accounts(bridge).await?;
+    let sbanken_accounts: Vec<_> = all_accounts
+        .iter()
+        .filter(|acc| bridge.account_filter.includes(acc))
+        .cloned()
+        .collect();
+
+    let firefly_accounts = match cached_firefly_accounts(bridge, state, &sbanken_accounts).await? {
+        Some(accounts) => accounts,
+        None => refresh_firefly_accounts(bridge, state).await?,
+    };
+
+    // Whether the accounts in Firefly were changed, so that they have to be listed again
+    let mut changed =
+        closed_accounts(bridge, state, &all_accounts, &firefly_accounts, plan).await?;
+    for account in &all_accounts {
+        if let (Some(account_id), Some(account_number)) =
+            (&account.account_id, &account.account_number)
+        {
+            state
+                .account_numbers
+                .insert(account_id.clone(), account_number.clone());
+            match &bridge.customer {
+                Some(customer) => {
+                    state
+                        .account_customers
+                        .insert(account_id.clone(), customer_key(customer));
+                }
+                None => {
+                    state.account_customers.remove(account_id);
+                }
+            }
+        }
+    }
+
+    let existing = AccountMap::new(&sbanken_accounts, firefly_accounts);
+    let mut to_create = Vec::new();
+    for sbanken_account in &sbanken_accounts {
+        let firefly_account = existing.mirror(sbanken_account);
+        if let Some(firefly_account) = firefly_account {
+            check_currency(firefly_account)?;
+        }
+
+        match firefly_account {
+            Some(firefly_account) if is_legacy_mapping(firefly_account, sbanken_account) => {
+                info!(
+                    "Account '{}' is mapped through its notes, moving mapping to account number...",
+                    firefly_account.attributes.name
+                );
+                if !bridge.dry_run {
+                    upgrade_legacy_mapping(bridge, firefly_account, sbanken_account).await?;
+                    changed = true;
+                }
+            }
+            Some(firefly_account) if bridge.update_accounts => {
+                let wanted = match wanted_account(bridge, sbanken_account) {
+                    Ok(wanted) => wanted,
+                    Err(e) => {
+                        warn!(
+                            "Unable to check account '{}' for changes: {:?}",
+                            firefly_account.attributes.name, e
+                        );
+                        continue;
+                    }
+                };
+                let mut changes = account_changes(firefly_account, &wanted);
+                if let Some(group) = &bridge.account_group {
+                    let current = account_group(bridge, firefly_account).await?;
+                    if current.as_ref() != Some(group) {
+                        changes.push(format!(
+                            "group {} -> {}",
+                            current.as_deref().unwrap_or("<none>"),
+                            group
+                        ));
+                    }
+                }
+                if changes.is_empty() {
+                    continue;
+                }
+                let changes = changes.join(", ");
+
+                info!(
+                    "Account '{}' has changed in Sbanken, updating {}...",
+                    firefly_account.attributes.name, changes
+                );
+                if bridge.dry_run {
+                    plan.account_updates
+                        .push(format!("{}: {}", firefly_account.attributes.name, changes));
+                } else {
+                    update_account(bridge, firefly_account, &wanted).await?;
+                    changed = true;
+                }
+            }
+            Some(_) => {}
+            None => to_create.push(sbanken_account),
+        }
+    }
+
+    for sbanken_account in to_create {
+        info!(
+            "Account '{}' does not already exist, creating...",
+            sbanken_account.name.as_ref().required("account name")?
+        );
+        let mut firefly_account =
+            wanted_account(bridge, sbanken_account).context("unable to convert account")?;
+
+        let start = state
+            .last_sync(
+                sbanken_account
+                    .account_id
+                    .as_deref()
+                    .required("account id")?,
+            )
+            .unwrap_or(first_day);
+        match opening_balance(bridge, sbanken_account, start).await {
+            Ok(balance) => set_opening_balance(&mut firefly_account, balance, start.pred()),
+            Err(e) => warn!(
+                "Unable to find opening balance of account '{}', starting at zero: {:?}",
+                sbanken_account.name.as_ref().required("account name")?,
+                e
+            ),
+        }
+
+        if bridge.dry_run {
+            plan.accounts.push(firefly_account);
+            continue;
+        }
+
+        // Not retried, as an attempt which failed may still have created the account
+        throttle::acquire(Api::Firefly).await;
+        let created = bridge
+            .firefly
+            .accounts_api()
+            .store_account(firefly_account)
+            .await
+            .context("unable to store account")?;
+        if let Some(group) = &bridge.account_group {
+            set_account_group(bridge, &created.data, group).await?;
+        }
+        state.record_created(
+            ObjectKind::Account,
+            created.data.id,
+            sbanken_account.account_id.as_ref().required("account id")?,
+            sbanken_account,
+        )?;
+        changed = true;
+    }
+
+    let accounts = if changed {
+        AccountMap::new(
+            &sbanken_accounts,
+            refresh_firefly_accounts(bridge, state).await?,
+        )
+    } else {
+        existing
+    };
+    if !bridge.dry_run {
+        for name in accounts.unmapped() {
+            warn!(
+                "Sbanken account '{}' has no account in Firefly, its transactions are skipped",
+                name
+            );
+        }
+    }
+
+    Ok((sbanken_accounts, accounts))
+}
+
+/// Store the transactions which were queued while Firefly was unreachable, before anything new
+/// is imported.
+///
+/// If Firefly is still unreachable the remaining transactions stay queued and the sync is aborted,
+/// so that no account moves on without them.
+async fn flush_queue(bridge: &Bridge, state: &mut State) -> Result<()> {
+    if bridge.dry_run || state.queued.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "Storing {} transaction(s) queued while Firefly was unreachable...",
+        state.queued.len()
+    );
+    let mut queued = std::mem::take(&mut state.queued).into_iter();
+    while let Some(item) = queued.next() {
+        match store_saved(bridge, state, &item).await {
+            Ok(()) => {}
+            Err(e) if firefly::is_unreachable(&e) => {
+                state.queued.push(item);
+                state.queued.extend(queued);
+                return Err(e.context(format!(
+                    "firefly is still unreachable, keeping {} transaction(s) queued",
+                    state.queued.len()
+                )));
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to store queued transaction, keeping it for retry-failed: {}",
+                    e
+                );
+                keep_failed(state, item, "retry", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Store the transactions which Firefly refused earlier, returning how many were stored.
+///
+/// Each transaction is stored with the bridge of the customer whose account it was found on. Those
+/// which are still refused are kept for the next attempt, with the new reason.
+pub async fn retry_failed(bridges: &[Bridge], state: &mut State) -> Result<usize> {
+    // Customer of every Sbanken account, by its account id
+    let mut owners = HashMap::new();
+    for bridge in bridges {
+        for account in list_sbanken_accounts(bridge).await? {
+            if let Some(account_id) = account.account_id {
+                owners.insert(account_id, bridge);
+            }
+        }
+    }
+
+    let mut stored = 0;
+    for failed in std::mem::take(&mut state.failed) {
+        let leg = &failed.item.legs[0];
+        info!(
+            "Failed transaction ({}): {}",
+            Item::new(&failed.item.account, &leg.transaction),
+            failed.reason
+        );
+        let bridge = match owners.get(&leg.account_id) {
+            Some(&bridge) => bridge,
+            None => {
+                warn!("No sbanken customer has the account of the transaction anymore, keeping it");
+                state.failed.push(failed);
+                continue;
+            }
+        };
+        if bridge.dry_run {
+            state.failed.push(failed);
+            continue;
+        }
+
+        match store_saved(bridge, state, &failed.item).await {
+            Ok(()) => stored += 1,
+            Err(e) => {
+                warn!("Still failing: {:#}", e);
+                keep_failed(state, failed.item, "retry", e);
+            }
+        }
+    }
+    Ok(stored)
+}
+
+/// Store a transaction which was converted in an earlier run, and record it like one which was
+/// stored right away.
+async fn store_saved(bridge: &Bridge, state: &mut State, item: &QueuedTransaction) -> Result<()> {
+    let stored = firefly::store_transaction(
+        &bridge.firefly_config,
+        &item.transaction,
+        bridge.fire_webhooks,
+    )
+    .await?;
+    match stored {
+        Stored::Created(id) => {
+            record_outcome(state, &saved_item(item), "retry", Outcome::Created);
+            for leg in &item.legs {
+                state.mark_imported(leg.fingerprint.clone());
+                state.record_created(
+                    ObjectKind::Transaction,
+                    id.clone(),
+                    &leg.account_id,
+                    &leg.transaction,
+                )?;
+            }
+        }
+        Stored::Duplicate => {
+            record_outcome(state, &saved_item(item), "retry", Outcome::Duplicate);
+            for leg in &item.legs {
+                state.mark_imported(leg.fingerprint.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Count what became of a transaction and log it as one event with the account, date and amount
+/// of the transaction, what was done with it and how that went.
+fn record_outcome(state: &mut State, item: &Item, action: &str, outcome: Outcome) {
+    state.count(&item.account, outcome);
+    info!(
+        account = %item.account,
+        date = %item.date,
+        amount = %item.amount,
+        action,
+        outcome = %outcome,
+        "{} ({})",
+        item,
+        outcome
+    );
+}
+
+/// The first leg of a saved transaction, which is the one it is counted on.
+fn saved_item(item: &QueuedTransaction) -> Item {
+    Item::new(&item.account, &item.legs[0].transaction)
+}
+
+/// Count a transaction which Firefly refused as failed, and keep it for `retry-failed`.
+fn keep_failed(state: &mut State, item: QueuedTransaction, action: &str, error: anyhow::Error) {
+    let saved = saved_item(&item);
+    record_outcome(state, &saved, action, Outcome::Failed);
+    reporting::transaction_failed(&saved, action, &error);
+    let reason = format!("{:#}", error);
+    state
+        .failures
+        .push(failure(&item.account, &item.legs[0].transaction, error));
+    state.failed.push(FailedTransaction { reason, item });
+}
+
+/// One line on a transaction of the Firefly account `account` which could not be stored, with
+/// what is needed to find it again.
+fn failure(account: &str, transaction: &TransactionV1, error: anyhow::Error) -> String {
+    SyncError::Store {
+        item: Item::new(account, transaction),
+        source: error,
+    }
+    .report()
+}
+
+/// Make sure the foreign currencies of card transactions can be used in Firefly.
+async fn ensure_currencies(
+    bridge: &Bridge,
+    currencies: &mut Currencies,
+    plan: &mut Plan,
+    sbanken_transactions: &[TransactionV1],
+    details: &[Option<TransactionDetails>],
+) -> Result<()> {
+    for (sbanken_transaction, details) in sbanken_transactions.iter().zip(details) {
+        if let Some(currency) = foreign_currency(sbanken_transaction, details.as_ref())? {
+            currencies.ensure(bridge, &currency, plan).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Convert a single transaction and store it in Firefly, unless it is already there.
+async fn import_transaction(
+    bridge: &Bridge,
+    state: &mut State,
+    plan: &mut Plan,
+    payees: &mut Payees,
+    bills: &Bills,
+    mapped_accounts: &AccountMap,
+    existing: &mut Existing,
+    firefly_account: &AccountRead,
+    account_id: &str,
+    sbanken_transaction: &TransactionV1,
+    details: Option<&TransactionDetails>,
+    fingerprint: String,
+) -> Result<Option<String>> {
+    let upload = match prepare_transaction(
+        bridge,
+        state,
+        plan,
+        payees,
+        bills,
+        mapped_accounts,
+        existing,
+        firefly_account,
+        sbanken_transaction,
+        details,
+        fingerprint,
+    )
+    .await?
+    {
+        Some(upload) => upload,
+        None => return Ok(None),
+    };
+
+    let stored = firefly::store_transaction(
+        &bridge.firefly_config,
+        &upload.transaction,
+        bridge.fire_webhooks,
+    )
+    .await;
+    record_stored(
+        bridge,
+        state,
+        firefly_account,
+        account_id,
+        sbanken_transaction,
+        upload,
+        stored,
+    )
+    .await
+}
+
+/// Most converted transactions of an account which are held in memory before they are stored,
+/// several accounts may hold this many at the same time with `jobs`.
+const UPLOAD_BATCH: usize = 100;
+
+/// A converted transaction which is ready to be stored in Firefly.
+struct Upload {
+    fingerprint: String,
+    transaction: Transaction,
+    /// Transactions in Firefly which the transaction replaces once it is stored.
+    placeholders: Vec<Placeholder>,
+}
+
+/// Transaction in Firefly which stands in for a transaction until it is booked.
+enum Placeholder {
+    Payment(ScheduledPayment),
+    Reservation(Reservation),
+    Transfer(RecordedTransfer),
+}
+
+impl Placeholder {
+    /// Delete the placeholder from Firefly, as the booked transaction is stored.
+    async fn remove(&self, bridge: &Bridge) {
+        match self {
+            Placeholder::Payment(scheduled) => payments::remove(bridge, scheduled).await,
+            Placeholder::Reservation(reservation) => {
+                reservations::remove(bridge, reservation).await
+            }
+            Placeholder::Transfer(recorded) => transfer::remove(bridge, recorded).await,
+        }
+    }
+
+    /// Keep the placeholder in Firefly, as the booked transaction could not be stored.
+    fn restore(self, state: &mut State) {
+        match self {
+            Placeholder::Payment(scheduled) => state.scheduled.push(scheduled),
+            Placeholder::Reservation(reservation) => state.reservations.push(reservation),
+            Placeholder::Transfer(recorded) => state.recorded_transfers.push(recorded),
+        }
+    }
+}
+
+/// Convert a single transaction for storing it in Firefly, unless it is already there or this is
+/// a dry run.
+async fn prepare_transaction(
+    bridge: &Bridge,
+    state: &mut State,
+    plan: &mut Plan,
+    payees: &mut Payees,
+    bills: &Bills,
+    mapped_accounts: &AccountMap,
+    existing: &mut Existing,
+    firefly_account: &AccountRead,
+    sbanken_transaction: &TransactionV1,
+    details: Option<&TransactionDetails>,
+    fingerprint: String,
+) -> Result<Option<Upload>> {
+    let conversion_error = |source| SyncError::Conversion {
+        item: Item::new(&firefly_account.attributes.name, sbanken_transaction),
+        source,
+    };
+
+    // A payment to a loan or mortgage in Firefly is a transfer which pays it down, which the leg
+    // on the loan is part of when the paying account is synced as well. Interest and fees on the
+    // loan stay withdrawals which increase the debt.
+    let amount = Money::of(sbanken_transaction).map_err(conversion_error)?;
+    let loan = match mapped_accounts.counterpart(firefly_account, sbanken_transaction) {
+        Some(loan) if amount.is_negative() && is_loan(loan) => Some(loan),
+        Some(payer)
+            if !amount.is_negative()
+                && is_loan(firefly_account)
+                && mapped_accounts.is_mirror(payer) =>
+        {
+            // Counted once as the transfer, like the second leg of a paired transfer
+            info!(
+                "Payment of {} is imported as a transfer from {}",
+                firefly_account.attributes.name, payer.attributes.name
+            );
+            state.mark_imported(fingerprint);
+            return Ok(None);
+        }
+        _ => None,
+    };
+
+    let mut firefly_transaction = info_span!("convert")
+        .in_scope(|| {
+            convert_transaction(
+                firefly_account,
+                sbanken_transaction,
+                details,
+                loan,
+                &fingerprint,
+                &bridge.conversion,
+            )
+        })
+        .map_err(conversion_error)?;
+
+    if existing
+        .contains(bridge, firefly_account, &firefly_transaction)
+        .await?
+    {
+        let item = Item::new(&firefly_account.attributes.name, sbanken_transaction);
+        record_outcome(state, &item, "import", Outcome::Duplicate);
+        state.mark_imported(fingerprint);
+        return Ok(None);
+    }
+
+    let t = &firefly_transaction.transactions[0];
+    info!(
+        "{} {}: {} -- {} --> {}",
+        t.date,
+        sbanken_transaction
+            .transaction_type
+            .as_deref()
+            .required("transaction type")?,
+        t.source_id
+            .map(|id| format!("<account {}>", id))
+            .or(t.source_name.clone())
+            .unwrap_or("<missing>".into()),
+        t.amount,
+        t.destination_id
+            .map(|id| format!("<account {}>", id))
+            .or(t.destination_name.clone())
+            .unwrap_or("<missing>".into()),
+    );
+
+    if bridge.dry_run {
+        plan.transactions.push(firefly_transaction);
+        return Ok(None);
+    }
+
+    // Payments to the same account number go to the same expense account, whatever their text
+    let split = &mut firefly_transaction.transactions[0];
+    let payee_account_number = sbanken_transaction
+        .other_account_number
+        .as_deref()
+        .filter(|number| !number.trim().is_empty());
+    if let (Some(name), Some(account_number)) =
+        (split.destination_name.clone(), payee_account_number)
+    {
+        match payees.expense_account(bridge, &name, account_number).await {
+            Ok(id) => {
+                split.destination_id = id.parse().ok();
+                split.destination_name = None;
+            }
+            Err(e) => warn!("Unable to find account of payee, using its name: {:?}", e),
+        }
+    }
+
+    if let Some(bill_id) = bills.bill_of(sbanken_transaction) {
+        for split in &mut firefly_transaction.transactions {
+            split.bill_id = bill_id.parse().ok();
+        }
+    }
+
+    Ok(Some(Upload {
+        fingerprint,
+        transaction: firefly_transaction,
+        placeholders: Vec::new(),
+    }))
+}
+
+/// Record the outcome of storing a transaction in Firefly, queueing it if Firefly was unreachable
+/// and keeping it for `retry-failed` if Firefly refused it.
+async fn record_stored(
+    bridge: &Bridge,
+    state: &mut State,
+    firefly_account: &AccountRead,
+    account_id: &str,
+    sbanken_transaction: &TransactionV1,
+    upload: Upload,
+    stored: Result<Stored>,
+) -> Result<Option<String>> {
+    let Upload {
+        fingerprint,
+        transaction: firefly_transaction,
+        placeholders,
+    } = upload;
+    if stored.is_ok() {
+        for placeholder in &placeholders {
+            placeholder.remove(bridge).await;
+        }
+    } else {
+        for placeholder in placeholders {
+            placeholder.restore(state);
+        }
+    }
+    match stored {
+        Ok(Stored::Created(id)) => {
+            let item = Item::new(&firefly_account.attributes.name, sbanken_transaction);
+            record_outcome(state, &item, "import", Outcome::Created);
+            state.mark_imported(fingerprint);
+            state.record_created(
+                ObjectKind::Transaction,
+                id.clone(),
+                account_id,
+                sbanken_transaction,
+            )?;
+            if bridge.attach_source {
+                if let Err(e) = attachments::attach_source(bridge, &id, sbanken_transaction).await {
+                    warn!("Unable to attach the sbanken transaction: {:?}", e);
+                }
+            }
+            return Ok(Some(id));
+        }
+        Ok(Stored::Duplicate) => {
+            let item = Item::new(&firefly_account.attributes.name, sbanken_transaction);
+            record_outcome(state, &item, "import", Outcome::Duplicate);
+            state.mark_imported(fingerprint);
+        }
+        Err(e) => {
+            let item = QueuedTransaction {
+                account: firefly_account.attributes.name.clone(),
+                legs: vec![QueuedLeg {
+                    account_id: account_id.into(),
+                    fingerprint,
+                    transaction: sbanken_transaction.clone(),
+                }],
+                transaction: firefly_transaction,
+            };
+            if firefly::is_unreachable(&e) {
+                warn!("Firefly is unreachable, queueing for the next run: {:#}", e);
+                state.queued.push(item);
+            } else {
+                warn!("Unable to store transaction, skipping: {}", e);
+                keep_failed(state, item, "import", e);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Converted transactions of an account which are waiting to be stored in Firefly.
+struct AccountUploads<'a> {
+    firefly_account: &'a AccountRead,
+    account_id: &'a str,
+    uploads: Vec<(TransactionV1, Upload)>,
+}
+
+/// Store the converted transactions of several accounts, `jobs` accounts and `concurrent_uploads`
+/// transactions of each at a time, and record them in the order they were booked once all are
+/// stored. The other years of the sync go on while the transactions are being stored.
+async fn store_uploads<'a>(
+    context: &Context<'a>,
+    shared: &Mutex<Shared<'a>>,
+    accounts: Vec<AccountUploads<'a>>,
+) -> Result<()> {
+    let bridge = context.bridge;
+    if accounts.is_empty() {
+        return Ok(());
+    }
+
+    let stored: Vec<_> = stream::iter(&accounts)
+        .map(|account| {
+            stream::iter(&account.uploads)
+                .map(|(_, upload)| {
+                    firefly::store_transaction(
+                        &bridge.firefly_config,
+                        &upload.transaction,
+                        bridge.fire_webhooks,
+                    )
+                })
+                .buffered(bridge.concurrent_uploads.max(1))
+                .collect::<Vec<_>>()
+        })
+        .buffered(bridge.jobs.max(1))
+        .collect()
+        .await;
+
+    let mut shared = shared.lock().await;
+    let shared = &mut *shared;
+    for (account, stored) in accounts.into_iter().zip(stored) {
+        let AccountUploads {
+            firefly_account,
+            account_id,
+            uploads,
+        } = account;
+        let failed = stored.iter().filter(|stored| stored.is_err()).count();
+        if failed > 0 {
+            warn!(
+                "Unable to store {} of {} transaction(s) for account {}",
+                failed,
+                stored.len(),
+                firefly_account.attributes.name
+            );
+        }
+
+        let mut created = 0;
+        for ((sbanken_transaction, upload), stored) in uploads.into_iter().zip(stored) {
+            let id = record_stored(
+                bridge,
+                shared.state,
+                firefly_account,
+                account_id,
+                &sbanken_transaction,
+                upload,
+                stored,
+            )
+            .await?;
+            if id.is_some() {
+                created += 1;
+                track_savings(
+                    bridge,
+                    &mut shared.piggy_banks,
+                    &context.savings_accounts,
+                    account_id,
+                    firefly_account,
+                    &sbanken_transaction,
+                )
+                .await;
+            }
+        }
+        info!(
+            "Stored {} transaction(s) for account {}",
+            created, firefly_account.attributes.name
+        );
+    }
+
+    if !bridge.dry_run {
+        context.state_store.checkpoint(shared.state).await?;
+    }
+    Ok(())
+}
+
+/// Piggy bank tracking the balance of the savings account `account_id`, if it has one.
+async fn savings_piggy_bank(
+    bridge: &Bridge,
+    piggy_banks: &mut PiggyBanks,
+    savings_accounts: &HashSet<&str>,
+    account_id: &str,
+    firefly_account: &AccountRead,
+) -> Option<String> {
+    if !savings_accounts.contains(account_id) {
+        return None;
+    }
+
+    let goal = firefly_account
+        .attributes
+        .account_number
+        .as_ref()
+        .and_then(|number| bridge.accounts.get(number))
+        .and_then(|config| config.savings_goal)
+        .unwrap_or(bridge.savings_goal);
+    match piggy_banks
+        .for_savings_account(
+            bridge,
+            &firefly_account.id,
+            &firefly_account.attributes.name,
+            goal,
+        )
+        .await
+    {
+        Ok(id) => Some(id),
+        Err(e) => {
+            warn!("Unable to find piggy bank of savings account: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Move the amount of a transaction on a savings account into or out of its piggy bank, which
+/// Firefly only does by itself for transfers. Errors are only logged, as the piggy bank can be
+/// corrected by hand.
+async fn track_savings(
+    bridge: &Bridge,
+    piggy_banks: &mut PiggyBanks,
+    savings_accounts: &HashSet<&str>,
+    account_id: &str,
+    firefly_account: &AccountRead,
+    sbanken_transaction: &TransactionV1,
+) {
+    let id = match savings_piggy_bank(
+        bridge,
+        piggy_banks,
+        savings_accounts,
+        account_id,
+        firefly_account,
+    )
+    .await
+    {
+        Some(id) => id,
+        None => return,
+    };
+
+    let result = match Money::of(sbanken_transaction) {
+        Ok(amount) => bsu::adjust_piggy_bank(bridge, &id, amount).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warn!("Unable to update piggy bank of savings account: {:?}", e);
+    }
+}
+
+/// The next item of `stream` if it belongs to the chunk `index`.
+async fn next_in_chunk<S, T>(stream: &mut Peekable<S>, index: usize) -> Option<T>
+where
+    S: Stream<Item = (usize, T)> + Unpin,
+{
+    let in_chunk = match Pin::new(&mut *stream).peek().await {
+        Some((next, _)) => *next == index,
+        None => false,
+    };
+    if !in_chunk {
+        return None;
+    }
+    stream.next().await.map(|(_, item)| item)
+}
+
+/// What the years of a sync read but never change.
+struct Context<'a> {
+    bridge: &'a Bridge,
+    sbanken_accounts: &'a [AccountV1],
+    mapped_accounts: &'a AccountMap,
+    state_store: &'a StateStore,
+    bills: &'a Bills,
+    /// Day each account to sync starts at, by its Sbanken account id.
+    account_starts: HashMap<&'a str, NaiveDate>,
+    /// BSU accounts whose deposits are tracked in piggy banks.
+    bsu_accounts: HashSet<&'a str>,
+    /// Savings accounts whose balance is tracked in a piggy bank.
+    savings_accounts: HashSet<&'a str>,
+    /// Transfer legs which wait for their counterpart since an earlier run.
+    pending_fingerprints: HashSet<String>,
+    /// Transfer legs of accounts which are not synced now, which are kept as they are.
+    parked: Vec<PendingTransfer>,
+    progress: Progress,
+    /// Currencies which are usable in Firefly, locked on their own as looking one up takes a
+    /// request.
+    currencies: Mutex<Currencies>,
+}
+
+/// What the years of a sync share, which one year uses at a time while the others fetch or store
+/// transactions.
+struct Shared<'a> {
+    state: &'a mut State,
+    plan: &'a mut Plan,
+    payees: Payees,
+    piggy_banks: PiggyBanks,
+    links: TransactionLinks,
+    existing: Existing,
+    /// Accounts which failed are not synced any further, so that their state stays consistent.
+    failed_accounts: HashSet<&'a str>,
+    /// Chunks which are done, by their index, until every chunk before them is saved as well.
+    done: BTreeMap<usize, DoneChunk<'a>>,
+    /// Index of the next chunk whose progress is saved.
+    next_chunk: usize,
+    /// Transfer legs which wait for their counterpart as of the last saved chunk of each year.
+    pending: BTreeMap<usize, Vec<PendingTransfer>>,
+}
+
+/// Progress of a chunk, which is saved once every chunk before it is saved.
+struct DoneChunk<'a> {
+    year: usize,
+    end: NaiveDate,
+    /// Accounts which were synced successfully in the chunk.
+    synced_accounts: Vec<&'a str>,
+    /// Transfer legs of the year which wait for their counterpart after the chunk.
+    pending: Vec<PendingTransfer>,
+}
+
+impl<'a> Shared<'a> {
+    /// Save the progress of the chunk at `index` together with that of the later chunks which were
+    /// done before it. The cursors only move once every earlier chunk is saved, so that an
+    /// interrupted run never skips a chunk.
+    async fn chunk_done(
+        &mut self,
+        context: &Context<'a>,
+        index: usize,
+        chunk: DoneChunk<'a>,
+    ) -> Result<()> {
+        self.done.insert(index, chunk);
+        while let Some(chunk) = self.done.remove(&self.next_chunk) {
+            for account_id in chunk.synced_accounts {
+                // Held back by a chunk of the account which failed in another year
+                if !self.failed_accounts.contains(account_id) {
+                    self.state.accounts.insert(account_id.into(), chunk.end);
+                }
+            }
+            self.pending.insert(chunk.year, chunk.pending);
+            self.next_chunk += 1;
+        }
+
+        self.state.pending = context
+            .parked
+            .iter()
+            .chain(self.pending.values().flatten())
+            .cloned()
+            .collect();
+        if !context.bridge.dry_run {
+            context.state_store.checkpoint(self.state).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Transfer legs as they are kept in the state until their counterpart shows up.
+fn pending_transfers(legs: &[TransferLeg]) -> Vec<PendingTransfer> {
+    legs.iter()
+        .map(|leg| PendingTransfer {
+            account_id: leg.account_id.into(),
+            fingerprint: leg.fingerprint.clone(),
+            transaction: leg.transaction.clone(),
+        })
+        .collect()
+}
+
+/// What is collected of the accounts of a chunk while they are synced.
+#[derive(Default)]
+struct ChunkWork<'a> {
+    /// Transfer legs which need to be paired with each other.
+    needs_deduplication: Vec<TransferLeg<'a>>,
+    /// Accounts which were synced successfully in the chunk.
+    synced_accounts: Vec<&'a str>,
+    /// Converted transactions which are stored a few accounts at a time.
+    unstored: Vec<AccountUploads<'a>>,
+}
+
+/// Import all transactions up until `last_day` (inclusive) into Firefly.
+///
+/// Every account starts at the day it was last synced according to `state`, or at `first_day` if
+/// it has never been synced. The state is advanced for each account which was synced without
+/// errors, so that a failing account does not hold back the others.
+///
+/// Transactions flow through the sync as they arrive: each account is handled as soon as its chunk
+/// is fetched, and converted transactions are stored a batch at a time, so that the memory used
+/// does not grow with the history of the accounts.
+///
+/// Each year is synced on its own, `parallel_years` of them at a time, and pairs the transfers
+/// within it. The legs near the ends of the years are paired across them once every year is done.
+///
+/// Progress is written to `state_store` after every batch of accounts and chunk, in the order of
+/// the chunks, so that an interrupted run does not post the same transactions again.
+pub async fn sync_transactions(
+    bridge: &Bridge,
+    sbanken_accounts: &[AccountV1],
+    mapped_accounts: &AccountMap,
+    state: &mut State,
+    state_store: &StateStore,
+    first_day: NaiveDate,
+    last_day: NaiveDate,
+    plan: &mut Plan,
+) -> Result<()> {
+    flush_queue(bridge, state).await?;
+
+    let mut account_starts = HashMap::new();
+    for sbanken_account in sbanken_accounts.iter() {
+        let account_id = sbanken_account.account_id.as_ref().required("account id")?;
+        let start = state.last_sync(account_id).unwrap_or(first_day);
+
+        if start >= last_day {
+            info!(
+                "Already updated everything until {} for account {}",
+                start,
+                sbanken_account.name.as_ref().required("account name")?
+            );
+            continue;
+        }
+
+        account_starts.insert(account_id.as_str(), start);
+    }
+
+    let first_day = match account_starts.values().min() {
+        Some(&day) => day,
+        None => return Ok(()),
+    };
+
+    // Firefly refuses transactions in currencies which do not exist or are disabled
+    let mut currencies = Currencies::default();
+    currencies
+        .ensure(bridge, CURRENCY_CODE, plan)
+        .await
+        .context("unable to use the currency of sbanken accounts in firefly")?;
+
+    let mut bills = Bills::default();
+    if bridge.efaktura_bills {
+        if let Err(e) = bills.sync(bridge, plan).await {
+            warn!("Unable to sync efakturas to bills: {:?}", e);
+        }
+    }
+
+    // BSU accounts whose deposits are tracked in piggy banks
+    let bsu_accounts: HashSet<&str> = sbanken_accounts
+        .iter()
+        .filter(|account| {
+            bridge.bsu_piggy_bank && account.account_type.as_deref() == Some(bsu::BSU_ACCOUNT_TYPE)
+        })
+        .filter_map(|account| account.account_id.as_deref())
+        .collect();
+
+    // Savings accounts whose balance is tracked in a piggy bank
+    let savings_accounts: HashSet<&str> = mapped_accounts
+        .iter()
+        .filter(|(account_id, firefly_account)| {
+            bridge.savings_piggy_banks
+                && !bsu_accounts.contains(*account_id)
+                && firefly_account.attributes.account_role
+                    == Some(firefly_iii::models::account::AccountRole::SavingAsset)
+        })
+        .map(|(account_id, _)| account_id)
+        .collect();
+
+    // Transfer legs left over from earlier runs are matched again, while those of accounts which
+    // are not synced now are kept as they are
+    let (carried, parked): (Vec<_>, Vec<_>) = state
+        .pending
+        .iter()
+        .cloned()
+        .partition(|leg| mapped_accounts.is_mapped(&leg.account_id));
+    let pending_fingerprints: HashSet<_> =
+        carried.iter().map(|leg| leg.fingerprint.clone()).collect();
+    let mut leftovers = carried
+        .iter()
+        .map(|leg| {
+            Ok(TransferLeg {
+                account_id: leg.account_id.as_str(),
+                fingerprint: leg.fingerprint.clone(),
+                amount: Money::of(&leg.transaction)?,
+                day: bridge.conversion.date_field.day(&leg.transaction)?,
+                transaction: leg.transaction.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+        .context("invalid pending transfer in state")?;
+
+    let chunks = bridge.chunk.windows(first_day, last_day);
+
+    let progress = Progress::new(
+        chunks.len(),
+        sbanken_accounts.iter().filter_map(|account| {
+            let account_id = account.account_id.as_deref()?;
+            if !account_starts.contains_key(account_id) {
+                return None;
+            }
+            Some((account_id, account.name.as_deref().unwrap_or(account_id)))
+        }),
+    );
+
+    // The chunks of every year, with their index among all chunks
+    let mut years: Vec<(i32, Vec<_>)> = Vec::new();
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let (chunk_start, _) = chunk;
+        match years.last_mut() {
+            Some((year, chunks)) if *year == chunk_start.year() => chunks.push((index, chunk)),
+            _ => years.push((chunk_start.year(), vec![(index, chunk)])),
+        }
+    }
+
+    let context = Context {
+        bridge,
+        sbanken_accounts,
+        mapped_accounts,
+        state_store,
+        bills: &bills,
+        account_starts,
+        bsu_accounts,
+        savings_accounts,
+        pending_fingerprints,
+        parked,
+        progress,
+        currencies: Mutex::new(currencies),
+    };
+    let mut pending = BTreeMap::new();
+    // Until the first chunk is saved, the legs of earlier runs still wait where they were
+    pending.insert(0, carried.clone());
+    let shared = Mutex::new(Shared {
+        state,
+        plan,
+        payees: Payees::default(),
+        piggy_banks: PiggyBanks::default(),
+        links: TransactionLinks::default(),
+        existing: Existing::default(),
+        failed_accounts: HashSet::new(),
+        done: BTreeMap::new(),
+        next_chunk: 0,
+        pending,
+    });
+
+    // Every year pairs the transfers within it, starting with the legs left over from earlier runs
+    let held = stream::iter(years.into_iter().map(|(_, chunks)| chunks).enumerate())
+        .map(|(year, chunks)| {
+            let leftovers = std::mem::take(&mut leftovers);
+            sync_year(&context, &shared, year, chunks, leftovers)
+        })
+        .buffered(bridge.parallel_years.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    // A transfer may start near the end of one year and arrive near the start of the next
+    let legs = held.into_iter().flatten().collect();
+    let pending_days = bridge.transfer_matching.pending_days;
+    let waiting = handle_transfers(&context, &shared, legs, |leg| {
+        (last_day - leg.day).num_days() < pending_days
+    })
+    .await?;
+
+    let mut shared = shared.lock().await;
+    // The counterpart of an unmatched leg often books a day later, so it is kept for the next run,
+    // until it has waited long enough to be imported on its own
+    for leg in &waiting {
+        let account = mapped_accounts.require(leg.account_id)?;
+        let accounting_date = leg
+            .transaction
+            .accounting_date
+            .as_ref()
+            .required("accounting date")?;
+        info!(
+            "Unmatched transfer leg, waiting for its counterpart: {} : {} -- {:>6} : {}",
+            accounting_date,
+            account.attributes.name,
+            leg.amount,
+            leg.transaction.text.as_ref().required("transaction text")?,
+        );
+        shared.plan.skipped.push(format!(
+            "{} : {} -- {:>6} : transfer leg waiting for its counterpart",
+            accounting_date, account.attributes.name, leg.amount,
+        ));
+    }
+    shared.state.pending = context
+        .parked
+        .iter()
+        .cloned()
+        .chain(pending_transfers(&waiting))
+        .collect();
+    if !bridge.dry_run {
+        state_store.checkpoint(shared.state).await?;
+    }
+
+    Ok(())
+}
+
+/// Sync the `chunks` of a year one after the other, pairing the transfers within the year on
+/// their own, starting with the legs in `leftovers`.
+///
+/// Returns the legs which are still unmatched near either end of the year, whose counterpart may
+/// be in the year before or after it.
+async fn sync_year<'a>(
+    context: &Context<'a>,
+    shared: &Mutex<Shared<'a>>,
+    year: usize,
+    chunks: Vec<(usize, (NaiveDate, NaiveDate))>,
+    mut leftovers: Vec<TransferLeg<'a>>,
+) -> Result<Vec<TransferLeg<'a>>> {
+    let bridge = context.bridge;
+    let (_, (year_start, _)) = chunks[0];
+
+    // Every account to sync in every chunk, with the day it starts at in that chunk
+    let windows = chunks
+        .iter()
+        .flat_map(|&(index, (chunk_start, chunk_end))| {
+            context
+                .sbanken_accounts
+                .iter()
+                .filter_map(move |sbanken_account| {
+                    let account_id = sbanken_account.account_id.as_deref()?;
+                    match context.account_starts.get(account_id) {
+                        Some(&start) if start <= chunk_end => Some((
+                            index,
+                            (
+                                sbanken_account,
+                                account_id,
+                                std::cmp::max(start, chunk_start),
+                                chunk_end,
+                            ),
+                        )),
+                        _ => None,
+                    }
+                })
+        })
+        .collect::<Vec<_>>();
+
+    // Fetch several accounts at a time, and handle each of them in order as soon as it has
+    // arrived instead of holding the chunk of every account in memory
+    let mut fetches = stream::iter(windows)
+        .map(
+            |(index, (sbanken_account, account_id, window_start, chunk_end))| async move {
+                let fetch_span = info_span!(
+                    "fetch",
+                    account = %sbanken_account.name.as_deref().unwrap_or_default(),
+                    from = %window_start,
+                    to = %chunk_end,
+                );
+                let result = transactions::fetch(bridge, account_id, window_start, chunk_end)
+                    .instrument(fetch_span)
+                    .await;
+                (index, (sbanken_account, account_id, window_start, result))
+            },
+        )
+        .buffered(bridge.jobs.max(1))
+        .peekable();
+
+    // Do one chunk at a time, transfer legs near its end are carried over to the next one
+    for (index, (chunk_start, chunk_end)) in chunks {
+        let chunk_span = info_span!("chunk", from = %chunk_start, to = %chunk_end);
+        context.progress.chunk(index);
+        async {
+            let mut work = ChunkWork {
+                needs_deduplication: std::mem::take(&mut leftovers),
+                ..ChunkWork::default()
+            };
+
+            // Loop through all transactions for all accounts and add them to firefly
+            while let Some((sbanken_account, account_id, window_start, fetched)) =
+                next_in_chunk(&mut fetches, index).await
+            {
+                shutdown::check()?;
+
+                let account_span = info_span!(
+                    "account",
+                    name = %sbanken_account.name.as_deref().unwrap_or_default(),
+                    from = %window_start,
+                );
+                sync_account(
+                    context,
+                    shared,
+                    &mut work,
+                    sbanken_account,
+                    account_id,
+                    (window_start, chunk_end),
+                    fetched,
+                )
+                .instrument(account_span)
+                .await?;
+            }
+
+            // Transfers are only paired once every account of the chunk is stored
+            store_uploads(context, shared, std::mem::take(&mut work.unstored)).await?;
+
+            // Legs near the start of the year may belong to a transfer from the year before
+            let pending_days = bridge.transfer_matching.pending_days;
+            let date_tolerance = bridge.transfer_matching.date_tolerance;
+            leftovers = handle_transfers(context, shared, work.needs_deduplication, |leg| {
+                (chunk_end - leg.day).num_days() < pending_days
+                    || (year > 0 && (leg.day - year_start).num_days() <= date_tolerance)
+            })
+            .await?;
+
+            let done = DoneChunk {
+                year,
+                end: chunk_end,
+                synced_accounts: work.synced_accounts,
+                pending: pending_transfers(&leftovers),
+            };
+            shared.lock().await.chunk_done(context, index, done).await
+        }
+        .instrument(chunk_span)
+        .await?;
+    }
+
+    Ok(leftovers)
+}
+
+/// Convert the transactions of an account which were fetched from `window_start` to `chunk_end`,
+/// and queue them in `work` to be stored in Firefly.
+async fn sync_account<'a>(
+    context: &Context<'a>,
+    shared: &Mutex<Shared<'a>>,
+    work: &mut ChunkWork<'a>,
+    sbanken_account: &'a AccountV1,
+    account_id: &'a str,
+    (window_start, chunk_end): (NaiveDate, NaiveDate),
+    fetched: Result<Vec<(TransactionV1, Option<TransactionDetails>)>>,
+) -> Result<()> {
+    let bridge = context.bridge;
+    let account_name = sbanken_account.name.as_deref().required("account name")?;
+
+    let mut guard = shared.lock().await;
+    let locked = &mut *guard;
+
+    // Fetched ahead before an earlier chunk of the account failed
+    if locked.failed_accounts.contains(account_id) {
+        return Ok(());
+    }
+
+    let sbanken_transactions = match fetched {
+        Ok(sbanken_transactions) => sbanken_transactions,
+        Err(e) => {
+            let skipped = SyncError::Fetch {
+                account: account_name.into(),
+                from: window_start,
+                to: chunk_end,
+                source: e,
+            }
+            .report();
+            info!("{}, skipping", skipped);
+            locked.state.failures.push(skipped.clone());
+            locked.plan.skipped.push(skipped);
+            locked.failed_accounts.insert(account_id);
+            return Ok(());
+        }
+    };
+
+    info!(
+        "Found {} transaction(s) for account {}",
+        sbanken_transactions.len(),
+        account_name
+    );
+    context
+        .progress
+        .fetched(account_id, sbanken_transactions.len());
+
+    let firefly_account = match context.mapped_accounts.get(account_id) {
+        Some(firefly_account) => firefly_account,
+        None => {
+            locked.plan.skipped.push(format!(
+                "{} ({} - {}): no matching account in Firefly",
+                account_name, window_start, chunk_end,
+            ));
+            return Ok(());
+        }
+    };
+
+    drop(guard);
+
+    info!("Updating transactions...");
+
+    // Firefly is asked without holding the lock, so that the other accounts and years go on
+    let (sbanken_transactions, details): (Vec<_>, Vec<_>) =
+        sbanken_transactions.into_iter().unzip();
+    let mut notes = Plan::default();
+    let (start, end) = sbanken_transactions
+        .iter()
+        .filter_map(|transaction| bridge.conversion.date_field.day(transaction).ok())
+        .fold((window_start, chunk_end), |(start, end), day| {
+            (start.min(day), end.max(day))
+        });
+    let covered = shared
+        .lock()
+        .await
+        .existing
+        .covers(&firefly_account.id, start, end);
+    let prepared = async {
+        let fingerprints = fingerprints(
+            account_id,
+            &firefly_account.attributes.name,
+            &sbanken_transactions,
+        )?;
+        ensure_currencies(
+            bridge,
+            &mut *context.currencies.lock().await,
+            &mut notes,
+            &sbanken_transactions,
+            &details,
+        )
+        .await?;
+        let splits = if covered {
+            Vec::new()
+        } else {
+            Existing::fetch_window(bridge, firefly_account, start, end).await?
+        };
+        Ok::<_, anyhow::Error>((fingerprints, splits))
+    }
+    .await;
+
+    let mut guard = shared.lock().await;
+    let locked = &mut *guard;
+    locked.plan.currencies.append(&mut notes.currencies);
+    let fingerprints = match prepared {
+        Ok((fingerprints, splits)) => {
+            if !covered {
+                locked
+                    .existing
+                    .insert(&firefly_account.id, (start, end), splits);
+            }
+            fingerprints
+        }
+        Err(e) => {
+            warn!(
+                "Unable to import transactions of account {}, skipping: {:#}",
+                account_name, e
+            );
+            let skipped = format!(
+                "{} ({} - {}): {:#}",
+                account_name, window_start, chunk_end, e
+            );
+            locked.state.failures.push(skipped.clone());
+            locked.plan.skipped.push(skipped);
+            locked.failed_accounts.insert(account_id);
+            return Ok(());
+        }
+    };
+    drop(guard);
+
+    let mut transactions = sbanken_transactions
+        .into_iter()
+        .zip(details)
+        .zip(fingerprints)
+        .peekable();
+    while transactions.peek().is_some() {
+        let mut guard = shared.lock().await;
+        let locked = &mut *guard;
+
+        let mut uploads = Vec::new();
+        // Replaced by transactions which are already in Firefly, deleted once the lock is released
+        let mut replaced = Vec::new();
+        for ((sbanken_transaction, details), fingerprint) in &mut transactions {
+            // Stop before the account is marked as synced, so that the rest of it is fetched
+            // again on the next run
+            shutdown::check()?;
+            context.progress.handled(account_id);
+            if locked.state.is_imported(&fingerprint) {
+                locked
+                    .state
+                    .count(&firefly_account.attributes.name, Outcome::Duplicate);
+                continue;
+            }
+            if context.pending_fingerprints.contains(&fingerprint) {
+                // Already waiting for its counterpart since an earlier run
+                continue;
+            }
+            if bridge.import_reservations && reservations::is_reservation(&sbanken_transaction) {
+                // Imported as pending transactions by `sync_reservations`
+                continue;
+            }
+
+            if bridge
+                .transfer_matching
+                .is_internal_transfer(&sbanken_transaction)
+            {
+                let amount = Money::of(&sbanken_transaction)?;
+                info!(
+                    "{} {}: {} -- {} -- {} **internal transaction for dedup**",
+                    DateField::Accounting.date(&sbanken_transaction)?,
+                    sbanken_transaction
+                        .transaction_type
+                        .as_deref()
+                        .required("transaction type")?,
+                    &firefly_account.attributes.name,
+                    amount,
+                    sbanken_transaction
+                        .text
+                        .as_deref()
+                        .required("transaction text")?,
+                );
+
+                // Transaction is an internal bank transfer and has to be deduplicated.
+                work.needs_deduplication.push(TransferLeg {
+                    account_id,
+                    fingerprint,
+                    amount,
+                    day: bridge.conversion.date_field.day(&sbanken_transaction)?,
+                    transaction: sbanken_transaction,
+                });
+                continue;
+            }
+
+            let item = Item::new(&firefly_account.attributes.name, &sbanken_transaction);
+            let transaction_span = info_span!(
+                "transaction",
+                date = %item.date,
+                amount = %item.amount
+            );
+            let (upload, placeholders) = async {
+                let mut placeholders = Vec::new();
+                if bridge.scheduled_payments {
+                    if let Some(scheduled) =
+                        payments::take_paid(bridge, locked.state, account_id, &sbanken_transaction)?
+                    {
+                        info!(
+                            "Replacing scheduled transaction of payment '{}'",
+                            scheduled.payment.text.as_deref().unwrap_or_default()
+                        );
+                        placeholders.push(Placeholder::Payment(scheduled));
+                    }
+                }
+                if bridge.import_reservations {
+                    if let Some(reservation) = reservations::take_booked(
+                        bridge,
+                        locked.state,
+                        account_id,
+                        &sbanken_transaction,
+                    )? {
+                        let text = reservation.transaction.text.as_deref();
+                        info!(
+                            "Replacing pending transaction of reservation '{}'",
+                            text.unwrap_or_default()
+                        );
+                        placeholders.push(Placeholder::Reservation(reservation));
+                    }
+                }
+
+                let upload = prepare_transaction(
+                    bridge,
+                    locked.state,
+                    locked.plan,
+                    &mut locked.payees,
+                    context.bills,
+                    context.mapped_accounts,
+                    &mut locked.existing,
+                    firefly_account,
+                    &sbanken_transaction,
+                    details.as_ref(),
+                    fingerprint,
+                )
+                .await?;
+                Ok::<_, anyhow::Error>((upload, placeholders))
+            }
+            .instrument(transaction_span)
+            .await?;
+            match upload {
+                Some(upload) => uploads.push((
+                    sbanken_transaction,
+                    Upload {
+                        placeholders,
+                        ..upload
+                    },
+                )),
+                // Already in Firefly, or a dry run
+                None => replaced.extend(placeholders),
+            }
+
+            if uploads.len() >= UPLOAD_BATCH {
+                break;
+            }
+        }
+        drop(guard);
+
+        for placeholder in &replaced {
+            placeholder.remove(bridge).await;
+        }
+        if !uploads.is_empty() {
+            work.unstored.push(AccountUploads {
+                firefly_account,
+                account_id,
+                uploads,
+            });
+        }
+        // The uploads of the next accounts are stored together with these
+        if work.unstored.len() >= bridge.jobs.max(1) {
+            store_uploads(context, shared, std::mem::take(&mut work.unstored)).await?;
+        }
+    }
+
+    work.synced_accounts.push(account_id);
+    Ok(())
+}
+
+/// Pair the transfer `legs` and store the transfers, keeping the unmatched legs for which `keep`
+/// is true for pairing them again later and importing the others on their own.
+///
+/// Returns the legs which are kept.
+async fn handle_transfers<'a>(
+    context: &Context<'a>,
+    shared: &Mutex<Shared<'a>>,
+    legs: Vec<TransferLeg<'a>>,
+    keep: impl Fn(&TransferLeg) -> bool,
+) -> Result<Vec<TransferLeg<'a>>> {
+    let bridge = context.bridge;
+    let mapped_accounts = context.mapped_accounts;
+    let mut guard = shared.lock().await;
+    let locked = &mut *guard;
+
+    let transfers = info_span!("dedup", legs = legs.len())
+        .in_scope(|| pair_transfers(legs, &bridge.transfer_matching));
+    metrics::transfers_matched(transfers.pairs.len());
+    locked.state.transfers_matched += transfers.pairs.len() as u64;
+
+    for (from, to) in transfers.pairs {
+        let (from_trans, to_trans) = (&from.transaction, &to.transaction);
+        let from_account = mapped_accounts.require(from.account_id)?;
+        let to_account = mapped_accounts.require(to.account_id)?;
+
+        info!(
+            "{} ({}) : {} -- {:>6} ({:>6}) --> {} : {} ({})",
+            from_trans
+                .accounting_date
+                .as_ref()
+                .required("accounting date")?,
+            to_trans
+                .accounting_date
+                .as_ref()
+                .required("accounting date")?,
+            from_account.attributes.name,
+            from.amount,
+            to.amount,
+            to_account.attributes.name,
+            from_trans.text.as_ref().required("transaction text")?,
+            to_trans.text.as_ref().required("transaction text")?,
+        );
+
+        let mut firefly_transaction = info_span!("convert")
+            .in_scope(|| {
+                convert_transaction(
+                    from_account,
+                    from_trans,
+                    None,
+                    Some(to_account),
+                    &from.fingerprint,
+                    &bridge.conversion,
+                )
+            })
+            .map_err(|source| SyncError::Conversion {
+                item: Item::new(&from_account.attributes.name, from_trans),
+                source,
+            })?;
+
+        let recorded = transfer::take_booked(
+            locked.state,
+            from.account_id,
+            to.account_id,
+            to.amount,
+            to.day,
+        )
+        .map(Placeholder::Transfer);
+        if recorded.is_some() {
+            info!("Replacing transfer recorded by the transfer command");
+        }
+
+        if bridge.transfer_matching.separate_legs {
+            let mut ids = Vec::new();
+            for (leg, account) in &[(&from, from_account), (&to, to_account)] {
+                let id = import_transaction(
+                    bridge,
+                    locked.state,
+                    locked.plan,
+                    &mut locked.payees,
+                    context.bills,
+                    context.mapped_accounts,
+                    &mut locked.existing,
+                    account,
+                    leg.account_id,
+                    &leg.transaction,
+                    None,
+                    leg.fingerprint.clone(),
+                )
+                .await?;
+                if id.is_some() {
+                    track_savings(
+                        bridge,
+                        &mut locked.piggy_banks,
+                        &context.savings_accounts,
+                        leg.account_id,
+                        account,
+                        &leg.transaction,
+                    )
+                    .await;
+                }
+                ids.push(id);
+            }
+            if let Some(recorded) = recorded {
+                if [&from, &to]
+                    .iter()
+                    .all(|leg| locked.state.is_imported(&leg.fingerprint))
+                {
+                    recorded.remove(bridge).await;
+                } else {
+                    recorded.restore(locked.state);
+                }
+            }
+            if let [Some(from_id), Some(to_id)] = ids.as_slice() {
+                let notes = format!(
+                    "Transfer from {} to {}",
+                    from_account.attributes.name, to_account.attributes.name
+                );
+                if let Err(e) = locked.links.link(bridge, from_id, to_id, &notes).await {
+                    warn!("Unable to link the legs of the transfer: {:?}", e);
+                }
+            }
+            continue;
+        }
+
+        if locked
+            .existing
+            .contains(bridge, from_account, &firefly_transaction)
+            .await?
+        {
+            if let Some(recorded) = &recorded {
+                recorded.remove(bridge).await;
+            }
+            let item = Item::new(&from_account.attributes.name, &from.transaction);
+            record_outcome(locked.state, &item, "transfer", Outcome::Duplicate);
+            locked.state.mark_imported(from.fingerprint);
+            locked.state.mark_imported(to.fingerprint);
+            continue;
+        }
+
+        if bridge.dry_run {
+            locked.plan.transfers.push(firefly_transaction);
+            continue;
+        }
+
+        if context.bsu_accounts.contains(to.account_id) {
+            let year = to.day.year();
+            match locked
+                .piggy_banks
+                .for_year(bridge, &to_account.id, &to_account.attributes.name, year)
+                .await
+            {
+                Ok(id) => {
+                    for split in &mut firefly_transaction.transactions {
+                        split.piggy_bank_id = id.parse().ok();
+                    }
+                }
+                Err(e) => warn!("Unable to find piggy bank of BSU account: {:?}", e),
+            }
+        } else {
+            // Firefly moves the money of a transfer into or out of the piggy bank by itself
+            let savings_leg = if context.savings_accounts.contains(to.account_id) {
+                Some((to.account_id, to_account))
+            } else if context.savings_accounts.contains(from.account_id) {
+                Some((from.account_id, from_account))
+            } else {
+                None
+            };
+            if let Some((account_id, account)) = savings_leg {
+                if let Some(id) = savings_piggy_bank(
+                    bridge,
+                    &mut locked.piggy_banks,
+                    &context.savings_accounts,
+                    account_id,
+                    account,
+                )
+                .await
+                {
+                    for split in &mut firefly_transaction.transactions {
+                        split.piggy_bank_id = id.parse().ok();
+                    }
+                }
+            }
+        }
+
+        let stored = firefly::store_transaction(
+            &bridge.firefly_config,
+            &firefly_transaction,
+            bridge.fire_webhooks,
+        )
+        .await;
+        if let Some(recorded) = recorded {
+            if stored.is_ok() {
+                recorded.remove(bridge).await;
+            } else {
+                recorded.restore(locked.state);
+            }
+        }
+        match stored {
+            Ok(Stored::Created(id)) => {
+                let item = Item::new(&from_account.attributes.name, &from.transaction);
+                record_outcome(locked.state, &item, "transfer", Outcome::Created);
+                locked.state.mark_imported(from.fingerprint);
+                locked.state.mark_imported(to.fingerprint);
+                for leg in &[&from, &to] {
+                    locked.state.record_created(
+                        ObjectKind::Transaction,
+                        id.clone(),
+                        leg.account_id,
+                        &leg.transaction,
+                    )?;
+                }
+                if bridge.attach_source {
+                    if let Err(e) = attachments::attach_source(bridge, &id, &from.transaction).await
+                    {
+                        warn!("Unable to attach the sbanken transaction: {:?}", e);
+                    }
+                }
+            }
+            Ok(Stored::Duplicate) => {
+                let item = Item::new(&from_account.attributes.name, &from.transaction);
+                record_outcome(locked.state, &item, "transfer", Outcome::Duplicate);
+                locked.state.mark_imported(from.fingerprint);
+                locked.state.mark_imported(to.fingerprint);
+            }
+            Err(e) => {
+                let item = QueuedTransaction {
+                    account: from_account.attributes.name.clone(),
+                    legs: vec![&from, &to]
+                        .into_iter()
+                        .map(|leg| QueuedLeg {
+                            account_id: leg.account_id.into(),
+                            fingerprint: leg.fingerprint.clone(),
+                            transaction: leg.transaction.clone(),
+                        })
+                        .collect(),
+                    transaction: firefly_transaction.clone(),
+                };
+                if firefly::is_unreachable(&e) {
+                    warn!("Firefly is unreachable, queueing for the next run: {:#}", e);
+                    locked.state.queued.push(item);
+                } else {
+                    warn!("Unable to store transaction, skipping: {}", e);
+                    keep_failed(locked.state, item, "transfer", e);
+                }
+            }
+        }
+    }
+
+    let mut kept = Vec::new();
+    for leg in transfers.unmatched {
+        if keep(&leg) {
+            kept.push(leg);
+            continue;
+        }
+
+        let transaction = &leg.transaction;
+        let account = mapped_accounts.require(leg.account_id)?;
+        info!(
+            "Unmatched transfer leg, importing it on its own: {} : {} -- {:>6} : {}",
+            transaction
+                .accounting_date
+                .as_ref()
+                .required("accounting date")?,
+            account.attributes.name,
+            leg.amount,
+            transaction.text.as_ref().required("transaction text")?,
+        );
+        let id = import_transaction(
+            bridge,
+            locked.state,
+            locked.plan,
+            &mut locked.payees,
+            context.bills,
+            context.mapped_accounts,
+            &mut locked.existing,
+            account,
+            leg.account_id,
+            &leg.transaction,
+            None,
+            leg.fingerprint,
+        )
+        .await?;
+        if id.is_some() {
+            track_savings(
+                bridge,
+                &mut locked.piggy_banks,
+                &context.savings_accounts,
+                leg.account_id,
+                account,
+                &leg.transaction,
+            )
+            .await;
+        }
+    }
+
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use firefly_iii::models::TransactionSplit;
+
+    fn transaction(amount: &str, description: &str, external_id: Option<&str>) -> Transaction {
+        let mut split = TransactionSplit::new(
+            "2020-03-01".into(),
+            amount.into(),
+            description.into(),
+            None,
+            None,
+        );
+        split.external_id = external_id.map(Into::into);
+        Transaction::new(vec![split])
+    }
+
+    fn existing(splits: Vec<(&str, &str, Option<&str>)>) -> (Existing, NaiveDate) {
+        let day = NaiveDate::from_ymd(2020, 3, 1);
+        let splits = splits
+            .into_iter()
+            .map(|(amount, description, external_id)| ListedSplit {
+                date: "2020-03-01T00:00:00+01:00".into(),
+                amount: amount.into(),
+                description: description.into(),
+                external_id: external_id.map(Into::into),
+            })
+            .collect();
+        let mut existing = Existing::default();
+        existing.splits.insert(("1".into(), day), splits);
+        (existing, day)
+    }
+
+    #[test]
+    fn finds_transaction_by_external_id_and_amount() {
+        let (existing, day) = existing(vec![("120.500000000000", "Kiwi", Some("abc"))]);
+        assert!(existing.has("1", day, &transaction("120.50", "KIWI 123", Some("abc"))));
+        assert!(!existing.has("1", day, &transaction("120.50", "Kiwi", Some("def"))));
+        assert!(!existing.has("1", day, &transaction("120.00", "Kiwi", Some("abc"))));
+    }
+
+    #[test]
+    fn falls_back_to_description_without_external_id() {
+        let (existing, day) = existing(vec![("99.000000000000", "Rema", None)]);
+        assert!(existing.has("1", day, &transaction("99.00", "Rema", Some("abc"))));
+        assert!(!existing.has("1", day, &transaction("99.00", "Coop", Some("abc"))));
+    }
+
+    #[test]
+    fn only_looks_at_the_same_account_and_day() {
+        let (existing, day) = existing(vec![("10.00", "Kiwi", Some("abc"))]);
+        let transaction = transaction("10.00", "Kiwi", Some("abc"));
+        assert!(!existing.has("2", day, &transaction));
+        assert!(!existing.has("1", day.succ(), &transaction));
+    }
+
+    #[test]
+    fn recorded_transfer_is_not_the_booked_transfer() {
+        let recorded = "sbanken-transfer-a-b-2020-03-01";
+        let (existing, day) = existing(vec![("500.00", "Sparing", Some(recorded))]);
+        assert!(!existing.has("1", day, &transaction("500.00", "Sparing", Some("abc"))));
+    }
+}
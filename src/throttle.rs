@@ -0,0 +1,88 @@
+//! Client side rate limiting of the requests to each API, so that large backfills do not get
+//! throttled by Sbanken or Firefly.
+
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The APIs which requests are made to, each with its own rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Api {
+    Sbanken,
+    Firefly,
+}
+
+impl Api {
+    /// Name of the API in metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            Api::Sbanken => "sbanken",
+            Api::Firefly => "firefly",
+        }
+    }
+
+    fn bucket(self) -> &'static Mutex<Bucket> {
+        match self {
+            Api::Sbanken => &SBANKEN,
+            Api::Firefly => &FIREFLY,
+        }
+    }
+}
+
+/// Token bucket which holds up to a second worth of requests.
+struct Bucket {
+    /// Requests per second, unlimited if not set.
+    rate: Option<f64>,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new() -> Bucket {
+        Bucket {
+            rate: None,
+            tokens: 0.0,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Take a token, or return how long to wait until one is available.
+    fn take(&mut self) -> Option<Duration> {
+        let rate = self.rate?;
+        let now = Instant::now();
+        let capacity = rate.max(1.0);
+        self.tokens = (self.tokens + (now - self.refilled).as_secs_f64() * rate).min(capacity);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+lazy_static! {
+    static ref SBANKEN: Mutex<Bucket> = Mutex::new(Bucket::new());
+    static ref FIREFLY: Mutex<Bucket> = Mutex::new(Bucket::new());
+}
+
+/// Make at most `requests_per_second` requests to `api`, or any number if not set.
+pub fn set_rate(api: Api, requests_per_second: Option<f64>) {
+    let mut bucket = api.bucket().lock().unwrap();
+    bucket.rate = requests_per_second.filter(|rate| *rate > 0.0);
+    bucket.tokens = bucket.rate.map_or(0.0, |rate| rate.max(1.0));
+    bucket.refilled = Instant::now();
+}
+
+/// Wait until a request may be made to `api`.
+pub async fn acquire(api: Api) {
+    loop {
+        let wait = api.bucket().lock().unwrap().take();
+        match wait {
+            Some(wait) => tokio::time::delay_for(wait).await,
+            None => return,
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Transactions from Sbanken, either through the generated client of the v1 API or from the v2
+//! endpoint, which has more details about card payments and counterparties.
+//!
+//! Old transactions always come from the v2 archive endpoint, as the others only reach a year back.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, NaiveDate};
+use sbanken::models::TransactionV1;
+use secrecy::ExposeSecret;
+use serde::Deserialize;
+use std::str::FromStr;
+
+use crate::bank;
+use crate::retry;
+use crate::sync::Bridge;
+use crate::throttle::Api;
+use crate::DATE_FORMAT;
+
+/// Most transactions Sbanken returns for a single request, more are fetched a page at a time.
+const MAX_TRANSACTIONS: i32 = 1000;
+
+/// Number of days back the regular transactions endpoints reach, older transactions are only
+/// available from the archive endpoint.
+const LIVE_WINDOW_DAYS: i64 = 365;
+
+/// Version of the Sbanken transactions endpoint to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+impl Default for ApiVersion {
+    fn default() -> Self {
+        ApiVersion::V1
+    }
+}
+
+impl FromStr for ApiVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "v1" => Ok(ApiVersion::V1),
+            "v2" => Ok(ApiVersion::V2),
+            _ => Err(anyhow!("unknown sbanken api version '{}'", s)),
+        }
+    }
+}
+
+/// Size of the windows which transactions are fetched and transfers are paired in, smaller chunks
+/// keep fewer transactions in memory at a time and stay further below the limits of Sbanken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Chunk {
+    Month,
+    Year,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Chunk::Month
+    }
+}
+
+impl FromStr for Chunk {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "month" => Ok(Chunk::Month),
+            "year" => Ok(Chunk::Year),
+            _ => Err(anyhow!("unknown chunk size '{}'", s)),
+        }
+    }
+}
+
+impl Chunk {
+    /// Last day of the chunk which `day` is in.
+    pub fn end(self, day: NaiveDate) -> NaiveDate {
+        match self {
+            Chunk::Year => NaiveDate::from_ymd(day.year(), 12, 31),
+            Chunk::Month if day.month() == 12 => NaiveDate::from_ymd(day.year(), 12, 31),
+            Chunk::Month => NaiveDate::from_ymd(day.year(), day.month() + 1, 1).pred(),
+        }
+    }
+
+    /// The chunks from `first_day` to `last_day` (inclusive) as their first and last day, where
+    /// the first and last chunk are cut short at `first_day` and `last_day`.
+    pub fn windows(self, first_day: NaiveDate, last_day: NaiveDate) -> Vec<(NaiveDate, NaiveDate)> {
+        let mut windows = Vec::new();
+        let mut start = first_day;
+        while start <= last_day {
+            let end = std::cmp::min(self.end(start), last_day);
+            windows.push((start, end));
+            start = end.succ();
+        }
+        windows
+    }
+}
+
+/// Details of a transaction which only the v2 endpoint returns.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionDetails {
+    /// Id of the transaction at Sbanken.
+    pub transaction_id: Option<String>,
+    pub card_details: Option<CardDetails>,
+    #[serde(alias = "transactionDetail")]
+    pub transaction_details: Option<PaymentDetails>,
+}
+
+/// Merchant and original currency of a card payment.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CardDetails {
+    pub merchant_name: Option<String>,
+    pub merchant_city: Option<String>,
+    /// Merchant category code (MCC), e.g. "5411" for groceries.
+    pub merchant_category_code: Option<String>,
+    pub merchant_category_description: Option<String>,
+    pub original_currency_code: Option<String>,
+    /// Amount in the original currency.
+    pub currency_amount: Option<f64>,
+}
+
+/// Counterparty of a giro payment or transfer.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentDetails {
+    pub receiver_name: Option<String>,
+    pub payer_name: Option<String>,
+}
+
+impl TransactionDetails {
+    /// Name of the merchant or other party of the transaction, if Sbanken knows it.
+    pub fn counterparty(&self) -> Option<&str> {
+        let card = self
+            .card_details
+            .as_ref()
+            .and_then(|card| card.merchant_name.as_deref());
+        let payment = self.transaction_details.as_ref().and_then(|payment| {
+            payment
+                .receiver_name
+                .as_deref()
+                .or_else(|| payment.payer_name.as_deref())
+        });
+
+        card.or(payment)
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+    }
+}
+
+/// Transactions of the Sbanken account `account_id` between `start` and `end` (inclusive), with
+/// their details if they come from the v2 endpoint.
+///
+/// The part of the range which is older than the regular endpoints reach is fetched from the
+/// archive, oldest transactions first like the regular endpoints return them.
+pub async fn fetch(
+    bridge: &Bridge,
+    account_id: &str,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<Vec<(TransactionV1, Option<TransactionDetails>)>> {
+    let window_start =
+        chrono::Local::today().naive_local() - chrono::Duration::days(LIVE_WINDOW_DAYS);
+    if start >= window_start {
+        return fetch_live(bridge, account_id, start, end).await;
+    }
+
+    let archive_end = std::cmp::min(end, window_start.pred());
+    let mut transactions = fetch_v2(
+        bridge,
+        &format!("/api/v2/Transactions/archive/{}", account_id),
+        start,
+        archive_end,
+    )
+    .await
+    .context("unable to get archived transactions")?;
+    if bridge.sbanken_api == ApiVersion::V1 {
+        // Keep the transactions the same as if they came from the v1 endpoint
+        for (_, details) in &mut transactions {
+            *details = None;
+        }
+    }
+
+    if end >= window_start {
+        transactions.extend(fetch_live(bridge, account_id, window_start, end).await?);
+    }
+
+    Ok(transactions)
+}
+
+async fn fetch_live(
+    bridge: &Bridge,
+    account_id: &str,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<Vec<(TransactionV1, Option<TransactionDetails>)>> {
+    match bridge.sbanken_api {
+        ApiVersion::V1 => {
+            let mut items = Vec::new();
+            loop {
+                let index = items.len() as i32;
+                let transactions = bridge
+                    .with_sbanken_token(|| async {
+                        let sbanken = bridge.sbanken();
+                        retry::retry(Api::Sbanken, "getting transactions", || {
+                            sbanken.transactions_api().get_transactions(
+                                account_id,
+                                Some(&bridge.sbanken_customer_id.expose_secret()),
+                                Some(start.format(DATE_FORMAT).to_string()),
+                                Some(end.format(DATE_FORMAT).to_string()),
+                                Some(index),
+                                Some(MAX_TRANSACTIONS),
+                            )
+                        })
+                        .await
+                    })
+                    .await
+                    .context("unable to get transactions")?;
+                if transactions.is_error.unwrap_or(true) {
+                    return Err(anyhow!(
+                        "unable to get transactions: {}",
+                        transactions.error_message.unwrap_or_default()
+                    ));
+                }
+
+                let page = transactions.items.unwrap_or_default();
+                let fetched = page.len();
+                items.extend(page);
+                if !bank::has_more(
+                    items.len(),
+                    fetched,
+                    transactions.available_items,
+                    MAX_TRANSACTIONS,
+                ) {
+                    break;
+                }
+            }
+
+            Ok(items
+                .into_iter()
+                .map(|transaction| (transaction, None))
+                .collect())
+        }
+        ApiVersion::V2 => {
+            fetch_v2(
+                bridge,
+                &format!("/api/v2/Transactions/{}", account_id),
+                start,
+                end,
+            )
+            .await
+        }
+    }
+}
+
+/// Transactions from the v2 endpoint at `path`, which is either the regular or the archive one.
+async fn fetch_v2(
+    bridge: &Bridge,
+    path: &str,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<Vec<(TransactionV1, Option<TransactionDetails>)>> {
+    let items: Vec<serde_json::Value> = bank::get_all_items(
+        bridge,
+        path,
+        &[
+            ("startDate", start.format(DATE_FORMAT).to_string()),
+            ("endDate", end.format(DATE_FORMAT).to_string()),
+        ],
+        MAX_TRANSACTIONS,
+    )
+    .await
+    .context("unable to get transactions")?;
+
+    // The fields shared with v1 keep their names, so fingerprints stay the same across versions
+    items
+        .into_iter()
+        .map(|item| {
+            let details = serde_json::from_value(item.clone())
+                .context("invalid transaction details from sbanken")?;
+            let transaction =
+                serde_json::from_value(item).context("invalid transaction from sbanken")?;
+            Ok((transaction, Some(details)))
+        })
+        .collect()
+}
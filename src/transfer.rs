@@ -0,0 +1,210 @@
+//! Transfers between own Sbanken accounts made from the command line, which are recorded in
+//! Firefly right away and replaced by the synced transfer once it is booked.
+
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDate;
+use firefly_iii::models::AccountRead;
+use sbanken::models::AccountV1;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::bank;
+use crate::convert::convert_transfer_request;
+use crate::error::Required;
+use crate::firefly::{self, Stored};
+use crate::money::Money;
+use crate::state::{RecordedTransfer, State};
+use crate::sync::{self, find_account, Bridge};
+
+/// Tag on the transactions of transfers made with the `transfer` command.
+pub const RECORDED_TAG: &str = "sbanken-transfer";
+
+/// Longest message Sbanken accepts on a transfer.
+const MAX_MESSAGE_LENGTH: usize = 30;
+
+/// Most days a transfer takes to be booked, after which the synced transfer is not matched with
+/// the recorded one anymore.
+const MAX_BOOKING_DAYS: i64 = 5;
+
+/// A transfer between two accounts of the same customer, as sent to Sbanken.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferRequest {
+    pub from_account_id: String,
+    pub to_account_id: String,
+    pub amount: Money,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// The account with the name or account number `account`.
+fn find_sbanken_account<'a>(accounts: &'a [AccountV1], account: &str) -> Option<&'a AccountV1> {
+    accounts.iter().find(|candidate| {
+        candidate.name.as_deref() == Some(account)
+            || candidate
+                .account_number
+                .as_deref()
+                .map(sync::normalize_account_number)
+                == Some(sync::normalize_account_number(account))
+    })
+}
+
+fn mirrored<'a>(
+    firefly_accounts: &'a [AccountRead],
+    account: &AccountV1,
+) -> Result<&'a AccountRead> {
+    find_account(firefly_accounts, account).ok_or_else(|| {
+        anyhow!(
+            "account '{}' is not mirrored in firefly, sync it first",
+            account.name.as_deref().unwrap_or_default()
+        )
+    })
+}
+
+/// Transfer `amount` kroner from the account `from` to the account `to` (by name or account
+/// number), and record the transfer in Firefly. Only the accounts are looked up on a dry run.
+///
+/// The accounts must belong to the same customer, which is the first of `bridges` that has
+/// `from`.
+pub async fn transfer(
+    bridges: &[Bridge],
+    state: &mut State,
+    from: &str,
+    to: &str,
+    amount: Money,
+    message: Option<String>,
+    dry_run: bool,
+) -> Result<()> {
+    if amount.is_negative() || amount.decimal().is_zero() {
+        return Err(anyhow!("the amount to transfer must be positive"));
+    }
+    if let Some(message) = &message {
+        if message.chars().count() > MAX_MESSAGE_LENGTH {
+            return Err(anyhow!(
+                "the message may be at most {} characters",
+                MAX_MESSAGE_LENGTH
+            ));
+        }
+    }
+
+    let mut found = None;
+    for bridge in bridges {
+        let accounts = sync::list_sbanken_accounts(bridge).await?;
+        if find_sbanken_account(&accounts, from).is_some() {
+            found = Some((bridge, accounts));
+            break;
+        }
+    }
+    let (bridge, sbanken_accounts) =
+        found.ok_or_else(|| anyhow!("no sbanken account named or numbered '{}'", from))?;
+    let from_account = find_sbanken_account(&sbanken_accounts, from).unwrap();
+    let to_account = find_sbanken_account(&sbanken_accounts, to).ok_or_else(|| {
+        anyhow!(
+            "no sbanken account named or numbered '{}' of the same customer as '{}'",
+            to,
+            from
+        )
+    })?;
+
+    let firefly_accounts = sync::list_firefly_accounts(bridge).await?;
+    let firefly_from = mirrored(&firefly_accounts, from_account)?;
+    let firefly_to = mirrored(&firefly_accounts, to_account)?;
+
+    let request = TransferRequest {
+        from_account_id: from_account.account_id.clone().required("account id")?,
+        to_account_id: to_account.account_id.clone().required("account id")?,
+        amount,
+        message,
+    };
+
+    if dry_run {
+        info!(
+            "Would transfer {} from '{}' to '{}'",
+            amount,
+            from_account.name.as_deref().unwrap_or_default(),
+            to_account.name.as_deref().unwrap_or_default()
+        );
+        return Ok(());
+    }
+
+    info!(
+        "Transferring {} from '{}' to '{}'...",
+        amount,
+        from_account.name.as_deref().unwrap_or_default(),
+        to_account.name.as_deref().unwrap_or_default()
+    );
+    bank::post(bridge, "/api/v1/Transfers", &request)
+        .await
+        .context("unable to make transfer in sbanken")?;
+    info!("Transferred {} in Sbanken", amount);
+
+    let day = chrono::Local::today().naive_local();
+    let firefly_transaction =
+        convert_transfer_request(firefly_from, firefly_to, &request, day, &bridge.conversion)?;
+    match firefly::store_transaction(
+        &bridge.firefly_config,
+        &firefly_transaction,
+        bridge.fire_webhooks,
+    )
+    .await
+    .context("transferred in sbanken, but unable to record the transfer in firefly")?
+    {
+        Stored::Created(id) => {
+            info!("Recorded transfer in Firefly <transaction {}>", id);
+            state.recorded_transfers.push(RecordedTransfer {
+                firefly_id: id,
+                day,
+                request,
+            });
+        }
+        Stored::Duplicate => info!("Transfer is already in Firefly"),
+    }
+
+    Ok(())
+}
+
+/// Take the recorded transfer which the synced transfer from `from_account_id` to
+/// `to_account_id` is the booked version of, if any.
+pub fn take_booked(
+    state: &mut State,
+    from_account_id: &str,
+    to_account_id: &str,
+    amount: Money,
+    day: NaiveDate,
+) -> Option<RecordedTransfer> {
+    let mut best: Option<(usize, i64)> = None;
+    for (index, recorded) in state.recorded_transfers.iter().enumerate() {
+        let request = &recorded.request;
+        if request.from_account_id != from_account_id
+            || request.to_account_id != to_account_id
+            || request.amount != amount.abs()
+        {
+            continue;
+        }
+        let days = (day - recorded.day).num_days();
+        if days < 0 || days > MAX_BOOKING_DAYS {
+            continue;
+        }
+        if best.map_or(true, |(_, best_days)| days < best_days) {
+            best = Some((index, days));
+        }
+    }
+
+    best.map(|(index, _)| state.recorded_transfers.remove(index))
+}
+
+/// Delete the transaction of a recorded transfer from Firefly. Errors are only logged, as the
+/// transaction can be deleted by hand.
+pub async fn remove(bridge: &Bridge, recorded: &RecordedTransfer) {
+    if bridge.dry_run {
+        return;
+    }
+
+    let path = format!("/transactions/{}", recorded.firefly_id);
+    if let Err(e) = firefly::delete(&bridge.firefly_config, &path).await {
+        warn!(
+            "Unable to delete recorded transfer <transaction {}>: {:?}",
+            recorded.firefly_id, e
+        );
+    }
+}